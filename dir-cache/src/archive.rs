@@ -0,0 +1,228 @@
+//! Packaging a cache into a tar archive and unpacking one into a cache, see
+//! [`crate::DirCache::export`] and [`crate::DirCache::import`].
+//! Each entry is archived as its manifest followed by its generation files, under the same
+//! relative directory as in the cache. Every generation in an archived manifest has a checksum
+//! recorded, so that the archive can be checked when it's unpacked.
+use crate::error::{Error, Result};
+use crate::opts::{ConflictPolicy, DirCacheOpts, UnknownEncodingOpt};
+use crate::path_util::SafePathJoin;
+use crate::time::unix_time_now;
+use crate::{checksum, manifest, DirCacheInner, MANIFEST_FILE};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// An archived entry being read, generations are collected until the next manifest
+struct ArchivedEntry {
+    key: PathBuf,
+    manifest: Vec<u8>,
+    generations: Vec<Vec<u8>>,
+}
+
+impl DirCacheInner {
+    pub(crate) fn export(&mut self, archive: &Path, opts: DirCacheOpts) -> Result<()> {
+        self.sync_to_disk(opts)?;
+        self.load_all(&opts)?;
+        let file = File::create(archive).map_err(|e| {
+            Error::WriteContent(format!("Failed to create archive at {archive:?}"), Some(e))
+        })?;
+        let mut builder = tar::Builder::new(BufWriter::new(file));
+        let now = unix_time_now()?;
+        let mut entries = self
+            .store
+            .iter()
+            .filter(|(_, entry)| !entry.expired(opts.generation_opt, now))
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        for (key, entry) in entries {
+            let dir = self.base.safe_join(key)?;
+            let mut generations = entry.standalone_generations();
+            let mut contents = Vec::with_capacity(generations.len());
+            for (ind, gen) in generations.iter_mut().enumerate() {
+                let path = dir.safe_join(format!("dir-cache-generation-{ind}"))?;
+                let content = self.storage.read_file(&path).map_err(|e| {
+                    Error::ReadContent(
+                        format!("Failed to read generation {ind} to export from {path:?}"),
+                        Some(e),
+                    )
+                })?;
+                gen.verify(&content, &path)?;
+                gen.checksum = Some(checksum(&content));
+                contents.push(content);
+            }
+            let manifest = manifest::serialize(&generations, entry.key.as_deref());
+            append(
+                &mut builder,
+                &key.safe_join(MANIFEST_FILE)?,
+                &manifest,
+                archive,
+            )?;
+            for (ind, content) in contents.iter().enumerate() {
+                let path = key.safe_join(format!("dir-cache-generation-{ind}"))?;
+                append(&mut builder, &path, content, archive)?;
+            }
+        }
+        builder
+            .into_inner()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| {
+                Error::WriteContent(format!("Failed to finish archive at {archive:?}"), Some(e))
+            })
+    }
+}
+
+impl DirCacheInner {
+    pub(crate) fn import(
+        &mut self,
+        archive: &Path,
+        conflict_policy: ConflictPolicy,
+        opts: DirCacheOpts,
+    ) -> Result<usize> {
+        let read_err =
+            |e| Error::ReadContent(format!("Failed to read archive at {archive:?}"), Some(e));
+        let file = File::open(archive).map_err(read_err)?;
+        let mut tar = tar::Archive::new(BufReader::new(file));
+        let mut imported = 0;
+        let mut pending: Option<ArchivedEntry> = None;
+        for file in tar.entries().map_err(read_err)? {
+            let mut file = file.map_err(read_err)?;
+            let path = file.path().map_err(read_err)?.into_owned();
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).map_err(read_err)?;
+            let (key, name) = split_archived(&path, archive)?;
+            if name == MANIFEST_FILE {
+                let next = ArchivedEntry {
+                    key,
+                    manifest: content,
+                    generations: Vec::new(),
+                };
+                if let Some(done) = pending.replace(next) {
+                    imported += usize::from(self.import_entry(done, conflict_policy, &opts)?);
+                }
+                continue;
+            }
+            // Generations follow their manifest in order
+            match pending.as_mut() {
+                Some(entry)
+                    if entry.key == key
+                        && name
+                            .strip_prefix("dir-cache-generation-")
+                            .and_then(|ind| ind.parse::<usize>().ok())
+                            == Some(entry.generations.len()) =>
+                {
+                    entry.generations.push(content);
+                }
+                _ => {
+                    return Err(Error::ParseMetadata(format!(
+                        "Unexpected {path:?} in archive at {archive:?}"
+                    )));
+                }
+            }
+        }
+        if let Some(done) = pending {
+            imported += usize::from(self.import_entry(done, conflict_policy, &opts)?);
+        }
+        Ok(imported)
+    }
+
+    /// Writes an archived entry into the cache, unless there's a conflicting key that should
+    /// be kept. Returns whether it was written
+    fn import_entry(
+        &mut self,
+        archived: ArchivedEntry,
+        conflict_policy: ConflictPolicy,
+        opts: &DirCacheOpts,
+    ) -> Result<bool> {
+        let dir = self.base.safe_join(&archived.key)?;
+        let (_version, generations, _key) = manifest::parse(
+            &archived.manifest,
+            &dir,
+            &opts.generation_opt,
+            UnknownEncodingOpt::Strict,
+        )?;
+        if generations.len() != archived.generations.len() {
+            return Err(Error::ParseMetadata(format!(
+                "Archived manifest of {:?} lists {} generations, but {} were archived",
+                archived.key,
+                generations.len(),
+                archived.generations.len()
+            )));
+        }
+        for (ind, (gen, content)) in generations.iter().zip(&archived.generations).enumerate() {
+            gen.verify(
+                content,
+                &dir.safe_join(format!("dir-cache-generation-{ind}"))?,
+            )?;
+        }
+        let Some(updated) = generations.front().map(|gen| gen.age) else {
+            return Ok(false);
+        };
+        if !self.resolve_conflict(&archived.key, updated, conflict_policy, opts)? {
+            return Ok(false);
+        }
+        self.storage.ensure_dir(&dir)?;
+        for (ind, content) in archived.generations.iter().enumerate() {
+            let path = dir.safe_join(format!("dir-cache-generation-{ind}"))?;
+            self.storage
+                .write_file(&path, content, opts.durability_opt)
+                .map_err(|e| {
+                    Error::WriteContent(
+                        format!("Failed to write imported generation to {path:?}"),
+                        Some(e),
+                    )
+                })?;
+        }
+        // Written last, the entry isn't there until its manifest is
+        let manifest_path = dir.safe_join(MANIFEST_FILE)?;
+        self.storage
+            .write_file(&manifest_path, &archived.manifest, opts.durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(
+                    format!("Failed to write imported manifest to {manifest_path:?}"),
+                    Some(e),
+                )
+            })?;
+        self.invalidate_index()?;
+        let Some(entry) = self.read_entry(&dir, opts)? else {
+            return Ok(false);
+        };
+        self.store_insert(archived.key, entry, opts);
+        Ok(true)
+    }
+}
+
+/// Splits the path of a file in an archive into the key of its entry and its file name,
+/// refusing anything that could end up outside of the cache
+fn split_archived<'a>(path: &'a Path, archive: &Path) -> Result<(PathBuf, &'a str)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|_| {
+            path.components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        })
+        .ok_or_else(|| {
+            Error::DangerousKey(format!("Archive at {archive:?} contains the path {path:?}"))
+        })?;
+    let key = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok((key, name))
+}
+
+fn append<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    content: &[u8],
+    archive: &Path,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, path, content)
+        .map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to add {path:?} to archive at {archive:?}"),
+                Some(e),
+            )
+        })
+}
@@ -0,0 +1,129 @@
+//! Staging inserts and removes to write them together, see [`crate::DirCache::batch`].
+use crate::blob::release_blobs;
+use crate::error::Result;
+use crate::opts::{DirCacheOpts, MemPushOpt};
+use crate::path_util::SafePathJoin;
+use crate::{DirCache, DirCacheInner, ValueMeta, BLOB_DIR};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Inserts and removes staged in memory, that are applied in order when the batch is
+/// committed, writing each touched key once.
+/// Nothing is applied if the batch is dropped without being committed.
+#[must_use = "nothing is applied until the batch is committed"]
+pub struct Batch<'a> {
+    dir_cache: &'a mut DirCache,
+    ops: Vec<BatchOp>,
+}
+
+enum BatchOp {
+    Insert(PathBuf, Vec<u8>),
+    Remove(PathBuf),
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(dir_cache: &'a mut DirCache) -> Self {
+        Self {
+            dir_cache,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Stage `content` as the value of `key`
+    pub fn insert(&mut self, key: &Path, content: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Insert(key.to_path_buf(), content));
+        self
+    }
+
+    /// Stage the removal of `key`
+    pub fn remove(&mut self, key: &Path) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.to_path_buf()));
+        self
+    }
+
+    /// The number of staged inserts and removes
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether nothing has been staged
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Applies the staged inserts and removes in order, then writes each key that was inserted
+    /// once, with a single manifest write, retaining values in memory according to the
+    /// [`MemPushOpt`]. A key inserted more than once only gets the last value as a new generation.
+    /// With [`MemPushOpt::MemoryOnly`], values are left in memory until the cache is synced.
+    /// # Errors
+    /// Same as [`DirCache::insert`] and [`DirCache::remove`], values staged before a failing
+    /// insert or remove are still written
+    pub fn commit(self) -> Result<()> {
+        let Batch { dir_cache, ops } = self;
+        let opts = dir_cache.opts;
+        let inner = &mut dir_cache.inner;
+        let mut inserted = Vec::new();
+        let applied = ops.into_iter().try_for_each(|op| match op {
+            BatchOp::Insert(key, content) => {
+                let key = inner.stored_key(opts.encode_key(&key), &opts)?;
+                inner.insert_opt(
+                    &key,
+                    Cow::Owned(content),
+                    ValueMeta::default(),
+                    opts.with_mem_push_opt(MemPushOpt::MemoryOnly),
+                )?;
+                inserted.push(key.into_owned());
+                Ok(())
+            }
+            BatchOp::Remove(key) => {
+                let key = inner.stored_key(opts.encode_key(&key), &opts)?;
+                inner.remove(&key, &opts).map(|_| ())
+            }
+        });
+        if matches!(opts.mem_push_opt, MemPushOpt::MemoryOnly) {
+            return applied;
+        }
+        let written = inner.write_staged(inserted, opts);
+        applied.and(written)
+    }
+}
+
+impl DirCacheInner {
+    /// Writes the values of `keys` that are only held in memory, like a sync restricted to them
+    fn write_staged(&mut self, mut keys: Vec<PathBuf>, opts: DirCacheOpts) -> Result<()> {
+        keys.sort_unstable();
+        keys.dedup();
+        let blob_dir = self.base.safe_join(BLOB_DIR)?;
+        let mut prev_blobs = Vec::new();
+        let mut written = Vec::with_capacity(keys.len());
+        for key in keys {
+            // Removed after it was inserted, or already written
+            let Some(entry) = self.store.get_mut(&key).filter(|entry| {
+                entry
+                    .in_mem
+                    .as_ref()
+                    .is_some_and(|in_mem| !in_mem.committed)
+            }) else {
+                continue;
+            };
+            let dir = self.base.safe_join(&key)?;
+            self.storage.ensure_dir(&dir)?;
+            prev_blobs.extend(entry.blobs());
+            let before = entry.in_mem_size();
+            let dumped = entry.dump_in_mem(&self.storage, &dir, &blob_dir, opts);
+            self.in_mem_bytes = self.in_mem_bytes - before + entry.in_mem_size();
+            dumped?;
+            entry.reweigh(&key, opts.disk_budget_opt, &mut self.disk_usage);
+            self.stats.disk_write(entry.written_size());
+            written.push(key);
+        }
+        release_blobs(&self.storage, &blob_dir, prev_blobs)?;
+        for key in written {
+            self.mirror(&key, &opts)?;
+        }
+        self.enforce_mem_budget(opts.mem_budget_opt, None);
+        self.enforce_disk_budget(&opts, None)
+    }
+}
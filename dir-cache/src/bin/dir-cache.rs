@@ -0,0 +1,244 @@
+//! Inspect and maintain a dir-cache from the command line
+use dir_cache::error::Error;
+use dir_cache::opts::{
+    CacheOpenOptions, DirCacheOpts, DirOpenOpt, GenerationOpt, PersistedOptsOpt,
+};
+use dir_cache::DirCache;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: dir-cache [--max-generations <n>] <cache-dir> <command>
+
+Commands:
+  list        List every key with its size on disk, generations, and age
+  get <key>   Write the value of <key> to stdout
+  inspect <key>
+              List the generations in the manifest of <key>, without opening the cache
+  rm <key>    Remove <key>
+  stat        Summarize the cache
+  vacuum      Remove everything on disk that's no longer needed
+  verify      Check the cache for inconsistencies
+
+Options:
+  --max-generations <n>  Generations to keep when vacuuming, all of them if not given.
+                         The options recorded with the cache take precedence";
+
+enum Command {
+    List,
+    Get(PathBuf),
+    Inspect(PathBuf),
+    Remove(PathBuf),
+    Stat,
+    Vacuum,
+    Verify,
+}
+
+enum CliError {
+    Cache(Error),
+    Io(std::io::Error),
+}
+
+impl From<Error> for CliError {
+    fn from(e: Error) -> Self {
+        Self::Cache(e)
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Cache(e) => Display::fmt(e, f),
+            CliError::Io(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+struct Args {
+    cache_dir: PathBuf,
+    max_generations: NonZeroUsize,
+    command: Command,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut max_generations = NonZeroUsize::MAX;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--max-generations" {
+            max_generations = args.next()?.parse().ok()?;
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut positional = positional.into_iter();
+    let cache_dir = PathBuf::from(positional.next()?);
+    let command = match positional.next()?.as_str() {
+        "list" => Command::List,
+        "get" => Command::Get(PathBuf::from(positional.next()?)),
+        "inspect" => Command::Inspect(PathBuf::from(positional.next()?)),
+        "rm" => Command::Remove(PathBuf::from(positional.next()?)),
+        "stat" => Command::Stat,
+        "vacuum" => Command::Vacuum,
+        "verify" => Command::Verify,
+        _ => return None,
+    };
+    positional.next().is_none().then_some(Args {
+        cache_dir,
+        max_generations,
+        command,
+    })
+}
+
+fn main() -> ExitCode {
+    let Some(args) = parse_args(std::env::args().skip(1)) else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+    match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("dir-cache: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<ExitCode, CliError> {
+    let mut generation_opt = GenerationOpt::default();
+    generation_opt.max_generations = args.max_generations;
+    if let Command::Inspect(key) = &args.command {
+        return inspect(&args.cache_dir, key, &generation_opt);
+    }
+    // Vacuuming with anything but the recorded options could keep expired entries around
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(generation_opt)
+        .open(
+            &args.cache_dir,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+                .with_persisted_opts_opt(PersistedOptsOpt::Adopt),
+        )?;
+    for invalid in dc.invalid_entries() {
+        eprintln!(
+            "dir-cache: skipped invalid entry {:?}: {}",
+            invalid.key(),
+            invalid.reason()
+        );
+    }
+    match args.command {
+        Command::List => list(&mut dc)?,
+        Command::Get(key) => return get(&mut dc, &key),
+        Command::Inspect(_) => unreachable!("inspected without opening the cache"),
+        Command::Remove(key) => {
+            if !dc.remove(&key)? {
+                eprintln!("dir-cache: no key {key:?}");
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Stat => stat(&mut dc)?,
+        Command::Vacuum => dc.vacuum()?,
+        Command::Verify => {
+            let report = dc.verify()?;
+            for issue in report.issues() {
+                println!("{issue:?}");
+            }
+            println!(
+                "{} entries checked, {} issues",
+                report.entries_checked(),
+                report.issues().len()
+            );
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn list(dc: &mut DirCache) -> Result<(), CliError> {
+    let mut metas = dc.iter_meta()?.collect::<Vec<_>>();
+    metas.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    let mut out = std::io::stdout().lock();
+    for (key, meta) in metas {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}s",
+            key.display(),
+            meta.disk_size() + meta.pooled_size(),
+            meta.generations(),
+            meta.age().as_secs()
+        )?;
+    }
+    Ok(())
+}
+
+fn get(dc: &mut DirCache, key: &Path) -> Result<ExitCode, CliError> {
+    let Some(value) = dc.get(key)? else {
+        eprintln!("dir-cache: no key {key:?}");
+        return Ok(ExitCode::FAILURE);
+    };
+    let mut out = std::io::stdout().lock();
+    out.write_all(&value)?;
+    out.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn inspect(
+    cache_dir: &Path,
+    key: &Path,
+    generation_opt: &GenerationOpt,
+) -> Result<ExitCode, CliError> {
+    // Keys never leave the cache directory
+    if !key
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        eprintln!("dir-cache: invalid key {key:?}");
+        return Ok(ExitCode::FAILURE);
+    }
+    let mut dir = cache_dir.to_path_buf();
+    dir.push(key);
+    let Some(entry) = dir_cache::inspect::read_entry(&dir, generation_opt)? else {
+        eprintln!("dir-cache: no manifest for {key:?}");
+        return Ok(ExitCode::FAILURE);
+    };
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "manifest version: {}", entry.manifest_version())?;
+    for gen in entry.generations() {
+        let size = gen
+            .size()
+            .map_or_else(|| "missing".to_string(), |size| size.to_string());
+        writeln!(
+            out,
+            "{}\t{}\t{:?}\t{}s{}{}",
+            gen.index(),
+            size,
+            gen.encoding(),
+            gen.age().as_secs(),
+            if gen.is_delta() { "\tdelta" } else { "" },
+            if gen.is_negative() { "\tnegative" } else { "" },
+        )?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn stat(dc: &mut DirCache) -> Result<(), CliError> {
+    let summary = dc.summary()?;
+    println!("path: {}", dc.path().display());
+    println!("entries: {}", summary.entries());
+    println!("disk bytes: {}", summary.disk_bytes());
+    if let Some(oldest) = summary.oldest_age() {
+        println!("oldest: {}s", oldest.as_secs());
+    }
+    if let Some(newest) = summary.newest_age() {
+        println!("newest: {}s", newest.as_secs());
+    }
+    Ok(())
+}
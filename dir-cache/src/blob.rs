@@ -0,0 +1,85 @@
+//! The pool of content-addressed values, see [`crate::opts::DirCacheOpts::with_content_addressed`].
+//! Each value is stored once, named by its hash, and generations holding it are hardlinks to it.
+//! A value is referenced for as long as anything besides the pool links to it, so the pool
+//! doesn't need any bookkeeping of its own.
+use crate::disk::{FileMeta, FileObjectExists, Storage};
+use crate::error::{Error, Result};
+use crate::opts::DurabilityOpt;
+use crate::path_util::SafePathJoin;
+use std::path::{Path, PathBuf};
+
+/// Makes sure `content` is in the pool, returns its id and the path to link to.
+/// Returns `None` if a different value is already stored under the same id, which should
+/// never happen, but then it has to be stored per entry
+pub(crate) fn pool_blob(
+    storage: &Storage,
+    blob_dir: &Path,
+    content: &[u8],
+    durability: DurabilityOpt,
+) -> Result<Option<(u128, PathBuf)>> {
+    storage.ensure_dir(blob_dir)?;
+    let id = xxhash_rust::xxh3::xxh3_128(content);
+    let path = blob_dir.safe_join(blob_name(id))?;
+    match storage.read_raw_if_present(&path)? {
+        Some(pooled) if pooled == content => {}
+        Some(_) => return Ok(None),
+        None => {
+            storage
+                .write_file(&path, content, durability)
+                .map_err(|e| {
+                    Error::WriteContent(format!("Failed to write blob to {path:?}"), Some(e))
+                })?;
+        }
+    }
+    Ok(Some((id, path)))
+}
+
+/// Removes the values in `ids` from the pool if nothing links to them anymore
+pub(crate) fn release_blobs<I: IntoIterator<Item = u128>>(
+    storage: &Storage,
+    blob_dir: &Path,
+    ids: I,
+) -> Result<()> {
+    for id in ids {
+        let path = blob_dir.safe_join(blob_name(id))?;
+        match storage.metadata(&path) {
+            Ok(md) if unreferenced(&md) => storage.ensure_removed_file(&path)?,
+            Ok(_) => {}
+            // Already released through another generation
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(Error::ReadContent(
+                    format!("Failed to read blob metadata at {path:?}"),
+                    Some(e),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes every value in the pool that nothing links to
+pub(crate) fn sweep_blobs(storage: &Storage, blob_dir: &Path) -> Result<()> {
+    if storage.exists(blob_dir)? != FileObjectExists::AsDir {
+        return Ok(());
+    }
+    storage.read_all_in_dir(blob_dir, |entry_path, entry_metadata| {
+        let is_blob = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.len() == 32 && name.bytes().all(|b| b.is_ascii_hexdigit()));
+        if is_blob && entry_metadata.is_file() && unreferenced(entry_metadata) {
+            storage.ensure_removed_file(entry_path)?;
+        }
+        Ok(())
+    })
+}
+
+fn blob_name(id: u128) -> String {
+    format!("{id:032x}")
+}
+
+// Values are only pooled where link counts can be read
+fn unreferenced(md: &FileMeta) -> bool {
+    md.links().is_some_and(|links| links <= 1)
+}
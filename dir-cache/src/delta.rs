@@ -0,0 +1,181 @@
+//! A simple binary delta format, a delta describes how to build a target from a base
+//! as a sequence of copies out of the base and inserts of new data.
+//! Base blocks are indexed at fixed offsets and found in the target using a rolling hash, so
+//! changes that shift the rest of the data around are handled fine.
+use crate::error::{Error, Result};
+use crate::varint;
+use std::collections::HashMap;
+
+const BLOCK_SIZE: usize = 32;
+const HASH_MULTIPLIER: u64 = 0x0100_0000_01b3;
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// Create a delta that turns `base` into `target`
+pub(crate) fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    varint::write(&mut out, target.len() as u64);
+    if base.len() < BLOCK_SIZE || target.len() < BLOCK_SIZE {
+        write_insert(&mut out, target);
+        return out;
+    }
+    let mut index = HashMap::with_capacity(base.len() / BLOCK_SIZE);
+    for offset in (0..=base.len() - BLOCK_SIZE).step_by(BLOCK_SIZE) {
+        index
+            .entry(block_hash(&base[offset..offset + BLOCK_SIZE]))
+            .or_insert(offset);
+    }
+    // Multiplier for the outgoing byte of the rolling hash
+    let out_multiplier = (1..BLOCK_SIZE).fold(1u64, |acc, _| acc.wrapping_mul(HASH_MULTIPLIER));
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut hash = block_hash(&target[..BLOCK_SIZE]);
+    while pos + BLOCK_SIZE <= target.len() {
+        if let Some(&found) = index.get(&hash) {
+            if base[found..found + BLOCK_SIZE] == target[pos..pos + BLOCK_SIZE] {
+                let (mut start, mut base_start) = (pos, found);
+                while start > literal_start
+                    && base_start > 0
+                    && base[base_start - 1] == target[start - 1]
+                {
+                    start -= 1;
+                    base_start -= 1;
+                }
+                let mut len = pos - start + BLOCK_SIZE;
+                while base_start + len < base.len()
+                    && start + len < target.len()
+                    && base[base_start + len] == target[start + len]
+                {
+                    len += 1;
+                }
+                if start > literal_start {
+                    write_insert(&mut out, &target[literal_start..start]);
+                }
+                out.push(OP_COPY);
+                varint::write(&mut out, base_start as u64);
+                varint::write(&mut out, len as u64);
+                pos = start + len;
+                literal_start = pos;
+                if pos + BLOCK_SIZE <= target.len() {
+                    hash = block_hash(&target[pos..pos + BLOCK_SIZE]);
+                }
+                continue;
+            }
+        }
+        if pos + BLOCK_SIZE < target.len() {
+            hash = hash
+                .wrapping_sub(u64::from(target[pos]).wrapping_mul(out_multiplier))
+                .wrapping_mul(HASH_MULTIPLIER)
+                .wrapping_add(u64::from(target[pos + BLOCK_SIZE]));
+        }
+        pos += 1;
+    }
+    if literal_start < target.len() {
+        write_insert(&mut out, &target[literal_start..]);
+    }
+    out
+}
+
+/// Apply a delta created by [`diff`] to the same `base` it was created from
+pub(crate) fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut cursor = delta;
+    let target_len = read_len(&mut cursor)?;
+    let mut out = Vec::with_capacity(target_len);
+    while let Some((&op, rest)) = cursor.split_first() {
+        cursor = rest;
+        match op {
+            OP_COPY => {
+                let offset = read_len(&mut cursor)?;
+                let len = read_len(&mut cursor)?;
+                let copied = offset
+                    .checked_add(len)
+                    .and_then(|end| base.get(offset..end))
+                    .ok_or_else(|| malformed("copy out of bounds of the base"))?;
+                out.extend_from_slice(copied);
+            }
+            OP_INSERT => {
+                let len = read_len(&mut cursor)?;
+                if cursor.len() < len {
+                    return Err(malformed("insert longer than the remaining delta"));
+                }
+                let (inserted, rest) = cursor.split_at(len);
+                out.extend_from_slice(inserted);
+                cursor = rest;
+            }
+            _ => return Err(malformed("unknown operation")),
+        }
+    }
+    if out.len() != target_len {
+        return Err(malformed("reconstructed value has the wrong length"));
+    }
+    Ok(out)
+}
+
+fn block_hash(block: &[u8]) -> u64 {
+    block.iter().fold(0u64, |acc, b| {
+        acc.wrapping_mul(HASH_MULTIPLIER)
+            .wrapping_add(u64::from(*b))
+    })
+}
+
+fn write_insert(out: &mut Vec<u8>, data: &[u8]) {
+    out.push(OP_INSERT);
+    varint::write(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn read_len(cursor: &mut &[u8]) -> Result<usize> {
+    varint::read(cursor)
+        .and_then(|val| usize::try_from(val).ok())
+        .ok_or_else(|| malformed("invalid length"))
+}
+
+fn malformed(reason: &str) -> Error {
+    Error::DecodingError(format!("Malformed delta, {reason}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_small_change() {
+        let base = (0..10_000u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        let mut target = base.clone();
+        target[5_000..5_010].copy_from_slice(b"0123456789");
+        target.splice(100..100, b"inserted".iter().copied());
+        target.drain(30_000..30_100);
+        let delta = diff(&base, &target);
+        assert!(delta.len() < 200, "delta was {} bytes", delta.len());
+        assert_eq!(target, apply(&base, &delta).unwrap());
+    }
+
+    #[test]
+    fn round_trip_edges() {
+        for (base, target) in [
+            (b"".as_slice(), b"".as_slice()),
+            (b"short", b"other"),
+            (
+                b"",
+                b"some longer target that is definitely longer than a block",
+            ),
+            (
+                b"some longer base that is definitely longer than a block",
+                b"",
+            ),
+        ] {
+            assert_eq!(target, apply(base, &diff(base, target)).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let base = b"some longer base that is definitely longer than a block";
+        let delta = diff(base, base);
+        assert!(apply(b"short base", &delta).is_err());
+        assert!(apply(base, &delta[..delta.len() - 1]).is_err());
+        assert!(apply(base, &[1, 2]).is_err());
+    }
+}
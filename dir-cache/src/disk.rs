@@ -1,8 +1,20 @@
+//! Everything the cache does on disk goes through here, through the [`Storage`] the cache is kept
+//! in, a cache kept in SQLite is read and written through the `sqlite` module rather than the file
+//! system
 use crate::error::{Error, Result};
-use crate::MANIFEST_FILE;
-use std::fs::Metadata;
-use std::io::ErrorKind;
-use std::path::Path;
+use crate::opts::DurabilityOpt;
+#[cfg(feature = "sqlite")]
+use crate::path_util::SafePathJoin;
+#[cfg(feature = "sqlite")]
+use crate::sqlite;
+use crate::{JOURNAL_FILE, MANIFEST_FILE};
+use std::fs::{File, Metadata};
+#[cfg(feature = "sqlite")]
+use std::io::Cursor;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "sqlite")]
+use std::sync::Arc;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub(crate) enum FileObjectExists {
@@ -11,119 +23,606 @@ pub(crate) enum FileObjectExists {
     AsFile,
 }
 
-pub(crate) fn read_all_in_dir<F: FnMut(&Path, &Metadata) -> Result<()>>(
-    path: &Path,
-    mut func: F,
-) -> Result<()> {
-    for e in std::fs::read_dir(path)
-        .map_err(|e| Error::ReadContent(format!("Failed to read dir at {path:?}"), Some(e)))?
-    {
-        let entry = e.map_err(|e| {
-            Error::ReadContent(format!("Failed to read dir entry at {path:?}"), Some(e))
-        })?;
-        let entry_path = entry.path();
-        let entry_md = entry.metadata().map_err(|e| {
-            Error::ReadContent(
-                format!("Failed to read entry metadata for entry at {entry_path:?}"),
-                Some(e),
-            )
-        })?;
-        func(&entry_path, &entry_md)?;
-    }
-    Ok(())
+/// What's known about a file or directory, wherever it's stored
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct FileMeta {
+    kind: FileKind,
+    len: u64,
+    // The number of hardlinks to a file, where it can be told
+    links: Option<u64>,
 }
 
-#[inline]
-pub(crate) fn ensure_dir(path: &Path) -> Result<()> {
-    std::fs::create_dir_all(path).map_err(|e| {
-        Error::WriteContent(format!("Failed to ensure dir exists at {path:?}"), Some(e))
-    })?;
-    Ok(())
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
 }
 
-pub(crate) fn exists(path: &Path) -> Result<FileObjectExists> {
-    match std::fs::metadata(path) {
-        Ok(md) => {
-            if md.is_dir() {
-                Ok(FileObjectExists::AsDir)
-            } else if md.is_file() {
-                Ok(FileObjectExists::AsFile)
-            } else {
-                Err(Error::ReadContent(
-                    format!("Invalid metadataa at {path:?}, was symlink"),
-                    None,
-                ))
-            }
+impl FileMeta {
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn file(len: u64, links: u64) -> Self {
+        Self {
+            kind: FileKind::File,
+            len,
+            links: Some(links),
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn dir() -> Self {
+        Self {
+            kind: FileKind::Dir,
+            len: 0,
+            links: None,
         }
-        Err(e) if e.kind() == ErrorKind::NotFound => Ok(FileObjectExists::No),
-        Err(e) => Err(Error::ReadContent(
-            format!("Failed to read metadata to check path existence at {path:?}"),
-            Some(e),
-        )),
+    }
+
+    pub(crate) fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+
+    pub(crate) fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub(crate) fn is_symlink(&self) -> bool {
+        self.kind == FileKind::Symlink
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn links(&self) -> Option<u64> {
+        self.links
     }
 }
 
-pub(crate) fn read_metadata_if_present(path: &Path) -> Result<Option<String>> {
-    match std::fs::read_to_string(path) {
-        Ok(content) => Ok(Some(content)),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-        Err(e) => Err(Error::ReadContent(
-            format!("Failed to read metadata at {path:?}"),
-            Some(e),
-        )),
+impl From<&Metadata> for FileMeta {
+    fn from(md: &Metadata) -> Self {
+        let file_type = md.file_type();
+        let kind = if file_type.is_symlink() {
+            FileKind::Symlink
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else {
+            FileKind::Other
+        };
+        Self {
+            kind,
+            len: md.len(),
+            links: hardlinks(md),
+        }
     }
 }
-pub(crate) fn read_raw_if_present(path: &Path) -> Result<Option<Vec<u8>>> {
-    match std::fs::read(path) {
-        Ok(content) => Ok(Some(content)),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-        Err(e) => Err(Error::ReadContent(
-            format!("Failed to read file at {path:?}"),
-            Some(e),
-        )),
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn hardlinks(md: &Metadata) -> Option<u64> {
+    Some(std::os::unix::fs::MetadataExt::nlink(md))
+}
+
+#[cfg(not(unix))]
+fn hardlinks(_md: &Metadata) -> Option<u64> {
+    None
+}
+
+/// A file opened for reading, wherever it's stored
+pub(crate) enum FileReader {
+    File(File),
+    // Read whole, a row can't be read bit by bit without holding on to the database
+    #[cfg(feature = "sqlite")]
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            FileReader::File(file) => file.read(buf),
+            #[cfg(feature = "sqlite")]
+            FileReader::Memory(content) => content.read(buf),
+        }
     }
 }
 
-pub(crate) fn ensure_removed_file(path: &Path) -> Result<()> {
-    if let Err(e) = std::fs::remove_file(path) {
-        if e.kind() != ErrorKind::NotFound {
-            return Err(Error::DeleteContent(
-                format!("Failed to ensure file was removed at {path:?}"),
-                Some(e),
-            ));
+/// A file created for writing, wherever it's stored
+pub(crate) enum FileWriter {
+    File(File),
+    // Written whole when it's finished
+    #[cfg(feature = "sqlite")]
+    Memory {
+        db: Arc<sqlite::Db>,
+        path: PathBuf,
+        content: Vec<u8>,
+    },
+}
+
+impl FileWriter {
+    /// Finishes writing the file, flushing it to disk as `durability` requires
+    pub(crate) fn finish(self, durability: DurabilityOpt) -> std::io::Result<()> {
+        match self {
+            FileWriter::File(file) => match durability {
+                DurabilityOpt::None => Ok(()),
+                DurabilityOpt::FlushData => file.sync_data(),
+                DurabilityOpt::Full => file.sync_all(),
+            },
+            #[cfg(feature = "sqlite")]
+            FileWriter::Memory { db, path, content } => db.write(&path, &content, durability),
         }
     }
-    Ok(())
 }
 
-pub(crate) fn try_remove_dir(path: &Path) -> Result<()> {
-    let mut anything_left = false;
-    if exists(path)? == FileObjectExists::No {
-        return Ok(());
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            FileWriter::File(file) => file.write(buf),
+            #[cfg(feature = "sqlite")]
+            FileWriter::Memory { content, .. } => content.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            FileWriter::File(file) => file.flush(),
+            #[cfg(feature = "sqlite")]
+            FileWriter::Memory { .. } => Ok(()),
+        }
     }
-    read_all_in_dir(path, |entry_path, entry_metadata| {
-        if entry_metadata.is_file() {
-            let f_name = entry_path.file_name().ok_or_else(|| {
+}
+
+/// Where the files of a cache are kept, everything the cache does on disk goes through one.
+/// Paths outside of the cache, such as a mirror or a backup, are always on the file system
+#[derive(Clone, Default)]
+pub(crate) enum Storage {
+    #[default]
+    FileSystem,
+    // Every path is below the directory the database is in
+    #[cfg(feature = "sqlite")]
+    Sqlite(Arc<sqlite::Db>),
+}
+
+#[cfg_attr(not(feature = "sqlite"), allow(clippy::unused_self))]
+impl Storage {
+    /// Whether this is the file system, that files can be renamed and linked to and from
+    pub(crate) fn is_file_system(&self) -> bool {
+        matches!(self, Storage::FileSystem)
+    }
+
+    /// Whether `other` is this same storage, that files can be renamed between
+    fn same_as(&self, other: &Storage) -> bool {
+        match (self, other) {
+            (Storage::FileSystem, Storage::FileSystem) => true,
+            #[cfg(feature = "sqlite")]
+            (Storage::Sqlite(db), Storage::Sqlite(other)) => Arc::ptr_eq(db, other),
+            #[cfg(feature = "sqlite")]
+            _ => false,
+        }
+    }
+
+    pub(crate) fn read_all_in_dir<F: FnMut(&Path, &FileMeta) -> Result<()>>(
+        &self,
+        path: &Path,
+        mut func: F,
+    ) -> Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            let entries = db.read_dir(path).map_err(|e| {
+                Error::ReadContent(format!("Failed to read dir at {path:?}"), Some(e))
+            })?;
+            for (name, md) in entries {
+                func(&path.safe_join(name)?, &md)?;
+            }
+            return Ok(());
+        }
+        for e in std::fs::read_dir(path)
+            .map_err(|e| Error::ReadContent(format!("Failed to read dir at {path:?}"), Some(e)))?
+        {
+            let entry = e.map_err(|e| {
+                Error::ReadContent(format!("Failed to read dir entry at {path:?}"), Some(e))
+            })?;
+            let entry_path = entry.path();
+            let entry_md = entry.metadata().map_err(|e| {
                 Error::ReadContent(
-                    format!("Entry to maybe remove has no file name at {entry_path:?}"),
-                    None,
+                    format!("Failed to read entry metadata for entry at {entry_path:?}"),
+                    Some(e),
                 )
             })?;
-            // Try to be restrictive in what's removed
-            if let Some(valid_utf8) = f_name.to_str() {
-                if valid_utf8 == MANIFEST_FILE || valid_utf8.starts_with("dir-cache-generation-") {
-                    ensure_removed_file(entry_path)?;
-                    return Ok(());
+            func(&entry_path, &FileMeta::from(&entry_md))?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn ensure_dir(&self, path: &Path) -> Result<()> {
+        #[cfg(feature = "sqlite")]
+        let created = match self {
+            Storage::FileSystem => std::fs::create_dir_all(path),
+            Storage::Sqlite(db) => db.create_dir_all(path),
+        };
+        #[cfg(not(feature = "sqlite"))]
+        let created = std::fs::create_dir_all(path);
+        created.map_err(|e| {
+            Error::WriteContent(format!("Failed to ensure dir exists at {path:?}"), Some(e))
+        })?;
+        Ok(())
+    }
+
+    /// The [`FileMeta`] of what's at `path`, following symlinks
+    pub(crate) fn metadata(&self, path: &Path) -> std::io::Result<FileMeta> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.metadata(path);
+        }
+        std::fs::metadata(path).map(|md| FileMeta::from(&md))
+    }
+
+    /// The canonical form of `path`, with symlinks resolved
+    pub(crate) fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        // There are no symlinks to resolve in SQLite storage
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(_) = self {
+            return Ok(path.to_path_buf());
+        }
+        std::fs::canonicalize(path)
+    }
+
+    pub(crate) fn exists(&self, path: &Path) -> Result<FileObjectExists> {
+        match self.metadata(path) {
+            Ok(md) => {
+                if md.is_dir() {
+                    Ok(FileObjectExists::AsDir)
+                } else if md.is_file() {
+                    Ok(FileObjectExists::AsFile)
+                } else {
+                    Err(Error::ReadContent(
+                        format!("Invalid metadata at {path:?}, neither a file nor a directory"),
+                        None,
+                    ))
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(FileObjectExists::No),
+            Err(e) => Err(Error::ReadContent(
+                format!("Failed to read metadata to check path existence at {path:?}"),
+                Some(e),
+            )),
+        }
+    }
+
+    pub(crate) fn is_empty_dir(&self, path: &Path) -> Result<bool> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            let entries = db.read_dir(path).map_err(|e| {
+                Error::ReadContent(format!("Failed to read dir at {path:?}"), Some(e))
+            })?;
+            return Ok(entries.is_empty());
+        }
+        let mut entries = std::fs::read_dir(path)
+            .map_err(|e| Error::ReadContent(format!("Failed to read dir at {path:?}"), Some(e)))?;
+        Ok(entries.next().is_none())
+    }
+
+    /// Reads the whole file at `path`
+    pub(crate) fn read_file(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.read(path);
+        }
+        std::fs::read(path)
+    }
+
+    /// Opens the file at `path` to read it bit by bit
+    pub(crate) fn open_file(&self, path: &Path) -> std::io::Result<FileReader> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db
+                .read(path)
+                .map(|content| FileReader::Memory(Cursor::new(content)));
+        }
+        File::open(path).map(FileReader::File)
+    }
+
+    /// Creates an empty file at `path` to write to, replacing whatever file is there
+    pub(crate) fn create_file(&self, path: &Path) -> std::io::Result<FileWriter> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            db.write(path, &[], DurabilityOpt::None)?;
+            return Ok(FileWriter::Memory {
+                db: Arc::clone(db),
+                path: path.to_path_buf(),
+                content: Vec::new(),
+            });
+        }
+        File::create(path).map(FileWriter::File)
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.remove_file(path);
+        }
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.remove_dir(path);
+        }
+        std::fs::remove_dir(path)
+    }
+
+    pub(crate) fn read_raw_if_present(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        match self.read_file(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::ReadContent(
+                format!("Failed to read file at {path:?}"),
+                Some(e),
+            )),
+        }
+    }
+
+    pub(crate) fn file_size(&self, path: &Path) -> Result<u64> {
+        let md = self.metadata(path).map_err(|e| {
+            Error::ReadContent(format!("Failed to read file size at {path:?}"), Some(e))
+        })?;
+        Ok(md.len())
+    }
+
+    pub(crate) fn ensure_removed_file(&self, path: &Path) -> Result<()> {
+        if let Err(e) = self.remove_file(path) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(Error::DeleteContent(
+                    format!("Failed to ensure file was removed at {path:?}"),
+                    Some(e),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn try_remove_dir(&self, path: &Path) -> Result<()> {
+        let mut anything_left = false;
+        if self.exists(path)? == FileObjectExists::No {
+            return Ok(());
+        }
+        self.read_all_in_dir(path, |entry_path, entry_metadata| {
+            if entry_metadata.is_file() {
+                let f_name = entry_path.file_name().ok_or_else(|| {
+                    Error::ReadContent(
+                        format!("Entry to maybe remove has no file name at {entry_path:?}"),
+                        None,
+                    )
+                })?;
+                // Try to be restrictive in what's removed
+                if let Some(valid_utf8) = f_name.to_str() {
+                    if valid_utf8 == MANIFEST_FILE
+                        || valid_utf8 == JOURNAL_FILE
+                        || valid_utf8.starts_with("dir-cache-generation-")
+                    {
+                        self.ensure_removed_file(entry_path)?;
+                        return Ok(());
+                    }
                 }
             }
+            anything_left = true;
+            Ok(())
+        })?;
+        if !anything_left {
+            self.remove_dir(path).map_err(|e| {
+                Error::DeleteContent(format!("Failed to remove dir at {path:?}"), Some(e))
+            })?;
         }
-        anything_left = true;
         Ok(())
-    })?;
-    if !anything_left {
-        std::fs::remove_dir(path).map_err(|e| {
-            Error::DeleteContent(format!("Failed to remove dir at {path:?}"), Some(e))
+    }
+
+    /// Removes all empty directories below `path`, returns whether `path` itself is empty
+    /// afterwards. Directories `traverse` returns false for are left alone, along with everything
+    /// below them
+    pub(crate) fn remove_empty_dirs<F: Fn(&Path) -> bool>(
+        &self,
+        path: &Path,
+        traverse: &F,
+    ) -> Result<bool> {
+        let mut anything_left = false;
+        self.read_all_in_dir(path, |entry_path, entry_metadata| {
+            if entry_metadata.is_dir()
+                && traverse(entry_path)
+                && self.remove_empty_dirs(entry_path, traverse)?
+            {
+                self.remove_dir(entry_path).map_err(|e| {
+                    Error::DeleteContent(
+                        format!("Failed to remove empty dir at {entry_path:?}"),
+                        Some(e),
+                    )
+                })?;
+            } else {
+                anything_left = true;
+            }
+            Ok(())
         })?;
+        Ok(!anything_left)
+    }
+
+    /// Removes `dir` and its ancestors below `base` for as long as they're empty
+    pub(crate) fn remove_empty_ancestors(&self, base: &Path, dir: &Path) -> Result<()> {
+        for ancestor in dir.ancestors() {
+            if ancestor == base || !ancestor.starts_with(base) {
+                break;
+            }
+            match self.exists(ancestor)? {
+                // Already removed
+                FileObjectExists::No => {}
+                FileObjectExists::AsDir if self.is_empty_dir(ancestor)? => {
+                    self.remove_dir(ancestor).map_err(|e| {
+                        Error::DeleteContent(
+                            format!("Failed to remove empty dir at {ancestor:?}"),
+                            Some(e),
+                        )
+                    })?;
+                }
+                FileObjectExists::AsDir | FileObjectExists::AsFile => break,
+            }
+        }
+        Ok(())
     }
+
+    /// Write `content` to the file at `path`, flushing it to disk as `durability` requires.
+    /// The content is written to a temporary file next to `path` that's then renamed over it, so
+    /// that `path` holds either the old or the new content, and an existing file is replaced
+    /// rather than written to, generations may share a file through hardlinks.
+    /// A temporary file left behind by a crash next to a generation is named like a generation
+    /// that isn't one, and is removed as an orphan
+    pub(crate) fn write_file(
+        &self,
+        path: &Path,
+        content: &[u8],
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.write(path, content, durability);
+        }
+        let Some(file_name) = path.file_name() else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("can't write to {path:?}, it has no file name"),
+            ));
+        };
+        let mut tmp_name = file_name.to_os_string();
+        tmp_name.push(".tmp");
+        let tmp = path.with_file_name(tmp_name);
+        if let Err(e) =
+            write_new_file(&tmp, content, durability).and_then(|()| std::fs::rename(&tmp, path))
+        {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        if matches!(durability, DurabilityOpt::Full) {
+            sync_parent_dir(path)?;
+        }
+        Ok(())
+    }
+
+    /// Hardlink the file at `from` to `to`, replacing whatever is at `to`,
+    /// flushing the new link to disk as `durability` requires
+    pub(crate) fn link_file(
+        &self,
+        from: &Path,
+        to: &Path,
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.link(from, to, durability);
+        }
+        if let Err(e) = std::fs::remove_file(to) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        std::fs::hard_link(from, to)?;
+        if matches!(durability, DurabilityOpt::Full) {
+            sync_parent_dir(to)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the file at `from` to `to` in `to_storage`, replacing whatever is at `to`,
+    /// flushing the copy to disk as `durability` requires
+    pub(crate) fn copy_file(
+        &self,
+        from: &Path,
+        to_storage: &Storage,
+        to: &Path,
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        if !self.is_file_system() || !to_storage.is_file_system() {
+            return to_storage.write_file(to, &self.read_file(from)?, durability);
+        }
+        if let Err(e) = std::fs::remove_file(to) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        std::fs::copy(from, to)?;
+        match durability {
+            DurabilityOpt::None => Ok(()),
+            DurabilityOpt::FlushData => File::open(to)?.sync_data(),
+            DurabilityOpt::Full => {
+                File::open(to)?.sync_all()?;
+                sync_parent_dir(to)
+            }
+        }
+    }
+
+    /// Rename the file at `from` to `to`, flushing the rename to disk as `durability` requires
+    pub(crate) fn rename_file(
+        &self,
+        from: &Path,
+        to: &Path,
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Storage::Sqlite(db) = self {
+            return db.rename(from, to, durability);
+        }
+        std::fs::rename(from, to)?;
+        if matches!(durability, DurabilityOpt::Full) {
+            sync_parent_dir(to)?;
+        }
+        Ok(())
+    }
+
+    /// Move the file at `from` to `to` in `to_storage`, renaming it if both are the same
+    /// storage, and copying it then removing it if it can't be renamed
+    pub(crate) fn move_file(
+        &self,
+        from: &Path,
+        to_storage: &Storage,
+        to: &Path,
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        // Can't rename across file systems either
+        if self.same_as(to_storage) && self.rename_file(from, to, durability).is_ok() {
+            return Ok(());
+        }
+        self.copy_file(from, to_storage, to, durability)?;
+        self.remove_file(from)
+    }
+}
+
+fn write_new_file(path: &Path, content: &[u8], durability: DurabilityOpt) -> std::io::Result<()> {
+    match durability {
+        DurabilityOpt::None => std::fs::write(path, content),
+        DurabilityOpt::FlushData => {
+            let mut file = File::create(path)?;
+            file.write_all(content)?;
+            file.sync_data()
+        }
+        DurabilityOpt::Full => {
+            let mut file = File::create(path)?;
+            file.write_all(content)?;
+            file.sync_all()
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Directories can't be opened as files to sync them on other platforms
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn sync_parent_dir(_path: &Path) -> std::io::Result<()> {
     Ok(())
 }
@@ -0,0 +1,55 @@
+//! Taking every value out of a cache, see [`crate::DirCache::drain`]
+use crate::error::Result;
+use crate::opts::DirCacheOpts;
+use crate::DirCacheInner;
+use std::path::{Path, PathBuf};
+
+/// Yields the key and value of every entry in a [`crate::DirCache`], in no particular order,
+/// removing each from memory and disk as it's yielded.
+/// Negative entries have no value and are removed without being yielded, expired ones are
+/// removed as they're found. An entry whose value can't be read is kept, and the error yielded
+/// in its place. Entries that haven't been yielded when the [`Drain`] is dropped are kept.
+pub struct Drain<'a> {
+    inner: &'a mut DirCacheInner,
+    keys: std::vec::IntoIter<PathBuf>,
+    opts: DirCacheOpts,
+}
+
+impl<'a> Drain<'a> {
+    pub(crate) fn new(inner: &'a mut DirCacheInner, opts: DirCacheOpts) -> Result<Self> {
+        inner.load_all(&opts)?;
+        let keys = inner.store.keys().cloned().collect::<Vec<_>>();
+        Ok(Self {
+            inner,
+            keys: keys.into_iter(),
+            opts,
+        })
+    }
+
+    fn take(&mut self, key: &Path) -> Result<Option<(PathBuf, Vec<u8>)>> {
+        if !self.inner.has_value(key, &self.opts)? {
+            // Negative, an expired entry is already gone
+            self.inner.remove(key, &self.opts)?;
+            return Ok(None);
+        }
+        let original = self.inner.original_key(key, &self.opts);
+        let value = self.inner.read_current(key, self.opts)?.into_owned();
+        self.inner.remove(key, &self.opts)?;
+        Ok(Some((original, value)))
+    }
+}
+
+impl Iterator for Drain<'_> {
+    type Item = Result<(PathBuf, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next()?;
+            match self.take(&key) {
+                Ok(Some(taken)) => return Some(Ok(taken)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
@@ -14,7 +14,21 @@ pub enum Error {
     InsertWithErr(Box<dyn std::error::Error>),
     DangerousKey(String),
     EncodingError(String),
+    DecodingError(String),
+    UnknownCodec(String),
+    UnsupportedEncoding(String),
+    ChecksumMismatch(String),
+    ValueTooLarge(String),
+    SerializeValue(String),
+    DeserializeValue(String),
+    FormatMismatch(String),
+    KeyCollision(String),
     PathRelativize(String),
+    Intercept(String),
+    InvalidLabel(String),
+    OptsConflict(String),
+    ParseOpts(String),
+    Unsupported(String),
 }
 
 impl Display for Error {
@@ -41,9 +55,29 @@ impl Display for Error {
             Error::ParseMetadata(s) => f.write_fmt(format_args!("Failed to parse metadata: '{s}'")),
             Error::DangerousKey(e) => f.write_fmt(format_args!("Dangerous key used: {e}")),
             Error::EncodingError(e) => f.write_fmt(format_args!("Failed to encode content: {e}")),
+            Error::DecodingError(e) => f.write_fmt(format_args!("Failed to decode content: {e}")),
+            Error::UnknownCodec(id) => f.write_fmt(format_args!(
+                "Content encoded with codec {id}, which is not registered"
+            )),
+            Error::UnsupportedEncoding(e) => f.write_fmt(format_args!("Unsupported encoding: {e}")),
+            Error::ChecksumMismatch(e) => f.write_fmt(format_args!("Checksum mismatch, {e}")),
+            Error::ValueTooLarge(s) => f.write_fmt(format_args!("Value too large: {s}")),
+            Error::SerializeValue(e) => f.write_fmt(format_args!("Failed to serialize value: {e}")),
+            Error::DeserializeValue(e) => {
+                f.write_fmt(format_args!("Failed to deserialize value: {e}"))
+            }
+            Error::FormatMismatch(e) => f.write_fmt(format_args!("Format mismatch, {e}")),
+            Error::KeyCollision(e) => f.write_fmt(format_args!("Key collision, {e}")),
             Error::PathRelativize(s) => {
                 f.write_fmt(format_args!("Failed to relativize paths: {s}"))
             }
+            Error::Intercept(e) => f.write_fmt(format_args!("Interceptor failed: {e}")),
+            Error::InvalidLabel(e) => f.write_fmt(format_args!("Invalid label: {e}")),
+            Error::OptsConflict(e) => f.write_fmt(format_args!(
+                "Options differ from the ones recorded in the cache: {e}"
+            )),
+            Error::ParseOpts(e) => f.write_fmt(format_args!("Failed to parse options: {e}")),
+            Error::Unsupported(e) => f.write_fmt(format_args!("Unsupported: {e}")),
         }
     }
 }
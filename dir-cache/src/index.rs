@@ -0,0 +1,77 @@
+//! An index of all keys in a cache and when they were last updated, kept at the root of the
+//! cache so that opening it doesn't have to walk the whole directory tree.
+//! A magic prefix, a version and the number of keys, followed by the length-prefixed utf8 key
+//! and the last updated timestamp of each key, all integers are varints.
+//! Ends with a checksum of everything before it, so that an index that was cut short is ignored.
+use crate::disk::Storage;
+use crate::error::{Error, Result};
+use crate::opts::DurabilityOpt;
+use crate::path_util::SafePathJoin;
+use crate::{checksum, varint, INDEX_FILE};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const INDEX_VERSION: u64 = 1;
+const MAGIC: &[u8] = b"DCI";
+
+/// Reads the index at the root of the cache at `base`, `None` if there is none or it isn't valid
+pub(crate) fn read(storage: &Storage, base: &Path) -> Result<Option<HashMap<PathBuf, Duration>>> {
+    let Some(content) = storage.read_raw_if_present(&base.safe_join(INDEX_FILE)?)? else {
+        return Ok(None);
+    };
+    Ok(content
+        .split_last_chunk::<8>()
+        .filter(|(index, trailer)| checksum(index).to_le_bytes() == **trailer)
+        .and_then(|(index, _)| parse(index)))
+}
+
+fn parse(index: &[u8]) -> Option<HashMap<PathBuf, Duration>> {
+    let mut cursor = index.strip_prefix(MAGIC)?;
+    if varint::read(&mut cursor)? != INDEX_VERSION {
+        return None;
+    }
+    let count = varint::read(&mut cursor)?;
+    let mut keys = HashMap::new();
+    for _ in 0..count {
+        let len = usize::try_from(varint::read(&mut cursor)?).ok()?;
+        let key = cursor.get(..len)?;
+        cursor = &cursor[len..];
+        let key = PathBuf::from(std::str::from_utf8(key).ok()?);
+        let secs = varint::read(&mut cursor)?;
+        let nanos = u32::try_from(varint::read(&mut cursor)?)
+            .ok()
+            .filter(|nanos| *nanos < 1_000_000_000)?;
+        keys.insert(key, Duration::new(secs, nanos));
+    }
+    cursor.is_empty().then_some(keys)
+}
+
+/// Writes an index of `keys` at the root of the cache at `base`.
+/// Keys that aren't valid utf8 can't be indexed, if there are any, any previous index is
+/// removed instead and the cache will be walked on open.
+pub(crate) fn write<'a>(
+    storage: &Storage,
+    base: &Path,
+    keys: impl ExactSizeIterator<Item = (&'a PathBuf, Duration)>,
+    durability_opt: DurabilityOpt,
+) -> Result<()> {
+    let path = base.safe_join(INDEX_FILE)?;
+    let mut out = MAGIC.to_vec();
+    varint::write(&mut out, INDEX_VERSION);
+    varint::write(&mut out, keys.len() as u64);
+    for (key, last_updated) in keys {
+        let Some(key) = key.to_str() else {
+            return storage.ensure_removed_file(&path);
+        };
+        varint::write(&mut out, key.len() as u64);
+        out.extend_from_slice(key.as_bytes());
+        varint::write(&mut out, last_updated.as_secs());
+        varint::write(&mut out, u64::from(last_updated.subsec_nanos()));
+    }
+    let index_checksum = checksum(&out);
+    out.extend_from_slice(&index_checksum.to_le_bytes());
+    storage
+        .write_file(&path, &out, durability_opt)
+        .map_err(|e| Error::WriteContent(format!("Failed to write index to {path:?}"), Some(e)))
+}
@@ -0,0 +1,258 @@
+//! Read-only access to the files of a cache directory, without opening it as a
+//! [`crate::DirCache`]. Nothing is written, locked, recovered, or repaired, so a cache can be
+//! inspected while it's in use, or after it's been left in a state that opening it would change.
+use crate::disk::Storage;
+use crate::error::Result;
+use crate::meta::Label;
+use crate::opts::{CacheOpenOptions, Encoding, ExpirationOpt, GenerationOpt, UnknownEncodingOpt};
+use crate::path_util::SafePathJoin;
+use crate::time::unix_time_now;
+use crate::walk::DirWalk;
+use crate::{DirCacheEntry, MANIFEST_FILE};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the manifest of an entry says about it, see [`read_entry`]
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub(crate) manifest_version: u64,
+    pub(crate) key: Option<PathBuf>,
+    pub(crate) generations: Vec<GenerationInfo>,
+}
+
+impl EntryInfo {
+    /// The version of the format the manifest was written in
+    #[inline]
+    #[must_use]
+    pub const fn manifest_version(&self) -> u64 {
+        self.manifest_version
+    }
+
+    /// The key recorded in the manifest, only entries stored under a hashed directory have one,
+    /// the key of others is their path relative to the cache directory
+    #[inline]
+    #[must_use]
+    pub fn key(&self) -> Option<&Path> {
+        self.key.as_deref()
+    }
+
+    /// The generations listed in the manifest, newest first
+    #[inline]
+    #[must_use]
+    pub fn generations(&self) -> &[GenerationInfo] {
+        &self.generations
+    }
+}
+
+/// One generation listed in a manifest, see [`EntryInfo::generations`]
+#[derive(Debug, Clone)]
+pub struct GenerationInfo {
+    pub(crate) index: usize,
+    pub(crate) path: PathBuf,
+    pub(crate) written: Duration,
+    pub(crate) encoding: Encoding,
+    pub(crate) size: Option<u64>,
+    pub(crate) recorded_size: Option<u64>,
+    pub(crate) delta: bool,
+    pub(crate) negative: bool,
+    pub(crate) checksum: Option<u64>,
+    pub(crate) expiration: Option<ExpirationOpt>,
+    pub(crate) read: Option<Duration>,
+    pub(crate) label: Option<Label>,
+}
+
+impl GenerationInfo {
+    /// The index of the generation, 0 being the current value
+    #[inline]
+    #[must_use]
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The path of the file the generation is stored in
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// When the generation was written
+    #[inline]
+    #[must_use]
+    pub fn written(&self) -> SystemTime {
+        UNIX_EPOCH + self.written
+    }
+
+    /// How long ago the generation was written, zero if that's in the future
+    #[inline]
+    #[must_use]
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.written())
+            .unwrap_or_default()
+    }
+
+    /// When the generation was last read as the current value, if that's been recorded
+    #[inline]
+    #[must_use]
+    pub fn last_read(&self) -> Option<SystemTime> {
+        self.read.map(|read| UNIX_EPOCH + read)
+    }
+
+    /// The [`Encoding`] the generation is stored with
+    #[inline]
+    #[must_use]
+    pub const fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The size in bytes of the generation's file, as stored (after encoding),
+    /// `None` if the file is missing
+    #[inline]
+    #[must_use]
+    pub const fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// The size in bytes of the generation as recorded in the manifest, missing in manifests
+    /// from before sizes were recorded. A file smaller than this was truncated
+    #[inline]
+    #[must_use]
+    pub const fn recorded_size(&self) -> Option<u64> {
+        self.recorded_size
+    }
+
+    /// Whether the generation is stored as a delta against the next newer one
+    #[inline]
+    #[must_use]
+    pub const fn is_delta(&self) -> bool {
+        self.delta
+    }
+
+    /// Whether the generation records a miss rather than a value,
+    /// see [`crate::DirCache::insert_negative`]
+    #[inline]
+    #[must_use]
+    pub const fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// The checksum of the generation as stored, missing in manifests from before checksums
+    #[inline]
+    #[must_use]
+    pub const fn checksum(&self) -> Option<u64> {
+        self.checksum
+    }
+
+    /// The expiration the generation was inserted with, overriding the cache's
+    #[inline]
+    #[must_use]
+    pub const fn expiration(&self) -> Option<ExpirationOpt> {
+        self.expiration
+    }
+
+    /// The [`Label`] the generation was inserted with, if any
+    #[inline]
+    #[must_use]
+    pub const fn label(&self) -> Option<Label> {
+        self.label
+    }
+
+    /// Whether the generation has expired according to `generation_opt`, the one the cache is
+    /// opened with
+    /// # Errors
+    /// System time is before the unix epoch
+    #[inline]
+    pub fn expired(&self, generation_opt: GenerationOpt) -> Result<bool> {
+        Ok(generation_opt.expired(self.expiration, self.written, self.read, unix_time_now()?))
+    }
+}
+
+/// The directories below `base` that hold an entry, in no particular order, walked like
+/// [`crate::DirCache`] does when it's opened with `cache_open_options`
+/// # Errors
+/// Failing to read a directory, or a symlink is found with [`crate::opts::SymlinkOpt::Refuse`]
+pub fn entry_dirs(base: &Path, cache_open_options: &CacheOpenOptions) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let mut walk = DirWalk::new(base, &Storage::FileSystem, cache_open_options);
+    while let Some(next) = walk.next_dir()? {
+        let mut has_manifest = false;
+        walk.read_dir(&next, |entry_path, _| {
+            has_manifest |= entry_path
+                .file_name()
+                .is_some_and(|name| name == MANIFEST_FILE);
+            Ok(())
+        })?;
+        if has_manifest {
+            dirs.push(next);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Parses the manifest of the entry in `dir`, `None` if there is none.
+/// `generation_opt` is only used for its [`crate::opts::Codec`]s, which are needed to parse
+/// the manifest if any generation is encoded with one
+/// # Errors
+/// The manifest can't be read or parsed, or names a [`crate::opts::Codec`] that isn't
+/// registered in `generation_opt`, or an [`Encoding`] whose feature isn't enabled
+pub fn read_entry(dir: &Path, generation_opt: &GenerationOpt) -> Result<Option<EntryInfo>> {
+    let Some((manifest_version, on_disk, key)) = DirCacheEntry::read_metadata(
+        &Storage::FileSystem,
+        dir,
+        generation_opt,
+        UnknownEncodingOpt::Strict,
+    )?
+    else {
+        return Ok(None);
+    };
+    let mut generations = Vec::with_capacity(on_disk.len());
+    for (index, gen) in on_disk.into_iter().enumerate() {
+        let path = dir.safe_join(format!("dir-cache-generation-{index}"))?;
+        let size = path
+            .is_file()
+            .then(|| Storage::FileSystem.file_size(&path))
+            .transpose()?;
+        generations.push(GenerationInfo {
+            index,
+            path,
+            written: gen.age,
+            encoding: gen.encoding,
+            size,
+            recorded_size: gen.size,
+            delta: gen.delta,
+            negative: gen.negative,
+            checksum: gen.checksum,
+            expiration: gen.expiration,
+            read: gen.read,
+            label: gen.label,
+        });
+    }
+    Ok(Some(EntryInfo {
+        manifest_version,
+        key,
+        generations,
+    }))
+}
+
+/// The generation files in `dir`, sorted, along with the generation they're named for if that
+/// can be parsed. Files left by an interrupted write have no generation.
+/// Whether they're listed in the manifest isn't checked, see [`read_entry`] for that
+/// # Errors
+/// Failing to read `dir`
+pub fn generation_files(dir: &Path) -> Result<Vec<(Option<usize>, PathBuf)>> {
+    let mut generation_files = Vec::new();
+    Storage::FileSystem.read_all_in_dir(dir, |entry_path, entry_metadata| {
+        if let Some(generation) = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("dir-cache-generation-"))
+            .filter(|_| entry_metadata.is_file())
+        {
+            generation_files.push((generation.parse::<usize>().ok(), entry_path.to_path_buf()));
+        }
+        Ok(())
+    })?;
+    generation_files.sort_unstable();
+    Ok(generation_files)
+}
@@ -0,0 +1,123 @@
+//! Escaping arbitrary strings into keys that are safe to use as a single directory name.
+//! ASCII alphanumerics, `-` and `_` are kept, as are dots that aren't first or last,
+//! everything else is percent-encoded byte by byte, `%` included, so escaping can be reversed.
+//! Names reserved on Windows, such as `CON`, get their first character encoded as well.
+use crate::path_util::is_windows_reserved;
+use crate::HASHED_DIR;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+/// Escape `raw` into a single path component
+pub(crate) fn escape(raw: &[u8]) -> String {
+    let mut out = String::with_capacity(raw.len());
+    let reserved = is_windows_reserved(raw);
+    for (ind, &b) in raw.iter().enumerate() {
+        // Leading dots would make hidden files, or `.` and `..`, trailing ones are dropped on Windows
+        let kept_dot = b == b'.' && ind != 0 && ind != raw.len() - 1;
+        let kept = b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || kept_dot;
+        if kept && !(reserved && ind == 0) {
+            out.push(char::from(b));
+        } else {
+            out.push('%');
+            out.push(char::from(HEX[usize::from(b >> 4)]));
+            out.push(char::from(HEX[usize::from(b & 0xf)]));
+        }
+    }
+    out
+}
+
+/// Reverse [`escape`], `None` if `escaped` isn't something [`escape`] produces
+pub(crate) fn unescape(escaped: &str) -> Option<PathBuf> {
+    let mut out = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let high = hex_value(bytes.next()?)?;
+            let low = hex_value(bytes.next()?)?;
+            out.push(high << 4 | low);
+        } else if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.' {
+            out.push(b);
+        } else {
+            return None;
+        }
+    }
+    bytes_to_path(out)
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    HEX.iter()
+        .position(|h| *h == b)
+        .and_then(|pos| u8::try_from(pos).ok())
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+pub(crate) fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+// Keys that aren't valid utf8 can't be rebuilt from bytes portably
+#[cfg(not(unix))]
+pub(crate) fn bytes_to_path(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// The key a string key is stored under
+pub(crate) fn str_key(key: &str) -> PathBuf {
+    PathBuf::from(escape(key.as_bytes()))
+}
+
+/// The key a key too long to be stored as it is gets stored under, a directory named after
+/// its hash
+pub(crate) fn hashed_key(key: &Path) -> PathBuf {
+    let hash = xxhash_rust::xxh3::xxh3_128(key.as_os_str().as_encoded_bytes());
+    [HASHED_DIR, &format!("{hash:032x}")].iter().collect()
+}
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_unsafe_characters() {
+        assert_eq!("plain-key_1", escape(b"plain-key_1"));
+        assert_eq!(
+            "https%3A%2F%2Fexample.com%2Fa%3Fb%3D1",
+            escape(b"https://example.com/a?b=1")
+        );
+        assert_eq!("%2E%2E", escape(b".."));
+        assert_eq!("%2Ehidden", escape(b".hidden"));
+        assert_eq!("trailing%2E", escape(b"trailing."));
+        assert_eq!("100%25", escape(b"100%"));
+        assert_eq!("a%5Cb%20c", escape(b"a\\b c"));
+        assert_eq!("%C3%A5", escape("å".as_bytes()));
+        assert_eq!("%43ON", escape(b"CON"));
+        assert_eq!("%6Eul.txt", escape(b"nul.txt"));
+        assert_eq!("CONSOLE", escape(b"CONSOLE"));
+    }
+
+    #[test]
+    fn unescapes_escaped() {
+        for raw in [
+            "plain",
+            "https://example.com/a?b=1",
+            "..",
+            ".a.",
+            "100%",
+            "å ø",
+            "CON",
+        ] {
+            assert_eq!(
+                Some(PathBuf::from(raw)),
+                unescape(&escape(raw.as_bytes())),
+                "{raw}"
+            );
+        }
+        assert_eq!(None, unescape("%2"));
+        assert_eq!(None, unescape("%ZZ"));
+        assert_eq!(None, unescape("a/b"));
+    }
+}
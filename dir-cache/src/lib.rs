@@ -6,28 +6,79 @@
 //! The cache is convenient for some use-cases, but not performant and should not be
 //! used in situations were cache-performance is important.
 //!
-use crate::disk::{
-    ensure_dir, ensure_removed_file, read_all_in_dir, read_metadata_if_present,
-    read_raw_if_present, try_remove_dir,
-};
+use crate::batch::Batch;
+use crate::blob::{pool_blob, release_blobs, sweep_blobs};
+use crate::disk::{FileObjectExists, Storage};
+use crate::drain::Drain;
 use crate::error::{Error, Result};
-use crate::opts::{DirCacheOpts, Encoding, GenerationOpt, MemPullOpt, MemPushOpt, SyncOpt};
-use crate::path_util::{relativize, SafePathJoin};
-use crate::time::{duration_from_nano_string, unix_time_now};
+use crate::meta::{CacheSummary, EntryMeta, Freshness, GenerationMeta, Label, ValueSource};
+use crate::opts::{
+    CacheOpenOptions, ConflictPolicy, DirCacheOpts, DiskBudgetOpt, DurabilityOpt, Encoding,
+    EvictReason, ExpirationOpt, GenerationOpt, Hook, IngestOpt, InsertMode, InvalidEntryOpt,
+    KeyCaseOpt, KeyDiscoveryOpt, MemBudgetOpt, MemPullOpt, MemPushOpt, OversizeOpt, RepairOpt,
+    SyncOpt, UnknownEncodingOpt, Weigher,
+};
+use crate::path_util::{relativize, same_ignoring_case, SafePathJoin};
+use crate::snapshot::Snapshot;
+use crate::stats::DirCacheStats;
+use crate::stream::{EntryReader, EntryWriter};
+use crate::time::unix_time_now;
+use crate::verify::{InvalidEntry, MigrationFailure, MigrationReport, VerifyIssue, VerifyReport};
+use crate::walk::DirWalk;
 use std::borrow::Cow;
 use std::collections::{HashMap, VecDeque};
-use std::fmt::Write;
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::ffi::OsStr;
+use std::fmt::{Debug, Formatter};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+// Declared first so that its macros are in scope for the other modules
+#[macro_use]
+mod logging;
+
+#[cfg(feature = "tar")]
+mod archive;
+pub mod batch;
+mod blob;
+mod delta;
 mod disk;
+pub mod drain;
 pub mod error;
+mod index;
+pub mod inspect;
+mod key;
+mod manifest;
+#[cfg(feature = "mmap")]
+pub mod mapped;
+#[cfg(feature = "moka")]
+mod mem_tier;
+pub mod meta;
 pub mod opts;
 mod path_util;
+mod persisted;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+pub mod stats;
+pub mod stream;
 mod time;
+pub mod typed;
+mod varint;
+pub mod verify;
+mod walk;
 
-const MANIFEST_VERSION: u64 = 1;
 const MANIFEST_FILE: &str = "dir-cache-manifest.txt";
+const JOURNAL_FILE: &str = "dir-cache-journal.bin";
+const INDEX_FILE: &str = "dir-cache-index.bin";
+const BLOB_DIR: &str = "dir-cache-blobs";
+const HASHED_DIR: &str = "dir-cache-hashed";
+// Room for the names of the files the cache writes in the directory of an entry
+const ENTRY_FILE_NAME_ROOM: usize = "dir-cache-generation-pending-".len() + 20;
+const MARKER_FILE: &str = ".dir-cache";
+const OPTS_FILE: &str = "dir-cache-opts.txt";
+const MARKER_CONTENT: &[u8] = b"This directory is a dir-cache\n";
 
 /// A directory-based cache with a map-like interface.
 /// # Example
@@ -53,7 +104,37 @@ pub struct DirCache {
     opts: DirCacheOpts,
 }
 
+/// The result of looking up a key with [`DirCache::lookup`]
+#[derive(Debug)]
+pub enum Lookup<'a> {
+    /// The key has a valid value
+    Value(Cow<'a, [u8]>),
+    /// A miss was cached for the key with [`DirCache::insert_negative`], and hasn't expired
+    Negative,
+    /// Nothing valid is cached for the key
+    Missing,
+}
+
+/// A value along with the metadata of its entry, see [`DirCache::get_with_meta`]
+#[derive(Debug)]
+pub struct ValueWithMeta<'a> {
+    pub value: Cow<'a, [u8]>,
+    /// The [`EntryMeta`] as of after the read
+    pub meta: EntryMeta,
+    pub source: ValueSource,
+}
+
 impl DirCache {
+    /// Open a [`DirCache`] with the default [`DirCacheOpts`] in a directory named `app_name` in
+    /// the platform's cache directory, see [`DirCacheOpts::open_user_cache`].
+    /// # Errors
+    /// Same as [`DirCacheOpts::open_user_cache`]
+    #[cfg(feature = "dirs")]
+    #[inline]
+    pub fn open_user_cache(app_name: &str) -> Result<Self> {
+        DirCacheOpts::default().open_user_cache(app_name)
+    }
+
     /// Get this [`DirCache`]'s [`DirCacheOpts`].
     /// To change one opt for an operation, for example.
     #[inline]
@@ -62,6 +143,13 @@ impl DirCache {
         &self.opts
     }
 
+    /// The directory this [`DirCache`] was opened at
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.inner.base
+    }
+
     /// Get the value of a key using this [`DirCache`]'s options.
     /// Returns [`Option::None`] if the key isn't stored in the cache.
     /// If the key is stored in the cache it will be retrieved either from memory or disk.
@@ -70,9 +158,74 @@ impl DirCache {
     /// # Errors
     /// Various io-errors reading and managing disk state
     #[inline]
-    pub fn get(&mut self, key: &Path) -> Result<Option<Cow<[u8]>>> {
-        self.inner
-            .get_opt(key, self.opts.mem_pull_opt, self.opts.generation_opt)
+    pub fn get(&mut self, key: &Path) -> Result<Option<Cow<'_, [u8]>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get`] but always returns an owned value.
+    /// Useful when the value is going to be owned by the caller anyway,
+    /// if [`MemPullOpt::KeepInMemoryOnRead`] is specified the retained value is cloned,
+    /// otherwise the value read from disk is handed out directly.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_owned(&mut self, key: &Path) -> Result<Option<Vec<u8>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        Ok(self.inner.get_opt(&key, self.opts)?.map(Cow::into_owned))
+    }
+
+    /// Same as [`DirCache::get`] but always returns a borrowed value.
+    /// To be able to hand out a borrow, a value read from disk is retained in memory regardless of
+    /// the [`MemPullOpt`] this [`DirCache`] was instantiated with, and a value found in the
+    /// [`opts::FallbackOpt`] is copied forward.
+    /// With [`opts::Interceptor`]s configured, the value they read is held on to in addition to
+    /// the stored one, until the next call.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_ref(&mut self, key: &Path) -> Result<Option<&[u8]>> {
+        let opts = self.opts.with_mem_pull_opt(MemPullOpt::KeepInMemoryOnRead);
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.load(&key, &opts)?;
+        self.inner.copy_forward(&key, &opts)?;
+        let intercepted = match self.inner.get_opt(&key, opts)? {
+            Some(Cow::Borrowed(_)) => None,
+            Some(Cow::Owned(value)) => Some(value),
+            None => return Ok(None),
+        };
+        Ok(Some(match intercepted {
+            Some(value) => self.inner.intercepted.insert(value),
+            None => self.inner.in_mem_content(&key),
+        }))
+    }
+
+    /// Same as [`DirCache::get`] but returns a cheaply clonable handle to the value, which can
+    /// be held on to without borrowing the [`DirCache`].
+    /// A value retained in memory is shared with the cache rather than copied, a value read from
+    /// disk and not retained is handed out directly.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_shared(&mut self, key: &Path) -> Result<Option<Arc<[u8]>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_shared_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_shared`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_shared`]
+    #[inline]
+    pub fn get_shared_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<Arc<[u8]>>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_shared_opt(&key, opts)
     }
 
     /// Same as [`DirCache::get`] but with opts other than what the [`DirCache`] was instantiated
@@ -80,9 +233,312 @@ impl DirCache {
     /// # Errors
     /// Same as [`DirCache::get`]
     #[inline]
-    pub fn get_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<Cow<[u8]>>> {
+    pub fn get_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<Cow<'_, [u8]>>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_opt(&key, opts)
+    }
+
+    /// Get the value of a key deserialized from JSON.
+    /// Returns [`Option::None`] if the key isn't stored in the cache.
+    /// # Errors
+    /// Same as [`DirCache::get`], and fails with [`Error::DeserializeValue`] if the value
+    /// isn't valid JSON for `T`, or with [`Error::FormatMismatch`] if it was inserted in
+    /// another format through a [`typed::TypedDirCache`]
+    #[cfg(feature = "json")]
+    #[inline]
+    pub fn get_json<T: serde::de::DeserializeOwned>(&mut self, key: &Path) -> Result<Option<T>> {
+        self.get_json_opt(key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_json`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_json`]
+    #[cfg(feature = "json")]
+    #[inline]
+    pub fn get_json_opt<T: serde::de::DeserializeOwned>(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<T>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
         self.inner
-            .get_opt(key, opts.mem_pull_opt, opts.generation_opt)
+            .get_formatted_opt(
+                &key,
+                typed::format_id(<typed::Json as typed::Format>::ID),
+                opts,
+            )?
+            .map(|val| {
+                serde_json::from_slice(&val).map_err(|e| {
+                    Error::DeserializeValue(format!("Failed to parse JSON at key {key:?}: {e}"))
+                })
+            })
+            .transpose()
+    }
+
+    /// Serialize `value` to JSON and insert it for the provided `key`, same as [`DirCache::insert`].
+    /// # Errors
+    /// Same as [`DirCache::insert`], and fails with [`Error::SerializeValue`] if `value` can't be
+    /// serialized
+    #[cfg(feature = "json")]
+    #[inline]
+    pub fn insert_json<T: serde::Serialize + ?Sized>(
+        &mut self,
+        key: &Path,
+        value: &T,
+    ) -> Result<()> {
+        self.insert_json_opt(key, value, self.opts)
+    }
+
+    /// Same as [`DirCache::insert_json`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::insert_json`]
+    #[cfg(feature = "json")]
+    #[inline]
+    pub fn insert_json_opt<T: serde::Serialize + ?Sized>(
+        &mut self,
+        key: &Path,
+        value: &T,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        let content = serde_json::to_vec(value).map_err(|e| {
+            Error::SerializeValue(format!("Failed to write JSON for key {key:?}: {e}"))
+        })?;
+        let meta = ValueMeta {
+            format: Some(typed::format_id(<typed::Json as typed::Format>::ID)),
+            ..ValueMeta::default()
+        };
+        self.inner.insert_opt(&key, Cow::Owned(content), meta, opts)
+    }
+
+    /// Get the path of the file holding the current value of a key, for use with tools that work
+    /// on files. Returns [`Option::None`] if the key isn't stored in the cache.
+    /// A value only held in memory is written to disk first.
+    /// The file holds the value as it's stored, which is only the value itself if the current
+    /// generation is stored as [`Encoding::Plain`].
+    /// The file belongs to the cache, it shouldn't be modified, and may be replaced or
+    /// removed by any later operation on the cache.
+    /// # Errors
+    /// Same as [`DirCache::get`], and various io-errors writing a value held in memory to disk.
+    /// Fails with [`Error::Unsupported`] for a cache stored in SQLite, which has no files
+    #[inline]
+    pub fn get_path(&mut self, key: &Path) -> Result<Option<PathBuf>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_path_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_path`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_path`]
+    #[inline]
+    pub fn get_path_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<PathBuf>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_path_opt(&key, opts)
+    }
+
+    /// Get a reader that streams the value of a key, decoding it as it's read, for values too
+    /// large to comfortably hold in memory.
+    /// Returns [`Option::None`] if the key isn't stored in the cache.
+    /// A value held in memory is read from memory, a value read from disk isn't kept in memory
+    /// regardless of [`MemPullOpt`].
+    /// Values encoded with a [`crate::opts::Codec`] can't be decoded piecemeal, and are decoded
+    /// into memory up front.
+    /// With [`DirCacheOpts::verify_on_read`], the file is verified before it's read, which
+    /// reads it an extra time.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn entry_reader(&mut self, key: &Path) -> Result<Option<EntryReader<'_>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.entry_reader_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::entry_reader`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::entry_reader`]
+    #[inline]
+    pub fn entry_reader_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<EntryReader<'_>>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.entry_reader_opt(&key, opts)
+    }
+
+    /// Get the value of a key mapped into memory straight from its file, so that reading large
+    /// values doesn't copy them onto the heap.
+    /// Returns [`Option::None`] if the key isn't stored in the cache.
+    /// Only values stored as [`Encoding::Plain`] can be mapped, others are decoded into memory,
+    /// as are values only held in memory. The value is never kept in memory by the cache.
+    /// The mapped value stays valid when the key is later replaced, expires or is removed.
+    /// On platforms that don't allow removing mapped files, such as Windows, replacing or
+    /// removing the key fails while the value is mapped.
+    /// # Errors
+    /// Same as [`DirCache::get`], and fails if the file can't be mapped
+    #[cfg(feature = "mmap")]
+    #[inline]
+    pub fn get_mapped(&mut self, key: &Path) -> Result<Option<mapped::MappedValue>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_mapped_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_mapped`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_mapped`]
+    #[cfg(feature = "mmap")]
+    #[inline]
+    pub fn get_mapped_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<mapped::MappedValue>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_mapped_opt(&key, opts)
+    }
+
+    /// Same as [`DirCache::get`], but also reports whether the value is older than the soft
+    /// TTL of the [`GenerationOpt`], see [`GenerationOpt::with_soft_ttl`].
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_with_freshness(&mut self, key: &Path) -> Result<Option<(Cow<'_, [u8]>, Freshness)>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_with_freshness_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_with_freshness`] but with opts other than what the [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_with_freshness`]
+    #[inline]
+    pub fn get_with_freshness_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<(Cow<'_, [u8]>, Freshness)>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_with_freshness_opt(&key, opts)
+    }
+
+    /// Same as [`DirCache::get`], but also returns the [`EntryMeta`] of the entry, and whether
+    /// the value was served from memory or disk.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_with_meta(&mut self, key: &Path) -> Result<Option<ValueWithMeta<'_>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_with_meta_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_with_meta`] but with opts other than what the [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_with_meta`]
+    #[inline]
+    pub fn get_with_meta_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<ValueWithMeta<'_>>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_with_meta_opt(&key, opts)
+    }
+
+    /// Get the value of generation `generation` of a key, 0 being the current value and
+    /// higher generations successively older values, decoded from the [`Encoding`] it was
+    /// stored with.
+    /// Returns [`Option::None`] if the key isn't stored in the cache, or doesn't have that many
+    /// generations, see [`GenerationOpt`].
+    /// Older generations are never kept in memory.
+    /// # Errors
+    /// Same as [`DirCache::get`], additionally if the key has generations encoded with lz4 or
+    /// zstd, but the feature needed to decode them isn't enabled.
+    #[inline]
+    pub fn get_generation(&mut self, key: &Path, generation: usize) -> Result<Option<Vec<u8>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_generation_opt(&key, generation, self.opts)
+    }
+
+    /// Same as [`DirCache::get_generation`] but with opts other than what the [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_generation`]
+    #[inline]
+    pub fn get_generation_opt(
+        &mut self,
+        key: &Path,
+        generation: usize,
+        opts: DirCacheOpts,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_generation_opt(&key, generation, opts)
+    }
+
+    /// The generations of a key, newest first, without reading their content, which can be
+    /// read with [`DirCache::get_generation`].
+    /// Empty if the key isn't stored in the cache.
+    /// # Errors
+    /// Various io-errors reading the entry, and removing it if it has expired
+    #[inline]
+    pub fn generations(&mut self, key: &Path) -> Result<impl Iterator<Item = GenerationMeta>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.generations(&key, &self.opts)
+    }
+
+    /// Same as [`DirCache::generations`] but with opts other than what the [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::generations`]
+    #[inline]
+    pub fn generations_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<impl Iterator<Item = GenerationMeta>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.generations(&key, &opts)
+    }
+
+    /// Same as [`DirCache::get`], but tells a key with a cached miss, see
+    /// [`DirCache::insert_negative`], apart from one with nothing cached.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn lookup(&mut self, key: &Path) -> Result<Lookup<'_>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.lookup_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::lookup`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::lookup`]
+    #[inline]
+    pub fn lookup_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Lookup<'_>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.lookup_opt(&key, opts)
     }
 
     /// Get a key if it exists and is valid according to [`GenerationOpt`], otherwise
@@ -102,14 +558,11 @@ impl DirCache {
         &mut self,
         key: &Path,
         insert_with: F,
-    ) -> Result<Cow<[u8]>> {
-        self.inner.get_or_insert_opt(
-            key,
-            insert_with,
-            self.opts.mem_pull_opt,
-            self.opts.mem_push_opt,
-            self.opts.generation_opt,
-        )
+    ) -> Result<Cow<'_, [u8]>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.get_or_insert_opt(&key, insert_with, self.opts)
     }
 
     /// Same as [`DirCache::get_or_insert`] but with [`DirCacheOpts`] different from what
@@ -125,14 +578,54 @@ impl DirCache {
         key: &Path,
         insert_with: F,
         opts: DirCacheOpts,
-    ) -> Result<Cow<[u8]>> {
-        self.inner.get_or_insert_opt(
-            key,
-            insert_with,
-            opts.mem_pull_opt,
-            opts.mem_push_opt,
-            opts.generation_opt,
-        )
+    ) -> Result<Cow<'_, [u8]>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_or_insert_opt(&key, insert_with, opts)
+    }
+
+    /// Stale-while-revalidate, get a key if it has a valid value, and if that value is older
+    /// than `soft_ttl`, also produce a new one with `refresh` and insert it as a new generation.
+    /// The value that was there is returned even if it was refreshed, a failing `refresh`
+    /// leaves it in place to be refreshed on the next call.
+    /// If there's no valid value, it's produced with `refresh` and inserted, same as
+    /// [`DirCache::get_or_insert`].
+    /// # Errors
+    /// Same as [`DirCache::get_or_insert`], the error of `refresh` is only returned if there
+    /// was no value to fall back on
+    #[inline]
+    pub fn get_or_refresh<
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
+    >(
+        &mut self,
+        key: &Path,
+        soft_ttl: Duration,
+        refresh: F,
+    ) -> Result<Cow<'_, [u8]>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner
+            .get_or_refresh_opt(&key, soft_ttl, refresh, self.opts)
+    }
+
+    /// Same as [`DirCache::get_or_refresh`] but with [`DirCacheOpts`] different from what
+    /// this [`DirCache`] was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_or_refresh`]
+    #[inline]
+    pub fn get_or_refresh_opt<
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
+    >(
+        &mut self,
+        key: &Path,
+        soft_ttl: Duration,
+        refresh: F,
+        opts: DirCacheOpts,
+    ) -> Result<Cow<'_, [u8]>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.get_or_refresh_opt(&key, soft_ttl, refresh, opts)
     }
 
     /// Insert `content` as a value for the provided `key` into this [`DirCache`].
@@ -140,17 +633,18 @@ impl DirCache {
     /// If [`MemPushOpt::MemoryOnly`] isn't used and [`GenerationOpt`] specifies more
     /// than one generation, a new generation will be written to disk, and previous generations
     /// will age.
+    /// `content` can be owned or borrowed, a borrowed value is only copied if it's retained
+    /// in memory.
     /// # Errors
     /// Will error on using a key that's not safe to use with [`DirCache`].
     /// May error on various io-errors relating to writing to disk.
     #[inline]
-    pub fn insert(&mut self, key: &Path, content: Vec<u8>) -> Result<()> {
-        self.inner.insert_opt(
-            key,
-            content,
-            self.opts.mem_push_opt,
-            self.opts.generation_opt,
-        )
+    pub fn insert<'a>(&mut self, key: &Path, content: impl Into<Cow<'a, [u8]>>) -> Result<()> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner
+            .insert_opt(&key, content.into(), ValueMeta::default(), self.opts)
     }
 
     /// Insert `content` as a value for the provided `key` using the specified `opts` instead
@@ -158,518 +652,4336 @@ impl DirCache {
     /// # Errors
     /// Same as [`DirCache::insert`]
     #[inline]
-    pub fn insert_opt(&mut self, key: &Path, content: Vec<u8>, opts: DirCacheOpts) -> Result<()> {
+    pub fn insert_opt<'a>(
+        &mut self,
+        key: &Path,
+        content: impl Into<Cow<'a, [u8]>>,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
         self.inner
-            .insert_opt(key, content, opts.mem_push_opt, opts.generation_opt)
+            .insert_opt(&key, content.into(), ValueMeta::default(), opts)
     }
 
-    /// Removes a key from the map, and cleans up the state left on disk.
+    /// Cache that there's no value for `key`, such as when its source has no data for it,
+    /// replacing any value as a new generation. The miss expires after `ttl` rather than
+    /// according to the [`ExpirationOpt`] of the cache's [`GenerationOpt`].
+    /// A key with a cached miss has no value, [`DirCache::get`] returns `None` for it,
+    /// use [`DirCache::lookup`] to tell it apart from a key with nothing cached.
     /// # Errors
-    /// Various io-errors relating to probing and deleting content from disk
+    /// Same as [`DirCache::insert`]
     #[inline]
-    pub fn remove(&mut self, key: &Path) -> Result<bool> {
-        self.inner.remove(key)
+    pub fn insert_negative(&mut self, key: &Path, ttl: Duration) -> Result<()> {
+        let opts = self.opts;
+        self.insert_negative_opt(key, ttl, opts)
     }
 
-    /// Sync in-memory written content to disk, same as [`DirCache::sync`].
-    /// If [`SyncOpt::ManualSync`] and [`MemPushOpt::MemoryOnly`] are both enabled,
-    /// calling this method is the only way to flush map-state to disk.
+    /// Same as [`DirCache::insert_negative`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
     /// # Errors
-    /// Various io-errors related to writing to disk
+    /// Same as [`DirCache::insert_negative`]
     #[inline]
-    pub fn sync(&mut self) -> Result<()> {
-        self.inner
-            .sync_to_disk(self.opts.mem_push_opt, self.opts.generation_opt)
+    pub fn insert_negative_opt(
+        &mut self,
+        key: &Path,
+        ttl: Duration,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        let meta = ValueMeta {
+            expiration: Some(ExpirationOpt::ExpiresAfter(ttl)),
+            negative: true,
+            ..ValueMeta::default()
+        };
+        self.inner.insert_opt(&key, Cow::Borrowed(&[]), meta, opts)
     }
 
-    /// Sync in-memory written content to disk, same as [`DirCache::sync`] but with options
-    /// different to those this [`DirCache`] was instantiated with.
+    /// Insert `content` for `key` with a time to live of its own, rather than the
+    /// [`ExpirationOpt`] of the cache's [`GenerationOpt`].
+    /// The time to live is recorded with the value and applies after reopening the cache,
+    /// until the key is inserted again.
     /// # Errors
-    /// Same as [`DirCache::sync`]
+    /// Same as [`DirCache::insert`]
     #[inline]
-    pub fn sync_opt(&mut self, opts: DirCacheOpts) -> Result<()> {
-        self.inner
-            .sync_to_disk(opts.mem_push_opt, opts.generation_opt)
+    pub fn insert_with_ttl<'a>(
+        &mut self,
+        key: &Path,
+        content: impl Into<Cow<'a, [u8]>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.insert_with_ttl_opt(key, content, ttl, self.opts)
     }
-}
 
-impl Drop for DirCache {
-    fn drop(&mut self) {
-        if matches!(self.opts.sync_opt, SyncOpt::SyncOnDrop) {
-            let _ = self
-                .inner
-                .sync_to_disk(self.opts.mem_push_opt, self.opts.generation_opt);
-        }
+    /// Same as [`DirCache::insert_with_ttl`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_with_ttl_opt<'a>(
+        &mut self,
+        key: &Path,
+        content: impl Into<Cow<'a, [u8]>>,
+        ttl: Duration,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        self.insert_with_expiration_opt(key, content, ExpirationOpt::ExpiresAfter(ttl), opts)
     }
-}
 
-struct DirCacheInner {
-    base: PathBuf,
-    store: HashMap<PathBuf, DirCacheEntry>,
-}
+    /// Insert `content` for `key` with an expiration of its own, rather than the
+    /// [`ExpirationOpt`] of the cache's [`GenerationOpt`], for example
+    /// [`ExpirationOpt::ExpiresAt`] the next time the source of the value is refreshed.
+    /// Recorded with the value like with [`DirCache::insert_with_ttl`].
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_with_expiration<'a>(
+        &mut self,
+        key: &Path,
+        content: impl Into<Cow<'a, [u8]>>,
+        expiration: ExpirationOpt,
+    ) -> Result<()> {
+        self.insert_with_expiration_opt(key, content, expiration, self.opts)
+    }
 
-impl DirCacheInner {
-    fn get_opt(
+    /// Same as [`DirCache::insert_with_expiration`] but with opts other than what the
+    /// [`DirCache`] was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_with_expiration_opt<'a>(
         &mut self,
         key: &Path,
-        mem_pull_opt: MemPullOpt,
-        generation_opt: GenerationOpt,
-    ) -> Result<Option<Cow<[u8]>>> {
-        // Borrow checker...
-        if !self.store.contains_key(key) {
-            return Ok(None);
-        }
-        let val = self.store.get(key).unwrap();
-        let now = unix_time_now()?;
-        let path = self.base.safe_join(key)?;
-        // To be able to remove this key, the below Cow borrow-return needs a separate borrow lasting
-        // for the remainder of this function, so here we are.
-        if val
-            .last_updated
-            .saturating_add(generation_opt.expiration.as_dur())
-            <= now
-        {
-            // The value in memory should be younger or equal to the first value on disk
-            // if it's too old, this key should be cleaned
-            try_remove_dir(&path)?;
-            self.store.remove(key);
-            return Ok(None);
-        }
+        content: impl Into<Cow<'a, [u8]>>,
+        expiration: ExpirationOpt,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        let meta = ValueMeta {
+            expiration: Some(expiration),
+            ..ValueMeta::default()
+        };
+        self.inner.insert_opt(&key, content.into(), meta, opts)
+    }
 
-        if let Some(f) = val.on_disk.front() {
-            if f.age.saturating_add(generation_opt.expiration.as_dur()) <= now {
-                // No value in mem, also first value on disk is too old, clean up
-                try_remove_dir(&path)?;
-                self.store.remove(key);
-                return Ok(None);
-            }
-        } else if val.in_mem.is_none() {
-            // No value in mem, no values on disk, clean
-            try_remove_dir(&path)?;
-            self.store.remove(key);
-            return Ok(None);
-        }
+    /// Insert `content` for `key` with a [`Label`] attached to its generation, for example the
+    /// upstream `ETag` it was fetched with, see [`DirCache::generations`].
+    /// The label stays with the generation as it ages, a value inserted without one has none.
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_labeled<'a>(
+        &mut self,
+        key: &Path,
+        content: impl Into<Cow<'a, [u8]>>,
+        label: Label,
+    ) -> Result<()> {
+        self.insert_labeled_opt(key, content, label, self.opts)
+    }
+
+    /// Same as [`DirCache::insert_labeled`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_labeled_opt<'a>(
+        &mut self,
+        key: &Path,
+        content: impl Into<Cow<'a, [u8]>>,
+        label: Label,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        let meta = ValueMeta {
+            label: Some(label),
+            ..ValueMeta::default()
+        };
+        self.inner.insert_opt(&key, content.into(), meta, opts)
+    }
+
+    /// Insert the content of the file at `file` as a value for the provided `key`, moving or
+    /// copying it into place as the new generation according to `ingest_opt`, without reading
+    /// it into memory. Useful for large values that are produced as files.
+    /// The value is written straight to disk regardless of [`MemPushOpt`], and isn't retained
+    /// in memory.
+    /// If the value has to be encoded, stored as a delta base, or content-addressed it has to be
+    /// read into memory after all.
+    /// # Errors
+    /// Same as [`DirCache::insert`], and fails if `file` can't be read, moved or copied
+    #[inline]
+    pub fn insert_file(&mut self, key: &Path, file: &Path, ingest_opt: IngestOpt) -> Result<()> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner
+            .insert_file_opt(&key, file, &Storage::FileSystem, ingest_opt, self.opts)
+    }
+
+    /// Same as [`DirCache::insert_file`] but with [`DirCacheOpts`] different from what
+    /// this [`DirCache`] was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::insert_file`]
+    #[inline]
+    pub fn insert_file_opt(
+        &mut self,
+        key: &Path,
+        file: &Path,
+        ingest_opt: IngestOpt,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner
+            .insert_file_opt(&key, file, &Storage::FileSystem, ingest_opt, opts)
+    }
+
+    /// Get a writer that streams a new value for `key` straight to disk, for values too large
+    /// to comfortably hold in memory. The value replaces the current one, aging the previous
+    /// generations, once [`EntryWriter::finish`] is called.
+    /// # Errors
+    /// Will error on using a key that's not safe to use with [`DirCache`],
+    /// or failing to create the file to write to
+    #[inline]
+    pub fn entry_writer(&mut self, key: &Path) -> Result<EntryWriter<'_>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        EntryWriter::new(&mut self.inner, &key, self.opts)
+    }
+
+    /// Same as [`DirCache::entry_writer`] but with [`DirCacheOpts`] different from what
+    /// this [`DirCache`] was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::entry_writer`]
+    #[inline]
+    pub fn entry_writer_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<EntryWriter<'_>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        EntryWriter::new(&mut self.inner, &key, opts)
+    }
+
+    /// Removes a key from the map, and cleans up the state left on disk.
+    /// # Errors
+    /// Various io-errors relating to probing and deleting content from disk
+    #[inline]
+    pub fn remove(&mut self, key: &Path) -> Result<bool> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.remove(&key, &self.opts)
+    }
+
+    /// Stage inserts and removes in a [`Batch`], to be applied together when it's committed,
+    /// writing each inserted key once rather than once per insert.
+    #[inline]
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    /// Marks the value of a key as updated now, without rewriting it, extending its life
+    /// under the [`ExpirationOpt`] as if it had just been inserted.
+    /// Useful after revalidating a value against its source.
+    /// Returns whether there was a valid value to touch.
+    /// # Errors
+    /// Various io-errors reading the entry, and writing its manifest
+    #[inline]
+    pub fn touch(&mut self, key: &Path) -> Result<bool> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.touch(&key, &self.opts)
+    }
+
+    /// Same as [`DirCache::touch`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::touch`]
+    #[inline]
+    pub fn touch_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<bool> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.touch(&key, &opts)
+    }
+
+    /// Metadata about the entry of a key, such as its size and how often it's been read,
+    /// without reading its value.
+    /// `None` if there's no valid value for the key.
+    /// # Errors
+    /// Various io-errors reading the entry, and removing it if it has expired
+    #[inline]
+    pub fn entry_meta(&mut self, key: &Path) -> Result<Option<EntryMeta>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.entry_meta(&key, &self.opts)
+    }
+
+    /// Same as [`DirCache::entry_meta`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::entry_meta`]
+    #[inline]
+    pub fn entry_meta_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<EntryMeta>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.entry_meta(&key, &opts)
+    }
+
+    /// When the current value of a key was written, without reading the value.
+    /// `None` if there's no valid value for the key.
+    /// # Errors
+    /// Various io-errors reading the entry, and removing it if it has expired
+    #[inline]
+    pub fn last_updated(&mut self, key: &Path) -> Result<Option<SystemTime>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.last_updated(&key, &self.opts)
+    }
+
+    /// Same as [`DirCache::last_updated`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::last_updated`]
+    #[inline]
+    pub fn last_updated_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<SystemTime>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.last_updated(&key, &opts)
+    }
+
+    /// How long ago the current value of a key was written, see [`DirCache::last_updated`].
+    /// `None` if there's no valid value for the key.
+    /// # Errors
+    /// Same as [`DirCache::last_updated`]
+    #[inline]
+    pub fn age(&mut self, key: &Path) -> Result<Option<Duration>> {
+        let opts = self.opts;
+        self.age_opt(key, opts)
+    }
+
+    /// Same as [`DirCache::age`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::age`]
+    #[inline]
+    pub fn age_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<Duration>> {
+        let Some(last_updated) = self.last_updated_opt(key, opts)? else {
+            return Ok(None);
+        };
+        // A clock set back makes values written in the future
+        Ok(Some(last_updated.elapsed().unwrap_or_default()))
+    }
+
+    /// When the value of a key expires according to its [`ExpirationOpt`].
+    /// `None` if there's no valid value for the key, or if it never expires.
+    /// # Errors
+    /// Various io-errors reading the entry, and removing it if it has expired
+    #[inline]
+    pub fn expires_at(&mut self, key: &Path) -> Result<Option<SystemTime>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.expires_at(&key, &self.opts)
+    }
+
+    /// Same as [`DirCache::expires_at`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::expires_at`]
+    #[inline]
+    pub fn expires_at_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<SystemTime>> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.expires_at(&key, &opts)
+    }
+
+    /// How long the value of a key has left until it expires, see [`DirCache::expires_at`].
+    /// `None` if there's no valid value for the key, or if it never expires.
+    /// # Errors
+    /// Same as [`DirCache::expires_at`]
+    #[inline]
+    pub fn remaining_ttl(&mut self, key: &Path) -> Result<Option<Duration>> {
+        let opts = self.opts;
+        self.remaining_ttl_opt(key, opts)
+    }
+
+    /// Same as [`DirCache::remaining_ttl`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::remaining_ttl`]
+    #[inline]
+    pub fn remaining_ttl_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<Duration>> {
+        let Some(expires_at) = self.expires_at_opt(key, opts)? else {
+            return Ok(None);
+        };
+        Ok(Some(
+            expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        ))
+    }
+
+    /// The keys with a valid value in the cache, in no particular order.
+    /// With [`KeyDiscoveryOpt::Lazy`] only keys that have been accessed are known.
+    /// # Errors
+    /// Various io-errors reading entries that haven't been read from disk yet, and removing
+    /// expired ones
+    #[inline]
+    pub fn keys(&mut self) -> Result<Vec<PathBuf>> {
+        self.inner.keys(&self.opts)
+    }
+
+    /// Same as [`DirCache::keys`] but with opts other than what the [`DirCache`] was instantiated
+    /// with.
+    /// # Errors
+    /// Same as [`DirCache::keys`]
+    #[inline]
+    pub fn keys_opt(&mut self, opts: DirCacheOpts) -> Result<Vec<PathBuf>> {
+        self.inner.keys(&opts)
+    }
+
+    /// The keys with a valid value in the cache along with their [`EntryMeta`], in no particular
+    /// order. Only manifests are read, not values, see [`DirCache::keys`].
+    /// # Errors
+    /// Same as [`DirCache::keys`]
+    #[inline]
+    pub fn iter_meta(&mut self) -> Result<impl Iterator<Item = (PathBuf, EntryMeta)>> {
+        self.inner.iter_meta(&self.opts)
+    }
+
+    /// Same as [`DirCache::iter_meta`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::iter_meta`]
+    #[inline]
+    pub fn iter_meta_opt(
+        &mut self,
+        opts: DirCacheOpts,
+    ) -> Result<impl Iterator<Item = (PathBuf, EntryMeta)>> {
+        self.inner.iter_meta(&opts)
+    }
+
+    /// Insert every key and value of `entries`, as with [`DirCache::insert`], stopping at the
+    /// first that fails. [`Extend`] does the same but panics on failure.
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn try_extend<I: IntoIterator<Item = (PathBuf, Vec<u8>)>>(
+        &mut self,
+        entries: I,
+    ) -> Result<()> {
+        entries
+            .into_iter()
+            .try_for_each(|(key, value)| self.insert(&key, value))
+    }
+
+    /// Take every value out of the cache along with its key, removing each entry from memory
+    /// and disk as it's yielded, see [`Drain`].
+    /// Values are read through the interceptors.
+    /// # Errors
+    /// Various io-errors reading entries that haven't been read from disk yet
+    #[inline]
+    pub fn drain(&mut self) -> Result<Drain<'_>> {
+        Drain::new(&mut self.inner, self.opts)
+    }
+
+    /// A [`CacheSummary`] of what's in the cache, entries that haven't been read from disk yet
+    /// are read first.
+    /// # Errors
+    /// Various io-errors reading entries that haven't been read from disk yet
+    #[inline]
+    pub fn summary(&mut self) -> Result<CacheSummary> {
+        self.inner.load_all(&self.opts)?;
+        Ok(self.inner.summarize(&self.opts, unix_time_now()?))
+    }
+
+    /// A [`Snapshot`] of the keys with a valid value in the cache along with their [`EntryMeta`],
+    /// which stays the same while the cache goes on being changed.
+    /// Only manifests are read, not values, see [`DirCache::keys`].
+    /// # Errors
+    /// Same as [`DirCache::keys`]
+    #[inline]
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        self.inner.snapshot(false, self.opts)
+    }
+
+    /// Same as [`DirCache::snapshot`], but with the values as well, read through the
+    /// interceptors. Values kept in memory are shared with the snapshot rather than copied,
+    /// reading the others counts as a read of them.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn snapshot_with_values(&mut self) -> Result<Snapshot> {
+        self.inner.snapshot(true, self.opts)
+    }
+
+    /// Same as [`DirCache::get`] but with a string key, such as a URL or an id.
+    /// String keys are stored as a single directory, named after the key with separators
+    /// and other characters that aren't safe in a file name percent-encoded.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_str(&mut self, key: &str) -> Result<Option<Cow<'_, [u8]>>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_str_key(key).into(), &self.opts)?;
+        self.inner.get_opt(&key, self.opts)
+    }
+
+    /// Same as [`DirCache::get_str`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get`]
+    #[inline]
+    pub fn get_str_opt(&mut self, key: &str, opts: DirCacheOpts) -> Result<Option<Cow<'_, [u8]>>> {
+        let key = self
+            .inner
+            .stored_key(opts.encode_str_key(key).into(), &opts)?;
+        self.inner.get_opt(&key, opts)
+    }
+
+    /// Same as [`DirCache::get_or_insert`] but with a string key, see [`DirCache::get_str`].
+    /// # Errors
+    /// Same as [`DirCache::get_or_insert`]
+    #[inline]
+    pub fn get_or_insert_str<
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
+    >(
+        &mut self,
+        key: &str,
+        insert_with: F,
+    ) -> Result<Cow<'_, [u8]>> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_str_key(key).into(), &self.opts)?;
+        self.inner.get_or_insert_opt(&key, insert_with, self.opts)
+    }
+
+    /// Same as [`DirCache::get_or_insert_str`] but with opts other than what the [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::get_or_insert`]
+    #[inline]
+    pub fn get_or_insert_str_opt<
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
+    >(
+        &mut self,
+        key: &str,
+        insert_with: F,
+        opts: DirCacheOpts,
+    ) -> Result<Cow<'_, [u8]>> {
+        let key = self
+            .inner
+            .stored_key(opts.encode_str_key(key).into(), &opts)?;
+        self.inner.get_or_insert_opt(&key, insert_with, opts)
+    }
+
+    /// Same as [`DirCache::insert`] but with a string key, see [`DirCache::get_str`].
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_str<'a>(&mut self, key: &str, content: impl Into<Cow<'a, [u8]>>) -> Result<()> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_str_key(key).into(), &self.opts)?;
+        self.inner
+            .insert_opt(&key, content.into(), ValueMeta::default(), self.opts)
+    }
+
+    /// Same as [`DirCache::insert_str`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::insert`]
+    #[inline]
+    pub fn insert_str_opt<'a>(
+        &mut self,
+        key: &str,
+        content: impl Into<Cow<'a, [u8]>>,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let key = self
+            .inner
+            .stored_key(opts.encode_str_key(key).into(), &opts)?;
+        self.inner
+            .insert_opt(&key, content.into(), ValueMeta::default(), opts)
+    }
+
+    /// Same as [`DirCache::remove`] but with a string key, see [`DirCache::get_str`].
+    /// # Errors
+    /// Same as [`DirCache::remove`]
+    #[inline]
+    pub fn remove_str(&mut self, key: &str) -> Result<bool> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_str_key(key).into(), &self.opts)?;
+        self.inner.remove(&key, &self.opts)
+    }
+
+    /// Sync in-memory written content to disk, same as [`DirCache::sync`].
+    /// If [`SyncOpt::ManualSync`] and [`MemPushOpt::MemoryOnly`] are both enabled,
+    /// calling this method is the only way to flush map-state to disk.
+    /// # Errors
+    /// Various io-errors related to writing to disk
+    #[inline]
+    pub fn sync(&mut self) -> Result<()> {
+        self.inner.sync_to_disk(self.opts)
+    }
+
+    /// Sync in-memory written content to disk, same as [`DirCache::sync`] but with options
+    /// different to those this [`DirCache`] was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::sync`]
+    #[inline]
+    pub fn sync_opt(&mut self, opts: DirCacheOpts) -> Result<()> {
+        self.inner.sync_to_disk(opts)
+    }
+
+    /// Bring everything on disk in line with this [`DirCache`]'s current [`GenerationOpt`].
+    /// Does everything [`DirCache::vacuum`] does, and additionally re-encodes generations that
+    /// aren't encoded the way they're currently configured to be, rewriting their manifests.
+    /// Useful to migrate existing data in place after changing encoding settings.
+    /// # Errors
+    /// Various io-errors relating to reading, writing, and deleting content on disk,
+    /// or failing to encode or decode content
+    #[inline]
+    pub fn compact(&mut self) -> Result<()> {
+        self.inner.compact(self.opts)
+    }
+
+    /// Remove everything on disk that's no longer needed, without touching what's still valid.
+    /// Expired generations and entries are removed according to this [`DirCache`]'s
+    /// [`GenerationOpt`], same as generations exceeding the max, generation files not referenced
+    /// by their entry's manifest are deleted, and empty directories are removed.
+    /// Expired entries are otherwise only cleaned up when they're accessed.
+    /// # Errors
+    /// Various io-errors relating to reading and deleting content on disk
+    #[inline]
+    pub fn vacuum(&mut self) -> Result<()> {
+        self.inner.vacuum(self.opts)
+    }
+
+    /// Remove every entry whose current value is older than `older_than`, and every older
+    /// generation older than that, regardless of the [`ExpirationOpt`].
+    /// Useful for periodic cleanup of caches where values never expire.
+    /// Returns the number of entries removed.
+    /// # Errors
+    /// Various io-errors relating to reading and deleting content on disk
+    #[inline]
+    pub fn prune(&mut self, older_than: Duration) -> Result<usize> {
+        self.inner.prune(older_than, self.opts)
+    }
+
+    /// Same as [`DirCache::prune`] but with opts other than what the [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::prune`]
+    #[inline]
+    pub fn prune_opt(&mut self, older_than: Duration, opts: DirCacheOpts) -> Result<usize> {
+        self.inner.prune(older_than, opts)
+    }
+
+    /// Remove all but the newest `keep` generations of a key, regardless of
+    /// [`GenerationOpt::max_generations`], a `keep` of 0 removes the key.
+    /// Returns the number of generations removed.
+    /// # Errors
+    /// Various io-errors relating to reading and deleting content on disk, and writing the
+    /// manifest
+    #[inline]
+    pub fn prune_generations(&mut self, key: &Path, keep: usize) -> Result<usize> {
+        let key = self
+            .inner
+            .stored_key(self.opts.encode_key(key), &self.opts)?;
+        self.inner.prune_generations(&key, keep, &self.opts)
+    }
+
+    /// Same as [`DirCache::prune_generations`] but with opts other than what the [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`DirCache::prune_generations`]
+    #[inline]
+    pub fn prune_generations_opt(
+        &mut self,
+        key: &Path,
+        keep: usize,
+        opts: DirCacheOpts,
+    ) -> Result<usize> {
+        let key = self.inner.stored_key(opts.encode_key(key), &opts)?;
+        self.inner.prune_generations(&key, keep, &opts)
+    }
+
+    /// Counts of lookups, inserts, evictions and disk traffic since this [`DirCache`] was
+    /// opened, or [`DirCache::reset_stats`] was last called
+    #[inline]
+    #[must_use]
+    pub fn stats(&self) -> DirCacheStats {
+        self.inner.stats
+    }
+
+    /// Reset the counts of [`DirCache::stats`]
+    #[inline]
+    pub fn reset_stats(&mut self) {
+        self.inner.stats = DirCacheStats::default();
+    }
+
+    /// Entries that couldn't be read when this [`DirCache`] was opened, and were skipped or purged
+    /// according to the [`InvalidEntryOpt`] it was opened with.
+    #[inline]
+    #[must_use]
+    pub fn invalid_entries(&self) -> &[InvalidEntry] {
+        &self.inner.invalid_entries
+    }
+
+    /// Walk everything this [`DirCache`] has on disk and report inconsistencies, such as
+    /// generations listed in a manifest with no file on disk, generation files not listed in any
+    /// manifest, unparsable manifests, and content not matching its recorded checksum.
+    /// Nothing is modified, [`DirCache::compact`] or removing the affected keys can be used
+    /// to clean up afterward.
+    /// # Errors
+    /// Various io-errors relating to reading content from disk
+    #[inline]
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.inner.verify(&self.opts)
+    }
+
+    /// Rewrite manifests of entries in this [`DirCache`] that were written in an older format
+    /// in the current format, returning how many were rewritten.
+    /// Older manifests are read fine and are upgraded whenever their entry is written to,
+    /// this upgrades the rest in place.
+    /// # Errors
+    /// Various io-errors relating to reading and writing manifests on disk
+    #[inline]
+    pub fn migrate_manifests(&mut self) -> Result<usize> {
+        self.inner.migrate_manifests(&self.opts)
+    }
+
+    /// Package everything in this [`DirCache`] into a tar archive at `archive`, with its
+    /// manifests and generation files under the same relative paths as in the cache, so that it
+    /// can be moved to another machine.
+    /// Values only held in memory are written to disk first, and expired entries are left out.
+    /// Every generation gets a checksum recorded in the archived manifest, and generations that
+    /// already had one are checked against it before they're archived.
+    /// # Errors
+    /// Various io-errors relating to reading content from disk and writing the archive,
+    /// or if a generation doesn't match its recorded checksum
+    #[cfg(feature = "tar")]
+    #[inline]
+    pub fn export(&mut self, archive: &Path) -> Result<()> {
+        self.inner.export(archive, self.opts)
+    }
+
+    /// Unpack an archive written by [`DirCache::export`] into this [`DirCache`], keys that are
+    /// already in it are resolved according to the [`ConflictPolicy`].
+    /// Returns the number of keys that were imported.
+    /// Every archived generation is checked against the checksum recorded for it before
+    /// anything of its entry is written, keys imported before a failure are kept.
+    /// # Errors
+    /// Various io-errors relating to reading the archive and writing content to disk,
+    /// if the archive is malformed or a generation doesn't match its checksum,
+    /// or [`Error::KeyCollision`] if a key is already in the cache and the
+    /// [`ConflictPolicy`] is [`ConflictPolicy::Error`]
+    #[cfg(feature = "tar")]
+    #[inline]
+    pub fn import(&mut self, archive: &Path, conflict_policy: ConflictPolicy) -> Result<usize> {
+        self.inner.import(archive, conflict_policy, self.opts)
+    }
+
+    /// Copy the entries of `other` into this [`DirCache`], keys that are already in it are
+    /// resolved according to the [`ConflictPolicy`], comparing when they were last updated.
+    /// Returns the number of keys that were copied.
+    /// `other` is synced first, and is otherwise left as it was, expired entries aren't copied.
+    /// # Errors
+    /// Various io-errors relating to reading content from `other` and writing it to disk,
+    /// if `other` is this same cache, or [`Error::KeyCollision`] if a key is already in the
+    /// cache and the [`ConflictPolicy`] is [`ConflictPolicy::Error`]
+    #[inline]
+    pub fn copy_from(
+        &mut self,
+        other: &mut DirCache,
+        conflict_policy: ConflictPolicy,
+    ) -> Result<usize> {
+        self.inner
+            .copy_from(&mut other.inner, other.opts, conflict_policy, self.opts)
+    }
+
+    /// Copy everything in this [`DirCache`] into `target`, which must not exist or be an empty
+    /// directory, leaving a separate [`DirCache`] that can be opened on its own.
+    /// Values only held in memory are written to disk first, and expired entries are left out,
+    /// copying the directory while the cache is in use could catch an entry halfway through
+    /// being written.
+    /// # Errors
+    /// If `target` isn't empty or is inside this [`DirCache`],
+    /// various io-errors relating to reading content from disk and writing the copy
+    #[inline]
+    pub fn backup_to(&mut self, target: &Path) -> Result<()> {
+        self.inner.backup_to(target, self.opts)
+    }
+
+    /// Re-encode every generation on disk stored with exactly `from`, levels included, as `to`,
+    /// rewriting manifests as it goes.
+    /// Useful to compress an existing cache, together with changing the [`GenerationOpt`] so
+    /// that new values are stored with `to` as well, [`DirCache::compact`] does the same for
+    /// everything that isn't encoded as configured.
+    /// A key that fails to migrate, for example because a generation can't be decoded, is
+    /// reported in the returned [`MigrationReport`] and the rest are migrated regardless.
+    /// Values only held in memory are written with the configured encoding when synced.
+    /// # Errors
+    /// Various io-errors relating to reading the entries of the cache from disk
+    #[inline]
+    pub fn migrate_encoding(&mut self, from: Encoding, to: Encoding) -> Result<MigrationReport> {
+        self.inner.migrate_encoding(from, to, &self.opts)
+    }
+}
+
+/// Summarizes the entries that have been read from disk, without reading the rest,
+/// see [`DirCache::summary`]
+impl Debug for DirCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let summary = self
+            .inner
+            .summarize(&self.opts, unix_time_now().unwrap_or_default());
+        f.debug_struct("DirCache")
+            .field("path", &self.inner.base)
+            .field("entries", &summary.entries)
+            .field("unloaded", &self.inner.unloaded.len())
+            .field("disk_bytes", &summary.disk_bytes)
+            .field("in_memory_bytes", &summary.in_memory_bytes)
+            .field("dirty", &summary.dirty)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Extend<(PathBuf, Vec<u8>)> for DirCache {
+    /// Inserts every key and value, see [`DirCache::try_extend`] to handle failures.
+    /// # Panics
+    /// If an insert fails
+    fn extend<I: IntoIterator<Item = (PathBuf, Vec<u8>)>>(&mut self, iter: I) {
+        if let Err(e) = self.try_extend(iter) {
+            panic!("Failed to extend dir-cache at {:?}: {e}", self.inner.base);
+        }
+    }
+}
+
+impl Drop for DirCache {
+    fn drop(&mut self) {
+        if self.inner.ephemeral {
+            if let Err(e) = std::fs::remove_dir_all(&self.inner.base) {
+                warn!(
+                    "Failed to remove ephemeral dir-cache at {:?} on drop: {e}",
+                    self.inner.base
+                );
+            }
+            return;
+        }
+        if matches!(self.opts.sync_opt, SyncOpt::SyncOnDrop) {
+            if let Err(e) = self.inner.sync_to_disk(self.opts) {
+                warn!(
+                    "Failed to sync dir-cache at {:?} on drop: {e}",
+                    self.inner.base
+                );
+            }
+        }
+    }
+}
+
+struct DirCacheInner {
+    base: PathBuf,
+    store: HashMap<PathBuf, DirCacheEntry>,
+    // Monotonic counter used to order in-memory values by their last access
+    access_tick: u64,
+    // Bytes of the values held in memory, kept up to date wherever one is set or dropped
+    in_mem_bytes: usize,
+    // What the entries take up on disk
+    disk_usage: DiskUsage,
+    // The value last read through the interceptors by `DirCache::get_ref`
+    intercepted: Option<Vec<u8>>,
+    // Entries that couldn't be read on open
+    invalid_entries: Vec<InvalidEntry>,
+    open_options: CacheOpenOptions,
+    // Keys listed in the index that haven't been read from disk yet, and when they were last updated
+    unloaded: HashMap<PathBuf, Duration>,
+    // Whether there's an index on disk that lists every key
+    index_written: bool,
+    // Removed along with everything in it when the cache is dropped
+    ephemeral: bool,
+    // Keys stored under a hashed directory, by the key they're stored under
+    hashed_keys: HashMap<PathBuf, PathBuf>,
+    // The keys in `store` and `unloaded` ignoring case, built when first needed by
+    // `KeyCaseOpt::Detect`
+    folded_keys: Option<FoldedKeys>,
+    stats: DirCacheStats,
+    // Decides which values are kept in memory with `MemBudgetOpt::Moka`
+    #[cfg(feature = "moka")]
+    mem_tier: Option<mem_tier::MemTier>,
+    // Where the entries are kept, see `StorageOpt`
+    storage: Storage,
+}
+
+/// What the entries of a [`DirCacheInner`] take up on disk, kept up to date as they're
+/// inserted, removed, and written
+#[derive(Default)]
+struct DiskUsage {
+    // Combined weight of the entries under the `DiskBudgetOpt`
+    weight: u128,
+    // The size of each pooled value referenced, and the number of entries referencing it
+    pooled: HashMap<u128, (u64, usize)>,
+    // Combined size of the pooled values, each counted once
+    pooled_bytes: u64,
+    // Weighs the entries under `DiskBudgetOpt::MaxWeight`, from the `CacheOpenOptions`
+    weigher: Option<Weigher>,
+}
+
+/// The keys of a [`DirCacheInner`] and the directories they're in, lowercased, to find keys that
+/// differ only in case without going through all of them
+#[derive(Default)]
+struct FoldedKeys {
+    // Each key and each directory above it by its lowercased path, with the path as it's
+    // written and the number of keys it's part of
+    paths: HashMap<PathBuf, (PathBuf, usize)>,
+}
+
+impl FoldedKeys {
+    fn add(&mut self, key: &Path) {
+        for path in key.ancestors().filter(|path| !path.as_os_str().is_empty()) {
+            let (_, keys) = self
+                .paths
+                .entry(KeyCaseOpt::Fold.fold(path).into_owned())
+                .or_insert_with(|| (path.to_path_buf(), 0));
+            *keys += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &Path) {
+        for path in key.ancestors().filter(|path| !path.as_os_str().is_empty()) {
+            let folded = KeyCaseOpt::Fold.fold(path);
+            if let Some((_, keys)) = self.paths.get_mut(folded.as_ref()) {
+                *keys -= 1;
+                if *keys == 0 {
+                    self.paths.remove(folded.as_ref());
+                }
+            }
+        }
+    }
+
+    /// A key, or a directory above one, that `key` or a directory above it differs from only
+    /// in case
+    fn collision(&self, key: &Path) -> Option<&Path> {
+        key.ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .find_map(|path| {
+                self.paths
+                    .get(KeyCaseOpt::Fold.fold(path).as_ref())
+                    .map(|(written, _)| written.as_path())
+                    .filter(|written| *written != path)
+            })
+    }
+}
+
+impl DiskUsage {
+    fn add(&mut self, entry: &DirCacheEntry) {
+        self.weight += u128::from(entry.weight);
+        for &(blob, size) in &entry.pooled {
+            let (_, references) = self.pooled.entry(blob).or_insert((size, 0));
+            if *references == 0 {
+                self.pooled_bytes += size;
+            }
+            *references += 1;
+        }
+    }
+
+    fn remove(&mut self, entry: &DirCacheEntry) {
+        self.weight -= u128::from(entry.weight);
+        for (blob, _) in &entry.pooled {
+            let Some((size, references)) = self.pooled.get_mut(blob) else {
+                continue;
+            };
+            *references -= 1;
+            if *references == 0 {
+                self.pooled_bytes -= *size;
+                self.pooled.remove(blob);
+            }
+        }
+    }
+
+    /// The number of entries referencing the pooled value `blob`
+    fn references(&self, blob: u128) -> usize {
+        self.pooled
+            .get(&blob)
+            .map_or(0, |(_, references)| *references)
+    }
+
+    /// What's counted against the `disk_budget_opt`, pooled values are only counted in bytes
+    fn total(&self, disk_budget_opt: DiskBudgetOpt) -> u128 {
+        match disk_budget_opt {
+            DiskBudgetOpt::MaxBytes(_) => self.weight + u128::from(self.pooled_bytes),
+            DiskBudgetOpt::Unbounded | DiskBudgetOpt::MaxWeight(_) => self.weight,
+        }
+    }
+}
+
+impl DirCacheInner {
+    /// The key an encoded key is stored under, a hashed directory if the paths written for it
+    /// would be longer than [`DirCacheOpts::max_path_len`].
+    /// Fails if the key is outside of the [`opts::KeyPolicy`]
+    fn stored_key<'a>(&mut self, key: Cow<'a, Path>, opts: &DirCacheOpts) -> Result<Cow<'a, Path>> {
+        opts.key_policy.check(&key)?;
+        let Some(max_path_len) = opts.max_path_len else {
+            return Ok(key);
+        };
+        // Keys that can't be joined are refused when used, hashing them would hide that
+        let Ok(dir) = self.base.safe_join(&key) else {
+            return Ok(key);
+        };
+        if dir.as_os_str().len() + 1 + ENTRY_FILE_NAME_ROOM <= max_path_len {
+            return Ok(key);
+        }
+        let hashed = key::hashed_key(&key);
+        self.hashed_keys
+            .entry(hashed.clone())
+            .or_insert_with(|| key.into_owned());
+        Ok(Cow::Owned(hashed))
+    }
+
+    /// The key that's stored under `stored`, as it was given to the cache
+    fn original_key(&self, stored: &Path, opts: &DirCacheOpts) -> PathBuf {
+        let encoded = self
+            .hashed_keys
+            .get(stored)
+            .map_or(stored, PathBuf::as_path);
+        opts.key_codec_opt.decode(encoded).into_owned()
+    }
+
+    fn hit(&mut self, stored: &Path, opts: &DirCacheOpts) {
+        self.stats.hit(stored);
+        self.fire(opts.hooks.on_hit, stored, opts);
+    }
+
+    fn miss(&mut self, stored: &Path, opts: &DirCacheOpts) {
+        self.stats.miss(stored);
+        self.fire(opts.hooks.on_miss, stored, opts);
+    }
+
+    /// Fires `hook` with the original key and the meta of its entry, if any
+    fn fire(&self, hook: Option<Hook>, stored: &Path, opts: &DirCacheOpts) {
+        if let Some(hook) = hook {
+            (hook.0)(
+                &self.original_key(stored, opts),
+                self.store.get(stored).map(DirCacheEntry::meta),
+            );
+        }
+    }
+
+    fn notify_evicted(&mut self, stored: &Path, reason: EvictReason, opts: &DirCacheOpts) {
+        self.stats.eviction();
+        if let Some(listener) = &self.open_options.eviction_listener {
+            (listener.0)(&self.original_key(stored, opts), reason);
+        }
+    }
+
+    /// Keys inside the content-addressed pool would be mistaken for pooled values, and keys
+    /// inside the hashed directory for hashed keys
+    fn check_not_reserved(&self, key: &Path) -> Result<()> {
+        match key.components().next() {
+            Some(Component::Normal(first)) if first == OsStr::new(BLOB_DIR) => {
+                Err(Error::DangerousKey(format!(
+                    "Key {key:?} is inside {BLOB_DIR}, which is reserved for content-addressed values"
+                )))
+            }
+            Some(Component::Normal(first))
+                if first == OsStr::new(HASHED_DIR) && !self.hashed_keys.contains_key(key) =>
+            {
+                Err(Error::DangerousKey(format!(
+                    "Key {key:?} is inside {HASHED_DIR}, which is reserved for hashed keys"
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<Cow<'_, [u8]>>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            // A fallback copied forward has already been loaded
+            let fallback = self
+                .open_options
+                .fallback_opt
+                .as_ref()
+                .filter(|fallback| !fallback.copy_forward)
+                .map(|fallback| fallback.dir.clone());
+            if let Some(fallback) = fallback {
+                if !self.store.contains_key(key) {
+                    if let Some(val) = self.read_fallback(key, &fallback, &opts)? {
+                        self.hit(key, &opts);
+                        return Ok(Some(Cow::Owned(val)));
+                    }
+                }
+            }
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        self.hit(key, &opts);
+        self.read_current(key, opts).map(Some)
+    }
+
+    /// Reads the current value of `key` from the cache at `fallback` without copying it,
+    /// through the interceptors
+    fn read_fallback(
+        &mut self,
+        key: &Path,
+        fallback: &Path,
+        opts: &DirCacheOpts,
+    ) -> Result<Option<Vec<u8>>> {
+        // Keys that can't be joined can't have an entry on disk either
+        let Ok(dir) = fallback.safe_join(key) else {
+            return Ok(None);
+        };
+        let Some((_version, generations, _key)) = DirCacheEntry::read_metadata(
+            &Storage::FileSystem,
+            &dir,
+            &opts.generation_opt,
+            UnknownEncodingOpt::Strict,
+        )?
+        else {
+            return Ok(None);
+        };
+        let Some(gen) = generations.front() else {
+            return Ok(None);
+        };
+        if gen.negative || gen.expired(opts.generation_opt, unix_time_now()?) {
+            return Ok(None);
+        }
+        let file_path = dir.safe_join("dir-cache-generation-0")?;
+        let Some(val) = Storage::FileSystem.read_raw_if_present(&file_path)? else {
+            return Ok(None);
+        };
+        self.stats.disk_read(val.len() as u64);
+        if opts.verify_on_read {
+            gen.verify(&val, &file_path)?;
+        }
+        let val = gen.encoding.decode(val)?;
+        if opts.interceptors.is_empty() {
+            return Ok(Some(val));
+        }
+        let original = self.original_key(key, opts);
+        Ok(Some(
+            opts.intercept_read(&original, Cow::Owned(val))?
+                .into_owned(),
+        ))
+    }
+
+    /// Reads the current value of `key`, which must have one, through the interceptors
+    fn read_current(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Cow<'_, [u8]>> {
+        if opts.interceptors.is_empty() {
+            return self.read_stored(key, opts);
+        }
+        let original = self.original_key(key, &opts);
+        let stored = self.read_stored(key, opts)?;
+        opts.intercept_read(&original, stored)
+    }
+
+    /// Reads the current value of `key` as stored, which must have one
+    fn read_stored(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Cow<'_, [u8]>> {
+        let path = self.base.safe_join(key)?;
+        let tick = self.next_access_tick();
+        let now = unix_time_now()?;
+        let entry = self.store.get_mut(key).unwrap();
+        entry.last_access = tick;
+        entry.record_read(now, opts.access_stats_opt.persisted());
+        if entry.in_mem.is_none() {
+            let file_path = path.safe_join("dir-cache-generation-0")?;
+            let val = self
+                .storage
+                .read_raw_if_present(&file_path)?
+                .ok_or_else(|| {
+                    Error::ReadContent(
+                        format!("No file present on disk where expected at {file_path:?}"),
+                        None,
+                    )
+                })?;
+            self.stats.disk_read(val.len() as u64);
+            // Checked above that there's a generation on disk if there's nothing in memory
+            let gen = entry.on_disk[0];
+            if opts.verify_on_read {
+                gen.verify(&val, &file_path)?;
+            }
+            let val = gen.encoding.decode(val)?;
+            if !opts.mem_pull_opt.keep_after_read(val.len()) {
+                return Ok(Cow::Owned(val));
+            }
+            entry.in_mem = Some(InMemEntry {
+                committed: true,
+                content: val.into(),
+            });
+            self.in_mem_bytes += entry.in_mem_size();
+            self.enforce_mem_budget(opts.mem_budget_opt, Some(key));
+        } else {
+            #[cfg(feature = "moka")]
+            if let Some(mem_tier) = &self.mem_tier {
+                mem_tier.touch(key);
+            }
+        }
+        Ok(Cow::Borrowed(self.in_mem_content(key)))
+    }
+
+    /// The value of `key` held in memory, which must have one
+    fn in_mem_content(&self, key: &Path) -> &[u8] {
+        self.store
+            .get(key)
+            .unwrap()
+            .in_mem
+            .as_ref()
+            .unwrap()
+            .content
+            .as_ref()
+    }
+
+    fn get_with_freshness_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<(Cow<'_, [u8]>, Freshness)>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        let freshness = opts
+            .generation_opt
+            .freshness(self.store[key].last_updated, unix_time_now()?);
+        Ok(self.get_opt(key, opts)?.map(|val| (val, freshness)))
+    }
+
+    fn get_with_meta_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<ValueWithMeta<'_>>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        let source = if self.store[key].in_mem.is_some() {
+            ValueSource::Memory
+        } else {
+            ValueSource::Disk
+        };
+        self.hit(key, &opts);
+        if let Cow::Owned(val) = self.read_current(key, opts)? {
+            return Ok(Some(ValueWithMeta {
+                value: Cow::Owned(val),
+                meta: self.store[key].meta(),
+                source,
+            }));
+        }
+        // Borrowed from what's retained in memory
+        let entry = &self.store[key];
+        Ok(Some(ValueWithMeta {
+            value: Cow::Borrowed(entry.in_mem.as_ref().unwrap().content.as_ref()),
+            meta: entry.meta(),
+            source,
+        }))
+    }
+
+    fn get_generation_opt(
+        &mut self,
+        key: &Path,
+        generation: usize,
+        opts: DirCacheOpts,
+    ) -> Result<Option<Vec<u8>>> {
+        self.load(key, &opts)?;
+        if !self.remove_if_expired(key, &opts)? {
+            return Ok(None);
+        }
+        let path = self.base.safe_join(key)?;
+        let entry = &self.store[key];
+        // A value that's only in memory is the current generation, ahead of those on disk
+        let on_disk = match &entry.in_mem {
+            Some(in_mem) if !in_mem.committed => match generation.checked_sub(1) {
+                Some(on_disk) => on_disk,
+                None => return Ok(Some(in_mem.content.to_vec())),
+            },
+            _ => generation,
+        };
+        let Some(content) = entry.read_generation(
+            &self.storage,
+            &path,
+            on_disk,
+            opts.verify_on_read,
+            &mut self.stats,
+        )?
+        else {
+            return Ok(None);
+        };
+        if opts.interceptors.is_empty() {
+            return Ok(Some(content));
+        }
+        opts.intercept_read(&self.original_key(key, &opts), Cow::Owned(content))
+            .map(|content| Some(content.into_owned()))
+    }
+
+    fn generations(
+        &mut self,
+        key: &Path,
+        opts: &DirCacheOpts,
+    ) -> Result<impl Iterator<Item = GenerationMeta>> {
+        self.load(key, opts)?;
+        if !self.remove_if_expired(key, opts)? {
+            return Ok(Vec::new().into_iter());
+        }
+        Ok(self.store[key].generations().into_iter())
+    }
+
+    /// Same as [`DirCacheInner::get_opt`], but fails if the value was recorded as serialized in
+    /// a format other than `format`
+    fn get_formatted_opt(
+        &mut self,
+        key: &Path,
+        format: u64,
+        opts: DirCacheOpts,
+    ) -> Result<Option<Cow<'_, [u8]>>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        if let Some(stored) = self.store.get(key).and_then(|entry| entry.format) {
+            if stored != format {
+                return Err(Error::FormatMismatch(format!(
+                    "value at key {key:?} was serialized in another format than it's read in"
+                )));
+            }
+        }
+        self.get_opt(key, opts)
+    }
+
+    fn get_shared_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<Arc<[u8]>>> {
+        match self.get_opt(key, opts)? {
+            None => return Ok(None),
+            Some(Cow::Owned(val)) => return Ok(Some(val.into())),
+            Some(Cow::Borrowed(_)) => {}
+        }
+        // Borrowed from what's retained in memory
+        Ok(self
+            .store
+            .get(key)
+            .and_then(|entry| entry.in_mem.as_ref())
+            .map(|in_mem| Arc::clone(&in_mem.content)))
+    }
+
+    fn get_path_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<PathBuf>> {
+        if !self.storage.is_file_system() {
+            return Err(Error::Unsupported(format!(
+                "getting the path of key {key:?}, the cache at {:?} is stored in SQLite",
+                self.base
+            )));
+        }
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        self.hit(key, &opts);
+        let path = self.base.safe_join(key)?;
+        let blob_dir = self.base.safe_join(BLOB_DIR)?;
+        let tick = self.next_access_tick();
+        let now = unix_time_now()?;
+        let entry = self.store.get_mut(key).unwrap();
+        entry.last_access = tick;
+        entry.record_read(now, opts.access_stats_opt.persisted());
+        if entry
+            .in_mem
+            .as_ref()
+            .is_some_and(|in_mem| !in_mem.committed)
+        {
+            self.storage.ensure_dir(&path)?;
+            let prev_blobs = entry.blobs();
+            let before = entry.in_mem_size();
+            let dumped = entry.dump_in_mem(&self.storage, &path, &blob_dir, opts);
+            self.in_mem_bytes = self.in_mem_bytes - before + entry.in_mem_size();
+            dumped?;
+            entry.reweigh(key, opts.disk_budget_opt, &mut self.disk_usage);
+            self.stats.disk_write(entry.written_size());
+            release_blobs(&self.storage, &blob_dir, prev_blobs)?;
+        }
+        Ok(Some(path.safe_join("dir-cache-generation-0")?))
+    }
+
+    fn entry_reader_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<EntryReader<'_>>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        self.hit(key, &opts);
+        let path = self.base.safe_join(key)?;
+        let tick = self.next_access_tick();
+        let now = unix_time_now()?;
+        let entry = self.store.get_mut(key).unwrap();
+        entry.last_access = tick;
+        entry.record_read(now, opts.access_stats_opt.persisted());
+        if let Some(in_mem) = &entry.in_mem {
+            return Ok(Some(EntryReader::from_memory(&in_mem.content)));
+        }
+        let file_path = path.safe_join("dir-cache-generation-0")?;
+        // Checked above that there's a generation on disk if there's nothing in memory
+        let gen = entry.on_disk[0];
+        if opts.verify_on_read {
+            gen.verify_file(&self.storage, &file_path)?;
+        }
+        self.stats.disk_read(gen.stored_size());
+        EntryReader::open(&self.storage, &file_path, gen.encoding).map(Some)
+    }
+
+    #[cfg(feature = "mmap")]
+    fn get_mapped_opt(
+        &mut self,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Option<mapped::MappedValue>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(None);
+        }
+        self.hit(key, &opts);
+        let path = self.base.safe_join(key)?;
+        let tick = self.next_access_tick();
+        let now = unix_time_now()?;
+        let entry = self.store.get_mut(key).unwrap();
+        entry.last_access = tick;
+        entry.record_read(now, opts.access_stats_opt.persisted());
+        if let Some(in_mem) = entry.in_mem.as_ref().filter(|in_mem| !in_mem.committed) {
+            return Ok(Some(mapped::MappedValue::owned(in_mem.content.to_vec())));
+        }
+        let file_path = path.safe_join("dir-cache-generation-0")?;
+        // Checked above that there's a generation on disk if there's nothing uncommitted in memory
+        let gen = entry.on_disk[0];
+        self.stats.disk_read(gen.stored_size());
+        if gen.encoding != Encoding::Plain {
+            let val = self
+                .storage
+                .read_raw_if_present(&file_path)?
+                .ok_or_else(|| {
+                    Error::ReadContent(
+                        format!("No file present on disk where expected at {file_path:?}"),
+                        None,
+                    )
+                })?;
+            if opts.verify_on_read {
+                gen.verify(&val, &file_path)?;
+            }
+            return Ok(Some(mapped::MappedValue::owned(gen.encoding.decode(val)?)));
+        }
+        let mapped = mapped::MappedValue::map(&self.storage, &file_path)?;
+        if opts.verify_on_read {
+            gen.verify(&mapped, &file_path)?;
+        }
+        Ok(Some(mapped))
+    }
+
+    fn keys(&mut self, opts: &DirCacheOpts) -> Result<Vec<PathBuf>> {
+        self.load_all(opts)?;
+        let stored = self.store.keys().cloned().collect::<Vec<_>>();
+        let mut keys = Vec::with_capacity(stored.len());
+        for key in stored {
+            if self.has_value(&key, opts)? {
+                keys.push(self.original_key(&key, opts));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Summarizes the entries in memory that haven't expired at `now`
+    fn summarize(&self, opts: &DirCacheOpts, now: Duration) -> CacheSummary {
+        let mut summary = CacheSummary::default();
+        // Pooled values are counted once, however many entries reference them
+        let mut pooled = HashMap::new();
+        for entry in self.store.values() {
+            if entry.expired(opts.generation_opt, now) {
+                continue;
+            }
+            pooled.extend(entry.pooled_values());
+            let meta = entry.meta();
+            summary.disk_bytes += meta.disk_size();
+            summary.in_memory_bytes += meta.in_memory_size().unwrap_or_default();
+            if entry
+                .in_mem
+                .as_ref()
+                .is_some_and(|in_mem| !in_mem.committed)
+            {
+                summary.dirty += 1;
+            }
+            if entry.negative {
+                continue;
+            }
+            summary.entries += 1;
+            let updated = meta.last_updated();
+            summary.oldest = Some(summary.oldest.map_or(updated, |oldest| oldest.min(updated)));
+            summary.newest = Some(summary.newest.map_or(updated, |newest| newest.max(updated)));
+        }
+        summary.disk_bytes += pooled.values().sum::<u64>();
+        summary
+    }
+
+    fn iter_meta(
+        &mut self,
+        opts: &DirCacheOpts,
+    ) -> Result<impl Iterator<Item = (PathBuf, EntryMeta)>> {
+        self.load_all(opts)?;
+        let stored = self.store.keys().cloned().collect::<Vec<_>>();
+        let mut metas = Vec::with_capacity(stored.len());
+        for key in stored {
+            if self.has_value(&key, opts)? {
+                metas.push((self.original_key(&key, opts), self.store[&key].meta()));
+            }
+        }
+        Ok(metas.into_iter())
+    }
+
+    /// Same as [`DirCacheInner::remove_if_expired`], but a negative entry isn't a value
+    fn has_value(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<bool> {
+        Ok(self.remove_if_expired(key, opts)? && !self.store[key].negative)
+    }
+
+    fn lookup_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Lookup<'_>> {
+        self.load(key, &opts)?;
+        if !self.remove_if_expired(key, &opts)? {
+            self.miss(key, &opts);
+            return Ok(Lookup::Missing);
+        }
+        if self.store[key].negative {
+            self.hit(key, &opts);
+            let tick = self.next_access_tick();
+            self.store.get_mut(key).unwrap().last_access = tick;
+            return Ok(Lookup::Negative);
+        }
+        Ok(self
+            .get_opt(key, opts)?
+            .map_or(Lookup::Missing, Lookup::Value))
+    }
+
+    /// Removes `key` if it has expired, or has no value left.
+    /// Returns whether there's a valid value for `key`
+    fn remove_if_expired(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<bool> {
+        let Some(val) = self.store.get(key) else {
+            return Ok(false);
+        };
+        let generation_opt = opts.generation_opt;
+        let now = unix_time_now()?;
+        let path = self.base.safe_join(key)?;
+        if val.expired(generation_opt, now) {
+            // The value in memory should be younger or equal to the first value on disk
+            // if it's too old, this key should be cleaned
+            let removed = self.store_remove(key);
+            self.remove_entry_dir(&path, removed.as_ref())?;
+            self.notify_evicted(key, EvictReason::Expired, opts);
+            return Ok(false);
+        }
+
+        if let Some(f) = val.on_disk.front() {
+            if f.expired(generation_opt, now) {
+                // No value in mem, also first value on disk is too old, clean up
+                let removed = self.store_remove(key);
+                self.remove_entry_dir(&path, removed.as_ref())?;
+                self.notify_evicted(key, EvictReason::Expired, opts);
+                return Ok(false);
+            }
+        } else if val.in_mem.is_none() {
+            // No value in mem, no values on disk, clean
+            let removed = self.store_remove(key);
+            self.remove_entry_dir(&path, removed.as_ref())?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn get_or_insert_opt<
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
+    >(
+        &mut self,
+        key: &Path,
+        insert_with: F,
+        opts: DirCacheOpts,
+    ) -> Result<Cow<'_, [u8]>> {
+        self.load(key, &opts)?;
+        // Dumb borrow checker, going to end up here on an if let https://blog.rust-lang.org/inside-rust/2023/10/06/polonius-update.html
+        if self.has_value(key, &opts)? {
+            return Ok(self.get_opt(key, opts)?.unwrap());
+        }
+        self.miss(key, &opts);
+        let val = match insert_with() {
+            Ok(val) => val,
+            Err(e) => {
+                return Err(Error::InsertWithErr(e.into()));
+            }
+        };
+        if !self.admit_value_size(key, val.len() as u64, &opts)? {
+            return Ok(Cow::Owned(val));
+        }
+        self.insert_opt(key, Cow::Owned(val), ValueMeta::default(), opts)?;
+        self.read_current(key, opts)
+    }
+
+    fn get_or_refresh_opt<
+        E: Into<Box<dyn std::error::Error>>,
+        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
+    >(
+        &mut self,
+        key: &Path,
+        soft_ttl: Duration,
+        refresh: F,
+        opts: DirCacheOpts,
+    ) -> Result<Cow<'_, [u8]>> {
+        self.load(key, &opts)?;
+        if !self.has_value(key, &opts)? {
+            return self.get_or_insert_opt(key, refresh, opts);
+        }
+        let age = unix_time_now()?.saturating_sub(self.store[key].last_updated);
+        if age < soft_ttl {
+            return Ok(self.get_opt(key, opts)?.unwrap());
+        }
+        let stale = self.get_opt(key, opts)?.unwrap().into_owned();
+        // Serving the stale value, a failed refresh is retried on the next call
+        if let Ok(val) = refresh() {
+            if self.admit_value_size(key, val.len() as u64, &opts)? {
+                self.insert_opt(key, Cow::Owned(val), ValueMeta::default(), opts)?;
+            }
+        }
+        Ok(Cow::Owned(stale))
+    }
+
+    /// Checks a value of `size` bytes about to be inserted for `key` against
+    /// [`DirCacheOpts::max_value_size`], returns whether it should be stored
+    fn admit_value_size(&mut self, key: &Path, size: u64, opts: &DirCacheOpts) -> Result<bool> {
+        let Some(max) = opts.max_value_size else {
+            return Ok(true);
+        };
+        if size <= max {
+            return Ok(true);
+        }
+        match opts.oversize_opt {
+            OversizeOpt::Fail => Err(Error::ValueTooLarge(format!(
+                "value of {size} bytes for key {key:?} exceeds the max of {max} bytes"
+            ))),
+            OversizeOpt::Skip => {
+                self.remove(key, opts)?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn insert_opt(
+        &mut self,
+        key: &Path,
+        content: Cow<'_, [u8]>,
+        meta: ValueMeta,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        self.check_not_reserved(key)?;
+        self.check_case_collision(key, &opts)?;
+        self.load(key, &opts)?;
+        // Negative entries have no value to intercept
+        let content = if meta.negative || opts.interceptors.is_empty() {
+            content
+        } else {
+            opts.intercept_write(&self.original_key(key, &opts), content)?
+        };
+        if !self.admit_value_size(key, content.len() as u64, &opts)? {
+            return Ok(());
+        }
+        self.stats.insert();
+        // Borrow checker strikes again
+        let path = self.base.safe_join(key)?;
+        let blob_dir = self.base.safe_join(BLOB_DIR)?;
+        let tick = self.next_access_tick();
+        if self.store.contains_key(key) {
+            let existing = self.store.get_mut(key).unwrap();
+            let prev_blobs = existing.blobs();
+            existing.format = meta.format;
+            existing.expiration = meta.expiration;
+            existing.label = meta.label;
+            existing.negative = meta.negative;
+            let before = existing.in_mem_size();
+            let written = Self::run_dir_cache_entry_write(
+                &self.storage,
+                existing,
+                &path,
+                &blob_dir,
+                content,
+                opts,
+                tick,
+                &mut self.stats,
+            );
+            self.in_mem_bytes = self.in_mem_bytes - before + existing.in_mem_size();
+            written?;
+            existing.reweigh(key, opts.disk_budget_opt, &mut self.disk_usage);
+            release_blobs(&self.storage, &blob_dir, prev_blobs)?;
+        } else {
+            self.invalidate_index()?;
+            let mut dc = DirCacheEntry::new();
+            dc.format = meta.format;
+            dc.expiration = meta.expiration;
+            dc.label = meta.label;
+            dc.negative = meta.negative;
+            dc.key = self.hashed_keys.get(key).cloned();
+            Self::run_dir_cache_entry_write(
+                &self.storage,
+                &mut dc,
+                &path,
+                &blob_dir,
+                content,
+                opts,
+                tick,
+                &mut self.stats,
+            )?;
+            self.store_insert(key.to_path_buf(), dc, &opts);
+        }
+        self.enforce_mem_budget(opts.mem_budget_opt, None);
+        self.enforce_disk_budget(&opts, Some(key))?;
+        self.mirror(key, &opts)?;
+        self.fire(opts.hooks.on_insert, key, &opts);
+        Ok(())
+    }
+
+    fn insert_file_opt(
+        &mut self,
+        key: &Path,
+        file: &Path,
+        source: &Storage,
+        ingest_opt: IngestOpt,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        self.check_not_reserved(key)?;
+        self.check_case_collision(key, &opts)?;
+        if source.exists(file)? != FileObjectExists::AsFile {
+            return Err(Error::ReadContent(
+                format!("No file to insert at {file:?}"),
+                None,
+            ));
+        }
+        self.load(key, &opts)?;
+        if !self.admit_value_size(key, source.file_size(file)?, &opts)? {
+            return Ok(());
+        }
+        self.stats.insert();
+        let path = self.base.safe_join(key)?;
+        let blob_dir = self.base.safe_join(BLOB_DIR)?;
+        let tick = self.next_access_tick();
+        self.storage.ensure_dir(&path)?;
+        if let Some(existing) = self.store.get_mut(key) {
+            let prev_blobs = existing.blobs();
+            existing.last_access = tick;
+            self.in_mem_bytes -= existing.in_mem_size();
+            existing.in_mem = None;
+            existing.ingest_file(
+                &self.storage,
+                &path,
+                &blob_dir,
+                source,
+                file,
+                ingest_opt,
+                opts,
+            )?;
+            existing.reweigh(key, opts.disk_budget_opt, &mut self.disk_usage);
+            self.stats.disk_write(existing.written_size());
+            release_blobs(&self.storage, &blob_dir, prev_blobs)?;
+        } else {
+            self.invalidate_index()?;
+            let mut dc = DirCacheEntry::new();
+            dc.last_access = tick;
+            dc.key = self.hashed_keys.get(key).cloned();
+            dc.ingest_file(
+                &self.storage,
+                &path,
+                &blob_dir,
+                source,
+                file,
+                ingest_opt,
+                opts,
+            )?;
+            self.stats.disk_write(dc.written_size());
+            self.store_insert(key.to_path_buf(), dc, &opts);
+        }
+        self.enforce_mem_budget(opts.mem_budget_opt, None);
+        self.enforce_disk_budget(&opts, Some(key))?;
+        self.mirror(key, &opts)?;
+        self.fire(opts.hooks.on_insert, key, &opts);
+        Ok(())
+    }
+
+    /// With [`KeyCaseOpt::Detect`], fails if `key` differs only in case from a key in the cache,
+    /// or a directory that one of its components would end up in on a case-insensitive
+    /// file system
+    fn check_case_collision(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<()> {
+        if !matches!(opts.key_case_opt, KeyCaseOpt::Detect) {
+            return Ok(());
+        }
+        let folded_keys = self.folded_keys.get_or_insert_with(|| {
+            let mut folded_keys = FoldedKeys::default();
+            for key in self.store.keys().chain(self.unloaded.keys()) {
+                folded_keys.add(key);
+            }
+            folded_keys
+        });
+        if let Some(other) = folded_keys.collision(key) {
+            return Err(Error::KeyCollision(format!(
+                "key {key:?} differs only in case from {other:?}, which is in the cache"
+            )));
+        }
+        // Every key is known unless they're discovered lazily, then there may be directories
+        // on disk the cache hasn't seen yet
+        if !matches!(self.open_options.key_discovery_opt, KeyDiscoveryOpt::Lazy) {
+            return Ok(());
+        }
+        let mut dir = self.base.clone();
+        for component in key.components() {
+            let name = component.as_os_str();
+            let mut collision = None;
+            if self.storage.exists(&dir)? == FileObjectExists::AsDir {
+                self.storage
+                    .read_all_in_dir(&dir, |entry_path, entry_metadata| {
+                        if let Some(existing) = entry_path.file_name() {
+                            if entry_metadata.is_dir()
+                                && existing != name
+                                && same_ignoring_case(existing, name)
+                            {
+                                collision = Some(entry_path.to_path_buf());
+                            }
+                        }
+                        Ok(())
+                    })?;
+            }
+            if let Some(collision) = collision {
+                return Err(Error::KeyCollision(format!(
+                    "key {key:?} differs only in case from the directory at {collision:?}"
+                )));
+            }
+            dir = dir.safe_join(name)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<bool> {
+        self.load(key, opts)?;
+        let Some(prev) = self.store_remove(key) else {
+            return Ok(false);
+        };
+        let path = self.base.safe_join(key)?;
+        self.remove_entry_dir(&path, Some(&prev))?;
+        self.mirror(key, opts)?;
+        if let Some(hook) = opts.hooks.on_remove {
+            (hook.0)(&self.original_key(key, opts), Some(prev.meta()));
+        }
+        Ok(true)
+    }
+
+    fn touch(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<bool> {
+        self.load(key, opts)?;
+        if !self.remove_if_expired(key, opts)? {
+            return Ok(false);
+        }
+        let path = self.base.safe_join(key)?;
+        let tick = self.next_access_tick();
+        let now = unix_time_now()?;
+        let entry = self.store.get_mut(key).unwrap();
+        entry.last_access = tick;
+        entry.last_updated = now;
+        // A value only in memory gets its age when it's written
+        if entry.in_mem.as_ref().is_none_or(|in_mem| in_mem.committed) {
+            if let Some(front) = entry.on_disk.front_mut() {
+                front.age = now;
+                entry.dump_metadata(&self.storage, &path, opts.durability_opt)?;
+            }
+        }
+        entry.reweigh(key, opts.disk_budget_opt, &mut self.disk_usage);
+        // The index records when entries were last updated
+        self.invalidate_index()?;
+        Ok(true)
+    }
+
+    fn last_updated(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<Option<SystemTime>> {
+        self.load(key, opts)?;
+        if !self.remove_if_expired(key, opts)? {
+            return Ok(None);
+        }
+        Ok(Some(UNIX_EPOCH + self.store[key].last_updated))
+    }
+
+    fn entry_meta(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<Option<EntryMeta>> {
+        self.load(key, opts)?;
+        if !self.remove_if_expired(key, opts)? {
+            return Ok(None);
+        }
+        Ok(Some(self.store[key].meta()))
+    }
+
+    fn expires_at(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<Option<SystemTime>> {
+        self.load(key, opts)?;
+        if !self.remove_if_expired(key, opts)? {
+            return Ok(None);
+        }
+        let expires_at = self.store[key].expires_at(opts.generation_opt);
+        // Never expires if it's past what a `SystemTime` can hold
+        Ok(UNIX_EPOCH.checked_add(expires_at))
+    }
+
+    /// Removes the directory of an entry, along with any directories above it that are left
+    /// empty, up to the base.
+    /// Content-addressed values of the `removed` entry that nothing else references are removed
+    /// as well
+    fn remove_entry_dir(&self, dir: &Path, removed: Option<&DirCacheEntry>) -> Result<()> {
+        self.storage.try_remove_dir(dir)?;
+        if let Some(removed) = removed {
+            release_blobs(
+                &self.storage,
+                &self.base.safe_join(BLOB_DIR)?,
+                removed.blobs(),
+            )?;
+        }
+        self.storage.remove_empty_ancestors(&self.base, dir)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn run_dir_cache_entry_write(
+        storage: &Storage,
+        dc: &mut DirCacheEntry,
+        path: &Path,
+        blob_dir: &Path,
+        content: Cow<'_, [u8]>,
+        opts: DirCacheOpts,
+        tick: u64,
+        stats: &mut DirCacheStats,
+    ) -> Result<()> {
+        dc.last_access = tick;
+        dc.last_read = None;
+        if opts.generation_opt.skip_unchanged && dc.holds(storage, path, &content)? {
+            return dc.mark_rewritten(storage, path, opts.durability_opt);
+        }
+        match opts.mem_push_opt {
+            MemPushOpt::MemoryOnly => {
+                dc.in_mem = Some(InMemEntry {
+                    committed: false,
+                    content: content.into(),
+                });
+                dc.last_updated = unix_time_now()?;
+            }
+            MemPushOpt::RetainAndWrite
+            | MemPushOpt::RetainIfSmallerThan(_)
+            | MemPushOpt::PassthroughWrite => {
+                dc.in_mem = None;
+                storage.ensure_dir(path)?;
+                dc.generational_write(storage, path, blob_dir, &content, opts)?;
+                stats.disk_write(dc.written_size());
+                if opts.mem_push_opt.retain_after_write(content.len()) {
+                    dc.in_mem = Some(InMemEntry {
+                        committed: true,
+                        content: content.into(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_to_disk(&mut self, opts: DirCacheOpts) -> Result<()> {
+        let started = Instant::now();
+        let blob_dir = self.base.safe_join(BLOB_DIR)?;
+        let mut prev_blobs = Vec::new();
+        let mut written = Vec::new();
+        for (k, v) in &mut self.store {
+            let dir = self.base.safe_join(k)?;
+            self.storage.ensure_dir(&dir)?;
+            prev_blobs.extend(v.blobs());
+            let uncommitted = v.in_mem.as_ref().is_some_and(|in_mem| !in_mem.committed);
+            let before = v.in_mem_size();
+            let dumped = v.dump_in_mem(&self.storage, &dir, &blob_dir, opts);
+            self.in_mem_bytes = self.in_mem_bytes - before + v.in_mem_size();
+            dumped?;
+            if uncommitted {
+                v.reweigh(k, opts.disk_budget_opt, &mut self.disk_usage);
+                self.stats.disk_write(v.written_size());
+                if self.open_options.mirror_opt.is_some() {
+                    written.push(k.clone());
+                }
+            }
+        }
+        release_blobs(&self.storage, &blob_dir, prev_blobs)?;
+        for k in written {
+            self.mirror(&k, &opts)?;
+        }
+        // Values that couldn't be dropped before being committed may be dropped now
+        self.enforce_mem_budget(opts.mem_budget_opt, None);
+        self.enforce_disk_budget(&opts, None)?;
+        if matches!(self.open_options.key_discovery_opt, KeyDiscoveryOpt::Index)
+            && !self.index_written
+        {
+            let keys = self
+                .store
+                .iter()
+                .map(|(k, v)| (k, v.last_updated))
+                .chain(
+                    self.unloaded
+                        .iter()
+                        .map(|(k, last_updated)| (k, *last_updated)),
+                )
+                .collect::<Vec<_>>();
+            index::write(
+                &self.storage,
+                &self.base,
+                keys.into_iter(),
+                opts.durability_opt,
+            )?;
+            self.index_written = true;
+        }
+        stats::report_sync(started.elapsed(), self.store.len() + self.unloaded.len());
+        Ok(())
+    }
+
+    /// Reads the entry for `key` from disk if it's listed in the index but hasn't been read yet,
+    /// or with lazy discovery, if it hasn't been seen yet and there's an entry for it on disk.
+    /// Promotes it from the cold tier if there's none, restores it from the [`MirrorOpt`],
+    /// or copies it from the lower layer
+    /// the cache was opened over, or a [`opts::FallbackOpt`] that's copied forward
+    fn load(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<()> {
+        self.discover(key, opts)?;
+        if !self.store.contains_key(key) {
+            if let Some(cold_tier) = self.open_options.cold_tier.clone() {
+                self.promote(key, &cold_tier, opts)?;
+            }
+        }
+        if !self.store.contains_key(key) {
+            if let Some(mirror_opt) = self.open_options.mirror_opt.clone() {
+                let res = self.copy_in(key, &mirror_opt.dir, opts);
+                mirror_opt.handle(res, key)?;
+            }
+        }
+        if !self.store.contains_key(key) {
+            if let Some(lower_layer) = self.open_options.lower_layer.clone() {
+                self.copy_in(key, &lower_layer, opts)?;
+            }
+        }
+        if self
+            .open_options
+            .fallback_opt
+            .as_ref()
+            .is_some_and(|fallback| fallback.copy_forward)
+        {
+            self.copy_forward(key, opts)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the entry of `key` forward from the [`opts::FallbackOpt`] if it's not in this
+    /// cache, whether the fallback is set to copy forward or not
+    fn copy_forward(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<()> {
+        if !self.store.contains_key(key) {
+            if let Some(fallback) = self.open_options.fallback_opt.clone() {
+                self.copy_in(key, &fallback.dir, opts)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn discover(&mut self, key: &Path, opts: &DirCacheOpts) -> Result<()> {
+        let discover = match self.open_options.key_discovery_opt {
+            KeyDiscoveryOpt::Lazy => !self.store.contains_key(key),
+            KeyDiscoveryOpt::WalkTree | KeyDiscoveryOpt::Index => {
+                let unloaded = self.unloaded.remove(key).is_some();
+                if unloaded {
+                    // Added back if there's an entry to read
+                    self.forget_folded(key);
+                }
+                unloaded
+            }
+        };
+        if !discover {
+            return Ok(());
+        }
+        // Keys that can't be joined can't have an entry on disk either
+        let Ok(dir) = self.base.safe_join(key) else {
+            return Ok(());
+        };
+        if matches!(self.storage.exists(&dir), Ok(FileObjectExists::AsDir)) {
+            if let Some(entry) = self.read_entry(&dir, opts)? {
+                self.store_insert(key.to_path_buf(), entry, opts);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads all entries that haven't been read from disk yet
+    fn load_all(&mut self, opts: &DirCacheOpts) -> Result<()> {
+        let unloaded = self.unloaded.keys().cloned().collect::<Vec<_>>();
+        for key in unloaded {
+            self.load(&key, opts)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the index when a key is added that it doesn't list, so that the next open
+    /// doesn't miss it. Keys that have been removed are fine to leave in the index, they're
+    /// found to be missing when read.
+    fn invalidate_index(&mut self) -> Result<()> {
+        if self.index_written {
+            self.storage
+                .ensure_removed_file(&self.base.safe_join(INDEX_FILE)?)?;
+            self.index_written = false;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self, opts: DirCacheOpts) -> Result<()> {
+        self.vacuum(opts)?;
+        let mut prev_blobs = Vec::new();
+        for (k, v) in &mut self.store {
+            let dir = self.base.safe_join(k)?;
+            prev_blobs.extend(v.blobs());
+            v.reencode(
+                &self.storage,
+                &dir,
+                opts.generation_opt,
+                opts.durability_opt,
+            )?;
+            v.reweigh(k, opts.disk_budget_opt, &mut self.disk_usage);
+        }
+        release_blobs(&self.storage, &self.base.safe_join(BLOB_DIR)?, prev_blobs)
+    }
+
+    fn vacuum(&mut self, opts: DirCacheOpts) -> Result<()> {
+        self.load_all(&opts)?;
+        let now = unix_time_now()?;
+        let mut expired = Vec::new();
+        let mut prev_blobs = Vec::new();
+        for (k, v) in &mut self.store {
+            if v.expired(opts.generation_opt, now) {
+                expired.push(k.clone());
+                continue;
+            }
+            let dir = self.base.safe_join(k)?;
+            prev_blobs.extend(v.blobs());
+            v.drop_stale_generations(
+                &self.storage,
+                &dir,
+                opts.generation_opt,
+                opts.durability_opt,
+                now,
+            )?;
+            v.reweigh(k, opts.disk_budget_opt, &mut self.disk_usage);
+            if v.on_disk.is_empty() && v.in_mem.is_none() {
+                expired.push(k.clone());
+            }
+        }
+        let blob_dir = self.base.safe_join(BLOB_DIR)?;
+        release_blobs(&self.storage, &blob_dir, prev_blobs)?;
+        for k in expired {
+            let removed = self.store_remove(&k);
+            self.remove_entry_dir(&self.base.safe_join(&k)?, removed.as_ref())?;
+            self.notify_evicted(&k, EvictReason::Expired, &opts);
+        }
+        self.remove_orphans()?;
+        // Catches values left behind by entries that were dropped without being read
+        sweep_blobs(&self.storage, &blob_dir)?;
+        self.storage.remove_empty_dirs(&self.base, &|dir| {
+            self.open_options.traverses(&self.base, dir)
+        })?;
+        Ok(())
+    }
+
+    fn prune(&mut self, older_than: Duration, opts: DirCacheOpts) -> Result<usize> {
+        self.load_all(&opts)?;
+        let cutoff = unix_time_now()?.saturating_sub(older_than);
+        let mut pruned = Vec::new();
+        let mut prev_blobs = Vec::new();
+        for (k, v) in &mut self.store {
+            if v.last_updated < cutoff {
+                pruned.push(k.clone());
+                continue;
+            }
+            let dir = self.base.safe_join(k)?;
+            prev_blobs.extend(v.blobs());
+            v.drop_oldest_generations(&self.storage, &dir, opts.durability_opt, |_, oldest| {
+                oldest.age < cutoff
+            })?;
+            v.reweigh(k, opts.disk_budget_opt, &mut self.disk_usage);
+            if v.on_disk.is_empty() && v.in_mem.is_none() {
+                pruned.push(k.clone());
+            }
+        }
+        release_blobs(&self.storage, &self.base.safe_join(BLOB_DIR)?, prev_blobs)?;
+        for k in &pruned {
+            let removed = self.store_remove(k);
+            self.remove_entry_dir(&self.base.safe_join(k)?, removed.as_ref())?;
+        }
+        Ok(pruned.len())
+    }
+
+    fn prune_generations(&mut self, key: &Path, keep: usize, opts: &DirCacheOpts) -> Result<usize> {
+        self.load(key, opts)?;
+        if !self.remove_if_expired(key, opts)? {
+            return Ok(0);
+        }
+        let entry = &self.store[key];
+        let generations = entry.on_disk.len();
+        // A value only in memory is the newest generation
+        let uncommitted = entry
+            .in_mem
+            .as_ref()
+            .is_some_and(|in_mem| !in_mem.committed);
+        if keep == 0 {
+            self.remove(key, opts)?;
+            return Ok(generations + usize::from(uncommitted));
+        }
+        let keep_on_disk = keep - usize::from(uncommitted);
+        let path = self.base.safe_join(key)?;
+        let entry = self.store.get_mut(key).unwrap();
+        let prev_blobs = entry.blobs();
+        entry.drop_oldest_generations(&self.storage, &path, opts.durability_opt, |count, _| {
+            count > keep_on_disk
+        })?;
+        entry.reweigh(key, opts.disk_budget_opt, &mut self.disk_usage);
+        let pruned = generations - entry.on_disk.len();
+        release_blobs(&self.storage, &self.base.safe_join(BLOB_DIR)?, prev_blobs)?;
+        Ok(pruned)
+    }
+
+    /// Removes generation files that aren't referenced by the entry they belong to,
+    /// directories with a manifest that isn't loaded (such as skipped invalid entries) are left alone
+    fn remove_orphans(&self) -> Result<()> {
+        let mut walk = DirWalk::new(&self.base, &self.storage, &self.open_options);
+        while let Some(next) = walk.next_dir()? {
+            let generation_files = list_generation_files(&next, &mut walk)?;
+            let key = relative_key(&self.base, &next)?;
+            let referenced = match self.store.get(&key) {
+                Some(entry) => entry.on_disk.len(),
+                None if self.storage.exists(&next.safe_join(MANIFEST_FILE)?)?
+                    != FileObjectExists::No =>
+                {
+                    continue;
+                }
+                None => 0,
+            };
+            for (generation, file) in generation_files {
+                if generation.is_none_or(|generation| generation >= referenced) {
+                    self.storage.ensure_removed_file(&file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn next_access_tick(&mut self) -> u64 {
+        self.access_tick = self.access_tick.wrapping_add(1);
+        self.access_tick
+    }
+
+    /// Inserts `entry` under `key`, counting the bytes of its value in memory, and what it
+    /// takes up on disk
+    fn store_insert(&mut self, key: PathBuf, mut entry: DirCacheEntry, opts: &DirCacheOpts) {
+        entry.weigh(&key, opts.disk_budget_opt, self.disk_usage.weigher.as_ref());
+        self.in_mem_bytes += entry.in_mem_size();
+        self.disk_usage.add(&entry);
+        if let Some(folded_keys) = &mut self.folded_keys {
+            if !self.store.contains_key(&key) && !self.unloaded.contains_key(&key) {
+                folded_keys.add(&key);
+            }
+        }
+        if let Some(replaced) = self.store.insert(key, entry) {
+            self.in_mem_bytes -= replaced.in_mem_size();
+            self.disk_usage.remove(&replaced);
+        }
+    }
+
+    /// Removes the entry of `key`, no longer counting the bytes of its value in memory,
+    /// or what it takes up on disk
+    fn store_remove(&mut self, key: &Path) -> Option<DirCacheEntry> {
+        let removed = self.store.remove(key)?;
+        self.in_mem_bytes -= removed.in_mem_size();
+        self.disk_usage.remove(&removed);
+        self.forget_folded(key);
+        Some(removed)
+    }
+
+    /// Stops counting `key` among the keys differing only in case, if it's no longer in the cache
+    fn forget_folded(&mut self, key: &Path) {
+        if let Some(folded_keys) = &mut self.folded_keys {
+            if !self.store.contains_key(key) && !self.unloaded.contains_key(key) {
+                folded_keys.remove(key);
+            }
+        }
+    }
+
+    /// Drops committed in-memory values, least recently accessed first, until the values
+    /// kept in memory fit in the budget.
+    /// Uncommitted values are never dropped since memory is their only copy,
+    /// and neither is `keep`, which the caller is about to hand out a borrow of.
+    fn enforce_mem_budget(&mut self, mem_budget_opt: MemBudgetOpt, keep: Option<&Path>) {
+        debug_assert_eq!(
+            self.in_mem_bytes,
+            self.store
+                .values()
+                .map(DirCacheEntry::in_mem_size)
+                .sum::<usize>(),
+            "in-memory byte count out of sync"
+        );
+        let max_bytes = match mem_budget_opt {
+            MemBudgetOpt::Unbounded => return,
+            MemBudgetOpt::MaxBytes(max_bytes) => max_bytes,
+            #[cfg(feature = "moka")]
+            MemBudgetOpt::Moka {
+                max_bytes,
+                time_to_idle,
+            } => {
+                self.enforce_mem_tier(max_bytes, time_to_idle, keep);
+                return;
+            }
+        };
+        if self.in_mem_bytes <= max_bytes {
+            return;
+        }
+        let mut candidates = self
+            .store
+            .iter()
+            .filter(|(k, _)| Some(k.as_path()) != keep)
+            .filter_map(|(k, e)| {
+                e.in_mem
+                    .as_ref()
+                    .filter(|in_mem| in_mem.committed)
+                    .map(|_| (e.last_access, k.clone()))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(last_access, _)| *last_access);
+        for (_, k) in candidates {
+            if self.in_mem_bytes <= max_bytes {
+                break;
+            }
+            if let Some(dropped) = self.store.get_mut(&k).and_then(|e| e.in_mem.take()) {
+                self.in_mem_bytes -= dropped.content.len();
+            }
+        }
+    }
+
+    /// Evicts entries, least recently used first, until the combined weight of all entries
+    /// fits in the budget, demoting them to the cold tier if there is one.
+    /// Entries with uncommitted values are never evicted, and neither is `keep`, which
+    /// was just written.
+    fn enforce_disk_budget(&mut self, opts: &DirCacheOpts, keep: Option<&Path>) -> Result<()> {
+        let disk_budget_opt = opts.disk_budget_opt;
+        if matches!(disk_budget_opt, DiskBudgetOpt::Unbounded) {
+            return Ok(());
+        }
+        // Only reads keys listed in an index that haven't been read yet, each of them once
+        self.load_all(opts)?;
+        debug_assert_eq!(
+            self.disk_usage.weight,
+            self.store
+                .values()
+                .map(|e| u128::from(e.weight))
+                .sum::<u128>(),
+            "disk weight out of sync"
+        );
+        let max_weight = u128::from(disk_budget_opt.max_weight());
+        let mut total_weight = self.disk_usage.total(disk_budget_opt);
+        if total_weight <= max_weight {
+            return Ok(());
+        }
+        let mut candidates = self
+            .store
+            .iter()
+            .filter(|(k, e)| {
+                Some(k.as_path()) != keep && e.in_mem.as_ref().is_none_or(|in_mem| in_mem.committed)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_unstable_by_key(|(_, e)| (e.last_access, e.last_updated));
+        // References to pooled values dropped by the evictions so far
+        let mut released = HashMap::new();
+        let mut evict = Vec::new();
+        for (k, e) in candidates {
+            if total_weight <= max_weight {
+                break;
+            }
+            total_weight -= u128::from(e.weight);
+            if matches!(disk_budget_opt, DiskBudgetOpt::MaxBytes(_)) {
+                for &(blob, size) in &e.pooled {
+                    let released = released.entry(blob).or_insert(0);
+                    *released += 1;
+                    // Only freed once nothing else references it
+                    if self.disk_usage.references(blob) == *released {
+                        total_weight -= u128::from(size);
+                    }
+                }
+            }
+            evict.push(k.clone());
+        }
+        for k in evict {
+            if let Some(cold_tier) = &self.open_options.cold_tier {
+                let entry = &self.store[&k];
+                copy_entry(
+                    &self.storage,
+                    &self.base.safe_join(&k)?,
+                    &Storage::FileSystem,
+                    &cold_tier.safe_join(&k)?,
+                    &entry.standalone_generations(),
+                    entry.key.as_deref(),
+                    opts.durability_opt,
+                )?;
+            }
+            let removed = self.store_remove(&k);
+            self.remove_entry_dir(&self.base.safe_join(&k)?, removed.as_ref())?;
+            self.notify_evicted(&k, EvictReason::Evicted, opts);
+        }
+        Ok(())
+    }
+
+    fn migrate_manifests(&mut self, opts: &DirCacheOpts) -> Result<usize> {
+        self.load_all(opts)?;
+        let mut migrated = 0;
+        for key in self.store.keys() {
+            let path = self.base.safe_join(key)?;
+            let Some((version, mut generations, original)) = DirCacheEntry::read_metadata(
+                &self.storage,
+                &path,
+                &opts.generation_opt,
+                UnknownEncodingOpt::Strict,
+            )?
+            else {
+                continue;
+            };
+            if version < manifest::MANIFEST_VERSION {
+                for (ind, gen) in generations.iter_mut().enumerate() {
+                    let file = path.safe_join(format!("dir-cache-generation-{ind}"))?;
+                    if gen.size.is_none() && self.storage.exists(&file)? == FileObjectExists::AsFile
+                    {
+                        gen.size = Some(self.storage.file_size(&file)?);
+                    }
+                }
+                let manifest_path = path.safe_join(MANIFEST_FILE)?;
+                self.storage
+                    .write_file(
+                        &manifest_path,
+                        &manifest::serialize(&generations, original.as_deref()),
+                        opts.durability_opt,
+                    )
+                    .map_err(|e| {
+                        Error::WriteContent(
+                            format!("Failed to write migrated manifest to {manifest_path:?}"),
+                            Some(e),
+                        )
+                    })?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
+    }
+
+    fn backup_to(&mut self, target: &Path, opts: DirCacheOpts) -> Result<()> {
+        if target.starts_with(&self.base) {
+            return Err(Error::Open(format!(
+                "Can't back up {:?} into {target:?}, which is inside of it",
+                self.base
+            )));
+        }
+        match Storage::FileSystem.exists(target)? {
+            FileObjectExists::No => {}
+            FileObjectExists::AsDir if Storage::FileSystem.is_empty_dir(target)? => {}
+            FileObjectExists::AsDir | FileObjectExists::AsFile => {
+                return Err(Error::Open(format!(
+                    "Can't back up into {target:?}, it already exists and isn't an empty directory"
+                )));
+            }
+        }
+        self.sync_to_disk(opts)?;
+        self.load_all(&opts)?;
+        Storage::FileSystem.ensure_dir(target)?;
+        let marker = target.safe_join(MARKER_FILE)?;
+        Storage::FileSystem
+            .write_file(&marker, MARKER_CONTENT, opts.durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(format!("Failed to write marker to {marker:?}"), Some(e))
+            })?;
+        let now = unix_time_now()?;
+        for (k, v) in &self.store {
+            if v.expired(opts.generation_opt, now) {
+                continue;
+            }
+            copy_entry(
+                &self.storage,
+                &self.base.safe_join(k)?,
+                &Storage::FileSystem,
+                &target.safe_join(k)?,
+                &v.standalone_generations(),
+                v.key.as_deref(),
+                opts.durability_opt,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn copy_from(
+        &mut self,
+        other: &mut DirCacheInner,
+        other_opts: DirCacheOpts,
+        conflict_policy: ConflictPolicy,
+        opts: DirCacheOpts,
+    ) -> Result<usize> {
+        let canonical = |storage: &Storage, base: &Path| {
+            storage.canonicalize(base).map_err(|e| {
+                Error::ReadContent(format!("Failed to canonicalize dir at {base:?}"), Some(e))
+            })
+        };
+        if canonical(&self.storage, &self.base)? == canonical(&other.storage, &other.base)? {
+            return Err(Error::Open(format!(
+                "Can't copy {:?} into itself",
+                self.base
+            )));
+        }
+        other.sync_to_disk(other_opts)?;
+        other.load_all(&other_opts)?;
+        let now = unix_time_now()?;
+        let mut entries = other
+            .store
+            .iter()
+            .filter(|(_, entry)| !entry.expired(other_opts.generation_opt, now))
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        let mut copied = 0;
+        for (key, entry) in entries {
+            if entry.on_disk.is_empty()
+                || !self.resolve_conflict(key, entry.last_updated, conflict_policy, &opts)?
+            {
+                continue;
+            }
+            let dir = self.base.safe_join(key)?;
+            copy_entry(
+                &other.storage,
+                &other.base.safe_join(key)?,
+                &self.storage,
+                &dir,
+                &entry.standalone_generations(),
+                entry.key.as_deref(),
+                opts.durability_opt,
+            )?;
+            self.invalidate_index()?;
+            if let Some(copied_entry) = self.read_entry(&dir, &opts)? {
+                self.store_insert(key.clone(), copied_entry, &opts);
+                copied += 1;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Makes way for an entry of `key` from elsewhere, last updated at `updated`, removing the
+    /// existing one if it should be replaced according to the `conflict_policy`.
+    /// Returns whether the entry should be written
+    fn resolve_conflict(
+        &mut self,
+        key: &Path,
+        updated: Duration,
+        conflict_policy: ConflictPolicy,
+        opts: &DirCacheOpts,
+    ) -> Result<bool> {
+        self.load(key, opts)?;
+        if self.remove_if_expired(key, opts)? {
+            match conflict_policy {
+                ConflictPolicy::KeepNewer if self.store[key].last_updated < updated => {}
+                ConflictPolicy::KeepNewer | ConflictPolicy::KeepExisting => return Ok(false),
+                ConflictPolicy::Error => {
+                    return Err(Error::KeyCollision(format!(
+                        "key {key:?} is already in the cache"
+                    )));
+                }
+            }
+            let existing = self.store_remove(key);
+            self.remove_entry_dir(&self.base.safe_join(key)?, existing.as_ref())?;
+        }
+        Ok(true)
+    }
+
+    /// Copies the entry of `key` to the mirror, or removes it there if it's been removed,
+    /// according to the [`MirrorOpt`]
+    fn mirror(&self, key: &Path, opts: &DirCacheOpts) -> Result<()> {
+        let Some(mirror_opt) = &self.open_options.mirror_opt else {
+            return Ok(());
+        };
+        mirror_opt.handle(self.write_mirror(key, &mirror_opt.dir, opts), key)
+    }
+
+    fn write_mirror(&self, key: &Path, mirror_dir: &Path, opts: &DirCacheOpts) -> Result<()> {
+        let to = mirror_dir.safe_join(key)?;
+        let Some(entry) = self.store.get(key) else {
+            Storage::FileSystem.try_remove_dir(&to)?;
+            return Storage::FileSystem.remove_empty_ancestors(mirror_dir, &to);
+        };
+        // Mirrored when it's written to disk
+        if entry
+            .in_mem
+            .as_ref()
+            .is_some_and(|in_mem| !in_mem.committed)
+        {
+            return Ok(());
+        }
+        copy_entry(
+            &self.storage,
+            &self.base.safe_join(key)?,
+            &Storage::FileSystem,
+            &to,
+            &entry.standalone_generations(),
+            entry.key.as_deref(),
+            opts.durability_opt,
+        )
+    }
+
+    /// Moves the entry of `key` from the cold tier back into this cache, if it's there, evicting
+    /// others if that goes over the budget
+    fn promote(&mut self, key: &Path, cold_tier: &Path, opts: &DirCacheOpts) -> Result<()> {
+        // Keys that can't be joined can't have an entry on disk either
+        let Ok(from) = cold_tier.safe_join(key) else {
+            return Ok(());
+        };
+        self.copy_in(key, cold_tier, opts)?;
+        // Removed even if it expired and wasn't kept
+        Storage::FileSystem.try_remove_dir(&from)?;
+        Storage::FileSystem.remove_empty_ancestors(cold_tier, &from)?;
+        if self.store.contains_key(key) {
+            self.enforce_disk_budget(opts, Some(key))?;
+        }
+        Ok(())
+    }
+
+    /// Copies the entry of `key` from the cache at `source` into this one, if it's there
+    fn copy_in(&mut self, key: &Path, source: &Path, opts: &DirCacheOpts) -> Result<()> {
+        // Keys that can't be joined can't have an entry on disk either
+        let Ok(from) = source.safe_join(key) else {
+            return Ok(());
+        };
+        let Some((_version, mut generations, original)) = DirCacheEntry::read_metadata(
+            &Storage::FileSystem,
+            &from,
+            &opts.generation_opt,
+            UnknownEncodingOpt::Strict,
+        )?
+        else {
+            return Ok(());
+        };
+        // Each generation is copied to a file of its own
+        for gen in &mut generations {
+            gen.linked = false;
+            gen.blob = None;
+        }
+        let dir = self.base.safe_join(key)?;
+        copy_entry(
+            &Storage::FileSystem,
+            &from,
+            &self.storage,
+            &dir,
+            &generations,
+            original.as_deref(),
+            opts.durability_opt,
+        )?;
+        self.invalidate_index()?;
+        if let Some(entry) = self.read_entry(&dir, opts)? {
+            self.store_insert(key.to_path_buf(), entry, opts);
+        }
+        Ok(())
+    }
+
+    fn migrate_encoding(
+        &mut self,
+        from: Encoding,
+        to: Encoding,
+        opts: &DirCacheOpts,
+    ) -> Result<MigrationReport> {
+        self.load_all(opts)?;
+        let mut report = MigrationReport::default();
+        let mut prev_blobs = Vec::new();
+        for (k, v) in &mut self.store {
+            let dir = self.base.safe_join(k)?;
+            prev_blobs.extend(v.blobs());
+            if let Err(e) = v.migrate_encoding(
+                &self.storage,
+                &dir,
+                from,
+                to,
+                opts.durability_opt,
+                &mut report.generations_migrated,
+            ) {
+                warn!("Failed to migrate the encoding of dir-cache entry at {dir:?}: {e}");
+                report.failures.push(MigrationFailure {
+                    key: k.clone(),
+                    reason: e.to_string(),
+                });
+            }
+            v.reweigh(k, opts.disk_budget_opt, &mut self.disk_usage);
+        }
+        release_blobs(&self.storage, &self.base.safe_join(BLOB_DIR)?, prev_blobs)?;
+        Ok(report)
+    }
 
-        let val_ref_in_mem = &mut self.store.get_mut(key).unwrap().in_mem;
-        let store = if let Some(in_mem) = val_ref_in_mem {
-            return Ok(Some(Cow::Borrowed(in_mem.content.as_slice())));
+    fn verify(&self, opts: &DirCacheOpts) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut walk = DirWalk::new(&self.base, &self.storage, &self.open_options);
+        while let Some(next) = walk.next_dir()? {
+            let generation_files = list_generation_files(&next, &mut walk)?;
+            let key = relative_key(&self.base, &next)?;
+            let generations = match DirCacheEntry::read_metadata(
+                &self.storage,
+                &next,
+                &opts.generation_opt,
+                UnknownEncodingOpt::Strict,
+            ) {
+                Ok(Some((_version, generations, _key))) => generations,
+                Ok(None) => VecDeque::new(),
+                Err(e) => {
+                    report.issues.push(VerifyIssue::UnparsableManifest {
+                        key,
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            if !generations.is_empty() {
+                report.entries_checked += 1;
+            }
+            for (ind, gen) in generations.iter().enumerate() {
+                let path = next.safe_join(format!("dir-cache-generation-{ind}"))?;
+                let Some(content) = self.storage.read_raw_if_present(&path)? else {
+                    report.issues.push(VerifyIssue::MissingGeneration {
+                        key: key.clone(),
+                        generation: ind,
+                    });
+                    continue;
+                };
+                if !gen.size_matches(content.len() as u64) {
+                    report.issues.push(VerifyIssue::SizeMismatch {
+                        key: key.clone(),
+                        generation: ind,
+                        recorded: gen.stored_size(),
+                        actual: content.len() as u64,
+                    });
+                } else if gen.verify(&content, &path).is_err() {
+                    report.issues.push(VerifyIssue::ChecksumMismatch {
+                        key: key.clone(),
+                        generation: ind,
+                    });
+                }
+            }
+            for (generation, file) in generation_files {
+                if generation.is_none_or(|generation| generation >= generations.len()) {
+                    report.issues.push(VerifyIssue::UnreferencedGeneration {
+                        key: key.clone(),
+                        file,
+                    });
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn read_from_disk(
+        base: PathBuf,
+        cache_open_options: CacheOpenOptions,
+        opts: &DirCacheOpts,
+        storage: Storage,
+    ) -> Result<Self> {
+        let index = if matches!(cache_open_options.key_discovery_opt, KeyDiscoveryOpt::Index) {
+            let index = index::read(&storage, &base)?;
+            if index.is_none() {
+                debug!("No valid index in dir-cache at {base:?}, walking it");
+            }
+            index
         } else {
-            let file_path = path.safe_join("dir-cache-generation-0")?;
-            let val = read_raw_if_present(&file_path)?.ok_or_else(|| {
+            None
+        };
+        let mut inner = Self {
+            index_written: match cache_open_options.key_discovery_opt {
+                KeyDiscoveryOpt::Index => index.is_some(),
+                // Any index left by an earlier open with one needs to be invalidated as well
+                KeyDiscoveryOpt::WalkTree | KeyDiscoveryOpt::Lazy => {
+                    storage.exists(&base.safe_join(INDEX_FILE)?)? != FileObjectExists::No
+                }
+            },
+            base,
+            store: HashMap::new(),
+            access_tick: 0,
+            in_mem_bytes: 0,
+            disk_usage: DiskUsage {
+                weigher: cache_open_options.weigher.clone(),
+                ..DiskUsage::default()
+            },
+            intercepted: None,
+            invalid_entries: Vec::new(),
+            open_options: cache_open_options,
+            unloaded: HashMap::new(),
+            hashed_keys: HashMap::new(),
+            folded_keys: None,
+            ephemeral: false,
+            stats: DirCacheStats::default(),
+            #[cfg(feature = "moka")]
+            mem_tier: None,
+            storage,
+        };
+        if matches!(inner.open_options.key_discovery_opt, KeyDiscoveryOpt::Lazy) {
+            return Ok(inner);
+        }
+        if let Some(index) = index {
+            inner.unloaded = index;
+            // Expired entries are cleaned up on open, same as when walking the tree.
+            // Entries expiring earlier than the cache's expiration are found when accessed
+            let now = unix_time_now()?;
+            let generation_opt = opts.generation_opt;
+            let expired = inner
+                .unloaded
+                .iter()
+                .filter(|(_, last_updated)| generation_opt.expired(None, **last_updated, None, now))
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<_>>();
+            for key in expired {
+                inner.load(&key, opts)?;
+            }
+            return Ok(inner);
+        }
+        let base = inner.base.clone();
+        let storage = inner.storage.clone();
+        let open_options = inner.open_options.clone();
+        let mut walk = DirWalk::new(&base, &storage, &open_options);
+        while let Some(next) = walk.next_dir()? {
+            walk.read_dir(&next, |_, _| Ok(()))?;
+            // Reading the entry may clean up the directory, so it's done after listing it
+            if let Some(entry) = inner.read_entry(&next, opts)? {
+                let relative = relativize(&inner.base, &next)?;
+                inner.store_insert(relative, entry, opts);
+            }
+        }
+        Ok(inner)
+    }
+
+    /// Reads the entry in `dir`, handling entries that can't be read according to the
+    /// [`InvalidEntryOpt`] the cache was opened with.
+    /// Returns `None` if there's no valid entry in `dir`
+    fn read_entry(&mut self, dir: &Path, opts: &DirCacheOpts) -> Result<Option<DirCacheEntry>> {
+        let entry =
+            match DirCacheEntry::read_from_dir(&self.storage, dir, &self.open_options, *opts) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    match self.open_options.invalid_entry_opt {
+                        InvalidEntryOpt::Strict => return Err(e),
+                        InvalidEntryOpt::SkipInvalid => {
+                            warn!("Skipping invalid dir-cache entry at {dir:?}: {e}");
+                        }
+                        InvalidEntryOpt::PurgeInvalid => {
+                            warn!("Purging invalid dir-cache entry at {dir:?}: {e}");
+                            self.remove_entry_dir(dir, None)?;
+                        }
+                    }
+                    let key = relative_key(&self.base, dir)?;
+                    // Lazily discovered entries may be read more than once
+                    if !self
+                        .invalid_entries
+                        .iter()
+                        .any(|invalid| invalid.key == key)
+                    {
+                        self.invalid_entries.push(InvalidEntry {
+                            key,
+                            reason: e.to_string(),
+                        });
+                    }
+                    return Ok(None);
+                }
+            };
+        match entry {
+            ReadEntry::Missing => Ok(None),
+            ReadEntry::Expired(original) => {
+                let stored = relativize(&self.base, dir)?;
+                if let Some(original) = original {
+                    self.hashed_keys.insert(stored.clone(), original);
+                }
+                self.notify_evicted(&stored, EvictReason::Expired, opts);
+                Ok(None)
+            }
+            ReadEntry::Present(de) => {
+                if let Some(original) = &de.key {
+                    self.hashed_keys
+                        .insert(relative_key(&self.base, dir)?, original.clone());
+                }
+                Ok(Some(*de))
+            }
+        }
+    }
+}
+
+/// Copies the entry in `from` with `generations` to `to`, removing any generation files left in
+/// `to` beyond them
+fn copy_entry(
+    from_storage: &Storage,
+    from: &Path,
+    to_storage: &Storage,
+    to: &Path,
+    generations: &VecDeque<ContentGeneration>,
+    key: Option<&Path>,
+    durability_opt: DurabilityOpt,
+) -> Result<()> {
+    to_storage.ensure_dir(to)?;
+    for ind in 0..generations.len() {
+        let name = format!("dir-cache-generation-{ind}");
+        let (from, to) = (from.safe_join(&name)?, to.safe_join(&name)?);
+        from_storage
+            .copy_file(&from, to_storage, &to, durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(
+                    format!("Failed to copy generation from {from:?} to {to:?}"),
+                    Some(e),
+                )
+            })?;
+    }
+    for ind in generations.len().. {
+        let stale = to.safe_join(format!("dir-cache-generation-{ind}"))?;
+        if to_storage.exists(&stale)? == FileObjectExists::No {
+            break;
+        }
+        to_storage.ensure_removed_file(&stale)?;
+    }
+    // Written last, the copied entry isn't there until its manifest is
+    let manifest_path = to.safe_join(MANIFEST_FILE)?;
+    to_storage
+        .write_file(
+            &manifest_path,
+            &manifest::serialize(generations, key),
+            durability_opt,
+        )
+        .map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to write copied manifest to {manifest_path:?}"),
+                Some(e),
+            )
+        })
+}
+
+/// Lists the generation files in `dir`, along with the generation they're named for if that
+/// can be parsed, subdirectories are queued onto `walk`
+fn list_generation_files(dir: &Path, walk: &mut DirWalk) -> Result<Vec<(Option<usize>, PathBuf)>> {
+    let mut generation_files = Vec::new();
+    walk.read_dir(dir, |entry_path, _| {
+        if let Some(generation) = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("dir-cache-generation-"))
+        {
+            generation_files.push((generation.parse::<usize>().ok(), entry_path.to_path_buf()));
+        }
+        Ok(())
+    })?;
+    Ok(generation_files)
+}
+
+/// The key of the entry in `dir`, the base directory itself gets an empty key
+fn relative_key(base: &Path, dir: &Path) -> Result<PathBuf> {
+    if dir == base {
+        Ok(PathBuf::new())
+    } else {
+        relativize(base, dir)
+    }
+}
+
+struct DirCacheEntry {
+    in_mem: Option<InMemEntry>,
+    on_disk: VecDeque<ContentGeneration>,
+    last_updated: Duration,
+    last_access: u64,
+    // The format the current value was serialized in, recorded on its generation when written
+    format: Option<u64>,
+    // Overrides the cache's expiration for the current value, recorded on its generation when
+    // written
+    expiration: Option<ExpirationOpt>,
+    // The key, if it's stored under a hashed directory, recorded in the manifest
+    key: Option<PathBuf>,
+    // When the current value was last read, recorded on its generation when it's written
+    last_read: Option<Duration>,
+    // The current value records a miss rather than a value, recorded on its generation when
+    // written
+    negative: bool,
+    // Number of reads of the key, across values
+    hits: u64,
+    // The label the current value was inserted with, recorded on its generation when written
+    label: Option<Label>,
+    // The weight under the `DiskBudgetOpt`, taken when it was last written or read from disk
+    weight: u64,
+    // The pooled values referenced, and their sizes, as of when it was weighed
+    pooled: Vec<(u128, u64)>,
+}
+
+impl DirCacheEntry {
+    #[must_use]
+    const fn new() -> Self {
+        Self {
+            in_mem: None,
+            on_disk: VecDeque::new(),
+            last_updated: Duration::ZERO,
+            last_access: 0,
+            format: None,
+            expiration: None,
+            key: None,
+            last_read: None,
+            negative: false,
+            hits: 0,
+            label: None,
+            weight: 0,
+            pooled: Vec::new(),
+        }
+    }
+
+    fn expired(&self, generation_opt: GenerationOpt, now: Duration) -> bool {
+        self.expires_at(generation_opt) <= now
+    }
+
+    /// The bytes of the value held in memory, 0 if there is none
+    fn in_mem_size(&self) -> usize {
+        self.in_mem
+            .as_ref()
+            .map_or(0, |in_mem| in_mem.content.len())
+    }
+
+    /// Weighs the entry again after it's changed, updating the `disk_usage` it's counted in
+    fn reweigh(&mut self, key: &Path, disk_budget_opt: DiskBudgetOpt, disk_usage: &mut DiskUsage) {
+        disk_usage.remove(self);
+        self.weigh(key, disk_budget_opt, disk_usage.weigher.as_ref());
+        disk_usage.add(self);
+    }
+
+    fn weigh(&mut self, key: &Path, disk_budget_opt: DiskBudgetOpt, weigher: Option<&Weigher>) {
+        self.weight = disk_budget_opt.weigh(key, &self.meta(), weigher);
+        self.pooled = self.pooled_values();
+    }
+
+    /// The distinct pooled values referenced by this entry's generations, and their sizes
+    fn pooled_values(&self) -> Vec<(u128, u64)> {
+        let mut pooled = self
+            .on_disk
+            .iter()
+            .filter_map(|gen| gen.blob.map(|blob| (blob, gen.stored_size())))
+            .collect::<Vec<_>>();
+        pooled.sort_unstable();
+        pooled.dedup_by_key(|(blob, _)| *blob);
+        pooled
+    }
+
+    /// When the current value expires since the unix epoch
+    fn expires_at(&self, generation_opt: GenerationOpt) -> Duration {
+        generation_opt.expires_at(self.expiration, self.last_updated, self.last_read)
+    }
+
+    /// Records that the current value was read at `now`, and the number of reads of the key
+    /// on its generation if they're `persisted`
+    fn record_read(&mut self, now: Duration, persisted: bool) {
+        self.last_read = Some(now);
+        self.hits += 1;
+        // Only the current value is ever read, it's on disk unless it's only in memory
+        if let Some(front) = self
+            .on_disk
+            .front_mut()
+            .filter(|_| self.in_mem.as_ref().is_none_or(|in_mem| in_mem.committed))
+        {
+            front.read = self.last_read;
+            front.hits = persisted.then_some(self.hits);
+        }
+    }
+
+    /// The size of the current generation as written, nothing if it was linked to an existing
+    /// file
+    fn written_size(&self) -> u64 {
+        self.on_disk
+            .front()
+            .filter(|gen| !gen.linked)
+            .map_or(0, ContentGeneration::stored_size)
+    }
+
+    /// The content-addressed values referenced by this entry's generations
+    fn blobs(&self) -> Vec<u128> {
+        self.on_disk.iter().filter_map(|gen| gen.blob).collect()
+    }
+
+    /// This entry's generations as they're listed when each is copied to a file of its own,
+    /// outside of this cache
+    fn standalone_generations(&self) -> VecDeque<ContentGeneration> {
+        let mut generations = self.on_disk.clone();
+        for gen in &mut generations {
+            gen.linked = false;
+            gen.blob = None;
+        }
+        generations
+    }
+
+    fn meta(&self) -> EntryMeta {
+        EntryMeta {
+            last_updated: UNIX_EPOCH + self.last_updated,
+            // A linked generation shares its file with the next older one, pooled values may be
+            // shared with other entries
+            disk_size: self
+                .on_disk
+                .iter()
+                .filter(|gen| !gen.linked && gen.blob.is_none())
+                .map(ContentGeneration::stored_size)
+                .sum(),
+            pooled_size: self.pooled_values().iter().map(|(_, size)| size).sum(),
+            in_memory_size: self.in_mem.as_ref().map(|in_mem| in_mem.content.len()),
+            generations: self.on_disk.len(),
+            hits: self.hits,
+            last_read: self.last_read.map(|read| UNIX_EPOCH + read),
+            encoding: self
+                .on_disk
+                .front()
+                .filter(|_| self.in_mem.as_ref().is_none_or(|in_mem| in_mem.committed))
+                .map(|gen| gen.encoding),
+        }
+    }
+
+    /// Writes `data` as the new current generation, aging the previous ones.
+    /// With [`DirCacheOpts::journal`], nothing is moved into place until all new content has
+    /// been written to pending files and the resulting manifest to a journal, an interrupted
+    /// write can then be rolled forward from the journal on the next open, or if the journal
+    /// didn't make it, back by discarding the pending files.
+    fn generational_write(
+        &mut self,
+        storage: &Storage,
+        base: &Path,
+        blob_dir: &Path,
+        data: &[u8],
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let generation_opt = opts.generation_opt;
+        let durability_opt = opts.durability_opt;
+        let prev_len = self.on_disk.len();
+        let delta_base = generation_opt.delta_generations.then_some(data);
+        let overwrite = self.overwrites(opts);
+        let (mut moves, gen_queue, older_rewritten) = if overwrite {
+            self.replace_current(storage, base, delta_base, opts)?
+        } else {
+            self.age_generations(storage, base, delta_base, opts)?
+        };
+        let last_update = unix_time_now()?;
+        let current_gen_encoding =
+            generation_opt.encoding_for(generation_opt.generation_encoding(0), data.len());
+        let encoded = current_gen_encoding.encode(data)?;
+        let mut next_gen = ContentGeneration {
+            encoding: current_gen_encoding,
+            age: last_update,
+            size: Some(encoded.len() as u64),
+            delta: false,
+            checksum: Some(checksum(&encoded)),
+            linked: false,
+            blob: None,
+            format: self.format,
+            expiration: self.expiration,
+            read: self.last_read,
+            negative: self.negative,
+            hits: opts.access_stats_opt.persisted().then_some(self.hits),
+            label: self.label,
+        };
+        let next_gen_path = base.safe_join("dir-cache-generation-0")?;
+        let dest = if opts.journal {
+            base.safe_join("dir-cache-generation-pending-0")?
+        } else {
+            next_gen_path.clone()
+        };
+        // The previous generation's file is only shared if it was moved as is
+        let prev = gen_queue.front().filter(|_| !older_rewritten);
+        // With the journal, an aged previous generation hasn't been moved yet
+        let prev_path = base.safe_join(if opts.journal && !overwrite {
+            "dir-cache-generation-0"
+        } else {
+            "dir-cache-generation-1"
+        })?;
+        let (link_source, blob) = Self::link_source(
+            storage, &next_gen, prev, &prev_path, blob_dir, &encoded, opts,
+        )?
+        .unzip();
+        if Self::write_current(storage, &dest, &encoded, link_source, durability_opt)? {
+            next_gen.blob = blob.flatten();
+            // Linked to the previous generation directly, or through the same blob
+            next_gen.linked = next_gen
+                .blob
+                .is_none_or(|blob| prev.is_some_and(|prev| prev.blob == Some(blob)));
+        }
+        self.on_disk.push_front(next_gen);
+        for old in gen_queue {
+            self.on_disk.push_back(old);
+        }
+        self.last_updated = last_update;
+        if opts.journal {
+            moves.push((dest, next_gen_path));
+        }
+        self.commit_write(storage, base, moves, prev_len, opts)
+    }
+
+    /// Writes the file at `file` as the new current generation, aging the previous ones,
+    /// moving or copying it into place as `ingest_opt` says.
+    /// If the value needs to be encoded, diffed against or pooled it has to be read, and is
+    /// written like any other value
+    #[allow(clippy::too_many_arguments)]
+    fn ingest_file(
+        &mut self,
+        storage: &Storage,
+        base: &Path,
+        blob_dir: &Path,
+        source: &Storage,
+        file: &Path,
+        ingest_opt: IngestOpt,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let generation_opt = opts.generation_opt;
+        let durability_opt = opts.durability_opt;
+        let size = source.file_size(file)?;
+        self.format = None;
+        self.expiration = None;
+        self.negative = false;
+        self.label = None;
+        self.last_read = None;
+        let needs_content = generation_opt.delta_generations
+            || (opts.content_addressed && cfg!(unix))
+            || usize::try_from(size).map_or(true, |size| {
+                generation_opt.encoding_for(generation_opt.generation_encoding(0), size)
+                    != Encoding::Plain
+            });
+        if needs_content {
+            let data = source.read_file(file).map_err(|e| {
                 Error::ReadContent(
-                    format!("No file present on disk where expected at {file_path:?}"),
-                    None,
+                    format!("Failed to read file to insert at {file:?}"),
+                    Some(e),
                 )
             })?;
-            if matches!(mem_pull_opt, MemPullOpt::DontKeepInMemoryOnRead) {
-                return Ok(Some(Cow::Owned(val)));
+            self.generational_write(storage, base, blob_dir, &data, opts)?;
+            if matches!(ingest_opt, IngestOpt::Move) {
+                source.ensure_removed_file(file)?;
             }
-            val
+            return Ok(());
+        }
+        let prev_len = self.on_disk.len();
+        let (mut moves, gen_queue, _older_rewritten) = if self.overwrites(opts) {
+            self.replace_current(storage, base, None, opts)?
+        } else {
+            self.age_generations(storage, base, None, opts)?
         };
-        *val_ref_in_mem = Some(InMemEntry {
-            committed: true,
-            content: store,
-        });
-        Ok(Some(Cow::Borrowed(
-            val_ref_in_mem.as_ref().unwrap().content.as_slice(),
-        )))
+        let last_update = unix_time_now()?;
+        let next_gen_path = base.safe_join("dir-cache-generation-0")?;
+        let dest = if opts.journal {
+            base.safe_join("dir-cache-generation-pending-0")?
+        } else {
+            next_gen_path.clone()
+        };
+        let placed = match ingest_opt {
+            IngestOpt::Move => source.move_file(file, storage, &dest, durability_opt),
+            IngestOpt::Copy => source.copy_file(file, storage, &dest, durability_opt),
+        };
+        placed.map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to place {file:?} as the new generation at {dest:?}"),
+                Some(e),
+            )
+        })?;
+        let next_gen = ContentGeneration {
+            encoding: Encoding::Plain,
+            age: last_update,
+            size: Some(size),
+            delta: false,
+            checksum: Some(checksum_file(storage, &dest)?),
+            linked: false,
+            blob: None,
+            format: None,
+            expiration: None,
+            read: None,
+            negative: false,
+            hits: opts.access_stats_opt.persisted().then_some(self.hits),
+            label: None,
+        };
+        self.on_disk.push_front(next_gen);
+        self.on_disk.extend(gen_queue);
+        self.last_updated = last_update;
+        if opts.journal {
+            moves.push((dest, next_gen_path));
+        }
+        self.commit_write(storage, base, moves, prev_len, opts)
     }
 
-    fn get_or_insert_opt<
-        E: Into<Box<dyn std::error::Error>>,
-        F: FnOnce() -> core::result::Result<Vec<u8>, E>,
-    >(
-        &mut self,
-        key: &Path,
-        insert_with: F,
-        mem_pull_opt: MemPullOpt,
-        mem_push_opt: MemPushOpt,
-        generation_opt: GenerationOpt,
-    ) -> Result<Cow<[u8]>> {
-        // Dumb borrow checker, going to end up here on an if let https://blog.rust-lang.org/inside-rust/2023/10/06/polonius-update.html
-        if self.store.contains_key(key) {
-            return Ok(self.get_opt(key, mem_pull_opt, generation_opt)?.unwrap());
+    /// Whether the current value is exactly `content`
+    fn holds(&self, storage: &Storage, base: &Path, content: &[u8]) -> Result<bool> {
+        if let Some(in_mem) = &self.in_mem {
+            return Ok(*in_mem.content == *content);
         }
-        let val = match insert_with() {
-            Ok(val) => val,
-            Err(e) => {
-                return Err(Error::InsertWithErr(e.into()));
-            }
+        let Some(front) = self.on_disk.front() else {
+            return Ok(false);
+        };
+        // The size of a plain generation is the size of the value, no need to read it
+        if front.encoding == Encoding::Plain
+            && (front.size.is_some_and(|size| size != content.len() as u64)
+                || front.checksum.is_some_and(|sum| sum != checksum(content)))
+        {
+            return Ok(false);
+        }
+        let Some(stored) =
+            storage.read_raw_if_present(&base.safe_join("dir-cache-generation-0")?)?
+        else {
+            return Ok(false);
         };
-        let mut entry = DirCacheEntry::new();
-        let use_path = self.base.safe_join(key)?;
-        ensure_dir(&use_path)?;
-        entry.insert_new_data(&use_path, val, mem_push_opt, generation_opt)?;
-        self.store.insert(key.to_path_buf(), entry);
-        Ok(self.get_opt(key, mem_pull_opt, generation_opt)?.unwrap())
+        Ok(front.encoding.decode(stored)? == content)
     }
 
-    fn insert_opt(
+    /// Marks the current value as written now, with the metadata the entry has been given,
+    /// instead of writing it again
+    fn mark_rewritten(
         &mut self,
-        key: &Path,
-        content: Vec<u8>,
-        mem_push_opt: MemPushOpt,
-        generation_opt: GenerationOpt,
+        storage: &Storage,
+        base: &Path,
+        durability_opt: DurabilityOpt,
     ) -> Result<()> {
-        // Borrow checker strikes again
-        let path = self.base.safe_join(key)?;
-        if self.store.contains_key(key) {
-            let existing = self.store.get_mut(key).unwrap();
-            Self::run_dir_cache_entry_write(
-                existing,
-                &path,
-                content,
-                mem_push_opt,
-                generation_opt,
+        let now = unix_time_now()?;
+        self.last_updated = now;
+        // A value only in memory gets its age when it's written
+        if self.in_mem.as_ref().is_none_or(|in_mem| in_mem.committed) {
+            if let Some(front) = self.on_disk.front_mut() {
+                front.age = now;
+                front.format = self.format;
+                front.expiration = self.expiration;
+                front.read = None;
+                front.negative = self.negative;
+                front.label = self.label;
+                self.dump_metadata(storage, base, durability_opt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a write replaces the current generation rather than aging it, see [`InsertMode`]
+    fn overwrites(&self, opts: DirCacheOpts) -> bool {
+        matches!(opts.insert_mode, InsertMode::Overwrite) && !self.on_disk.is_empty()
+    }
+
+    /// Drops the current generation so that it can be replaced, leaving the older ones in place.
+    /// If the next older generation is a delta against the current one it's rewritten, as a
+    /// delta against `delta_base` if given and smaller, otherwise in full.
+    /// Returns the same as [`DirCacheEntry::age_generations`]
+    #[allow(clippy::type_complexity)]
+    fn replace_current(
+        &mut self,
+        storage: &Storage,
+        base: &Path,
+        delta_base: Option<&[u8]>,
+        opts: DirCacheOpts,
+    ) -> Result<(Vec<(PathBuf, PathBuf)>, VecDeque<ContentGeneration>, bool)> {
+        let mut moves = Vec::new();
+        let mut older_rewritten = false;
+        if self.on_disk.get(1).is_some_and(|gen| gen.delta) {
+            // Internal reads aren't counted
+            let full = self
+                .read_generation(storage, base, 1, false, &mut DirCacheStats::default())?
+                .unwrap();
+            let generation_opt = opts.generation_opt;
+            let gen = &mut self.on_disk[1];
+            let new_content = gen.encode_as(
+                full,
+                delta_base,
+                generation_opt.generation_encoding(1),
+                &generation_opt,
             )?;
-        } else {
-            let mut dc = DirCacheEntry::new();
-            Self::run_dir_cache_entry_write(&mut dc, &path, content, mem_push_opt, generation_opt)?;
-            self.store.insert(key.to_path_buf(), dc);
+            let path = base.safe_join("dir-cache-generation-1")?;
+            let dest = if opts.journal {
+                base.safe_join("dir-cache-generation-pending-1")?
+            } else {
+                path.clone()
+            };
+            storage
+                .write_file(&dest, &new_content, opts.durability_opt)
+                .map_err(|e| {
+                    Error::WriteContent(
+                        format!("Failed to write encoded content to {dest:?}"),
+                        Some(e),
+                    )
+                })?;
+            if opts.journal {
+                moves.push((dest, path));
+            }
+            older_rewritten = true;
+        }
+        self.on_disk.pop_front();
+        Ok((moves, self.on_disk.drain(..).collect(), older_rewritten))
+    }
+
+    /// Moves the generations that are kept one step older, re-encoding them as configured,
+    /// the oldest is dropped if there are already as many as can be kept.
+    /// The generation that was current is stored as a delta against `delta_base`, if given.
+    /// Returns the moves deferred until a journal has been written, the aged generations,
+    /// and whether the previously current generation got a new file
+    #[allow(clippy::type_complexity)]
+    fn age_generations(
+        &mut self,
+        storage: &Storage,
+        base: &Path,
+        delta_base: Option<&[u8]>,
+        opts: DirCacheOpts,
+    ) -> Result<(Vec<(PathBuf, PathBuf)>, VecDeque<ContentGeneration>, bool)> {
+        let generation_opt = opts.generation_opt;
+        let durability_opt = opts.durability_opt;
+        let max_rem = generation_opt.max_generations.get();
+        let pending_path =
+            |ind: usize| base.safe_join(format!("dir-cache-generation-pending-{ind}"));
+        // Moves that are deferred until the journal has been written
+        let mut moves = Vec::new();
+        let mut gen_queue = VecDeque::with_capacity(max_rem);
+        // Whether the next older generation got a new file, breaking any link to it,
+        // the oldest generation is dropped
+        let mut older_rewritten = true;
+        for (ind, mut gen) in self.on_disk.drain(..).enumerate().take(max_rem - 1).rev() {
+            if older_rewritten {
+                gen.linked = false;
+            }
+            let n1 = base.safe_join(format!("dir-cache-generation-{ind}"))?;
+            let n2 = base.safe_join(format!("dir-cache-generation-{}", ind + 1))?;
+            // The size of a plain generation is the size of the value, no need to read it
+            let skip_encoding = gen.encoding == Encoding::Plain
+                && gen
+                    .size
+                    .and_then(|size| usize::try_from(size).ok())
+                    .is_some_and(|size| size < generation_opt.min_compress_size);
+            let target_encoding = generation_opt.generation_encoding(ind + 1);
+            // The current generation is always stored in full, it's the base of the next delta
+            let delta_base = delta_base.filter(|_| ind == 0);
+            if (gen.encoding != target_encoding && !skip_encoding) || delta_base.is_some() {
+                let new_content =
+                    gen.recode(storage, &n1, delta_base, target_encoding, &generation_opt)?;
+                older_rewritten = true;
+                let dest = if opts.journal {
+                    pending_path(ind + 1)?
+                } else {
+                    n2.clone()
+                };
+                storage
+                    .write_file(&dest, &new_content, durability_opt)
+                    .map_err(|e| {
+                        Error::WriteContent(
+                            format!("Failed to write encoded content to {dest:?}"),
+                            Some(e),
+                        )
+                    })?;
+                if opts.journal {
+                    moves.push((dest, n2));
+                }
+                // Don't need to remove the old file, it'll be overwritten on the next loop, or in the next step
+            } else if opts.journal {
+                older_rewritten = false;
+                moves.push((n1, n2));
+            } else {
+                older_rewritten = false;
+                // No recoding necessary, just replace
+                storage.rename_file(&n1, &n2, durability_opt).map_err(|e| {
+                    Error::WriteContent(
+                        format!("Failed to migrate generations from {n1:?} to {n2:?}"),
+                        Some(e),
+                    )
+                })?;
+            }
+            gen_queue.push_front(gen);
+        }
+        Ok((moves, gen_queue, older_rewritten))
+    }
+
+    /// Finishes a write of the generations now in `on_disk`, with a journal the pending files
+    /// are moved into place after the journal has been written.
+    fn commit_write(
+        &self,
+        storage: &Storage,
+        base: &Path,
+        moves: Vec<(PathBuf, PathBuf)>,
+        prev_len: usize,
+        opts: DirCacheOpts,
+    ) -> Result<()> {
+        let durability_opt = opts.durability_opt;
+        if opts.journal {
+            self.write_journal(storage, base, moves, durability_opt)?;
+        }
+        // Generations past the max that are left over from before
+        for ind in self.on_disk.len()..prev_len {
+            storage.ensure_removed_file(&base.safe_join(format!("dir-cache-generation-{ind}"))?)?;
+        }
+        self.dump_metadata(storage, base, durability_opt)?;
+        if opts.journal {
+            storage.ensure_removed_file(&base.safe_join(JOURNAL_FILE)?)?;
+        }
+        Ok(())
+    }
+
+    fn write_journal(
+        &self,
+        storage: &Storage,
+        base: &Path,
+        moves: Vec<(PathBuf, PathBuf)>,
+        durability_opt: DurabilityOpt,
+    ) -> Result<()> {
+        let journal_path = base.safe_join(JOURNAL_FILE)?;
+        // The trailing checksum tells a complete journal from one that was cut short
+        let mut journal = manifest::serialize(&self.on_disk, self.key.as_deref());
+        let journal_checksum = checksum(&journal);
+        journal.extend_from_slice(&journal_checksum.to_le_bytes());
+        storage
+            .write_file(&journal_path, &journal, durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(
+                    format!("Failed to write journal to {journal_path:?}"),
+                    Some(e),
+                )
+            })?;
+        for (from, to) in moves {
+            storage
+                .rename_file(&from, &to, durability_opt)
+                .map_err(|e| {
+                    Error::WriteContent(
+                        format!("Failed to move generation from {from:?} to {to:?}"),
+                        Some(e),
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Finishes a journaled write that was interrupted, if there is one.
+    /// If the journal was written, the entry is rolled forward to the generations it lists,
+    /// otherwise the pending files are discarded and the entry is left as it was.
+    fn recover_journal(storage: &Storage, base: &Path, opts: DirCacheOpts) -> Result<()> {
+        let journal_path = base.safe_join(JOURNAL_FILE)?;
+        let Some(journal) = storage.read_raw_if_present(&journal_path)? else {
+            return Ok(());
+        };
+        let complete = journal
+            .split_last_chunk::<8>()
+            .filter(|(manifest, trailer)| checksum(manifest).to_le_bytes() == **trailer)
+            .map(|(manifest, _)| manifest);
+        match complete.map(|manifest| {
+            manifest::parse(
+                manifest,
+                base,
+                &opts.generation_opt,
+                UnknownEncodingOpt::Strict,
+            )
+        }) {
+            Some(Ok((_version, target, original))) => {
+                let on_disk =
+                    Self::repair(storage, base, &target, opts.durability_opt)?.unwrap_or(target);
+                storage
+                    .write_file(
+                        &base.safe_join(MANIFEST_FILE)?,
+                        &manifest::serialize(&on_disk, original.as_deref()),
+                        opts.durability_opt,
+                    )
+                    .map_err(|e| {
+                        Error::WriteContent(
+                            format!("Failed to write manifest recovered from {journal_path:?}"),
+                            Some(e),
+                        )
+                    })?;
+            }
+            // The journal itself was interrupted, nothing has been moved yet
+            _ => {
+                storage.read_all_in_dir(base, |entry_path, entry_metadata| {
+                    if entry_metadata.is_file()
+                        && entry_path
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .is_some_and(|name| name.starts_with("dir-cache-generation-pending-"))
+                    {
+                        storage.ensure_removed_file(entry_path)?;
+                    }
+                    Ok(())
+                })?;
+            }
         }
-        Ok(())
+        storage.ensure_removed_file(&journal_path)
     }
 
-    fn remove(&mut self, key: &Path) -> Result<bool> {
-        let Some(_prev) = self.store.remove(key) else {
-            return Ok(false);
-        };
-        let path = self.base.safe_join(key)?;
-        try_remove_dir(&path)?;
-        Ok(true)
+    /// Picks a file holding exactly `encoded` that the new current generation can be hardlinked
+    /// to, and the content-addressed value it is if any.
+    /// That's the pooled value if [`DirCacheOpts::content_addressed`], otherwise the previous
+    /// generation `prev` if [`GenerationOpt::with_link_identical`] and it's stored the same way
+    fn link_source(
+        storage: &Storage,
+        next: &ContentGeneration,
+        prev: Option<&ContentGeneration>,
+        prev_path: &Path,
+        blob_dir: &Path,
+        encoded: &[u8],
+        opts: DirCacheOpts,
+    ) -> Result<Option<(PathBuf, Option<u128>)>> {
+        if opts.content_addressed && cfg!(unix) {
+            let pooled = pool_blob(storage, blob_dir, encoded, opts.durability_opt)?;
+            return Ok(pooled.map(|(blob, path)| (path, Some(blob))));
+        }
+        let candidate = prev.is_some_and(|prev| {
+            opts.generation_opt.link_identical
+                && prev.encoding == next.encoding
+                && prev.size == next.size
+                && prev.checksum == next.checksum
+        });
+        if !candidate {
+            return Ok(None);
+        }
+        // The checksum matched, but make sure
+        let identical = storage
+            .read_raw_if_present(prev_path)?
+            .is_some_and(|stored| stored == encoded);
+        Ok(identical.then_some((prev_path.to_path_buf(), None)))
     }
 
-    fn run_dir_cache_entry_write(
-        dc: &mut DirCacheEntry,
-        path: &Path,
-        content: Vec<u8>,
-        mem_push_opt: MemPushOpt,
-        generation_opt: GenerationOpt,
-    ) -> Result<()> {
-        match mem_push_opt {
-            MemPushOpt::RetainAndWrite => {
-                ensure_dir(path)?;
-                dc.generational_write(
-                    path,
-                    &content,
-                    generation_opt.old_gen_encoding,
-                    generation_opt.max_generations.get(),
-                )?;
-                dc.in_mem = Some(InMemEntry {
-                    committed: true,
-                    content,
-                });
-            }
-            MemPushOpt::MemoryOnly => {
-                dc.in_mem = Some(InMemEntry {
-                    committed: false,
-                    content,
-                });
-                dc.last_updated = unix_time_now()?;
-            }
-            MemPushOpt::PassthroughWrite => {
-                dc.in_mem = None;
-                ensure_dir(path)?;
-                dc.generational_write(
-                    path,
-                    &content,
-                    generation_opt.old_gen_encoding,
-                    generation_opt.max_generations.get(),
-                )?;
+    /// Writes the new current generation to `dest`, or hardlinks it to `link_source`,
+    /// which holds the exact same bytes.
+    /// Returns whether the generation was linked
+    fn write_current(
+        storage: &Storage,
+        dest: &Path,
+        encoded: &[u8],
+        link_source: Option<PathBuf>,
+        durability_opt: DurabilityOpt,
+    ) -> Result<bool> {
+        if let Some(source) = link_source {
+            // Not all file systems support hardlinks, fall back to writing the content
+            if storage.link_file(&source, dest, durability_opt).is_ok() {
+                return Ok(true);
             }
         }
-        Ok(())
+        storage
+            .write_file(dest, encoded, durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(
+                    format!("Failed to write new generation to {dest:?}"),
+                    Some(e),
+                )
+            })?;
+        Ok(false)
     }
 
-    fn sync_to_disk(
+    /// Removes generations that have expired or exceed the max generations
+    fn drop_stale_generations(
         &mut self,
-        mem_push_opt: MemPushOpt,
+        storage: &Storage,
+        base: &Path,
         generation_opt: GenerationOpt,
+        durability_opt: DurabilityOpt,
+        now: Duration,
     ) -> Result<()> {
-        for (k, v) in &mut self.store {
-            let dir = self.base.safe_join(k)?;
-            ensure_dir(&dir)?;
-            let max_rem = generation_opt.max_generations.get();
-            v.dump_in_mem(
-                &dir,
-                matches!(mem_push_opt, MemPushOpt::RetainAndWrite),
-                max_rem,
-                generation_opt.old_gen_encoding,
-            )?;
-        }
-        Ok(())
+        self.drop_oldest_generations(storage, base, durability_opt, |count, oldest| {
+            count > generation_opt.max_generations.get() || oldest.expired(generation_opt, now)
+        })
     }
 
-    fn read_from_disk(
-        base: PathBuf,
-        eager_load: bool,
-        generation_opt: GenerationOpt,
-    ) -> Result<Self> {
-        let mut check_next = VecDeque::new();
-        check_next.push_front(base.clone());
-        let mut store = HashMap::new();
-        while let Some(next) = check_next.pop_front() {
-            let entry = DirCacheEntry::read_from_dir(&next, eager_load, generation_opt)?;
-            read_all_in_dir(&next, |entry_path, entry_metadata| {
-                if entry_metadata.is_dir() {
-                    check_next.push_back(entry_path.to_path_buf());
-                }
-                Ok(())
-            })?;
-            if let Some(de) = entry {
-                let relative = relativize(&base, &next)?;
-                store.insert(relative, de);
-            }
+    /// Removes the oldest generation for as long as `drop` returns true for it, given the
+    /// number of generations left
+    fn drop_oldest_generations<F: Fn(usize, &ContentGeneration) -> bool>(
+        &mut self,
+        storage: &Storage,
+        base: &Path,
+        durability_opt: DurabilityOpt,
+        drop: F,
+    ) -> Result<()> {
+        let before = self.on_disk.len();
+        while self
+            .on_disk
+            .back()
+            .is_some_and(|oldest| drop(self.on_disk.len(), oldest))
+        {
+            self.on_disk.pop_back();
+            let file_name = format!("dir-cache-generation-{}", self.on_disk.len());
+            storage.ensure_removed_file(&base.safe_join(file_name)?)?;
+        }
+        if self.on_disk.len() == before {
+            return Ok(());
+        }
+        if let Some(last) = self.on_disk.back_mut() {
+            last.linked = false;
+        }
+        if self.on_disk.is_empty() {
+            storage.ensure_removed_file(&base.safe_join(MANIFEST_FILE)?)?;
+        } else {
+            self.dump_metadata(storage, base, durability_opt)?;
         }
-        Ok(Self { base, store })
+        Ok(())
     }
-}
 
-struct DirCacheEntry {
-    in_mem: Option<InMemEntry>,
-    on_disk: VecDeque<ContentGeneration>,
-    last_updated: Duration,
-}
+    /// The generations of this entry, newest first, a value only in memory ahead of those on disk
+    fn generations(&self) -> Vec<GenerationMeta> {
+        let in_mem = self
+            .in_mem
+            .as_ref()
+            .filter(|in_mem| !in_mem.committed)
+            .map(|in_mem| GenerationMeta {
+                index: 0,
+                written: UNIX_EPOCH + self.last_updated,
+                encoding: None,
+                size: in_mem.content.len() as u64,
+                label: self.label,
+            });
+        let on_disk = self.on_disk.iter().map(|gen| GenerationMeta {
+            index: 0,
+            written: UNIX_EPOCH + gen.age,
+            encoding: Some(gen.encoding),
+            size: gen.stored_size(),
+            label: gen.label,
+        });
+        in_mem
+            .into_iter()
+            .chain(on_disk)
+            .enumerate()
+            .map(|(index, meta)| GenerationMeta { index, ..meta })
+            .collect()
+    }
 
-impl DirCacheEntry {
-    #[must_use]
-    const fn new() -> Self {
-        Self {
-            in_mem: None,
-            on_disk: VecDeque::new(),
-            last_updated: Duration::ZERO,
+    /// Reads and decodes generation `generation` on disk, `None` if there is no such generation.
+    /// A delta is applied to the full values of the newer generations it's based on
+    fn read_generation(
+        &self,
+        storage: &Storage,
+        base: &Path,
+        generation: usize,
+        verify: bool,
+        stats: &mut DirCacheStats,
+    ) -> Result<Option<Vec<u8>>> {
+        if generation >= self.on_disk.len() {
+            return Ok(None);
+        }
+        // Reading starts at the closest generation that's stored in full
+        let start = (0..=generation)
+            .rev()
+            .find(|ind| !self.on_disk[*ind].delta)
+            .ok_or_else(|| {
+                Error::ParseManifest(format!(
+                    "Generation {generation} at {base:?} is a delta without a newer generation"
+                ))
+            })?;
+        let mut full = Vec::new();
+        for ind in start..=generation {
+            let gen = self.on_disk[ind];
+            let path = base.safe_join(format!("dir-cache-generation-{ind}"))?;
+            let content = storage.read_file(&path).map_err(|e| {
+                Error::ReadContent(
+                    format!("Failed to read generation {ind} from {path:?}"),
+                    Some(e),
+                )
+            })?;
+            stats.disk_read(content.len() as u64);
+            if verify {
+                gen.verify(&content, &path)?;
+            }
+            let content = gen.encoding.decode(content)?;
+            full = if gen.delta {
+                delta::apply(&full, &content)?
+            } else {
+                content
+            };
         }
+        Ok(Some(full))
     }
 
-    fn insert_new_data(
+    /// Re-encodes generations stored as `from` as `to`, counting them in `migrated`.
+    /// The manifest is rewritten even if a later generation fails, to keep the ones already
+    /// re-encoded
+    fn migrate_encoding(
         &mut self,
-        path: &Path,
-        data: Vec<u8>,
-        mem_push_opt: MemPushOpt,
-        generation_opt: GenerationOpt,
+        storage: &Storage,
+        base: &Path,
+        from: Encoding,
+        to: Encoding,
+        durability_opt: DurabilityOpt,
+        migrated: &mut usize,
     ) -> Result<()> {
-        match mem_push_opt {
-            MemPushOpt::RetainAndWrite => {
-                self.generational_write(
-                    path,
-                    &data,
-                    generation_opt.old_gen_encoding,
-                    generation_opt.max_generations.get(),
-                )?;
-                self.in_mem = Some(InMemEntry {
-                    committed: false,
-                    content: data,
-                });
+        let mut result = Ok(());
+        let mut rewritten = false;
+        for ind in 0..self.on_disk.len() {
+            if self.on_disk[ind].encoding != from {
+                continue;
             }
-            MemPushOpt::MemoryOnly => {
-                self.in_mem = Some(InMemEntry {
-                    committed: false,
-                    content: data,
-                });
-                self.last_updated = unix_time_now()?;
-            }
-            MemPushOpt::PassthroughWrite => {
-                self.generational_write(
-                    path,
-                    &data,
-                    generation_opt.old_gen_encoding,
-                    generation_opt.max_generations.get(),
-                )?;
+            let path = base.safe_join(format!("dir-cache-generation-{ind}"))?;
+            if let Err(e) = self.on_disk[ind].migrate_file(storage, &path, to, durability_opt) {
+                result = Err(e);
+                break;
             }
+            // A rewritten generation has its own file, it's no longer linked to or from
+            if let Some(newer) = ind.checked_sub(1) {
+                self.on_disk[newer].linked = false;
+            }
+            rewritten = true;
+            *migrated += 1;
         }
-        Ok(())
+        if rewritten {
+            self.dump_metadata(storage, base, durability_opt)?;
+        }
+        result
     }
 
-    fn generational_write(
+    /// Re-encodes generations not encoded as configured
+    fn reencode(
         &mut self,
+        storage: &Storage,
         base: &Path,
-        data: &[u8],
-        old_gen_encoding: Encoding,
-        max_rem: usize,
+        generation_opt: GenerationOpt,
+        durability_opt: DurabilityOpt,
     ) -> Result<()> {
-        while self.on_disk.len() > max_rem {
-            let file_name = format!("dir-cache-generation-{}", self.on_disk.len());
-            let file = base.safe_join(&file_name)?;
-            ensure_removed_file(&file)?;
-            self.on_disk.pop_back();
-        }
-        let mut gen_queue = VecDeque::with_capacity(max_rem);
-        for (ind, gen) in self.on_disk.drain(..).enumerate().take(max_rem - 1).rev() {
-            let n1 = base.safe_join(format!("dir-cache-generation-{ind}"))?;
-            let n2 = base.safe_join(format!("dir-cache-generation-{}", ind + 1))?;
-            if ind == 0 && !matches!(old_gen_encoding, Encoding::Plain) {
-                let content = std::fs::read(&n1).map_err(|e| {
-                    Error::ReadContent(
-                        format!("Failed to read first generation from {n1:?}"),
-                        Some(e),
-                    )
-                })?;
-                let new_content = old_gen_encoding.encode(content)?;
-                std::fs::write(&n2, new_content).map_err(|e| {
-                    Error::WriteContent(
-                        format!("Failed to write encoded content to {n2:?}"),
-                        Some(e),
-                    )
-                })?;
-                // Don't need to remove the old file, it'll be overwritten on the next loop, or in the next step
-            } else {
-                // No recoding necessary, just replace
-                std::fs::rename(&n1, &n2).map_err(|e| {
+        let mut rewritten = Vec::new();
+        // Deltas need the full value of the next newer generation to be read or created
+        let any_delta =
+            generation_opt.delta_generations || self.on_disk.iter().any(|gen| gen.delta);
+        let mut newer: Option<Vec<u8>> = None;
+        for (ind, gen) in self.on_disk.iter_mut().enumerate() {
+            let configured = generation_opt.generation_encoding(ind);
+            // The size of a plain generation is the size of the value, no need to read it
+            if !any_delta
+                && gen.encoding == Encoding::Plain
+                && gen
+                    .size
+                    .and_then(|size| usize::try_from(size).ok())
+                    .is_some_and(|size| {
+                        generation_opt.encoding_for(configured, size) == Encoding::Plain
+                    })
+            {
+                continue;
+            }
+            let path = base.safe_join(format!("dir-cache-generation-{ind}"))?;
+            let content = storage.read_file(&path).map_err(|e| {
+                Error::ReadContent(
+                    format!("Failed to read generation {ind} from {path:?}"),
+                    Some(e),
+                )
+            })?;
+            let content = gen.encoding.decode(content)?;
+            let full = match (gen.delta, newer.as_deref()) {
+                (false, _) => content,
+                (true, Some(newer)) => delta::apply(newer, &content)?,
+                (true, None) => {
+                    return Err(Error::ParseManifest(format!(
+                        "Generation {ind} at {base:?} is a delta without a newer generation"
+                    )));
+                }
+            };
+            let delta = newer
+                .as_deref()
+                .filter(|_| generation_opt.delta_generations)
+                .map(|newer| delta::diff(newer, &full))
+                .filter(|delta| delta.len() < full.len());
+            let is_delta = delta.is_some();
+            let stored = delta.as_deref().unwrap_or(&full);
+            let encoding = generation_opt.encoding_for(configured, stored.len());
+            if encoding == gen.encoding && is_delta == gen.delta {
+                newer = Some(full);
+                continue;
+            }
+            let new_content = encoding.encode(stored)?;
+            storage
+                .write_file(&path, &new_content, durability_opt)
+                .map_err(|e| {
                     Error::WriteContent(
-                        format!("Failed to migrate generations from {n1:?} to {n2:?}"),
+                        format!("Failed to write re-encoded content to {path:?}"),
                         Some(e),
                     )
                 })?;
-            }
-            gen_queue.push_front(gen);
+            gen.encoding = encoding;
+            gen.delta = is_delta;
+            gen.blob = None;
+            gen.size = Some(new_content.len() as u64);
+            gen.checksum = Some(checksum(&new_content));
+            newer = Some(full);
+            rewritten.push(ind);
         }
-        let last_update = unix_time_now()?;
-        let next_gen = ContentGeneration {
-            encoding: Encoding::Plain,
-            age: last_update,
-        };
-        self.on_disk.push_front(next_gen);
-        for old in gen_queue {
-            self.on_disk.push_back(old);
+        if !rewritten.is_empty() {
+            // A rewritten generation has its own file, it's no longer linked to or from
+            for ind in rewritten {
+                self.on_disk[ind].linked = false;
+                if let Some(newer) = ind.checked_sub(1) {
+                    self.on_disk[newer].linked = false;
+                }
+            }
+            self.dump_metadata(storage, base, durability_opt)?;
         }
-        self.last_updated = last_update;
-        let next_gen_path = base.safe_join("dir-cache-generation-0")?;
-        std::fs::write(&next_gen_path, data).map_err(|e| {
-            Error::WriteContent(
-                format!("Failed to write new generation to {next_gen_path:?}"),
-                Some(e),
-            )
-        })?;
-        self.dump_metadata(base)?;
         Ok(())
     }
 
     fn read_from_dir(
+        storage: &Storage,
         base: &Path,
-        eager_load: bool,
-        generation_opt: GenerationOpt,
-    ) -> Result<Option<Self>> {
-        let Some((version, entries)) = Self::read_metadata(base)? else {
-            return Ok(None);
+        cache_open_options: &CacheOpenOptions,
+        opts: DirCacheOpts,
+    ) -> Result<ReadEntry> {
+        let generation_opt = opts.generation_opt;
+        Self::recover_journal(storage, base, opts)?;
+        let metadata = match Self::read_metadata(
+            storage,
+            base,
+            &generation_opt,
+            cache_open_options.unknown_encoding_opt,
+        ) {
+            // None of the generations can be trusted to be what the manifest says
+            Err(Error::ChecksumMismatch(reason))
+                if matches!(cache_open_options.repair_opt, RepairOpt::Repair) =>
+            {
+                warn!("Removing dir-cache entry at {base:?}, {reason}");
+                storage.try_remove_dir(base)?;
+                return Ok(ReadEntry::Missing);
+            }
+            metadata => metadata?,
         };
-        if version != MANIFEST_VERSION {
-            return Err(Error::ParseManifest(format!(
-                "Version mismatch, want={MANIFEST_VERSION}, got={version}"
-            )));
+        let Some((_version, mut entries, key)) = metadata else {
+            return Ok(ReadEntry::Missing);
+        };
+        let mut repaired = false;
+        if matches!(cache_open_options.repair_opt, RepairOpt::Repair) {
+            if let Some(kept) = Self::repair(storage, base, &entries, opts.durability_opt)? {
+                entries = kept;
+                repaired = true;
+            }
         }
         let now = unix_time_now()?;
         let mut in_mem = None;
         let mut on_disk = VecDeque::with_capacity(entries.len());
         let mut last_updated = None;
-        for (ind, (age, enc)) in entries.into_iter().enumerate() {
-            if age.saturating_add(generation_opt.expiration.as_dur()) <= now {
-                ensure_removed_file(&base.safe_join(format!("dir-cache-generation-{ind}"))?)?;
-                continue;
+        let num_generations = entries.len();
+        let mut truncated = false;
+        for (ind, mut gen) in entries.into_iter().enumerate() {
+            // Older generations are dropped with it, the kept ones have to stay numbered in order
+            if gen.expired(generation_opt, now) {
+                for older in ind..num_generations {
+                    let older = base.safe_join(format!("dir-cache-generation-{older}"))?;
+                    storage.ensure_removed_file(&older)?;
+                }
+                truncated = true;
+                break;
+            }
+            let path = base.safe_join(format!("dir-cache-generation-{ind}"))?;
+            // Only statted if the manifest is from before sizes were recorded
+            if gen.size.is_none() {
+                gen.size = Some(storage.file_size(&path)?);
             }
             if ind == 0 {
-                last_updated = Some(age);
-                if eager_load {
-                    let path = base.safe_join(format!("dir-cache-generation-{ind}"))?;
-                    let content = std::fs::read(&path).map_err(|e| {
+                last_updated = Some(gen.age);
+                if cache_open_options.eager_load_to_ram {
+                    let content = storage.read_file(&path).map_err(|e| {
                         Error::ReadContent(
                             format!("Failed to eager load content from {path:?}"),
                             Some(e),
                         )
                     })?;
+                    if opts.verify_on_read {
+                        gen.verify(&content, &path)?;
+                    }
+                    let content = gen.encoding.decode(content)?;
                     in_mem = Some(InMemEntry {
                         committed: true,
-                        content,
+                        content: content.into(),
                     });
                 }
             }
-            on_disk.push_back(ContentGeneration { encoding: enc, age });
+            on_disk.push_back(gen);
+        }
+        if let Some(last) = on_disk.back_mut() {
+            last.linked = false;
         }
         if let Some(last_updated) = last_updated {
-            Ok(Some(Self {
+            let format = on_disk.front().and_then(|gen| gen.format);
+            let expiration = on_disk.front().and_then(|gen| gen.expiration);
+            let last_read = on_disk.front().and_then(|gen| gen.read);
+            let negative = on_disk.front().is_some_and(|gen| gen.negative);
+            let hits = on_disk.front().and_then(|gen| gen.hits).unwrap_or_default();
+            let label = on_disk.front().and_then(|gen| gen.label);
+            let entry = Self {
                 in_mem,
                 on_disk,
                 last_updated,
-            }))
+                last_access: 0,
+                format,
+                expiration,
+                key,
+                last_read,
+                negative,
+                hits,
+                label,
+                // Weighed when it's inserted into the store
+                weight: 0,
+                pooled: Vec::new(),
+            };
+            if repaired || truncated {
+                entry.dump_metadata(storage, base, opts.durability_opt)?;
+            }
+            Ok(ReadEntry::Present(Box::new(entry)))
         } else {
-            Ok(None)
+            // Everything has expired, clean up the manifest as well
+            storage.try_remove_dir(base)?;
+            Ok(ReadEntry::Expired(key))
         }
     }
 
-    #[allow(clippy::type_complexity)]
-    fn read_metadata(base: &Path) -> Result<Option<(u64, VecDeque<(Duration, Encoding)>)>> {
-        let Some(content) = read_metadata_if_present(&base.safe_join(MANIFEST_FILE)?)? else {
-            return Ok(None);
-        };
-        let mut lines = content.lines();
-        let Some(first) = lines.next() else {
-            return Err(Error::ParseMetadata(format!(
-                "Manifest at {base:?} was empty"
-            )));
-        };
-        let version: u64 = first.parse().map_err(|_| {
-            Error::ParseMetadata(format!("Failed to parse version from metadata at {base:?}"))
+    /// Checks that the generation files on disk are exactly the ones the manifest lists, with
+    /// the recorded sizes, if not matches files to the listed generations, by size and checksum
+    /// where they're recorded and by position otherwise.
+    /// Generations without a matching file are dropped, along with any deltas depending on them,
+    /// files that don't match any kept generation are deleted, and the rest are renumbered.
+    /// Returns the kept generations, or `None` if nothing needed repairing.
+    fn repair(
+        storage: &Storage,
+        base: &Path,
+        generations: &VecDeque<ContentGeneration>,
+        durability_opt: DurabilityOpt,
+    ) -> Result<Option<VecDeque<ContentGeneration>>> {
+        let mut files = Vec::new();
+        storage.read_all_in_dir(base, |entry_path, entry_metadata| {
+            if entry_metadata.is_file()
+                && entry_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("dir-cache-generation-"))
+            {
+                files.push((entry_path.to_path_buf(), entry_metadata.len()));
+            }
+            Ok(())
         })?;
-        let mut generations = VecDeque::new();
-        for line in lines {
-            let (age_nanos_raw, encoding_raw) = line.split_once(',').ok_or_else(|| {
-                Error::ParseMetadata(format!("Metadata was not comma separated at {base:?}"))
-            })?;
-            let age = duration_from_nano_string(age_nanos_raw)?;
-            let encoding = Encoding::deserialize(encoding_raw)?;
-            generations.push_front((age, encoding));
+        let expected = (0..generations.len())
+            .map(|ind| base.safe_join(format!("dir-cache-generation-{ind}")))
+            .collect::<Result<Vec<_>>>()?;
+        // A file in place, but of the wrong size, was truncated or replaced
+        if files.len() == expected.len()
+            && generations.iter().zip(&expected).all(|(gen, own_path)| {
+                files
+                    .iter()
+                    .any(|(path, len)| path == own_path && gen.size_matches(*len))
+            })
+        {
+            return Ok(None);
+        }
+        let mut unmatched = files
+            .into_iter()
+            .map(|(path, len)| {
+                let content = storage.read_file(&path).map_err(|e| {
+                    Error::ReadContent(
+                        format!("Failed to read generation to repair at {path:?}"),
+                        Some(e),
+                    )
+                })?;
+                Ok((checksum(&content), len, path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut kept = Vec::new();
+        let mut prev_kept = false;
+        for (gen, own_path) in generations.iter().zip(&expected) {
+            let matched = unmatched.iter().position(|(sum, len, path)| {
+                gen.size_matches(*len)
+                    && gen
+                        .checksum
+                        .map_or(path == own_path, |expected_sum| *sum == expected_sum)
+            });
+            // A delta can only be reconstructed from the generation right before it
+            let keep = matched.filter(|_| !gen.delta || prev_kept);
+            prev_kept = keep.is_some();
+            if let Some(ind) = keep {
+                // Files may have been matched differently, don't assume any links survived
+                let gen = ContentGeneration {
+                    linked: false,
+                    ..*gen
+                };
+                kept.push((gen, unmatched.swap_remove(ind).2));
+            }
+        }
+        // Move kept files out of the way first, they may need to take each other's places
+        let repair_path = |ind: usize| base.safe_join(format!("dir-cache-generation-repair-{ind}"));
+        for (ind, (_, path)) in kept.iter().enumerate() {
+            let to = repair_path(ind)?;
+            storage
+                .rename_file(path, &to, durability_opt)
+                .map_err(|e| {
+                    Error::WriteContent(
+                        format!("Failed to move generation from {path:?} to {to:?} to repair it"),
+                        Some(e),
+                    )
+                })?;
+        }
+        for (_, _, path) in unmatched {
+            storage.ensure_removed_file(&path)?;
         }
-        Ok(Some((version, generations)))
+        for (ind, to) in expected.iter().enumerate().take(kept.len()) {
+            let from = repair_path(ind)?;
+            storage
+                .rename_file(&from, to, durability_opt)
+                .map_err(|e| {
+                    Error::WriteContent(
+                        format!("Failed to move repaired generation from {from:?} to {to:?}"),
+                        Some(e),
+                    )
+                })?;
+        }
+        Ok(Some(kept.into_iter().map(|(gen, _)| gen).collect()))
+    }
+
+    /// Reads the manifest, generation sizes are left as 0 if they weren't recorded
+    fn read_metadata(
+        storage: &Storage,
+        base: &Path,
+        generation_opt: &GenerationOpt,
+        unknown_encoding_opt: UnknownEncodingOpt,
+    ) -> Result<Option<manifest::Parsed>> {
+        let Some(content) = storage.read_raw_if_present(&base.safe_join(MANIFEST_FILE)?)? else {
+            return Ok(None);
+        };
+        manifest::parse(&content, base, generation_opt, unknown_encoding_opt).map(Some)
     }
 
     fn dump_in_mem(
         &mut self,
+        storage: &Storage,
         base: &Path,
-        keep_in_mem: bool,
-        keep_generations: usize,
-        old_gen_encoding: Encoding,
+        blob_dir: &Path,
+        opts: DirCacheOpts,
     ) -> Result<()> {
         let maybe_in_mem = self.in_mem.take();
         if let Some(mut in_mem) = maybe_in_mem {
             if !in_mem.committed {
-                self.generational_write(base, &in_mem.content, old_gen_encoding, keep_generations)?;
-                if keep_in_mem {
+                self.generational_write(storage, base, blob_dir, &in_mem.content, opts)?;
+                if opts.mem_push_opt.retain_after_write(in_mem.content.len()) {
                     in_mem.committed = true;
                     self.in_mem = Some(in_mem);
                 }
                 return Ok(());
             }
+            // Already on disk, nothing to write
+            self.in_mem = Some(in_mem);
         }
-        self.dump_metadata(base)?;
+        self.dump_metadata(storage, base, opts.durability_opt)?;
         Ok(())
     }
 
-    fn dump_metadata(&self, base: &Path) -> Result<()> {
-        let mut metadata = format!("{MANIFEST_VERSION}\n");
-        for gen in &self.on_disk {
-            let _ = metadata.write_fmt(format_args!(
-                "{},{}\n",
-                gen.age.as_nanos(),
-                gen.encoding.serialize()
-            ));
-        }
+    fn dump_metadata(
+        &self,
+        storage: &Storage,
+        base: &Path,
+        durability_opt: DurabilityOpt,
+    ) -> Result<()> {
+        let metadata = manifest::serialize(&self.on_disk, self.key.as_deref());
         let manifest_path = base.safe_join(MANIFEST_FILE)?;
-        std::fs::write(&manifest_path, metadata).map_err(|e| {
-            Error::WriteContent(
-                format!("Failed to write manifest to {manifest_path:?}"),
-                Some(e),
-            )
-        })?;
+        storage
+            .write_file(&manifest_path, &metadata, durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(
+                    format!("Failed to write manifest to {manifest_path:?}"),
+                    Some(e),
+                )
+            })?;
         Ok(())
     }
 }
 
+enum ReadEntry {
+    Missing,
+    // With the key, if it was stored under a hashed directory
+    Expired(Option<PathBuf>),
+    Present(Box<DirCacheEntry>),
+}
+
+/// Recorded with a value when it's inserted
+#[derive(Debug, Default, Copy, Clone)]
+struct ValueMeta {
+    format: Option<u64>,
+    expiration: Option<ExpirationOpt>,
+    negative: bool,
+    label: Option<Label>,
+}
+
 struct InMemEntry {
     committed: bool,
-    content: Vec<u8>,
+    content: Arc<[u8]>,
 }
 
 #[derive(Debug, Clone, Copy)]
 struct ContentGeneration {
     encoding: Encoding,
     age: Duration,
+    // Size of the file as stored, missing in manifests from before sizes were recorded
+    // until it's statted on open
+    size: Option<u64>,
+    // Stored as a delta against the next newer generation
+    delta: bool,
+    // Checksum of the content as stored on disk, missing in manifests from before checksums
+    checksum: Option<u64>,
+    // Hardlinked to the next older generation's file
+    linked: bool,
+    // The content-addressed value this generation's file is a hardlink to
+    blob: Option<u128>,
+    // The format a typed value was serialized in, see `typed::format_id`
+    format: Option<u64>,
+    // Overrides the cache's expiration for this generation
+    expiration: Option<ExpirationOpt>,
+    // When this generation was last read as the current value
+    read: Option<Duration>,
+    // Records a miss rather than a value, see `DirCache::insert_negative`
+    negative: bool,
+    // Number of reads of the key when this generation was last current, if they're persisted
+    hits: Option<u64>,
+    // The label the generation was inserted with
+    label: Option<Label>,
+}
+
+impl ContentGeneration {
+    fn expired(&self, generation_opt: GenerationOpt, now: Duration) -> bool {
+        generation_opt.expired(self.expiration, self.age, self.read, now)
+    }
+
+    /// The size of the file as stored, known for every generation of an entry that's been read
+    fn stored_size(&self) -> u64 {
+        self.size.unwrap_or_default()
+    }
+
+    /// Reads this generation from `path` and encodes it as `target`, as a delta against
+    /// `delta_base` if given and smaller. Returns the new content to store
+    fn recode(
+        &mut self,
+        storage: &Storage,
+        path: &Path,
+        delta_base: Option<&[u8]>,
+        target: Encoding,
+        generation_opt: &GenerationOpt,
+    ) -> Result<Vec<u8>> {
+        let content = storage.read_file(path).map_err(|e| {
+            Error::ReadContent(
+                format!("Failed to read generation to recode from {path:?}"),
+                Some(e),
+            )
+        })?;
+        let content = self.encoding.decode(content)?;
+        self.encode_as(content, delta_base, target, generation_opt)
+    }
+
+    /// Re-encodes the stored content of this generation at `path` as `to`, a delta stays a delta
+    fn migrate_file(
+        &mut self,
+        storage: &Storage,
+        path: &Path,
+        to: Encoding,
+        durability_opt: DurabilityOpt,
+    ) -> Result<()> {
+        let content = storage.read_file(path).map_err(|e| {
+            Error::ReadContent(
+                format!("Failed to read generation to migrate from {path:?}"),
+                Some(e),
+            )
+        })?;
+        self.verify(&content, path)?;
+        let decoded = self.encoding.decode(content)?;
+        let new_content = to.encode(&decoded)?;
+        storage
+            .write_file(path, &new_content, durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(
+                    format!("Failed to write migrated content to {path:?}"),
+                    Some(e),
+                )
+            })?;
+        self.encoding = to;
+        self.size = Some(new_content.len() as u64);
+        self.checksum = Some(checksum(&new_content));
+        self.linked = false;
+        self.blob = None;
+        Ok(())
+    }
+
+    /// Encodes the full value `content` of this generation as `target`, as a delta against
+    /// `delta_base` if given and smaller. Returns the new content to store
+    fn encode_as(
+        &mut self,
+        mut content: Vec<u8>,
+        delta_base: Option<&[u8]>,
+        target: Encoding,
+        generation_opt: &GenerationOpt,
+    ) -> Result<Vec<u8>> {
+        self.delta = false;
+        if let Some(delta_base) = delta_base {
+            let delta = delta::diff(delta_base, &content);
+            if delta.len() < content.len() {
+                content = delta;
+                self.delta = true;
+            }
+        }
+        let encoding = generation_opt.encoding_for(target, content.len());
+        let new_content = encoding.encode(&content)?;
+        self.encoding = encoding;
+        self.size = Some(new_content.len() as u64);
+        self.checksum = Some(checksum(&new_content));
+        // Written to a new file
+        self.linked = false;
+        self.blob = None;
+        Ok(new_content.into_owned())
+    }
+
+    fn verify(&self, stored: &[u8], path: &Path) -> Result<()> {
+        self.check_size(stored.len() as u64, path)?;
+        if self.checksum.is_none() {
+            return Ok(());
+        }
+        self.check_checksum(checksum(stored), path)
+    }
+
+    /// Same as [`ContentGeneration::verify`], without reading the whole file into memory
+    fn verify_file(&self, storage: &Storage, path: &Path) -> Result<()> {
+        if self.size.is_some() {
+            self.check_size(storage.file_size(path)?, path)?;
+        }
+        if self.checksum.is_none() {
+            return Ok(());
+        }
+        self.check_checksum(checksum_file(storage, path)?, path)
+    }
+
+    /// Whether a file of `actual` bytes can hold this generation, always if no size was recorded
+    fn size_matches(&self, actual: u64) -> bool {
+        self.size.is_none_or(|size| size == actual)
+    }
+
+    fn check_size(&self, actual: u64, path: &Path) -> Result<()> {
+        if let Some(expected) = self.size.filter(|&size| size != actual) {
+            return Err(Error::ChecksumMismatch(format!(
+                "content at {path:?} is {actual} bytes, manifest expects {expected}"
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_checksum(&self, actual: u64, path: &Path) -> Result<()> {
+        let Some(expected) = self.checksum else {
+            return Ok(());
+        };
+        if actual != expected {
+            return Err(Error::ChecksumMismatch(format!(
+                "content at {path:?} has checksum {actual:016x}, manifest expects {expected:016x}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn checksum(stored: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(stored)
+}
+
+/// Same as [`checksum`], without reading the whole file into memory
+fn checksum_file(storage: &Storage, path: &Path) -> Result<u64> {
+    let mut file = storage.open_file(path).map_err(|e| {
+        Error::ReadContent(format!("Failed to open {path:?} to checksum it"), Some(e))
+    })?;
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).map_err(|e| {
+            Error::ReadContent(format!("Failed to read {path:?} to checksum it"), Some(e))
+        })?;
+        if read == 0 {
+            return Ok(hasher.digest());
+        }
+        hasher.update(&buf[..read]);
+    }
 }
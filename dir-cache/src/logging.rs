@@ -0,0 +1,30 @@
+//! Log records emitted through the `log` facade with the `log` feature, without it the
+//! arguments are only type-checked.
+
+#[cfg(feature = "log")]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        log::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+#[cfg(feature = "log")]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        log::warn!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "log"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
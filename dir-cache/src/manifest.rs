@@ -0,0 +1,552 @@
+//! The manifest lists the generations of an entry, newest first.
+//! Version 1 is a line based text format, a version header followed by one
+//! `{age_nanos},{encoding}[,d][,h:{checksum}]` line per generation.
+//! Version 2 is a compact binary format, a magic prefix, the version and the number of
+//! generations followed by one length-prefixed record per generation, all integers are varints.
+//! Entries stored under a hashed directory have their key appended after the records,
+//! length-prefixed.
+//! Version 3 is version 2 followed by a little-endian checksum of everything before it, so that
+//! a torn or corrupted manifest is detected rather than parsed into the wrong generations.
+//! All are read, only version 3 is written.
+use crate::error::{Error, Result};
+use crate::meta::Label;
+use crate::opts::{Encoding, ExpirationOpt, GenerationOpt, UnknownEncodingOpt};
+use crate::time::duration_from_nano_string;
+use crate::{checksum, key, varint, ContentGeneration};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+pub(crate) const MANIFEST_VERSION: u64 = 3;
+const TEXT_VERSION: u64 = 1;
+// Binary, but from before manifests were checksummed
+const UNCHECKED_VERSION: u64 = 2;
+const MAGIC: &[u8] = b"DCM";
+const FLAG_DELTA: u8 = 1;
+const FLAG_CHECKSUM: u8 = 1 << 1;
+const FLAG_LINKED: u8 = 1 << 2;
+const FLAG_BLOB: u8 = 1 << 3;
+const FLAG_FORMAT: u8 = 1 << 4;
+const FLAG_EXPIRATION: u8 = 1 << 5;
+const FLAG_READ: u8 = 1 << 6;
+const FLAG_NEGATIVE: u8 = 1 << 7;
+// Flags of fields after the ones above, in a second flag byte that's only written if any are set
+const EXT_FLAG_HITS: u8 = 1;
+const EXT_FLAG_LABEL: u8 = 1 << 1;
+const EXT_FLAG_SIZE: u8 = 1 << 2;
+const TAG_PLAIN: u8 = 0;
+const TAG_LZ4: u8 = 1;
+const TAG_ZSTD: u8 = 2;
+const TAG_CUSTOM: u8 = 3;
+const TAG_NO_EXPIRY: u8 = 0;
+const TAG_EXPIRES_AFTER: u8 = 1;
+const TAG_EXPIRES_AT: u8 = 2;
+const TAG_EXPIRES_AFTER_IDLE: u8 = 3;
+
+/// The version a manifest was written in, its generations, and the key if one was recorded
+pub(crate) type Parsed = (u64, VecDeque<ContentGeneration>, Option<PathBuf>);
+
+/// Parses a manifest of any supported version, returning the version it was written in,
+/// and the key if one was recorded.
+/// Generation sizes are left unknown in manifests from before they were recorded
+pub(crate) fn parse(
+    content: &[u8],
+    base: &Path,
+    generation_opt: &GenerationOpt,
+    unknown_encoding_opt: UnknownEncodingOpt,
+) -> Result<Parsed> {
+    if let Some(binary) = content.strip_prefix(MAGIC) {
+        return parse_binary(content, binary, base, generation_opt, unknown_encoding_opt);
+    }
+    let content = std::str::from_utf8(content).map_err(|_| {
+        Error::ParseMetadata(format!("Manifest at {base:?} is neither binary nor text"))
+    })?;
+    let (version, generations) = parse_text(content, base, generation_opt, unknown_encoding_opt)?;
+    Ok((version, generations, None))
+}
+
+/// Serializes the generations, and the key of an entry stored under a hashed directory,
+/// into a manifest of the current version
+pub(crate) fn serialize(generations: &VecDeque<ContentGeneration>, key: Option<&Path>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    varint::write(&mut out, MANIFEST_VERSION);
+    varint::write(&mut out, generations.len() as u64);
+    let mut record = Vec::new();
+    for gen in generations {
+        record.clear();
+        varint::write(&mut record, gen.age.as_secs());
+        varint::write(&mut record, u64::from(gen.age.subsec_nanos()));
+        let mut flags = 0;
+        if gen.delta {
+            flags |= FLAG_DELTA;
+        }
+        if gen.checksum.is_some() {
+            flags |= FLAG_CHECKSUM;
+        }
+        if gen.linked {
+            flags |= FLAG_LINKED;
+        }
+        if gen.blob.is_some() {
+            flags |= FLAG_BLOB;
+        }
+        if gen.format.is_some() {
+            flags |= FLAG_FORMAT;
+        }
+        if gen.expiration.is_some() {
+            flags |= FLAG_EXPIRATION;
+        }
+        if gen.read.is_some() {
+            flags |= FLAG_READ;
+        }
+        if gen.negative {
+            flags |= FLAG_NEGATIVE;
+        }
+        record.push(flags);
+        write_encoding(&mut record, gen.encoding);
+        if let Some(checksum) = gen.checksum {
+            record.extend_from_slice(&checksum.to_le_bytes());
+        }
+        if let Some(blob) = gen.blob {
+            record.extend_from_slice(&blob.to_le_bytes());
+        }
+        if let Some(format) = gen.format {
+            record.extend_from_slice(&format.to_le_bytes());
+        }
+        if let Some(expiration) = gen.expiration {
+            write_expiration(&mut record, expiration);
+        }
+        if let Some(read) = gen.read {
+            write_duration(&mut record, read);
+        }
+        let mut ext_flags = 0;
+        if gen.hits.is_some() {
+            ext_flags |= EXT_FLAG_HITS;
+        }
+        if gen.label.is_some() {
+            ext_flags |= EXT_FLAG_LABEL;
+        }
+        if gen.size.is_some() {
+            ext_flags |= EXT_FLAG_SIZE;
+        }
+        if ext_flags != 0 {
+            record.push(ext_flags);
+        }
+        if let Some(hits) = gen.hits {
+            varint::write(&mut record, hits);
+        }
+        if let Some(label) = gen.label {
+            let label = label.as_str().as_bytes();
+            varint::write(&mut record, label.len() as u64);
+            record.extend_from_slice(label);
+        }
+        if let Some(size) = gen.size {
+            varint::write(&mut record, size);
+        }
+        varint::write(&mut out, record.len() as u64);
+        out.extend_from_slice(&record);
+    }
+    if let Some(key) = key {
+        let key = key.as_os_str().as_encoded_bytes();
+        varint::write(&mut out, key.len() as u64);
+        out.extend_from_slice(key);
+    }
+    let checksum = checksum(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn write_encoding(out: &mut Vec<u8>, encoding: Encoding) {
+    match encoding {
+        Encoding::Plain => out.push(TAG_PLAIN),
+        #[cfg(feature = "lz4")]
+        Encoding::Lz4 { level } => {
+            out.push(TAG_LZ4);
+            varint::write(out, u64::from(level));
+        }
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd { level } => {
+            out.push(TAG_ZSTD);
+            // Sign and magnitude, zstd levels can be negative
+            varint::write(
+                out,
+                u64::from(level.unsigned_abs()) << 1 | u64::from(level < 0),
+            );
+        }
+        Encoding::Custom(codec) => {
+            out.push(TAG_CUSTOM);
+            let id = codec.id().as_bytes();
+            varint::write(out, id.len() as u64);
+            out.extend_from_slice(id);
+        }
+    }
+}
+
+fn write_expiration(out: &mut Vec<u8>, expiration: ExpirationOpt) {
+    match expiration {
+        ExpirationOpt::NoExpiry => out.push(TAG_NO_EXPIRY),
+        ExpirationOpt::ExpiresAfter(ttl) => {
+            out.push(TAG_EXPIRES_AFTER);
+            write_duration(out, ttl);
+        }
+        ExpirationOpt::ExpiresAt(at) => {
+            out.push(TAG_EXPIRES_AT);
+            write_duration(out, at.duration_since(UNIX_EPOCH).unwrap_or_default());
+        }
+        ExpirationOpt::ExpiresAfterIdle(tti) => {
+            out.push(TAG_EXPIRES_AFTER_IDLE);
+            write_duration(out, tti);
+        }
+    }
+}
+
+fn write_duration(out: &mut Vec<u8>, dur: Duration) {
+    varint::write(out, dur.as_secs());
+    varint::write(out, u64::from(dur.subsec_nanos()));
+}
+
+/// Parses `cursor`, the `content` of a binary manifest after its magic prefix
+fn parse_binary(
+    content: &[u8],
+    mut cursor: &[u8],
+    base: &Path,
+    generation_opt: &GenerationOpt,
+    unknown_encoding_opt: UnknownEncodingOpt,
+) -> Result<Parsed> {
+    let version = read_varint(&mut cursor, base)?;
+    match version {
+        UNCHECKED_VERSION => {}
+        MANIFEST_VERSION => {
+            let (checked, trailer) = content
+                .split_last_chunk::<8>()
+                .filter(|_| cursor.len() >= 8)
+                .ok_or_else(|| malformed(base, "missing checksum"))?;
+            if checksum(checked).to_le_bytes() != *trailer {
+                return Err(Error::ChecksumMismatch(format!(
+                    "manifest at {base:?} is corrupt, it doesn't match its checksum"
+                )));
+            }
+            cursor = &cursor[..cursor.len() - 8];
+        }
+        _ => return Err(version_mismatch(version)),
+    }
+    let count = read_varint(&mut cursor, base)?;
+    let mut generations = VecDeque::new();
+    let mut skipping = false;
+    for _ in 0..count {
+        let len = usize::try_from(read_varint(&mut cursor, base)?)
+            .map_err(|_| malformed(base, "record length too large"))?;
+        if cursor.len() < len {
+            return Err(malformed(base, "truncated record"));
+        }
+        let (record, rest) = cursor.split_at(len);
+        cursor = rest;
+        if skipping {
+            continue;
+        }
+        match parse_record(record, base, generation_opt) {
+            Ok(gen) => generations.push_back(gen),
+            Err(e) => {
+                skip_unknown_encoding(&generations, e, base, unknown_encoding_opt)?;
+                skipping = true;
+            }
+        }
+    }
+    let key = if cursor.is_empty() {
+        None
+    } else {
+        let len = usize::try_from(read_varint(&mut cursor, base)?)
+            .ok()
+            .filter(|len| *len == cursor.len())
+            .ok_or_else(|| malformed(base, "trailing data after the last record"))?;
+        Some(
+            key::bytes_to_path(cursor[..len].to_vec())
+                .ok_or_else(|| malformed(base, "invalid key"))?,
+        )
+    };
+    Ok((version, generations, key))
+}
+
+fn parse_record(
+    mut record: &[u8],
+    base: &Path,
+    generation_opt: &GenerationOpt,
+) -> Result<ContentGeneration> {
+    let secs = read_varint(&mut record, base)?;
+    let nanos = u32::try_from(read_varint(&mut record, base)?)
+        .ok()
+        .filter(|nanos| *nanos < 1_000_000_000)
+        .ok_or_else(|| malformed(base, "invalid timestamp"))?;
+    let flags = read_bytes::<1>(&mut record, base)?[0];
+    let encoding = read_encoding(&mut record, base, generation_opt)?;
+    let checksum = if flags & FLAG_CHECKSUM == 0 {
+        None
+    } else {
+        Some(u64::from_le_bytes(read_bytes(&mut record, base)?))
+    };
+    let blob = if flags & FLAG_BLOB == 0 {
+        None
+    } else {
+        Some(u128::from_le_bytes(read_bytes(&mut record, base)?))
+    };
+    let format = if flags & FLAG_FORMAT == 0 {
+        None
+    } else {
+        Some(u64::from_le_bytes(read_bytes(&mut record, base)?))
+    };
+    let expiration = if flags & FLAG_EXPIRATION == 0 {
+        None
+    } else {
+        Some(read_expiration(&mut record, base)?)
+    };
+    let read = if flags & FLAG_READ == 0 {
+        None
+    } else {
+        Some(read_duration(&mut record, base)?)
+    };
+    let ext_flags = if record.is_empty() {
+        0
+    } else {
+        read_bytes::<1>(&mut record, base)?[0]
+    };
+    let hits = if ext_flags & EXT_FLAG_HITS == 0 {
+        None
+    } else {
+        Some(read_varint(&mut record, base)?)
+    };
+    let label = if ext_flags & EXT_FLAG_LABEL == 0 {
+        None
+    } else {
+        Some(read_label(&mut record, base)?)
+    };
+    let size = if ext_flags & EXT_FLAG_SIZE == 0 {
+        None
+    } else {
+        Some(read_varint(&mut record, base)?)
+    };
+    // Anything left in the record was added by a later version and can be ignored
+    Ok(ContentGeneration {
+        encoding,
+        age: Duration::new(secs, nanos),
+        size,
+        delta: flags & FLAG_DELTA != 0,
+        checksum,
+        linked: flags & FLAG_LINKED != 0,
+        blob,
+        format,
+        expiration,
+        read,
+        negative: flags & FLAG_NEGATIVE != 0,
+        hits,
+        label,
+    })
+}
+
+fn read_encoding(
+    cursor: &mut &[u8],
+    base: &Path,
+    generation_opt: &GenerationOpt,
+) -> Result<Encoding> {
+    match read_bytes::<1>(cursor, base)?[0] {
+        TAG_PLAIN => Ok(Encoding::Plain),
+        #[cfg(feature = "lz4")]
+        TAG_LZ4 => Ok(Encoding::Lz4 {
+            level: u32::try_from(read_varint(cursor, base)?)
+                .map_err(|_| malformed(base, "invalid lz4 level"))?,
+        }),
+        #[cfg(not(feature = "lz4"))]
+        TAG_LZ4 => {
+            read_varint(cursor, base)?;
+            Err(missing_feature(base, "lz4"))
+        }
+        #[cfg(feature = "zstd")]
+        TAG_ZSTD => {
+            let raw = read_varint(cursor, base)?;
+            let magnitude =
+                i32::try_from(raw >> 1).map_err(|_| malformed(base, "invalid zstd level"))?;
+            Ok(Encoding::Zstd {
+                level: if raw & 1 == 0 { magnitude } else { -magnitude },
+            })
+        }
+        #[cfg(not(feature = "zstd"))]
+        TAG_ZSTD => {
+            read_varint(cursor, base)?;
+            Err(missing_feature(base, "zstd"))
+        }
+        TAG_CUSTOM => {
+            let len = usize::try_from(read_varint(cursor, base)?)
+                .ok()
+                .filter(|len| *len <= cursor.len())
+                .ok_or_else(|| malformed(base, "truncated codec id"))?;
+            let (id, rest) = cursor.split_at(len);
+            *cursor = rest;
+            let id = std::str::from_utf8(id).map_err(|_| malformed(base, "invalid codec id"))?;
+            generation_opt
+                .find_codec(id)
+                .map(Encoding::Custom)
+                .ok_or_else(|| Error::UnknownCodec(id.to_string()))
+        }
+        tag => Err(Error::ParseMetadata(format!(
+            "Failed to parse encoding {tag} from manifest at {base:?}"
+        ))),
+    }
+}
+
+fn read_expiration(cursor: &mut &[u8], base: &Path) -> Result<ExpirationOpt> {
+    match read_bytes::<1>(cursor, base)?[0] {
+        TAG_NO_EXPIRY => Ok(ExpirationOpt::NoExpiry),
+        TAG_EXPIRES_AFTER => Ok(ExpirationOpt::ExpiresAfter(read_duration(cursor, base)?)),
+        TAG_EXPIRES_AT => UNIX_EPOCH
+            .checked_add(read_duration(cursor, base)?)
+            .map(ExpirationOpt::ExpiresAt)
+            .ok_or_else(|| malformed(base, "expiration out of range")),
+        TAG_EXPIRES_AFTER_IDLE => Ok(ExpirationOpt::ExpiresAfterIdle(read_duration(
+            cursor, base,
+        )?)),
+        tag => Err(Error::ParseMetadata(format!(
+            "Failed to parse expiration {tag} from manifest at {base:?}"
+        ))),
+    }
+}
+
+fn read_label(cursor: &mut &[u8], base: &Path) -> Result<Label> {
+    let len = usize::try_from(read_varint(cursor, base)?)
+        .ok()
+        .filter(|len| *len <= cursor.len())
+        .ok_or_else(|| malformed(base, "truncated label"))?;
+    let (label, rest) = cursor.split_at(len);
+    *cursor = rest;
+    std::str::from_utf8(label)
+        .ok()
+        .and_then(|label| Label::new(label).ok())
+        .ok_or_else(|| malformed(base, "invalid label"))
+}
+
+fn read_duration(cursor: &mut &[u8], base: &Path) -> Result<Duration> {
+    let secs = read_varint(cursor, base)?;
+    let nanos = u32::try_from(read_varint(cursor, base)?)
+        .ok()
+        .filter(|nanos| *nanos < 1_000_000_000)
+        .ok_or_else(|| malformed(base, "invalid duration"))?;
+    Ok(Duration::new(secs, nanos))
+}
+
+fn parse_text(
+    content: &str,
+    base: &Path,
+    generation_opt: &GenerationOpt,
+    unknown_encoding_opt: UnknownEncodingOpt,
+) -> Result<(u64, VecDeque<ContentGeneration>)> {
+    let mut lines = content.lines();
+
+    let Some(first) = lines.next() else {
+        return Err(Error::ParseMetadata(format!(
+            "Manifest at {base:?} was empty"
+        )));
+    };
+    let version: u64 = first.parse().map_err(|_| {
+        Error::ParseMetadata(format!("Failed to parse version from metadata at {base:?}"))
+    })?;
+    if version != TEXT_VERSION {
+        return Err(version_mismatch(version));
+    }
+    let mut generations = VecDeque::new();
+    for line in lines {
+        let (age_nanos_raw, rest) = line.split_once(',').ok_or_else(|| {
+            Error::ParseMetadata(format!("Metadata was not comma separated at {base:?}"))
+        })?;
+        let mut fields = rest.split(',');
+        let encoding_raw = fields.next().unwrap_or_default();
+        let mut delta = false;
+        let mut checksum = None;
+        for field in fields {
+            if field == "d" {
+                delta = true;
+            } else if let Some(hex) = field.strip_prefix("h:") {
+                checksum = Some(u64::from_str_radix(hex, 16).map_err(|_| {
+                    Error::ParseMetadata(format!(
+                        "Failed to parse checksum {hex} from metadata at {base:?}"
+                    ))
+                })?);
+            } else {
+                return Err(Error::ParseMetadata(format!(
+                    "Unknown generation field {field} in metadata at {base:?}"
+                )));
+            }
+        }
+        let encoding = match Encoding::deserialize(encoding_raw, generation_opt) {
+            Ok(encoding) => encoding,
+            Err(e) => {
+                skip_unknown_encoding(&generations, e, base, unknown_encoding_opt)?;
+                break;
+            }
+        };
+        generations.push_back(ContentGeneration {
+            encoding,
+            age: duration_from_nano_string(age_nanos_raw)?,
+            size: None,
+            delta,
+            checksum,
+            linked: false,
+            blob: None,
+            format: None,
+            expiration: None,
+            read: None,
+            negative: false,
+            hits: None,
+            label: None,
+        });
+    }
+    Ok((version, generations))
+}
+
+/// Checks whether the generation after `generations` that failed to parse its encoding with `e`,
+/// and all older ones, can be skipped according to the [`UnknownEncodingOpt`].
+/// The current generation can never be skipped
+fn skip_unknown_encoding(
+    generations: &VecDeque<ContentGeneration>,
+    e: Error,
+    base: &Path,
+    unknown_encoding_opt: UnknownEncodingOpt,
+) -> Result<()> {
+    let unknown = matches!(e, Error::UnsupportedEncoding(_) | Error::UnknownCodec(_));
+    if !unknown
+        || generations.is_empty()
+        || matches!(unknown_encoding_opt, UnknownEncodingOpt::Strict)
+    {
+        return Err(e);
+    }
+    warn!(
+        "Skipping generations from {} of the dir-cache entry at {base:?}: {e}",
+        generations.len()
+    );
+    Ok(())
+}
+
+fn read_varint(cursor: &mut &[u8], base: &Path) -> Result<u64> {
+    varint::read(cursor).ok_or_else(|| malformed(base, "invalid varint"))
+}
+
+fn read_bytes<const N: usize>(cursor: &mut &[u8], base: &Path) -> Result<[u8; N]> {
+    let (bytes, rest) = cursor
+        .split_first_chunk::<N>()
+        .ok_or_else(|| malformed(base, "truncated record"))?;
+    *cursor = rest;
+    Ok(*bytes)
+}
+
+fn version_mismatch(version: u64) -> Error {
+    Error::ParseManifest(format!(
+        "Version mismatch, want={MANIFEST_VERSION}, got={version}"
+    ))
+}
+
+fn malformed(base: &Path, reason: &str) -> Error {
+    Error::ParseMetadata(format!("Malformed manifest at {base:?}, {reason}"))
+}
+
+#[cfg(any(not(feature = "lz4"), not(feature = "zstd")))]
+fn missing_feature(base: &Path, feature: &str) -> Error {
+    Error::UnsupportedEncoding(format!(
+        "Manifest at {base:?} has generations encoded with {feature}, which requires the `{feature}` feature to be enabled"
+    ))
+}
@@ -0,0 +1,89 @@
+//! Reading values through memory maps, see [`crate::DirCache::get_mapped`]
+use crate::disk::{FileReader, Storage};
+use crate::error::{Error, Result};
+use memmap2::Mmap;
+use std::ops::Deref;
+use std::path::Path;
+
+/// A value of a [`crate::DirCache`], mapped into memory straight from its file if it's stored
+/// unencoded, otherwise decoded into memory.
+/// Stays valid after the value is replaced, expires, or is removed from the cache, the cache
+/// never modifies a value file in place, it replaces or removes it, leaving the mapped file
+/// intact until it's unmapped.
+pub struct MappedValue {
+    inner: MappedInner,
+}
+
+enum MappedInner {
+    Mapped(Mmap),
+    // Empty files can't be mapped on all platforms
+    Empty,
+    Owned(Vec<u8>),
+}
+
+impl MappedValue {
+    pub(crate) fn map(storage: &Storage, path: &Path) -> Result<Self> {
+        let open = storage.open_file(path).map_err(|e| {
+            Error::ReadContent(format!("Failed to open value to map at {path:?}"), Some(e))
+        })?;
+        #[cfg_attr(not(feature = "sqlite"), allow(clippy::infallible_destructuring_match))]
+        let file = match open {
+            FileReader::File(file) => file,
+            // Already read into memory, there's no file to map
+            #[cfg(feature = "sqlite")]
+            FileReader::Memory(content) => return Ok(Self::owned(content.into_inner())),
+        };
+        let len = file
+            .metadata()
+            .map_err(|e| {
+                Error::ReadContent(format!("Failed to read metadata of {path:?}"), Some(e))
+            })?
+            .len();
+        if len == 0 {
+            return Ok(Self {
+                inner: MappedInner::Empty,
+            });
+        }
+        // Safety: The cache never writes to an existing value file, new values are written to new
+        // files that replace the old ones. Modifying the file from outside the cache while it's
+        // mapped is not supported.
+        let mapped = unsafe { Mmap::map(&file) }
+            .map_err(|e| Error::ReadContent(format!("Failed to map value at {path:?}"), Some(e)))?;
+        Ok(Self {
+            inner: MappedInner::Mapped(mapped),
+        })
+    }
+
+    pub(crate) fn owned(content: Vec<u8>) -> Self {
+        Self {
+            inner: MappedInner::Owned(content),
+        }
+    }
+
+    /// Whether the value is mapped from its file rather than held on the heap
+    #[inline]
+    #[must_use]
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.inner, MappedInner::Mapped(_))
+    }
+}
+
+impl Deref for MappedValue {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        match &self.inner {
+            MappedInner::Mapped(mapped) => mapped,
+            MappedInner::Empty => &[],
+            MappedInner::Owned(content) => content,
+        }
+    }
+}
+
+impl AsRef<[u8]> for MappedValue {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
@@ -0,0 +1,115 @@
+//! Deciding which values are kept in memory with a `moka` cache, see [`crate::opts::MemBudgetOpt::Moka`].
+//! The values themselves stay in their entries so that they can be borrowed, the `moka` cache
+//! only tracks the keys of committed values with their sizes, and the values of the keys it
+//! evicts are dropped from memory. Disk remains the source of truth, uncommitted values are never
+//! tracked and never dropped.
+use crate::DirCacheInner;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+pub(crate) struct MemTier {
+    cache: moka::sync::Cache<PathBuf, u32>,
+    // Keys in the cache, with the weight they were inserted with
+    tracked: HashMap<PathBuf, u32>,
+    // Keys the cache has evicted since they were last dropped
+    evicted: Arc<Mutex<Vec<PathBuf>>>,
+    // Evicted while a borrow of them was handed out, dropped on the next enforcement
+    deferred: Vec<PathBuf>,
+}
+
+impl MemTier {
+    fn new(max_bytes: u64, time_to_idle: Option<Duration>) -> Self {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener_evicted = evicted.clone();
+        let mut builder = moka::sync::Cache::builder()
+            .max_capacity(max_bytes)
+            .weigher(|_key: &PathBuf, weight: &u32| *weight)
+            .eviction_listener(move |key: Arc<PathBuf>, _weight, cause| {
+                // Not when invalidated or replaced by the cache itself
+                if cause.was_evicted() {
+                    listener_evicted
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(key.as_ref().clone());
+                }
+            });
+        if let Some(time_to_idle) = time_to_idle {
+            builder = builder.time_to_idle(time_to_idle);
+        }
+        Self {
+            cache: builder.build(),
+            tracked: HashMap::new(),
+            evicted,
+            deferred: Vec::new(),
+        }
+    }
+
+    /// Records an access of the in-memory value of `key`
+    pub(crate) fn touch(&self, key: &Path) {
+        // Only for the access, the weight is already tracked
+        let _ = self.cache.get(key);
+    }
+
+    fn take_evicted(&mut self) -> Vec<PathBuf> {
+        self.cache.run_pending_tasks();
+        let mut evicted = std::mem::take(&mut self.deferred);
+        evicted.append(&mut self.evicted.lock().unwrap_or_else(PoisonError::into_inner));
+        evicted
+    }
+}
+
+impl DirCacheInner {
+    /// Tracks the committed in-memory values in the `moka` cache, created on first use, and
+    /// drops the ones it evicts from memory. `keep`, which the caller is about to hand out a
+    /// borrow of, is dropped on the next call if it's evicted.
+    pub(crate) fn enforce_mem_tier(
+        &mut self,
+        max_bytes: u64,
+        time_to_idle: Option<Duration>,
+        keep: Option<&Path>,
+    ) {
+        let tier = self
+            .mem_tier
+            .get_or_insert_with(|| MemTier::new(max_bytes, time_to_idle));
+        // Values that have since been dropped, removed, or overwritten by an uncommitted one
+        tier.tracked.retain(|k, _| {
+            let in_mem = self
+                .store
+                .get(k)
+                .and_then(|e| e.in_mem.as_ref())
+                .is_some_and(|in_mem| in_mem.committed);
+            if !in_mem {
+                tier.cache.invalidate(k);
+            }
+            in_mem
+        });
+        for (k, e) in &self.store {
+            let Some(in_mem) = e.in_mem.as_ref().filter(|in_mem| in_mem.committed) else {
+                continue;
+            };
+            let weight = u32::try_from(in_mem.content.len()).unwrap_or(u32::MAX);
+            if tier.tracked.get(k) != Some(&weight) {
+                tier.cache.insert(k.clone(), weight);
+                tier.tracked.insert(k.clone(), weight);
+            }
+        }
+        if let Some(keep) = keep {
+            tier.touch(keep);
+        }
+        for k in tier.take_evicted() {
+            if Some(k.as_path()) == keep {
+                tier.deferred.push(k);
+                continue;
+            }
+            tier.tracked.remove(&k);
+            if let Some(e) = self.store.get_mut(&k) {
+                if e.in_mem.as_ref().is_some_and(|in_mem| in_mem.committed) {
+                    self.in_mem_bytes -= e.in_mem_size();
+                    e.in_mem = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::error::{Error, Result};
+use crate::opts::Encoding;
+use std::fmt::{Debug, Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+/// The longest [`Label`] in bytes
+pub const MAX_LABEL_LEN: usize = 64;
+
+/// Whether a value is older than the soft TTL of its [`crate::opts::GenerationOpt`],
+/// see [`crate::DirCache::get_with_freshness`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Freshness {
+    /// Younger than the soft TTL, or there is none
+    Fresh,
+    /// Older than the soft TTL, but not yet expired
+    Stale,
+}
+
+/// Metadata about an entry in a [`crate::DirCache`], obtainable without reading its value.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EntryMeta {
+    pub(crate) last_updated: SystemTime,
+    pub(crate) disk_size: u64,
+    pub(crate) pooled_size: u64,
+    pub(crate) in_memory_size: Option<usize>,
+    pub(crate) generations: usize,
+    pub(crate) hits: u64,
+    pub(crate) last_read: Option<SystemTime>,
+    pub(crate) encoding: Option<Encoding>,
+}
+
+impl EntryMeta {
+    /// When the current value was written
+    #[inline]
+    #[must_use]
+    pub const fn last_updated(&self) -> SystemTime {
+        self.last_updated
+    }
+
+    /// The combined size in bytes of all generations on disk, as stored (after encoding),
+    /// not counting values in the content-addressed pool, see [`EntryMeta::pooled_size`]
+    #[inline]
+    #[must_use]
+    pub const fn disk_size(&self) -> u64 {
+        self.disk_size
+    }
+
+    /// The combined size in bytes of the values in the content-addressed pool that the entry's
+    /// generations hold, which may be shared with other entries,
+    /// see [`crate::opts::DirCacheOpts::with_content_addressed`]
+    #[inline]
+    #[must_use]
+    pub const fn pooled_size(&self) -> u64 {
+        self.pooled_size
+    }
+
+    /// The size in bytes of the value kept in memory, if any
+    #[inline]
+    #[must_use]
+    pub const fn in_memory_size(&self) -> Option<usize> {
+        self.in_memory_size
+    }
+
+    /// The number of generations on disk
+    #[inline]
+    #[must_use]
+    pub const fn generations(&self) -> usize {
+        self.generations
+    }
+
+    /// The number of times the key has been read, since the cache was opened unless
+    /// [`crate::opts::AccessStatsOpt::Persisted`] is used
+    #[inline]
+    #[must_use]
+    pub const fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// When the current value was last read, if it has been
+    #[inline]
+    #[must_use]
+    pub const fn last_read(&self) -> Option<SystemTime> {
+        self.last_read
+    }
+
+    /// How long ago the current value was written, zero if that's in the future
+    #[inline]
+    #[must_use]
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.last_updated)
+            .unwrap_or_default()
+    }
+
+    /// The [`Encoding`] the current value is stored with on disk, `None` if it's only in memory
+    #[inline]
+    #[must_use]
+    pub const fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+}
+
+/// An overview of what's in a [`crate::DirCache`], see [`crate::DirCache::summary`].
+/// Negative entries count towards the sizes, but not the number of entries or their ages.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct CacheSummary {
+    pub(crate) entries: usize,
+    pub(crate) disk_bytes: u64,
+    pub(crate) in_memory_bytes: usize,
+    pub(crate) dirty: usize,
+    pub(crate) oldest: Option<SystemTime>,
+    pub(crate) newest: Option<SystemTime>,
+}
+
+impl CacheSummary {
+    /// The number of keys with a valid value
+    #[inline]
+    #[must_use]
+    pub const fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// The combined size in bytes of all generations on disk, as stored (after encoding),
+    /// values in the content-addressed pool are counted once however many entries hold them
+    #[inline]
+    #[must_use]
+    pub const fn disk_bytes(&self) -> u64 {
+        self.disk_bytes
+    }
+
+    /// The combined size in bytes of the values kept in memory
+    #[inline]
+    #[must_use]
+    pub const fn in_memory_bytes(&self) -> usize {
+        self.in_memory_bytes
+    }
+
+    /// The number of values only held in memory, that haven't been written to disk yet
+    #[inline]
+    #[must_use]
+    pub const fn dirty(&self) -> usize {
+        self.dirty
+    }
+
+    /// How long ago the least recently updated value was written, `None` if there are none
+    #[inline]
+    #[must_use]
+    pub fn oldest_age(&self) -> Option<Duration> {
+        self.oldest.map(age)
+    }
+
+    /// How long ago the most recently updated value was written, `None` if there are none
+    #[inline]
+    #[must_use]
+    pub fn newest_age(&self) -> Option<Duration> {
+        self.newest.map(age)
+    }
+}
+
+fn age(written: SystemTime) -> Duration {
+    SystemTime::now()
+        .duration_since(written)
+        .unwrap_or_default()
+}
+
+/// Metadata about one generation of a key, see [`crate::DirCache::generations`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GenerationMeta {
+    pub(crate) index: usize,
+    pub(crate) written: SystemTime,
+    pub(crate) encoding: Option<Encoding>,
+    pub(crate) size: u64,
+    pub(crate) label: Option<Label>,
+}
+
+impl GenerationMeta {
+    /// The index of the generation, 0 being the current value, read it with
+    /// [`crate::DirCache::get_generation`]
+    #[inline]
+    #[must_use]
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    /// When the generation was written
+    #[inline]
+    #[must_use]
+    pub const fn written(&self) -> SystemTime {
+        self.written
+    }
+
+    /// How long ago the generation was written, zero if that's in the future
+    #[inline]
+    #[must_use]
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.written)
+            .unwrap_or_default()
+    }
+
+    /// The [`Encoding`] the generation is stored with on disk, `None` if it's only in memory
+    #[inline]
+    #[must_use]
+    pub const fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    /// The size in bytes of the generation, as stored (after encoding)
+    #[inline]
+    #[must_use]
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The [`Label`] the generation was inserted with, if any
+    #[inline]
+    #[must_use]
+    pub const fn label(&self) -> Option<Label> {
+        self.label
+    }
+}
+
+/// A short tag attached to a generation when it's inserted, such as the upstream `ETag` or a
+/// git sha it was produced from, see [`crate::DirCache::insert_labeled`].
+/// Recorded in the manifest, so that generations can be told apart without reading them
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Label {
+    len: u8,
+    bytes: [u8; MAX_LABEL_LEN],
+}
+
+impl Label {
+    /// Create a label from `label`
+    /// # Errors
+    /// Fails with [`Error::InvalidLabel`] if `label` is longer than [`MAX_LABEL_LEN`] bytes
+    pub fn new(label: &str) -> Result<Self> {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|len| usize::from(*len) <= MAX_LABEL_LEN)
+            .ok_or_else(|| {
+                Error::InvalidLabel(format!(
+                    "{label:?} is longer than the max of {MAX_LABEL_LEN} bytes"
+                ))
+            })?;
+        let mut bytes = [0; MAX_LABEL_LEN];
+        bytes[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(Self { len, bytes })
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // Only ever created from a str
+        std::str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl Debug for Label {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where a value was read from, see [`crate::DirCache::get_with_meta`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ValueSource {
+    /// The value was already kept in memory
+    Memory,
+    /// The value was read from disk
+    Disk,
+}
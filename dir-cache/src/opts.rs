@@ -1,19 +1,52 @@
-use crate::disk::{ensure_dir, exists, FileObjectExists};
+use crate::disk::{FileObjectExists, Storage};
 use crate::error::{Error, Result};
-use crate::{DirCache, DirCacheInner};
-use std::fmt::Display;
+use crate::meta::{EntryMeta, Freshness};
+use crate::path_util::{glob_match, SafePathJoin};
+use crate::{DirCache, DirCacheInner, BLOB_DIR, MARKER_CONTENT, MARKER_FILE};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt::{Debug, Formatter};
 use std::num::NonZeroUsize;
-use std::path::Path;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Options for controlling the behavior of operations on a [`DirCache`].
-/// See the specific options for more details
+/// See the specific options for more details.
+/// With the `serde` feature they can be (de)serialized, for example from a config file with
+/// [`DirCacheOpts::from_toml_file`]. Options that are code or `'static` references, such as
+/// hooks, interceptors, and codecs, are skipped, and left at their defaults
+/// when deserialized. Options missing from what's deserialized are left at their defaults too
 #[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct DirCacheOpts {
     pub mem_pull_opt: MemPullOpt,
     pub mem_push_opt: MemPushOpt,
     pub generation_opt: GenerationOpt,
     pub sync_opt: SyncOpt,
+    pub mem_budget_opt: MemBudgetOpt,
+    pub disk_budget_opt: DiskBudgetOpt,
+    pub verify_on_read: bool,
+    pub durability_opt: DurabilityOpt,
+    pub journal: bool,
+    pub content_addressed: bool,
+    pub max_value_size: Option<u64>,
+    pub oversize_opt: OversizeOpt,
+    pub key_codec_opt: KeyCodecOpt,
+    pub key_case_opt: KeyCaseOpt,
+    pub max_path_len: Option<usize>,
+    pub key_policy: KeyPolicy,
+    pub access_stats_opt: AccessStatsOpt,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub hooks: Hooks,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub interceptors: &'static [&'static dyn Interceptor],
+    pub insert_mode: InsertMode,
 }
 
 impl DirCacheOpts {
@@ -29,6 +62,22 @@ impl DirCacheOpts {
             mem_push_opt,
             generation_opt,
             sync_opt,
+            mem_budget_opt: MemBudgetOpt::Unbounded,
+            disk_budget_opt: DiskBudgetOpt::Unbounded,
+            verify_on_read: false,
+            durability_opt: DurabilityOpt::None,
+            journal: false,
+            content_addressed: false,
+            max_value_size: None,
+            oversize_opt: OversizeOpt::Fail,
+            key_codec_opt: KeyCodecOpt::Verbatim,
+            key_case_opt: KeyCaseOpt::Sensitive,
+            max_path_len: None,
+            key_policy: KeyPolicy::new(),
+            access_stats_opt: AccessStatsOpt::InMemory,
+            hooks: Hooks::new(),
+            interceptors: &[],
+            insert_mode: InsertMode::NewGeneration,
         }
     }
 
@@ -56,44 +105,378 @@ impl DirCacheOpts {
         self
     }
 
+    #[must_use]
+    pub const fn with_mem_budget_opt(mut self, mem_budget_opt: MemBudgetOpt) -> Self {
+        self.mem_budget_opt = mem_budget_opt;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_disk_budget_opt(mut self, disk_budget_opt: DiskBudgetOpt) -> Self {
+        self.disk_budget_opt = disk_budget_opt;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_durability_opt(mut self, durability_opt: DurabilityOpt) -> Self {
+        self.durability_opt = durability_opt;
+        self
+    }
+
+    /// Write a journal before replacing an entry's generations, so that a write interrupted
+    /// midway can be rolled forward (or back, if the journal itself wasn't completed)
+    /// when the cache is next opened.
+    /// For this to hold up against power loss, use at least [`DurabilityOpt::FlushData`].
+    #[must_use]
+    pub const fn with_journal(mut self, journal: bool) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Store each distinct value once, in a pool shared by all keys, with the generations
+    /// holding it hardlinked to it. Saves space when many keys hold the same values.
+    /// Values are removed from the pool once no generation references them.
+    /// Only the current generation is pooled, generations re-encoded as they age are stored
+    /// per key, and where hardlinks or their link counts aren't available values are stored
+    /// per key as usual.
+    /// Keys inside `dir-cache-blobs` are reserved for the pool.
+    #[must_use]
+    pub const fn with_content_addressed(mut self, content_addressed: bool) -> Self {
+        self.content_addressed = content_addressed;
+        self
+    }
+
+    /// Refuse values larger than `max_value_size` bytes, handling them according to
+    /// [`DirCacheOpts::with_oversize_opt`].
+    /// Guards against a misbehaving producer filling up the disk.
+    #[must_use]
+    pub const fn with_max_value_size(mut self, max_value_size: u64) -> Self {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_oversize_opt(mut self, oversize_opt: OversizeOpt) -> Self {
+        self.oversize_opt = oversize_opt;
+        self
+    }
+
+    /// How keys are turned into paths on disk.
+    /// Has to be the same every time a cache is used, keys stored with one [`KeyCodecOpt`]
+    /// aren't found with another.
+    #[must_use]
+    pub const fn with_key_codec_opt(mut self, key_codec_opt: KeyCodecOpt) -> Self {
+        self.key_codec_opt = key_codec_opt;
+        self
+    }
+
+    /// Keys whose files would end up at paths longer than `max_path_len` bytes are stored in a
+    /// directory named after a hash of the key instead, with the key recorded in its manifest.
+    /// Transparent to everything but tools reading the directory, useful on Windows
+    /// where paths are by default limited to 260 characters.
+    /// Has to be the same every time a cache is used, or long keys aren't found.
+    #[must_use]
+    pub const fn with_max_path_len(mut self, max_path_len: usize) -> Self {
+        self.max_path_len = Some(max_path_len);
+        self
+    }
+
+    /// Whether the number of times each key has been read is recorded in its manifest,
+    /// see [`AccessStatsOpt`]
+    #[must_use]
+    pub const fn with_access_stats_opt(mut self, access_stats_opt: AccessStatsOpt) -> Self {
+        self.access_stats_opt = access_stats_opt;
+        self
+    }
+
+    /// Callbacks fired on cache operations, see [`Hooks`]
+    #[must_use]
+    pub const fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Transform values on their way into and out of storage, see [`Interceptor`].
+    /// Values are passed through the interceptors in order when written, and in reverse order
+    /// when read
+    #[must_use]
+    pub const fn with_interceptors(
+        mut self,
+        interceptors: &'static [&'static dyn Interceptor],
+    ) -> Self {
+        self.interceptors = interceptors;
+        self
+    }
+
+    /// Whether inserts age the current value into an older generation, or replace it,
+    /// see [`InsertMode`]
+    #[must_use]
+    pub const fn with_insert_mode(mut self, insert_mode: InsertMode) -> Self {
+        self.insert_mode = insert_mode;
+        self
+    }
+
+    /// Limits on the keys the cache accepts, keys outside of them fail with
+    /// [`Error::DangerousKey`] before anything is read or written.
+    #[must_use]
+    pub const fn with_key_policy(mut self, key_policy: KeyPolicy) -> Self {
+        self.key_policy = key_policy;
+        self
+    }
+
+    /// How keys differing only in case are treated, on case-insensitive file systems they
+    /// end up in the same directory.
+    #[must_use]
+    pub const fn with_key_case_opt(mut self, key_case_opt: KeyCaseOpt) -> Self {
+        self.key_case_opt = key_case_opt;
+        self
+    }
+
+    /// Verify values read from disk against the checksum recorded when they were written,
+    /// failing with [`Error::ChecksumMismatch`] instead of returning corrupted data.
+    /// Values written before checksums were recorded aren't verified.
+    #[must_use]
+    pub const fn with_verify_on_read(mut self, verify_on_read: bool) -> Self {
+        self.verify_on_read = verify_on_read;
+        self
+    }
+
+    /// The key `key` is stored under
+    pub(crate) fn encode_key<'a>(&self, key: &'a Path) -> Cow<'a, Path> {
+        let folded = self.key_case_opt.fold(key);
+        match self.key_codec_opt.encode(&folded) {
+            Cow::Borrowed(_) => folded,
+            Cow::Owned(encoded) => Cow::Owned(encoded),
+        }
+    }
+
+    /// The key the string key `key` is stored under
+    pub(crate) fn encode_str_key(&self, key: &str) -> PathBuf {
+        match self.key_case_opt {
+            KeyCaseOpt::Fold => crate::key::str_key(&key.to_lowercase()),
+            KeyCaseOpt::Sensitive | KeyCaseOpt::Detect => crate::key::str_key(key),
+        }
+    }
+
+    /// Passes `content` through the [`Interceptor`]s on its way into storage
+    pub(crate) fn intercept_write<'a>(
+        &self,
+        key: &Path,
+        content: Cow<'a, [u8]>,
+    ) -> Result<Cow<'a, [u8]>> {
+        let mut content = content;
+        for interceptor in self.interceptors {
+            content = Cow::Owned(interceptor.on_write(key, &content).map_err(|e| {
+                Error::Intercept(format!("{interceptor:?} failed writing key {key:?}: {e}"))
+            })?);
+        }
+        Ok(content)
+    }
+
+    /// Passes `content` back through the [`Interceptor`]s, in reverse, on its way out of storage
+    pub(crate) fn intercept_read<'a>(
+        &self,
+        key: &Path,
+        content: Cow<'a, [u8]>,
+    ) -> Result<Cow<'a, [u8]>> {
+        let mut content = content;
+        for interceptor in self.interceptors.iter().rev() {
+            content = Cow::Owned(interceptor.on_read(key, &content).map_err(|e| {
+                Error::Intercept(format!("{interceptor:?} failed reading key {key:?}: {e}"))
+            })?);
+        }
+        Ok(content)
+    }
+
+    /// Reads options from the TOML file at `path`, any option that isn't in the file is left
+    /// at its default, see [`DirCacheOpts`] for which can't be read from a file.
+    /// Enum variants are written in `snake_case`, durations as `{ secs, nanos }`
+    /// ```toml
+    /// mem_push_opt = { retain_if_smaller_than = 4096 }
+    /// sync_opt = "sync_on_drop"
+    ///
+    /// [generation_opt]
+    /// max_generations = 3
+    /// expiration = { expires_after = { secs = 3600, nanos = 0 } }
+    /// ```
+    /// # Errors
+    /// The file can't be read, or isn't valid TOML for the options
+    #[cfg(feature = "toml")]
+    pub fn from_toml_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            Error::ReadContent(format!("Failed to read options from {path:?}"), Some(e))
+        })?;
+        toml::from_str(&content).map_err(|e| Error::ParseOpts(format!("{path:?}: {e}")))
+    }
+
     /// Use these [`DirCacheOpts`] to open a [`DirCache`].
     /// # Errors
     /// Depending on the open options a directory already being present or not may cause failure.
     /// Various io-errors, from creating the [`DirCache`].
     pub fn open(self, path: &Path, cache_open_options: CacheOpenOptions) -> Result<DirCache> {
-        match cache_open_options.dir_open {
-            DirOpenOpt::OnlyIfExists => {
-                match exists(path)? {
-                    FileObjectExists::AsDir => {}
-                    FileObjectExists::No => {
-                        return Err(Error::Open(format!(
-                            "Opened with OnlyIfExists but path {path:?} does not exist"
-                        )));
-                    }
-                    FileObjectExists::AsFile => {
-                        return Err(Error::Open(format!(
-                            "Wanted to open at {path:?}, but path is a file"
-                        )));
-                    }
-                };
-            }
+        let created = match cache_open_options.dir_open {
+            DirOpenOpt::OnlyIfExists => match Storage::FileSystem.exists(path)? {
+                FileObjectExists::AsDir => false,
+                FileObjectExists::No => {
+                    return Err(Error::Open(format!(
+                        "Opened with OnlyIfExists but path {path:?} does not exist"
+                    )));
+                }
+                FileObjectExists::AsFile => {
+                    return Err(Error::Open(format!(
+                        "Wanted to open at {path:?}, but path is a file"
+                    )));
+                }
+            },
             DirOpenOpt::CreateIfMissing => {
-                ensure_dir(path)?;
+                let created = Storage::FileSystem.exists(path)? == FileObjectExists::No;
+                Storage::FileSystem.ensure_dir(path)?;
+                created
+            }
+        };
+        // Everything below goes through the storage, and is kept in it while the cache is open
+        let storage = match cache_open_options.storage_opt {
+            StorageOpt::Directory => Storage::FileSystem,
+            #[cfg(feature = "sqlite")]
+            StorageOpt::Sqlite => Storage::Sqlite(crate::sqlite::open(path)?),
+        };
+        let marker = path.safe_join(MARKER_FILE)?;
+        let write_marker = if created {
+            true
+        } else if cache_open_options.require_marker
+            && storage.exists(&marker)? == FileObjectExists::No
+        {
+            if !storage.is_empty_dir(path)? {
+                return Err(Error::Open(format!(
+                    "Opened with a required marker, but {path:?} has no {MARKER_FILE} and isn't empty"
+                )));
             }
+            true
+        } else {
+            false
+        };
+        if write_marker {
+            storage
+                .write_file(&marker, MARKER_CONTENT, self.durability_opt)
+                .map_err(|e| {
+                    Error::WriteContent(format!("Failed to write marker to {marker:?}"), Some(e))
+                })?;
         }
-        let inner = DirCacheInner::read_from_disk(
-            path.to_path_buf(),
-            cache_open_options.eager_load_to_ram,
-            self.generation_opt,
+        let opts = crate::persisted::reconcile(
+            &storage,
+            self,
+            path,
+            cache_open_options.persisted_opts_opt,
         )?;
-        Ok(DirCache { inner, opts: self })
+        let mut inner =
+            DirCacheInner::read_from_disk(path.to_path_buf(), cache_open_options, &opts, storage)?;
+        inner.enforce_mem_budget(opts.mem_budget_opt, None);
+        inner.enforce_disk_budget(&opts, None)?;
+        Ok(DirCache { inner, opts })
+    }
+
+    /// Use these [`DirCacheOpts`] to open a [`DirCache`], then insert every key and value of
+    /// `entries`, see [`DirCache::try_extend`].
+    /// # Errors
+    /// Same as [`DirCacheOpts::open`] and [`DirCache::insert`]
+    pub fn open_from_iter<I: IntoIterator<Item = (PathBuf, Vec<u8>)>>(
+        self,
+        path: &Path,
+        cache_open_options: CacheOpenOptions,
+        entries: I,
+    ) -> Result<DirCache> {
+        let mut dir_cache = self.open(path, cache_open_options)?;
+        dir_cache.try_extend(entries)?;
+        Ok(dir_cache)
+    }
+
+    /// Use these [`DirCacheOpts`] to open a [`DirCache`] in a new directory under the system's
+    /// temporary directory, which is removed along with everything in it when the [`DirCache`]
+    /// is dropped, without syncing anything first.
+    /// Values still move between memory and disk as the opts say, see [`DirCache::path`] for
+    /// where the directory is.
+    /// # Errors
+    /// Various io-errors, from creating the directory and the [`DirCache`].
+    pub fn open_ephemeral(self) -> Result<DirCache> {
+        static OPENED: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let path = std::env::temp_dir().safe_join(format!(
+            "dir-cache-{}-{nanos}-{}",
+            std::process::id(),
+            OPENED.fetch_add(1, Ordering::Relaxed)
+        ))?;
+        // Fails rather than taking over a directory that's already there
+        std::fs::create_dir(&path).map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to create ephemeral dir at {path:?}"),
+                Some(e),
+            )
+        })?;
+        match self.open(
+            &path,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        ) {
+            Ok(mut dir_cache) => {
+                dir_cache.inner.ephemeral = true;
+                Ok(dir_cache)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Use these [`DirCacheOpts`] to open a [`DirCache`] in a directory named `app_name` in
+    /// the platform's cache directory, such as `~/.cache` on Linux, `~/Library/Caches` on macOS,
+    /// or `%LOCALAPPDATA%` on Windows, creating it if it's missing.
+    /// # Errors
+    /// If the platform has no cache directory, or `app_name` isn't a single directory name.
+    /// Various io-errors, from creating the [`DirCache`].
+    #[cfg(feature = "dirs")]
+    pub fn open_user_cache(self, app_name: &str) -> Result<DirCache> {
+        let mut components = Path::new(app_name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        ) {
+            return Err(Error::Open(format!(
+                "App name {app_name:?} isn't a single directory name"
+            )));
+        }
+        let cache_dir = dirs::cache_dir().ok_or_else(|| {
+            Error::Open("Found no cache directory for the current user".to_string())
+        })?;
+        self.open(
+            &cache_dir.safe_join(app_name)?,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
     }
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct CacheOpenOptions {
     pub(crate) dir_open: DirOpenOpt,
     pub(crate) eager_load_to_ram: bool,
+    pub(crate) repair_opt: RepairOpt,
+    pub(crate) invalid_entry_opt: InvalidEntryOpt,
+    pub(crate) unknown_encoding_opt: UnknownEncodingOpt,
+    pub(crate) key_discovery_opt: KeyDiscoveryOpt,
+    pub(crate) ignore_patterns: &'static [&'static str],
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) require_marker: bool,
+    pub(crate) symlink_opt: SymlinkOpt,
+    pub(crate) lower_layer: Option<PathBuf>,
+    pub(crate) persisted_opts_opt: PersistedOptsOpt,
+    pub(crate) storage_opt: StorageOpt,
+    pub(crate) weigher: Option<Weigher>,
+    pub(crate) eviction_listener: Option<EvictionListener>,
+    pub(crate) mirror_opt: Option<MirrorOpt>,
+    pub(crate) fallback_opt: Option<FallbackOpt>,
+    pub(crate) cold_tier: Option<PathBuf>,
 }
 
 impl CacheOpenOptions {
@@ -102,8 +485,294 @@ impl CacheOpenOptions {
         Self {
             dir_open,
             eager_load_to_ram,
+            repair_opt: RepairOpt::NoRepair,
+            invalid_entry_opt: InvalidEntryOpt::Strict,
+            unknown_encoding_opt: UnknownEncodingOpt::Strict,
+            key_discovery_opt: KeyDiscoveryOpt::WalkTree,
+            ignore_patterns: &[],
+            max_depth: None,
+            require_marker: false,
+            symlink_opt: SymlinkOpt::Skip,
+            lower_layer: None,
+            persisted_opts_opt: PersistedOptsOpt::Ignore,
+            storage_opt: StorageOpt::Directory,
+            weigher: None,
+            eviction_listener: None,
+            mirror_opt: None,
+            fallback_opt: None,
+            cold_tier: None,
         }
     }
+
+    /// Skip directories whose name matches any of these patterns when walking the cache, along
+    /// with everything below them, for example `.git` or `node_*`.
+    /// `*` matches any sequence of characters and `?` any single character.
+    /// Keys inside ignored directories can still be used, but aren't found when the cache is
+    /// opened by walking the directory tree.
+    #[must_use]
+    pub const fn with_ignore_patterns(mut self, ignore_patterns: &'static [&'static str]) -> Self {
+        self.ignore_patterns = ignore_patterns;
+        self
+    }
+
+    /// Open the cache as the writable upper layer over a read-only cache at `lower_layer`,
+    /// for example a pre-warmed cache shipped with an application.
+    /// Reads of keys that aren't in the cache fall back to the lower layer, and a key found there
+    /// is copied into the cache when it's first accessed, the lower layer is never written to.
+    /// Keys only in the lower layer aren't listed until they've been accessed, and removing a
+    /// key only removes its copy, it's found in the lower layer again on the next access.
+    #[must_use]
+    pub fn with_lower_layer(mut self, lower_layer: impl Into<PathBuf>) -> Self {
+        self.lower_layer = Some(lower_layer.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn with_symlink_opt(mut self, symlink_opt: SymlinkOpt) -> Self {
+        self.symlink_opt = symlink_opt;
+        self
+    }
+
+    /// Refuse to open an existing directory that doesn't have the marker file written at the root
+    /// of a cache when it's created, unless the directory is empty.
+    /// Protects against accidentally pointing the cache at some other directory and walking it.
+    /// Caches created before markers were written can be marked by creating an empty
+    /// `.dir-cache` file at their root.
+    #[must_use]
+    pub const fn with_require_marker(mut self, require_marker: bool) -> Self {
+        self.require_marker = require_marker;
+        self
+    }
+
+    /// Where the entries are stored, a cache has to be opened with the same [`StorageOpt`]
+    /// every time, entries stored one way aren't found when it's opened the other way
+    #[must_use]
+    pub const fn with_storage_opt(mut self, storage_opt: StorageOpt) -> Self {
+        self.storage_opt = storage_opt;
+        self
+    }
+
+    /// Weigh entries with `weigher` for [`DiskBudgetOpt::MaxWeight`]
+    #[must_use]
+    pub fn with_weigher(mut self, weigher: Weigher) -> Self {
+        self.weigher = Some(weigher);
+        self
+    }
+
+    /// Notify `eviction_listener` of entries the cache removes itself
+    #[must_use]
+    pub fn with_eviction_listener(mut self, eviction_listener: EvictionListener) -> Self {
+        self.eviction_listener = Some(eviction_listener);
+        self
+    }
+
+    /// Mirror writes to a second directory, see [`MirrorOpt`]
+    #[must_use]
+    pub fn with_mirror_opt(mut self, mirror_opt: MirrorOpt) -> Self {
+        self.mirror_opt = Some(mirror_opt);
+        self
+    }
+
+    /// Consult another cache when a key isn't found, see [`FallbackOpt`]
+    #[must_use]
+    pub fn with_fallback_opt(mut self, fallback_opt: FallbackOpt) -> Self {
+        self.fallback_opt = Some(fallback_opt);
+        self
+    }
+
+    /// Demote entries evicted to stay within the [`DiskBudgetOpt`] to a cache at `cold_tier`,
+    /// for example on a larger but slower disk, instead of removing them.
+    /// A demoted entry is promoted back when its key is accessed, and removed from the cold tier.
+    /// Only the keys in this cache are listed, demoted keys are not.
+    #[must_use]
+    pub fn with_cold_tier(mut self, cold_tier: impl Into<PathBuf>) -> Self {
+        self.cold_tier = Some(cold_tier.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn with_persisted_opts_opt(mut self, persisted_opts_opt: PersistedOptsOpt) -> Self {
+        self.persisted_opts_opt = persisted_opts_opt;
+        self
+    }
+
+    /// Don't walk into directories deeper than `max_depth` below the cache root, keys with more
+    /// components than that aren't found when the cache is opened by walking the directory tree.
+    /// A depth of 0 only reads the entry at the root itself.
+    #[must_use]
+    pub const fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Whether the directory at `dir`, below the cache root at `base`, should be walked into
+    pub(crate) fn traverses(&self, base: &Path, dir: &Path) -> bool {
+        // The content-addressed pool isn't an entry
+        if dir.parent() == Some(base) && dir.file_name() == Some(OsStr::new(BLOB_DIR)) {
+            return false;
+        }
+        if let Some(max_depth) = self.max_depth {
+            let depth = dir
+                .strip_prefix(base)
+                .map_or(0, |relative| relative.components().count());
+            if depth > max_depth {
+                return false;
+            }
+        }
+        !dir.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                self.ignore_patterns
+                    .iter()
+                    .any(|pattern| glob_match(pattern, name))
+            })
+    }
+
+    #[must_use]
+    pub const fn with_key_discovery_opt(mut self, key_discovery_opt: KeyDiscoveryOpt) -> Self {
+        self.key_discovery_opt = key_discovery_opt;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_invalid_entry_opt(mut self, invalid_entry_opt: InvalidEntryOpt) -> Self {
+        self.invalid_entry_opt = invalid_entry_opt;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_unknown_encoding_opt(
+        mut self,
+        unknown_encoding_opt: UnknownEncodingOpt,
+    ) -> Self {
+        self.unknown_encoding_opt = unknown_encoding_opt;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_repair_opt(mut self, repair_opt: RepairOpt) -> Self {
+        self.repair_opt = repair_opt;
+        self
+    }
+}
+
+/// How the keys of a [`DirCache`] are found on open
+#[derive(Debug, Copy, Clone, Default)]
+pub enum KeyDiscoveryOpt {
+    /// Walk the whole directory tree and read every entry's manifest
+    #[default]
+    WalkTree,
+    /// Read the keys from an index at the root of the cache, written on [`DirCache::sync`],
+    /// entries are read from disk on first access, which is when the [`InvalidEntryOpt`] applies.
+    /// Falls back to walking the tree if there's no valid index, which is the case when
+    /// keys have been added since the last sync.
+    /// Operations on the whole cache, such as [`DirCache::vacuum`], or enforcing a
+    /// [`DiskBudgetOpt`], read all entries first.
+    Index,
+    /// Don't look for keys on open at all, a key is found when it's first accessed by checking
+    /// for an entry where it would be on disk.
+    /// Useful for large caches where only a few keys are used each time it's opened.
+    /// Operations on the whole cache, such as [`DirCache::vacuum`], or enforcing a
+    /// [`DiskBudgetOpt`], only consider keys that have been accessed.
+    Lazy,
+}
+
+/// What to do with symlinks found when walking the directory tree of a [`DirCache`]
+#[derive(Debug, Copy, Clone, Default)]
+pub enum SymlinkOpt {
+    /// Fail with an error
+    Refuse,
+    /// Follow symlinks as if they were what they point to, keys below a followed directory
+    /// symlink are relative to the symlink. Directories already walked through some other path,
+    /// such as a symlink pointing back up the tree, aren't walked again
+    Follow,
+    /// Ignore symlinks
+    #[default]
+    Skip,
+}
+
+/// Where the entries of a [`DirCache`] are stored
+#[derive(Debug, Copy, Clone, Default)]
+pub enum StorageOpt {
+    /// A directory tree of manifests and generation files, one directory per key
+    #[default]
+    Directory,
+    /// A single SQLite database at the root of the directory, for file systems that handle
+    /// many small files poorly, such as network file systems.
+    /// The cache works the same, except that there are no files to hand out, so
+    /// [`DirCache::get_path`] fails with [`Error::Unsupported`], and memory maps read the
+    /// value into memory. Keys have to be valid utf8.
+    /// Only the cache's own entries are kept in the database, files inserted with
+    /// [`DirCache::insert_file`], backups, mirrors, and the other caches it's layered over are
+    /// all on the file system, even if they're inside the directory.
+    /// [`crate::inspect`] only reads caches stored in directories, and so does the
+    /// `dir-cache` command line tool
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// Whether the generations, encodings, and expiration of the [`GenerationOpt`] a cache is
+/// opened with are recorded in a file at its root, so that everything opening the same cache
+/// agrees on them. [`GenerationOpt::with_encoding_tiers`] still overrides the recorded
+/// encodings, and the rest of the [`GenerationOpt`] is never recorded
+#[derive(Debug, Copy, Clone, Default)]
+pub enum PersistedOptsOpt {
+    /// Nothing is recorded or checked
+    #[default]
+    Ignore,
+    /// Record them if nothing is recorded yet, otherwise open with the recorded ones in place
+    /// of the given ones
+    Adopt,
+    /// Record them if nothing is recorded yet, otherwise fail to open with
+    /// [`Error::OptsConflict`] if the given ones differ from the recorded ones
+    Strict,
+}
+
+/// What to do on open with entries that can't be read, for example because of a malformed
+/// manifest. Invalid entries that aren't failed on can be found through
+/// [`DirCache::invalid_entries`].
+#[derive(Debug, Copy, Clone, Default)]
+pub enum InvalidEntryOpt {
+    /// Fail opening the cache
+    #[default]
+    Strict,
+    /// Leave the entry on disk, but don't load it
+    SkipInvalid,
+    /// Delete the entry from disk
+    PurgeInvalid,
+}
+
+/// What to do on open with generations stored with an [`Encoding`] this build can't decode,
+/// such as lz4 or zstd without their feature enabled, or a custom [`Codec`] that isn't
+/// registered
+#[derive(Debug, Copy, Clone, Default)]
+pub enum UnknownEncodingOpt {
+    /// The entry can't be read, and is handled according to the [`InvalidEntryOpt`]
+    #[default]
+    Strict,
+    /// Treat the first such generation, and all generations older than it, as unavailable.
+    /// They're left on disk as long as the entry isn't written to, so a build that can decode
+    /// them can still read them, but are dropped from the manifest on the next write.
+    /// If the current generation can't be decoded, the entry is handled according to the
+    /// [`InvalidEntryOpt`]
+    SkipGenerations,
+}
+
+/// What to do on open with entries whose generation files on disk don't match their manifest,
+/// for example after a crash in the middle of writing a new generation
+#[derive(Debug, Copy, Clone, Default)]
+pub enum RepairOpt {
+    /// Leave the entry as is. Generations whose size is recorded in the manifest aren't checked
+    /// on open, a missing one fails when it's read, a truncated one when it's read with
+    /// [`DirCacheOpts::verify_on_read`], and both are found by [`DirCache::verify`].
+    /// Opening fails if a listed generation without a recorded size is missing
+    #[default]
+    NoRepair,
+    /// Match the generation files to the manifest, by size and checksum where they were recorded
+    /// and by position otherwise, drop generations that can't be found, delete files that don't match
+    /// any generation, and renumber the rest.
+    /// An entry whose manifest doesn't match its own checksum is removed entirely
+    Repair,
 }
 
 /// Options for when a [`DirCache`] is opened
@@ -116,8 +785,304 @@ pub enum DirOpenOpt {
     CreateIfMissing,
 }
 
+/// How hard to try to make sure that written data survives a crash or power loss
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum DurabilityOpt {
+    /// Leave flushing to the OS
+    #[default]
+    None,
+    /// Flush the content of written files to disk before moving on
+    FlushData,
+    /// Flush written files and their metadata to disk, and flush directories after files in them
+    /// are created or renamed, so that the new directory entries survive as well
+    Full,
+}
+
+/// Where the number of times each key has been read, [`crate::meta::EntryMeta::hits`], is kept
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum AccessStatsOpt {
+    /// Only in memory, reads are counted since the cache was opened
+    #[default]
+    InMemory,
+    /// Recorded in the manifest of each entry, reads are counted across opens.
+    /// Like the time of the last read, it's written when the entry is next written to disk
+    Persisted,
+}
+
+impl AccessStatsOpt {
+    #[inline]
+    pub(crate) fn persisted(self) -> bool {
+        matches!(self, AccessStatsOpt::Persisted)
+    }
+}
+
+/// Whether an insert ages the current value into an older generation, or replaces it.
+/// Use with [`DirCache::insert_opt`] to pick per insert
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum InsertMode {
+    /// The current value becomes the next older generation, and the oldest generation is
+    /// dropped if there are already as many as the [`GenerationOpt`] keeps
+    #[default]
+    NewGeneration,
+    /// The current value is replaced, leaving older generations as they are, for example to
+    /// correct a value without using up a generation
+    Overwrite,
+}
+
+/// How [`DirCache::insert_file`] gets a file into the cache
+#[derive(Debug, Copy, Clone, Default)]
+pub enum IngestOpt {
+    /// Copy the file, leaving the original in place.
+    /// Uses the platform's copy, which may clone the file without copying its data on
+    /// file systems that support it
+    #[default]
+    Copy,
+    /// Move the file into the cache, falling back to copying it and removing the original
+    /// if it's on a different file system
+    Move,
+}
+
+/// What to do when a key brought into a [`DirCache`] from elsewhere, such as when importing an
+/// archive, is already in it
+#[derive(Debug, Copy, Clone, Default)]
+pub enum ConflictPolicy {
+    /// Keep whichever value was updated last, the existing one if they were updated at the
+    /// same time
+    #[default]
+    KeepNewer,
+    /// Keep the existing value
+    KeepExisting,
+    /// Fail with [`Error::KeyCollision`]
+    Error,
+}
+
+/// How keys are turned into paths on disk
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum KeyCodecOpt {
+    /// Keys are used as paths as they are, each component becoming a directory.
+    /// Keys with components that aren't safe to use as directory names are refused
+    #[default]
+    Verbatim,
+    /// Keys are taken as opaque strings and stored as a single directory each, with
+    /// separators, dots and anything else that isn't safe in a file name percent-encoded.
+    /// Lets a URL like `https://example.com/a?b=1` be used as a key verbatim.
+    /// Keys handed back by the cache, such as in [`DirCache::keys`], are decoded
+    PercentEncode,
+}
+
+impl KeyCodecOpt {
+    /// The key `key` is stored under
+    pub(crate) fn encode(self, key: &Path) -> Cow<'_, Path> {
+        match self {
+            KeyCodecOpt::Verbatim => Cow::Borrowed(key),
+            KeyCodecOpt::PercentEncode => Cow::Owned(PathBuf::from(crate::key::escape(
+                key.as_os_str().as_encoded_bytes(),
+            ))),
+        }
+    }
+
+    /// The key that's stored under `stored`.
+    /// Stored keys that aren't percent-encoded, such as ones from before the codec was used,
+    /// are returned as they are
+    pub(crate) fn decode(self, stored: &Path) -> Cow<'_, Path> {
+        match self {
+            KeyCodecOpt::Verbatim => Cow::Borrowed(stored),
+            KeyCodecOpt::PercentEncode => stored
+                .to_str()
+                .and_then(crate::key::unescape)
+                .map_or(Cow::Borrowed(stored), Cow::Owned),
+        }
+    }
+}
+
+/// How keys that differ only in case are treated.
+/// On case-insensitive file systems, the default on Windows and macOS, `Foo` and `foo` are
+/// stored in the same directory while being different keys in memory, overwriting each other
+/// on disk.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum KeyCaseOpt {
+    /// Keys differing in case are different keys, which is only safe on case-sensitive
+    /// file systems
+    #[default]
+    Sensitive,
+    /// Keys are lowercased, `Foo` and `foo` are the same key everywhere.
+    /// Keys handed back by the cache, such as in [`DirCache::keys`], are lowercase
+    Fold,
+    /// Writing a key that differs only in case from a key in the cache, or a directory that holds
+    /// one, fails with [`Error::KeyCollision`].
+    /// With [`KeyDiscoveryOpt::Lazy`] the directories on disk are checked too, since the cache
+    /// may not have seen every key yet
+    Detect,
+}
+
+impl KeyCaseOpt {
+    pub(crate) fn fold(self, key: &Path) -> Cow<'_, Path> {
+        match self {
+            KeyCaseOpt::Sensitive | KeyCaseOpt::Detect => Cow::Borrowed(key),
+            KeyCaseOpt::Fold => match key.to_str() {
+                Some(utf8) if utf8.chars().any(char::is_uppercase) => {
+                    Cow::Owned(PathBuf::from(utf8.to_lowercase()))
+                }
+                Some(_) => Cow::Borrowed(key),
+                // Only ascii can be folded without knowing the encoding
+                None => Cow::Owned(PathBuf::from(key.as_os_str().to_ascii_lowercase())),
+            },
+        }
+    }
+}
+
+/// Limits on the keys a [`DirCache`] accepts.
+/// Applied to keys as they're stored, after encoding them with a [`KeyCodecOpt`] or folding
+/// their case with [`KeyCaseOpt::Fold`]. Nothing is limited by default.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct KeyPolicy {
+    pub(crate) max_components: Option<usize>,
+    pub(crate) max_len: Option<usize>,
+    pub(crate) charset: KeyCharset,
+}
+
+impl KeyPolicy {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            max_components: None,
+            max_len: None,
+            charset: KeyCharset::Any,
+        }
+    }
+
+    /// Refuse keys with more than `max_components` components, directories nested deeper
+    /// than that
+    #[must_use]
+    pub const fn with_max_components(mut self, max_components: usize) -> Self {
+        self.max_components = Some(max_components);
+        self
+    }
+
+    /// Refuse keys longer than `max_len` bytes, separators included
+    #[must_use]
+    pub const fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Refuse keys with characters outside of `charset`, separators aside
+    #[must_use]
+    pub const fn with_charset(mut self, charset: KeyCharset) -> Self {
+        self.charset = charset;
+        self
+    }
+
+    pub(crate) fn check(&self, key: &Path) -> Result<()> {
+        let len = key.as_os_str().len();
+        if let Some(max_len) = self.max_len.filter(|max_len| len > *max_len) {
+            return Err(Error::DangerousKey(format!(
+                "Key {key:?} is {len} bytes long, the key policy allows at most {max_len}"
+            )));
+        }
+        let components = key.components().count();
+        if let Some(max_components) = self
+            .max_components
+            .filter(|max_components| components > *max_components)
+        {
+            return Err(Error::DangerousKey(format!(
+                "Key {key:?} has {components} components, the key policy allows at most {max_components}"
+            )));
+        }
+        for component in key.components() {
+            let name = component.as_os_str().as_encoded_bytes();
+            if let Some(b) = name.iter().find(|b| !self.charset.allows(**b)) {
+                return Err(Error::DangerousKey(format!(
+                    "Key {key:?} has the byte {b:#04x}, which isn't in the allowed charset {:?}",
+                    self.charset
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The characters allowed in keys by a [`KeyPolicy`]
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum KeyCharset {
+    /// Anything [`DirCache`] otherwise accepts
+    #[default]
+    Any,
+    /// Printable ascii, except characters that aren't allowed in file names on Windows:
+    /// `<>:"|?*\`
+    Ascii,
+    /// The POSIX portable file name characters, ascii alphanumerics, `.`, `_` and `-`
+    Portable,
+}
+
+impl KeyCharset {
+    fn allows(self, b: u8) -> bool {
+        match self {
+            KeyCharset::Any => true,
+            KeyCharset::Ascii => b == b' ' || (b.is_ascii_graphic() && !b"<>:\"|?*\\".contains(&b)),
+            KeyCharset::Portable => b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'),
+        }
+    }
+}
+
+/// What to do with a value larger than [`DirCacheOpts::max_value_size`]
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum OversizeOpt {
+    /// Fail the insert with [`Error::ValueTooLarge`]
+    #[default]
+    Fail,
+    /// Don't cache the value, removing the key's previous value so that it isn't returned in
+    /// place of the newer one. [`DirCache::get_or_insert`] still returns the value
+    Skip,
+}
+
 /// Memory push option, determines whether the data should be retained in memory when written to disk
 #[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum MemPushOpt {
     /// Keep the data in memory after writing
     RetainAndWrite,
@@ -126,36 +1091,387 @@ pub enum MemPushOpt {
     /// Remove the data from memory after writing
     #[default]
     PassthroughWrite,
+    /// Keep the data in memory after writing if it's smaller than the specified amount of bytes,
+    /// otherwise remove it from memory after writing
+    RetainIfSmallerThan(usize),
+}
+
+impl MemPushOpt {
+    /// Whether a value of `len` bytes should be kept in memory after having been written to disk
+    #[inline]
+    pub(crate) fn retain_after_write(self, len: usize) -> bool {
+        match self {
+            MemPushOpt::RetainAndWrite => true,
+            MemPushOpt::MemoryOnly | MemPushOpt::PassthroughWrite => false,
+            MemPushOpt::RetainIfSmallerThan(max) => len < max,
+        }
+    }
 }
 
 /// Memory pull options, determines whether data should be cached in memory when pulled from disk,
 /// such as during a `get` operation.
 #[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum MemPullOpt {
     /// Reads the value from disk, then retains it in memory
     #[default]
     KeepInMemoryOnRead,
     /// Reads the value from disk, but does not keep it stored in memory
     DontKeepInMemoryOnRead,
+    /// Reads the value from disk, then retains it in memory if it's smaller than the
+    /// specified amount of bytes
+    KeepIfSmallerThan(usize),
+}
+
+impl MemPullOpt {
+    /// Whether a value of `len` bytes should be kept in memory after having been read from disk
+    #[inline]
+    pub(crate) fn keep_after_read(self, len: usize) -> bool {
+        match self {
+            MemPullOpt::KeepInMemoryOnRead => true,
+            MemPullOpt::DontKeepInMemoryOnRead => false,
+            MemPullOpt::KeepIfSmallerThan(max) => len < max,
+        }
+    }
+}
+
+/// Memory budget options, determines how much value data retained in memory is allowed to take up.
+/// Values that are dropped from memory to stay within the budget are still on disk,
+/// and will be read from there on the next access.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum MemBudgetOpt {
+    /// Keep all retained values in memory
+    #[default]
+    Unbounded,
+    /// Keep at most this many bytes of retained values in memory, dropping the least
+    /// recently used values first. Values written with [`MemPushOpt::MemoryOnly`] that
+    /// have not yet been synced to disk are never dropped.
+    MaxBytes(usize),
+    /// Let a `moka` cache of at most `max_bytes` decide which retained values are kept in memory,
+    /// admitting and evicting them by how frequently and recently they're used, and dropping
+    /// values that haven't been accessed for `time_to_idle` if given.
+    /// The cache is created the first time it's used, later changes to this option have no
+    /// effect on it. Values written with [`MemPushOpt::MemoryOnly`] that have not yet been
+    /// synced to disk are never dropped.
+    #[cfg(feature = "moka")]
+    Moka {
+        max_bytes: u64,
+        time_to_idle: Option<Duration>,
+    },
+}
+
+/// Disk budget options, determines how much the entries kept on disk are allowed to weigh.
+/// When the budget is exceeded after a write, the least recently used entries are evicted
+/// from both memory and disk.
+/// An entry is weighed when it's written, read from disk, or has generations removed, with the
+/// budget in effect at the time, not when it's read.
+/// Entries with values written with [`MemPushOpt::MemoryOnly`] that have not yet been synced
+/// to disk are never evicted.
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum DiskBudgetOpt {
+    /// Never evict entries
+    #[default]
+    Unbounded,
+    /// Evict entries when all generations of all entries take up more than this many bytes on disk.
+    /// Values in the content-addressed pool are counted once however many entries hold them,
+    /// and only count as freed when the last entry holding them is evicted
+    MaxBytes(u64),
+    /// Evict entries when their combined weight, as determined by the [`Weigher`] the cache is
+    /// opened with, see [`CacheOpenOptions::with_weigher`], exceeds this.
+    /// Without a [`Weigher`], entries weigh what they take up on disk
+    MaxWeight(u64),
+}
+
+impl DiskBudgetOpt {
+    #[inline]
+    pub(crate) fn max_weight(self) -> u64 {
+        match self {
+            DiskBudgetOpt::Unbounded => u64::MAX,
+            DiskBudgetOpt::MaxBytes(max_bytes) => max_bytes,
+            DiskBudgetOpt::MaxWeight(max_weight) => max_weight,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn weigh(self, key: &Path, meta: &EntryMeta, weigher: Option<&Weigher>) -> u64 {
+        match (self, weigher) {
+            (DiskBudgetOpt::Unbounded, _) => 0,
+            (DiskBudgetOpt::MaxWeight(_), Some(weigher)) => (weigher.0)(key, meta),
+            (DiskBudgetOpt::MaxBytes(_) | DiskBudgetOpt::MaxWeight(_), _) => meta.disk_size(),
+        }
+    }
+}
+
+/// User-supplied function determining the weight of an entry, used to express a [`DiskBudgetOpt`]
+/// in units other than bytes on disk, for example the cost of regenerating a value.
+/// Receives the entry's key and its [`EntryMeta`], see [`CacheOpenOptions::with_weigher`].
+/// # Example
+/// ```
+/// use std::collections::HashMap;
+/// use std::path::PathBuf;
+/// use dir_cache::opts::{CacheOpenOptions, DirCacheOpts, DirOpenOpt, DiskBudgetOpt, Weigher};
+/// // The cost of regenerating some keys, read from configuration at startup
+/// let costs = HashMap::from([(PathBuf::from("report"), 1_000)]);
+/// let weigher = Weigher::new(move |key, meta| {
+///     costs.get(key).copied().unwrap_or(meta.disk_size())
+/// });
+/// let opts = DirCacheOpts::default().with_disk_budget_opt(DiskBudgetOpt::MaxWeight(10_000));
+/// let open_options =
+///     CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false).with_weigher(weigher);
+/// ```
+#[derive(Clone)]
+pub struct Weigher(Arc<WeighFn>);
+
+type WeighFn = dyn Fn(&Path, &EntryMeta) -> u64 + Send + Sync;
+
+impl Weigher {
+    #[must_use]
+    pub fn new<F: Fn(&Path, &EntryMeta) -> u64 + Send + Sync + 'static>(weigher: F) -> Self {
+        Self(Arc::new(weigher))
+    }
+}
+
+impl Debug for Weigher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Weigher")
+    }
+}
+
+/// Why an entry was removed by the [`DirCache`] itself, rather than by the user
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EvictReason {
+    /// The entry expired according to the [`ExpirationOpt`]
+    Expired,
+    /// The entry was evicted to stay within the [`DiskBudgetOpt`], or demoted to the
+    /// [`CacheOpenOptions::with_cold_tier`]
+    Evicted,
+}
+
+/// User-supplied function invoked with the key and the [`EvictReason`] whenever the [`DirCache`]
+/// removes an entry because it expired or was evicted.
+/// Not invoked on [`DirCache::remove`], see [`CacheOpenOptions::with_eviction_listener`].
+/// # Example
+/// ```
+/// use std::sync::mpsc::channel;
+/// use std::sync::Mutex;
+/// use dir_cache::opts::{CacheOpenOptions, DirOpenOpt, EvictionListener};
+/// // A channel to invalidate data derived from evicted entries through
+/// let (evicted, invalidate) = channel();
+/// let evicted = Mutex::new(evicted);
+/// let listener = EvictionListener::new(move |key, _reason| {
+///     let _ = evicted.lock().unwrap().send(key.to_path_buf());
+/// });
+/// let open_options =
+///     CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false).with_eviction_listener(listener);
+/// ```
+#[derive(Clone)]
+pub struct EvictionListener(pub(crate) Arc<EvictFn>);
+
+type EvictFn = dyn Fn(&Path, EvictReason) + Send + Sync;
+
+impl EvictionListener {
+    #[must_use]
+    pub fn new<F: Fn(&Path, EvictReason) + Send + Sync + 'static>(listener: F) -> Self {
+        Self(Arc::new(listener))
+    }
+}
+
+impl Debug for EvictionListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EvictionListener")
+    }
+}
+
+/// User-supplied function invoked with the key, and the [`EntryMeta`] of the entry if there is one,
+/// see [`Hooks`]
+#[derive(Copy, Clone)]
+pub struct Hook(pub &'static (dyn Fn(&Path, Option<EntryMeta>) + Send + Sync));
+
+impl Debug for Hook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/// A second directory that the entries of a [`DirCache`] are copied to as they're written,
+/// and removed from as they're removed, for example on a network share.
+/// When a key isn't found in the cache, it's restored from the mirror if it's there.
+/// Only writes of values and removals through [`DirCache::remove`] are mirrored, the mirror isn't
+/// subject to budgets or expiry, expired entries restored from it are cleaned up as usual.
+/// Values only held in memory are mirrored when they're written to disk.
+#[derive(Debug, Clone)]
+pub struct MirrorOpt {
+    pub dir: PathBuf,
+    pub error_opt: MirrorErrorOpt,
+}
+
+impl MirrorOpt {
+    #[must_use]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            error_opt: MirrorErrorOpt::Fail,
+        }
+    }
+
+    #[must_use]
+    pub fn with_error_opt(mut self, error_opt: MirrorErrorOpt) -> Self {
+        self.error_opt = error_opt;
+        self
+    }
+
+    /// Handles the result of mirroring `key` according to the [`MirrorErrorOpt`]
+    pub(crate) fn handle(&self, res: Result<()>, key: &Path) -> Result<()> {
+        match (res, self.error_opt) {
+            (Err(e), MirrorErrorOpt::Ignore) => {
+                warn!("Failed to mirror key {key:?} to {:?}: {e}", self.dir);
+                Ok(())
+            }
+            (res, _) => res,
+        }
+    }
+}
+
+/// A directory with another cache that's consulted when a key isn't in this one, for example the
+/// previous location of a cache that's being moved, it's never written to.
+/// Values found in it are either read from it directly, only by [`DirCache::get`] and the
+/// variants of it that return the value, or copied forward into this cache when the key is first
+/// accessed, after which it's used as any other key.
+/// [`DirCache::get_ref`] always copies forward, to be able to hand out a borrow.
+#[derive(Debug, Clone)]
+pub struct FallbackOpt {
+    pub dir: PathBuf,
+    pub copy_forward: bool,
+}
+
+impl FallbackOpt {
+    #[must_use]
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            copy_forward: false,
+        }
+    }
+
+    #[must_use]
+    pub fn with_copy_forward(mut self, copy_forward: bool) -> Self {
+        self.copy_forward = copy_forward;
+        self
+    }
+}
+
+/// What to do when copying to or from the [`MirrorOpt`] fails
+#[derive(Debug, Copy, Clone, Default)]
+pub enum MirrorErrorOpt {
+    /// Fail the operation, the cache itself has already been written to at that point
+    #[default]
+    Fail,
+    /// Carry on without the mirror, the failure is logged with the `log` feature
+    Ignore,
+}
+
+/// Callbacks fired on cache operations, after they've succeeded
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Hooks {
+    /// Fired when a read finds a value, with the entry read
+    pub on_hit: Option<Hook>,
+    /// Fired when a read finds no value, without an entry
+    pub on_miss: Option<Hook>,
+    /// Fired when a value is inserted, with the entry it was inserted into,
+    /// no entry if it was evicted right away to stay within the [`DiskBudgetOpt`]
+    pub on_insert: Option<Hook>,
+    /// Fired when a key is removed through [`DirCache::remove`], with the entry removed.
+    /// Entries removed by the cache itself are reported to the [`EvictionListener`] instead
+    pub on_remove: Option<Hook>,
+}
+
+impl Hooks {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            on_hit: None,
+            on_miss: None,
+            on_insert: None,
+            on_remove: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn with_on_hit(mut self, on_hit: Hook) -> Self {
+        self.on_hit = Some(on_hit);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_on_miss(mut self, on_miss: Hook) -> Self {
+        self.on_miss = Some(on_miss);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_on_insert(mut self, on_insert: Hook) -> Self {
+        self.on_insert = Some(on_insert);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_on_remove(mut self, on_remove: Hook) -> Self {
+        self.on_remove = Some(on_remove);
+        self
+    }
 }
 
 /// Expiration options, how to determine if an entry has expired
 #[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum ExpirationOpt {
     /// Entries never expire
     #[default]
     NoExpiry,
     /// Entries expire after
     ExpiresAfter(Duration),
+    /// Entries expire at a point in time, regardless of when they were written,
+    /// for example when the source of the values is refreshed
+    ExpiresAt(SystemTime),
+    /// Entries expire after going unread for, counting from when they were written if they
+    /// never were.
+    /// Reads are recorded in the manifest when the entry is next written to disk, at the
+    /// latest on [`DirCache::sync`], reads since then are lost if the cache is never synced
+    ExpiresAfterIdle(Duration),
 }
 
 impl ExpirationOpt {
+    /// When a value written at `written` and last read at `read` expires, all since the
+    /// unix epoch
     #[inline]
-    pub(crate) fn as_dur(self) -> Duration {
+    pub(crate) fn expires_at(self, written: Duration, read: Option<Duration>) -> Duration {
         match self {
             // End of all times
             ExpirationOpt::NoExpiry => Duration::MAX,
-            ExpirationOpt::ExpiresAfter(dur) => dur,
+            ExpirationOpt::ExpiresAfter(dur) => written.saturating_add(dur),
+            ExpirationOpt::ExpiresAfterIdle(dur) => {
+                read.unwrap_or(written).max(written).saturating_add(dur)
+            }
+            // Before the epoch has passed long ago
+            ExpirationOpt::ExpiresAt(at) => at.duration_since(UNIX_EPOCH).unwrap_or_default(),
         }
     }
 }
@@ -163,13 +1479,38 @@ impl ExpirationOpt {
 /// Data can be saved as generations (keeping older values of keys),
 /// these options determine how those generations are managed
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct GenerationOpt {
     /// How many old copies to keep, 1 effectively means no generations, just one value.
     pub max_generations: NonZeroUsize,
     /// How to encode older generations
     pub(crate) old_gen_encoding: Encoding,
+    /// How to encode the current generation
+    pub(crate) current_gen_encoding: Encoding,
+    /// Values smaller than this amount of bytes are never encoded
+    pub(crate) min_compress_size: usize,
+    /// Store older generations as deltas against the next newer generation
+    pub(crate) delta_generations: bool,
+    /// Hardlink a new generation to the previous one if they're stored identically
+    pub(crate) link_identical: bool,
+    /// Don't write a new generation when a value is identical to the current one
+    pub(crate) skip_unchanged: bool,
+    /// Encoding per generation, overrides the old and current generation encodings if not empty
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) encoding_tiers: &'static [Encoding],
+    /// Codecs that may be needed to decode generations already on disk
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) codecs: &'static [&'static dyn Codec],
     /// How to determine when a value of any generation has expired
     pub(crate) expiration: ExpirationOpt,
+    /// Age after which a value is still served, but reported as stale
+    pub(crate) soft_ttl: Option<Duration>,
+    /// Upper bound of the random delay added to relative expirations
+    pub(crate) expiry_jitter: Option<Duration>,
 }
 
 impl Default for GenerationOpt {
@@ -189,64 +1530,478 @@ impl GenerationOpt {
         Self {
             max_generations,
             old_gen_encoding,
+            current_gen_encoding: Encoding::Plain,
+            min_compress_size: 0,
+            delta_generations: false,
+            link_identical: false,
+            skip_unchanged: false,
+            encoding_tiers: &[],
+            codecs: &[],
             expiration,
+            soft_ttl: None,
+            expiry_jitter: None,
+        }
+    }
+
+    /// Report values older than `soft_ttl` as [`Freshness::Stale`] through
+    /// [`DirCache::get_with_freshness`], rather than only ever serving or removing them.
+    /// Values are still removed once they expire according to the [`ExpirationOpt`], which
+    /// then acts as the hard TTL.
+    #[must_use]
+    pub const fn with_soft_ttl(mut self, soft_ttl: Duration) -> Self {
+        self.soft_ttl = Some(soft_ttl);
+        self
+    }
+
+    /// Delay the expiration of each value by a random amount less than `jitter`, so that values
+    /// written together don't all expire together.
+    /// Applies to [`ExpirationOpt::ExpiresAfter`] and [`ExpirationOpt::ExpiresAfterIdle`],
+    /// the delay is derived from when the value was written so that it stays the same for the
+    /// value across opens.
+    #[must_use]
+    pub const fn with_expiry_jitter(mut self, jitter: Duration) -> Self {
+        self.expiry_jitter = Some(jitter);
+        self
+    }
+
+    /// When a value written at `written` and last read at `read` expires, all since the unix
+    /// epoch, under `expiration` if it overrides the configured one
+    pub(crate) fn expires_at(
+        self,
+        expiration: Option<ExpirationOpt>,
+        written: Duration,
+        read: Option<Duration>,
+    ) -> Duration {
+        let expiration = expiration.unwrap_or(self.expiration);
+        let expires_at = expiration.expires_at(written, read);
+        match (expiration, self.expiry_jitter) {
+            (ExpirationOpt::ExpiresAfter(_) | ExpirationOpt::ExpiresAfterIdle(_), Some(jitter))
+                if !jitter.is_zero() =>
+            {
+                let max = u64::try_from(jitter.as_nanos()).unwrap_or(u64::MAX);
+                let hash = xxhash_rust::xxh3::xxh3_64(&written.as_nanos().to_le_bytes());
+                expires_at.saturating_add(Duration::from_nanos(hash % max))
+            }
+            _ => expires_at,
+        }
+    }
+
+    /// Whether a value written at `written` and last read at `read` has expired at `now`,
+    /// see [`GenerationOpt::expires_at`]
+    #[inline]
+    pub(crate) fn expired(
+        self,
+        expiration: Option<ExpirationOpt>,
+        written: Duration,
+        read: Option<Duration>,
+        now: Duration,
+    ) -> bool {
+        self.expires_at(expiration, written, read) <= now
+    }
+
+    /// Whether a value written at `written` is stale at `now`, both since the unix epoch
+    #[inline]
+    pub(crate) fn freshness(self, written: Duration, now: Duration) -> Freshness {
+        match self.soft_ttl {
+            Some(soft_ttl) if now.saturating_sub(written) >= soft_ttl => Freshness::Stale,
+            _ => Freshness::Fresh,
+        }
+    }
+
+    /// Register [`Codec`]s that may be needed to decode generations already on disk.
+    /// Codecs used in this [`GenerationOpt`]'s encodings are always registered.
+    /// Reading a generation encoded with a codec that isn't registered fails with
+    /// [`Error::UnknownCodec`].
+    #[must_use]
+    pub const fn with_codecs(mut self, codecs: &'static [&'static dyn Codec]) -> Self {
+        self.codecs = codecs;
+        self
+    }
+
+    pub(crate) fn find_codec(&self, id: &str) -> Option<&'static dyn Codec> {
+        [self.current_gen_encoding, self.old_gen_encoding]
+            .into_iter()
+            .chain(self.encoding_tiers.iter().copied())
+            .filter_map(|encoding| {
+                if let Encoding::Custom(codec) = encoding {
+                    Some(codec)
+                } else {
+                    None
+                }
+            })
+            .chain(self.codecs.iter().copied())
+            .find(|codec| codec.id() == id)
+    }
+
+    /// Store values smaller than `min_compress_size` bytes as [`Encoding::Plain`] regardless of
+    /// the configured encodings, compressing small values tends to make them larger.
+    #[must_use]
+    pub const fn with_min_compress_size(mut self, min_compress_size: usize) -> Self {
+        self.min_compress_size = min_compress_size;
+        self
+    }
+
+    /// The encoding to actually use for a value of `len` bytes that's configured to use `encoding`
+    #[inline]
+    pub(crate) fn encoding_for(self, encoding: Encoding, len: usize) -> Encoding {
+        if len < self.min_compress_size {
+            Encoding::Plain
+        } else {
+            encoding
+        }
+    }
+
+    /// Encode each generation according to its age, `encoding_tiers[0]` is used for the current
+    /// generation, `encoding_tiers[1]` for the one before that, and so on.
+    /// Generations older than the list is long use its last encoding.
+    /// Generations are recoded as they age past a tier, so that recent values can be kept fast
+    /// and old ones small.
+    /// When set (non-empty), this takes precedence over the current and old generation encodings.
+    /// # Example
+    /// ```
+    /// # #[cfg(all(feature = "lz4", feature = "zstd"))]
+    /// # {
+    /// use dir_cache::opts::{Encoding, ExpirationOpt, GenerationOpt};
+    /// use std::num::NonZeroUsize;
+    /// // Plain current generation, lz4 for the two after that, zstd for anything older
+    /// static TIERS: [Encoding; 4] = [
+    ///     Encoding::Plain,
+    ///     Encoding::Lz4 { level: 0 },
+    ///     Encoding::Lz4 { level: 0 },
+    ///     Encoding::Zstd { level: 19 },
+    /// ];
+    /// let opt = GenerationOpt::new(
+    ///     NonZeroUsize::new(8).unwrap(),
+    ///     Encoding::Plain,
+    ///     ExpirationOpt::NoExpiry,
+    /// )
+    /// .with_encoding_tiers(&TIERS);
+    /// # }
+    /// ```
+    #[must_use]
+    pub const fn with_encoding_tiers(mut self, encoding_tiers: &'static [Encoding]) -> Self {
+        self.encoding_tiers = encoding_tiers;
+        self
+    }
+
+    /// Store generations older than the current one as a binary delta against the next newer
+    /// generation, when that's smaller than storing the value itself.
+    /// Saves a lot of disk for large values where only a small part changes between generations.
+    /// Deltas are encoded like any other value, and reconstructing an old generation
+    /// requires reading every generation newer than it.
+    #[must_use]
+    pub const fn with_delta_generations(mut self, delta_generations: bool) -> Self {
+        self.delta_generations = delta_generations;
+        self
+    }
+
+    /// When a value is written that's stored exactly like the previous generation, hardlink the
+    /// new generation to the previous one instead of storing the same bytes twice.
+    /// Useful when values rarely change between writes.
+    /// Only applies if the previous generation isn't re-encoded as it ages, and falls back to
+    /// writing the value where hardlinks aren't supported.
+    #[must_use]
+    pub const fn with_link_identical(mut self, link_identical: bool) -> Self {
+        self.link_identical = link_identical;
+        self
+    }
+
+    /// When a value is written that's identical to the current one, don't write a new
+    /// generation, only mark the current one as updated now, as with [`DirCache::touch`].
+    /// Useful when values are refreshed periodically and mostly come back unchanged, unlike
+    /// [`GenerationOpt::with_link_identical`] older generations are kept as they are.
+    /// The current value may have to be read to compare it.
+    #[must_use]
+    pub const fn with_skip_unchanged(mut self, skip_unchanged: bool) -> Self {
+        self.skip_unchanged = skip_unchanged;
+        self
+    }
+
+    /// The configured encoding for the generation at index `generation`, 0 being the current one
+    pub(crate) fn generation_encoding(self, generation: usize) -> Encoding {
+        if let Some(last) = self.encoding_tiers.last() {
+            self.encoding_tiers
+                .get(generation)
+                .copied()
+                .unwrap_or(*last)
+        } else if generation == 0 {
+            self.current_gen_encoding
+        } else {
+            self.old_gen_encoding
         }
     }
+
+    /// Encode the current generation as well, not just older generations.
+    /// Values are decoded transparently when read.
+    #[must_use]
+    pub const fn with_current_gen_encoding(mut self, current_gen_encoding: Encoding) -> Self {
+        self.current_gen_encoding = current_gen_encoding;
+        self
+    }
 }
 
 /// Different encoding options
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum Encoding {
     /// No encoding
     Plain,
-    /// Compress using lz4
+    /// Compress using lz4 at the specified compression level, 0 means the lz4 default
     #[cfg(feature = "lz4")]
-    Lz4,
+    Lz4 { level: u32 },
+    /// Compress using zstd at the specified compression level, 0 means the zstd default
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// Transform using a user-supplied [`Codec`]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(&'static dyn Codec),
 }
 
+impl PartialEq for Encoding {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Encoding::Plain, Encoding::Plain) => true,
+            #[cfg(feature = "lz4")]
+            (Encoding::Lz4 { level: a }, Encoding::Lz4 { level: b }) => a == b,
+            #[cfg(feature = "zstd")]
+            (Encoding::Zstd { level: a }, Encoding::Zstd { level: b }) => a == b,
+            (Encoding::Custom(a), Encoding::Custom(b)) => a.id() == b.id(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Encoding {}
+
 impl Encoding {
-    pub(crate) fn serialize(self) -> impl Display {
+    /// The inverse of [`Encoding::deserialize`]
+    pub(crate) fn serialize(self) -> String {
         match self {
-            Encoding::Plain => 0u8,
+            Self::Plain => "0".to_string(),
             #[cfg(feature = "lz4")]
-            Encoding::Lz4 => 1u8,
+            Self::Lz4 { level } => format!("1:{level}"),
+            #[cfg(feature = "zstd")]
+            Self::Zstd { level } => format!("2:{level}"),
+            Self::Custom(codec) => format!("3:{}", codec.id()),
         }
     }
 
-    pub(crate) fn deserialize(s: &str) -> Result<Self> {
-        match s {
+    pub(crate) fn deserialize(s: &str, generation_opt: &GenerationOpt) -> Result<Self> {
+        let id = s.split_once(':').map_or(s, |(id, _level)| id);
+        match id {
             "0" => Ok(Self::Plain),
             #[cfg(feature = "lz4")]
-            "1" => Ok(Self::Lz4),
-            v => Err(Error::ParseMetadata(format!(
-                "Failed to parse encoding from {v}"
+            "1" => Ok(Self::Lz4 {
+                level: Self::parse_level(s)?,
+            }),
+            #[cfg(feature = "zstd")]
+            "2" => Ok(Self::Zstd {
+                level: Self::parse_level(s)?,
+            }),
+            #[cfg(not(feature = "lz4"))]
+            "1" => Err(Error::UnsupportedEncoding(format!(
+                "Encoding {s} requires the `lz4` feature to be enabled"
+            ))),
+            #[cfg(not(feature = "zstd"))]
+            "2" => Err(Error::UnsupportedEncoding(format!(
+                "Encoding {s} requires the `zstd` feature to be enabled"
+            ))),
+            "3" => {
+                let codec_id = s.split_once(':').map_or("", |(_id, codec_id)| codec_id);
+                generation_opt
+                    .find_codec(codec_id)
+                    .map(Self::Custom)
+                    .ok_or_else(|| Error::UnknownCodec(codec_id.to_string()))
+            }
+            _ => Err(Error::ParseMetadata(format!(
+                "Failed to parse encoding from {s}"
             ))),
         }
     }
 
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn parse_level<T: std::str::FromStr>(s: &str) -> Result<T> {
+        // Encodings written before levels were configurable use the default level
+        let level = s.split_once(':').map_or("0", |(_id, level)| level);
+        level
+            .parse()
+            .map_err(|_| Error::ParseMetadata(format!("Failed to parse encoding level from {s}")))
+    }
+
     #[inline]
     #[allow(clippy::unnecessary_wraps)]
-    pub(crate) fn encode(self, content: Vec<u8>) -> Result<Vec<u8>> {
+    pub(crate) fn encode(self, content: &[u8]) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Encoding::Plain => Ok(Cow::Borrowed(content)),
+            #[cfg(feature = "lz4")]
+            Encoding::Lz4 { level } => {
+                let mut encoder = lz4::EncoderBuilder::new()
+                    .level(level)
+                    .build(Vec::new())
+                    .map_err(|e| {
+                        Error::EncodingError(format!("Failed to create lz4 encoder builder: {e}"))
+                    })?;
+                std::io::Write::write_all(&mut encoder, content).map_err(|e| {
+                    Error::EncodingError(format!("Failed to lz4 encode content: {e}"))
+                })?;
+                let (buf, res) = encoder.finish();
+                res.map_err(|e| {
+                    Error::EncodingError(format!("Failed to finish lz4 encoding content: {e}"))
+                })?;
+                Ok(Cow::Owned(buf))
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd { level } => zstd::bulk::compress(content, level)
+                .map(Cow::Owned)
+                .map_err(|e| Error::EncodingError(format!("Failed to zstd encode content: {e}"))),
+            Encoding::Custom(codec) => {
+                let id = codec.id();
+                if id.is_empty() || !id.bytes().all(|b| b.is_ascii_graphic() && b != b',') {
+                    return Err(Error::EncodingError(format!(
+                        "Codec id {id:?} must be non-empty printable ascii without commas"
+                    )));
+                }
+                codec.encode(content).map(Cow::Owned).map_err(|e| {
+                    Error::EncodingError(format!("Codec {id} failed to encode content: {e}"))
+                })
+            }
+        }
+    }
+
+    #[inline]
+    #[allow(clippy::unnecessary_wraps)]
+    pub(crate) fn decode(self, content: Vec<u8>) -> Result<Vec<u8>> {
         match self {
             Encoding::Plain => Ok(content),
             #[cfg(feature = "lz4")]
-            Encoding::Lz4 => {
-                let mut buf = Vec::new();
-                let mut encoder = lz4::EncoderBuilder::new().build(&mut buf).map_err(|e| {
-                    Error::EncodingError(format!("Failed to create lz4 encoder builder: {e}"))
+            Encoding::Lz4 { .. } => {
+                let mut decoder = lz4::Decoder::new(content.as_slice()).map_err(|e| {
+                    Error::DecodingError(format!("Failed to create lz4 decoder: {e}"))
                 })?;
-                std::io::Write::write(&mut encoder, &content).map_err(|e| {
-                    Error::EncodingError(format!("Failed to lz4 encode content: {e}"))
+                let mut buf = Vec::new();
+                std::io::Read::read_to_end(&mut decoder, &mut buf).map_err(|e| {
+                    Error::DecodingError(format!("Failed to lz4 decode content: {e}"))
                 })?;
                 Ok(buf)
             }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd { .. } => zstd::decode_all(content.as_slice())
+                .map_err(|e| Error::DecodingError(format!("Failed to zstd decode content: {e}"))),
+            Encoding::Custom(codec) => codec.decode(&content).map_err(|e| {
+                Error::DecodingError(format!(
+                    "Codec {} failed to decode content: {e}",
+                    codec.id()
+                ))
+            }),
         }
     }
 }
 
+/// A user-supplied transformation of values on disk, for example domain-specific compression
+/// or encryption, used through [`Encoding::Custom`].
+/// # Example
+/// ```
+/// use dir_cache::opts::{Codec, Encoding};
+/// #[derive(Debug)]
+/// struct Reverse;
+/// impl Codec for Reverse {
+///     fn id(&self) -> &str {
+///         "reverse"
+///     }
+///     fn encode(&self, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+///         Ok(content.iter().rev().copied().collect())
+///     }
+///     fn decode(&self, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+///         Ok(content.iter().rev().copied().collect())
+///     }
+/// }
+/// static REVERSE: Reverse = Reverse;
+/// let encoding = Encoding::Custom(&REVERSE);
+/// ```
+pub trait Codec: Debug + Send + Sync {
+    /// A stable identifier for this codec, recorded in the manifest of each generation
+    /// it has encoded, and used to find the codec again when decoding.
+    /// Needs to be non-empty printable ascii without commas, and must not change once
+    /// data has been written with it.
+    fn id(&self) -> &str;
+
+    /// Transform `content` before it's written to disk
+    /// # Errors
+    /// Any error, which will be propagated wrapped
+    fn encode(&self, content: &[u8]) -> core::result::Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+    /// Reverse the transformation done by [`Codec::encode`]
+    /// # Errors
+    /// Any error, which will be propagated wrapped
+    fn decode(&self, content: &[u8]) -> core::result::Result<Vec<u8>, Box<dyn std::error::Error>>;
+}
+
+/// A user-supplied transformation of values on their way into and out of storage, such as adding
+/// and stripping an envelope, or validating a schema, see [`DirCacheOpts::with_interceptors`].
+/// Applies to values as inserted and read, values inserted from files, and read as paths,
+/// streams or mappings, are left as they are on disk.
+/// Unlike a [`Codec`], the transformation isn't recorded, so values written with one chain of
+/// interceptors need to be read with the same chain.
+/// # Example
+/// ```
+/// use dir_cache::opts::{DirCacheOpts, Interceptor};
+/// use std::path::Path;
+/// #[derive(Debug)]
+/// struct Envelope;
+/// impl Interceptor for Envelope {
+///     fn on_write(&self, _key: &Path, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+///         Ok([b"v1:".as_slice(), content].concat())
+///     }
+///     fn on_read(&self, _key: &Path, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+///         content
+///             .strip_prefix(b"v1:")
+///             .map(<[u8]>::to_vec)
+///             .ok_or_else(|| "missing envelope".into())
+///     }
+/// }
+/// static ENVELOPE: Envelope = Envelope;
+/// static INTERCEPTORS: [&dyn Interceptor; 1] = [&ENVELOPE];
+/// let opts = DirCacheOpts::default().with_interceptors(&INTERCEPTORS);
+/// ```
+pub trait Interceptor: Debug + Send + Sync {
+    /// Transform `content` before it's stored under `key`, passes it through unchanged by default
+    /// # Errors
+    /// Any error, which fails the write, propagated wrapped
+    fn on_write(
+        &self,
+        key: &Path,
+        content: &[u8],
+    ) -> core::result::Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let _ = key;
+        Ok(content.to_vec())
+    }
+
+    /// Reverse the transformation done by [`Interceptor::on_write`] on `content` read from `key`,
+    /// passes it through unchanged by default
+    /// # Errors
+    /// Any error, which fails the read, propagated wrapped
+    fn on_read(
+        &self,
+        key: &Path,
+        content: &[u8],
+    ) -> core::result::Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let _ = key;
+        Ok(content.to_vec())
+    }
+}
+
 /// Options controlling syncing, ensuring that the [`DirCache`]'s state kept in memory is committed to disk.
 /// Unnecessary if all keys are not written with [`MemPushOpt::MemoryOnly`]
 #[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum SyncOpt {
     /// Sync when dropped (syncing can still be done manually)
     SyncOnDrop,
@@ -1,4 +1,5 @@
 use crate::error::{Error, Result};
+use std::ffi::OsStr;
 use std::path::{Component, Path, PathBuf};
 
 pub(crate) trait SafePathJoin {
@@ -12,7 +13,7 @@ pub(crate) trait SafePathJoin {
     fn safe_join<P: AsRef<Path>>(&self, other: P) -> Result<PathBuf>;
 }
 
-impl<'a> SafePathJoin for &'a Path {
+impl SafePathJoin for &Path {
     #[allow(clippy::disallowed_methods)]
     fn safe_join<P: AsRef<Path>>(&self, other: P) -> Result<PathBuf> {
         let other_ref = other.as_ref();
@@ -41,6 +42,11 @@ impl<'a> SafePathJoin for &'a Path {
                     "Found key with an unexpected path component {component:?} when trying to join {self:?} and {other_ref:?}"
                 )));
             };
+            if !portable_name(os) {
+                return Err(Error::DangerousKey(format!(
+                    "Found key with a component {os:?} that's a reserved name or ends with a dot or a space on Windows when trying to join {self:?} and {other_ref:?}"
+                )));
+            }
             cumulative_len += os.len();
             num_components += 1;
         }
@@ -62,6 +68,39 @@ impl SafePathJoin for PathBuf {
     }
 }
 
+/// Whether `a` and `b` are the same name on a case-insensitive file system.
+/// Names that aren't valid utf8 are only compared ignoring ascii case
+pub(crate) fn same_ignoring_case(a: &OsStr, b: &OsStr) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => a.eq_ignore_ascii_case(b),
+    }
+}
+
+/// Device names that Windows reserves in every directory, with any extension
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM0", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether `name` is a reserved device name on Windows, such as `con` or `NUL.txt`
+pub(crate) fn is_windows_reserved(name: &[u8]) -> bool {
+    let stem = name.split(|b| *b == b'.').next().unwrap_or_default();
+    let stem = stem.trim_ascii_end();
+    WINDOWS_RESERVED
+        .iter()
+        .any(|reserved| reserved.as_bytes().eq_ignore_ascii_case(stem))
+}
+
+/// Whether `name` can be used as a file name everywhere.
+/// Besides reserved names, Windows strips trailing dots and spaces, aliasing `a.` and `a`,
+/// and leaving files that can't be deleted with regular tools
+fn portable_name(name: &OsStr) -> bool {
+    let bytes = name.as_encoded_bytes();
+    !is_windows_reserved(bytes) && !matches!(bytes.last(), Some(b'.' | b' '))
+}
+
 pub(crate) fn relativize(base: &Path, ext: &Path) -> Result<PathBuf> {
     let mut base_components = base.components();
     let mut ext_components = ext.components();
@@ -93,7 +132,40 @@ pub(crate) fn relativize(base: &Path, ext: &Path) -> Result<PathBuf> {
     }
 }
 
+/// Whether `name` matches the glob `pattern`, where `*` matches any sequence of characters,
+/// including none, and `?` matches any single character
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where the last star was, and where in the name it started matching, to backtrack to
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => {
+                let Some((star, matched_from)) = backtrack else {
+                    return false;
+                };
+                // Let the star swallow one more character
+                backtrack = Some((star, matched_from + 1));
+                p = star + 1;
+                n = matched_from + 1;
+            }
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 #[cfg(test)]
+#[allow(clippy::disallowed_methods)]
 mod tests {
     use super::*;
 
@@ -145,5 +217,47 @@ mod tests {
             .safe_join(Path::new("dir-cache-manifest.txt/."))
             .is_err());
         assert!(base.safe_join(Path::new("nullterm\0")).is_err());
+        assert!(base.safe_join(Path::new("CON")).is_err());
+        assert!(base.safe_join(Path::new("a/nul.txt")).is_err());
+        assert!(base.safe_join(Path::new("Com1 ")).is_err());
+        assert!(base.safe_join(Path::new("lpt9.tar.gz/b")).is_err());
+        assert!(base.safe_join(Path::new("trailing./b")).is_err());
+        assert!(base.safe_join(Path::new("a/trailing ")).is_err());
+    }
+
+    #[test]
+    fn compares_ignoring_case() {
+        assert!(same_ignoring_case(OsStr::new("Foo"), OsStr::new("fOO")));
+        assert!(same_ignoring_case(OsStr::new("ÅSA"), OsStr::new("åsa")));
+        assert!(!same_ignoring_case(OsStr::new("foo"), OsStr::new("fo")));
+    }
+
+    #[test]
+    fn windows_reserved_names() {
+        for reserved in [
+            "CON", "con", "Nul.txt", "AUX .md", "COM1", "lpt0", "CONOUT$",
+        ] {
+            assert!(is_windows_reserved(reserved.as_bytes()), "{reserved}");
+        }
+        for fine in ["CONSOLE", "NULL", "COM", "COM10", "a.CON", "xCON"] {
+            assert!(!is_windows_reserved(fine.as_bytes()), "{fine}");
+        }
+        let base = Path::new("base");
+        base.safe_join("console/null.txt").unwrap();
+        base.safe_join("a.con").unwrap();
+    }
+
+    #[test]
+    fn glob_matches() {
+        assert!(glob_match(".git", ".git"));
+        assert!(!glob_match(".git", ".github"));
+        assert!(glob_match(".*", ".github"));
+        assert!(glob_match("node_*", "node_modules"));
+        assert!(glob_match("*_modules", "node_modules"));
+        assert!(glob_match("n?de*s", "node_modules"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(!glob_match("?", ""));
     }
 }
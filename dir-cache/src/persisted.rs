@@ -0,0 +1,129 @@
+//! The options a cache was created with, recorded at its root, see [`PersistedOptsOpt`].
+//! A line based text format, a version header followed by one `{name}={value}` line per option,
+//! encodings are written as in version 1 manifests, durations as nanoseconds.
+use crate::disk::Storage;
+use crate::error::{Error, Result};
+use crate::opts::{DirCacheOpts, Encoding, ExpirationOpt, GenerationOpt, PersistedOptsOpt};
+use crate::path_util::SafePathJoin;
+use crate::time::duration_from_nano_string;
+use crate::OPTS_FILE;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+const OPTS_VERSION: u64 = 1;
+
+/// Records the [`GenerationOpt`] of `opts` at `base` if nothing is recorded yet, otherwise
+/// checks them against, or replaces them with, the recorded ones.
+/// Returns the options to open the cache with
+pub(crate) fn reconcile(
+    storage: &Storage,
+    opts: DirCacheOpts,
+    base: &Path,
+    persisted_opts_opt: PersistedOptsOpt,
+) -> Result<DirCacheOpts> {
+    if matches!(persisted_opts_opt, PersistedOptsOpt::Ignore) {
+        return Ok(opts);
+    }
+    let path = base.safe_join(OPTS_FILE)?;
+    let given = serialize(&opts.generation_opt);
+    let Some(recorded) = storage.read_raw_if_present(&path)? else {
+        storage
+            .write_file(&path, given.as_bytes(), opts.durability_opt)
+            .map_err(|e| {
+                Error::WriteContent(format!("Failed to write options to {path:?}"), Some(e))
+            })?;
+        return Ok(opts);
+    };
+    let recorded = std::str::from_utf8(&recorded)
+        .map_err(|_| Error::ParseMetadata(format!("Options at {path:?} aren't utf8")))?;
+    // Parsed even when only compared, so that a malformed file isn't reported as a conflict
+    let adopted = parse(recorded, &path, opts.generation_opt)?;
+    if matches!(persisted_opts_opt, PersistedOptsOpt::Adopt) {
+        return Ok(opts.with_generation_opt(adopted));
+    }
+    let recorded = serialize(&adopted);
+    let conflicts = recorded
+        .lines()
+        .zip(given.lines())
+        .filter(|(recorded, given)| recorded != given)
+        .map(|(recorded, given)| format!("recorded {recorded}, given {given}"))
+        .collect::<Vec<_>>();
+    if conflicts.is_empty() {
+        Ok(opts)
+    } else {
+        Err(Error::OptsConflict(format!(
+            "{path:?}: {}",
+            conflicts.join(", ")
+        )))
+    }
+}
+
+fn serialize(generation_opt: &GenerationOpt) -> String {
+    format!(
+        "{OPTS_VERSION}\nmax_generations={}\ncurrent_gen_encoding={}\nold_gen_encoding={}\nexpiration={}\n",
+        generation_opt.max_generations,
+        generation_opt.current_gen_encoding.serialize(),
+        generation_opt.old_gen_encoding.serialize(),
+        serialize_expiration(generation_opt.expiration),
+    )
+}
+
+fn serialize_expiration(expiration: ExpirationOpt) -> String {
+    match expiration {
+        ExpirationOpt::NoExpiry => "none".to_string(),
+        ExpirationOpt::ExpiresAfter(ttl) => format!("after:{}", ttl.as_nanos()),
+        ExpirationOpt::ExpiresAt(at) => format!(
+            "at:{}",
+            at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+        ),
+        ExpirationOpt::ExpiresAfterIdle(tti) => format!("idle:{}", tti.as_nanos()),
+    }
+}
+
+/// Parses the recorded options into `generation_opt`, which supplies everything that isn't
+/// recorded, including the codecs needed to parse custom encodings
+fn parse(content: &str, path: &Path, generation_opt: GenerationOpt) -> Result<GenerationOpt> {
+    let mut lines = content.lines();
+    let version = lines.next().unwrap_or_default();
+    if version != OPTS_VERSION.to_string() {
+        return Err(Error::ParseMetadata(format!(
+            "Options at {path:?} have version {version:?}, want {OPTS_VERSION}"
+        )));
+    }
+    let mut parsed = generation_opt;
+    for line in lines {
+        let malformed = || Error::ParseMetadata(format!("Malformed option {line:?} at {path:?}"));
+        let (name, value) = line.split_once('=').ok_or_else(malformed)?;
+        match name {
+            "max_generations" => {
+                parsed.max_generations = value.parse::<NonZeroUsize>().map_err(|_| malformed())?;
+            }
+            "current_gen_encoding" => {
+                parsed.current_gen_encoding = Encoding::deserialize(value, &generation_opt)?;
+            }
+            "old_gen_encoding" => {
+                parsed.old_gen_encoding = Encoding::deserialize(value, &generation_opt)?;
+            }
+            "expiration" => {
+                parsed.expiration = parse_expiration(value).ok_or_else(malformed)?;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_expiration(value: &str) -> Option<ExpirationOpt> {
+    if value == "none" {
+        return Some(ExpirationOpt::NoExpiry);
+    }
+    let (kind, nanos) = value.split_once(':')?;
+    let dur = duration_from_nano_string(nanos).ok()?;
+    match kind {
+        "after" => Some(ExpirationOpt::ExpiresAfter(dur)),
+        "at" => UNIX_EPOCH.checked_add(dur).map(ExpirationOpt::ExpiresAt),
+        "idle" => Some(ExpirationOpt::ExpiresAfterIdle(dur)),
+        _ => None,
+    }
+}
@@ -0,0 +1,109 @@
+//! A read-only view of a [`crate::DirCache`] at a point in time, see
+//! [`crate::DirCache::snapshot`].
+use crate::error::Result;
+use crate::meta::EntryMeta;
+use crate::opts::DirCacheOpts;
+use crate::DirCacheInner;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The keys with a valid value in a [`crate::DirCache`] when the snapshot was taken, along with
+/// their [`EntryMeta`] and, if they were asked for, their values.
+/// Owns everything it holds, so it stays the same while the cache goes on being changed.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub(crate) entries: BTreeMap<PathBuf, SnapshotEntry>,
+}
+
+impl Snapshot {
+    /// The number of keys in the snapshot
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the snapshot has no keys
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The entry of `key`, if it was in the cache
+    #[inline]
+    #[must_use]
+    pub fn get(&self, key: &Path) -> Option<&SnapshotEntry> {
+        self.entries.get(key)
+    }
+
+    /// The keys in the snapshot, in order
+    #[inline]
+    pub fn keys(&self) -> impl Iterator<Item = &Path> {
+        self.entries.keys().map(PathBuf::as_path)
+    }
+
+    /// The keys in the snapshot with their entries, in order of the keys
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &SnapshotEntry)> {
+        self.entries.iter().map(|(k, v)| (k.as_path(), v))
+    }
+}
+
+/// A key in a [`Snapshot`]
+#[derive(Debug, Clone)]
+pub struct SnapshotEntry {
+    pub(crate) meta: EntryMeta,
+    pub(crate) value: Option<Arc<[u8]>>,
+}
+
+impl SnapshotEntry {
+    /// The metadata of the entry when the snapshot was taken
+    #[inline]
+    #[must_use]
+    pub const fn meta(&self) -> &EntryMeta {
+        &self.meta
+    }
+
+    /// The value, if the snapshot was taken with values
+    #[inline]
+    #[must_use]
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+}
+
+impl DirCacheInner {
+    pub(crate) fn snapshot(&mut self, with_values: bool, opts: DirCacheOpts) -> Result<Snapshot> {
+        self.load_all(&opts)?;
+        let stored = self.store.keys().cloned().collect::<Vec<_>>();
+        let mut snapshot = Snapshot::default();
+        for key in stored {
+            if !self.has_value(&key, &opts)? {
+                continue;
+            }
+            // Before the value is read, which counts as a read
+            let meta = self.store[&key].meta();
+            let original = self.original_key(&key, &opts);
+            let value = if with_values {
+                // Values kept in memory are shared rather than copied
+                let in_mem = self.store[&key]
+                    .in_mem
+                    .as_ref()
+                    .filter(|_| opts.interceptors.is_empty())
+                    .map(|in_mem| in_mem.content.clone());
+                match in_mem {
+                    Some(content) => Some(content),
+                    None => Some(Arc::from(self.read_current(&key, opts)?.as_ref())),
+                }
+            } else {
+                None
+            };
+            snapshot
+                .entries
+                .insert(original, SnapshotEntry { meta, value });
+        }
+        Ok(snapshot)
+    }
+}
@@ -0,0 +1,385 @@
+//! Keeping a cache in a single SQLite file, see [`crate::opts::StorageOpt::Sqlite`].
+//! The database holds a file system of its own, rooted at the directory of the cache: a cache
+//! opened with it reads and writes every path below that directory through its
+//! [`crate::disk::Storage`] as rows rather than files, so the cache works on it the same as on a
+//! directory, down to hardlinks.
+//! Paths are stored relative to the directory, as utf8 with `/` between components, and each
+//! file's content is stored once however many paths link to it.
+use crate::disk::FileMeta;
+use crate::error::{Error, Result};
+use crate::opts::DurabilityOpt;
+use crate::path_util::SafePathJoin;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+pub(crate) const SQLITE_FILE: &str = "dir-cache.sqlite3";
+const SCHEMA_VERSION: u32 = 1;
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS contents (
+    id INTEGER PRIMARY KEY,
+    data BLOB NOT NULL,
+    links INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS nodes (
+    path TEXT PRIMARY KEY NOT NULL,
+    parent TEXT NOT NULL,
+    -- Null for directories
+    content INTEGER REFERENCES contents (id)
+);
+CREATE INDEX IF NOT EXISTS nodes_by_parent ON nodes (parent);
+";
+
+/// Opens the database in the directory at `base`, creating it if it's missing.
+/// Caches opened on the same directory each get a connection of their own
+pub(crate) fn open(base: &Path) -> Result<Arc<Db>> {
+    let path = base.safe_join(SQLITE_FILE)?;
+    let db = Db::open(base, &path).map_err(|e| {
+        Error::ReadContent(
+            format!("Failed to open SQLite storage at {path:?}"),
+            Some(std::io::Error::other(e)),
+        )
+    })?;
+    Ok(Arc::new(db))
+}
+
+/// A SQLite database holding the files below the directory of a cache
+pub(crate) struct Db {
+    root: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Node {
+    Dir,
+    File(i64),
+}
+
+impl Db {
+    fn open(root: &Path, path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // Waits out other processes writing to it, the default rollback journal is kept since
+        // write-ahead logging doesn't work on network file systems
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version == 0 {
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        } else if version != SCHEMA_VERSION {
+            return Err(rusqlite::Error::InvalidParameterName(format!(
+                "unknown schema version {version}"
+            )));
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub(crate) fn metadata(&self, path: &Path) -> std::io::Result<FileMeta> {
+        let relative = self.relative(path)?;
+        let conn = self.lock();
+        match node(&conn, &relative)? {
+            None => Err(not_found(path)),
+            Some(Node::Dir) => Ok(FileMeta::dir()),
+            Some(Node::File(id)) => conn
+                .query_row(
+                    "SELECT length(data), links FROM contents WHERE id = ?1",
+                    [id],
+                    |row| Ok(FileMeta::file(row.get(0)?, row.get(1)?)),
+                )
+                .map_err(std::io::Error::other),
+        }
+    }
+
+    /// The names of everything in the directory at `path`, and what they are
+    pub(crate) fn read_dir(&self, path: &Path) -> std::io::Result<Vec<(String, FileMeta)>> {
+        let relative = self.relative(path)?;
+        let conn = self.lock();
+        require_dir(&conn, &relative, path)?;
+        let mut statement = conn
+            .prepare_cached(
+                "SELECT nodes.path, nodes.content IS NULL, length(contents.data), contents.links \
+                 FROM nodes LEFT JOIN contents ON contents.id = nodes.content \
+                 WHERE nodes.parent = ?1",
+            )
+            .map_err(std::io::Error::other)?;
+        let entries = statement
+            .query_map([&relative], |row| {
+                let child: String = row.get(0)?;
+                let name = child
+                    .rsplit_once('/')
+                    .map_or(child.as_str(), |(_, name)| name)
+                    .to_string();
+                let md = if row.get(1)? {
+                    FileMeta::dir()
+                } else {
+                    FileMeta::file(row.get(2)?, row.get(3)?)
+                };
+                Ok((name, md))
+            })
+            .and_then(Iterator::collect)
+            .map_err(std::io::Error::other)?;
+        Ok(entries)
+    }
+
+    pub(crate) fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        let relative = self.relative(path)?;
+        let conn = self.lock();
+        let id = require_file(&conn, &relative, path)?;
+        conn.query_row("SELECT data FROM contents WHERE id = ?1", [id], |row| {
+            row.get(0)
+        })
+        .map_err(std::io::Error::other)
+    }
+
+    /// Writes `content` to the file at `path`, replacing any file there, the same as
+    /// [`crate::disk::Storage::write_file`] a file linked elsewhere keeps its content there
+    pub(crate) fn write(
+        &self,
+        path: &Path,
+        content: &[u8],
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        let relative = self.relative(path)?;
+        let mut conn = self.lock();
+        set_durability(&conn, durability)?;
+        let tx = conn.transaction().map_err(std::io::Error::other)?;
+        tx.execute(
+            "INSERT INTO contents (data, links) VALUES (?1, 1)",
+            params![content],
+        )
+        .map_err(std::io::Error::other)?;
+        place(&tx, &relative, tx.last_insert_rowid(), path)?;
+        tx.commit().map_err(std::io::Error::other)
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        let relative = self.relative(path)?;
+        let mut conn = self.lock();
+        let tx = conn.transaction().map_err(std::io::Error::other)?;
+        let id = require_file(&tx, &relative, path)?;
+        unlink(&tx, &relative, id)?;
+        tx.commit().map_err(std::io::Error::other)
+    }
+
+    /// Creates the directory at `path` along with any missing ancestors
+    pub(crate) fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        let relative = self.relative(path)?;
+        let mut conn = self.lock();
+        let tx = conn.transaction().map_err(std::io::Error::other)?;
+        let mut dir = String::new();
+        for name in relative.split('/').filter(|name| !name.is_empty()) {
+            let parent = dir.clone();
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir.push_str(name);
+            match node(&tx, &dir)? {
+                Some(Node::Dir) => {}
+                Some(Node::File(_)) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::NotADirectory,
+                        format!("{path:?} has a file where a directory should be"),
+                    ));
+                }
+                None => {
+                    tx.execute(
+                        "INSERT INTO nodes (path, parent, content) VALUES (?1, ?2, NULL)",
+                        params![dir, parent],
+                    )
+                    .map_err(std::io::Error::other)?;
+                }
+            }
+        }
+        tx.commit().map_err(std::io::Error::other)
+    }
+
+    /// Removes the empty directory at `path`
+    pub(crate) fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        let relative = self.relative(path)?;
+        // The root is the directory of the cache itself, which holds the database
+        if relative.is_empty() {
+            return Ok(());
+        }
+        let conn = self.lock();
+        require_dir(&conn, &relative, path)?;
+        let has_children = conn
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM nodes WHERE parent = ?1)",
+                [&relative],
+                |row| row.get::<_, bool>(0),
+            )
+            .map_err(std::io::Error::other)?;
+        if has_children {
+            return Err(std::io::Error::new(
+                ErrorKind::DirectoryNotEmpty,
+                format!("{path:?} isn't empty"),
+            ));
+        }
+        conn.execute("DELETE FROM nodes WHERE path = ?1", [&relative])
+            .map_err(std::io::Error::other)?;
+        Ok(())
+    }
+
+    /// Moves the file at `from` to `to`, replacing any file there
+    pub(crate) fn rename(
+        &self,
+        from: &Path,
+        to: &Path,
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        let relative_from = self.relative(from)?;
+        let relative_to = self.relative(to)?;
+        let mut conn = self.lock();
+        set_durability(&conn, durability)?;
+        let tx = conn.transaction().map_err(std::io::Error::other)?;
+        let id = require_file(&tx, &relative_from, from)?;
+        if relative_from == relative_to {
+            return Ok(());
+        }
+        tx.execute("DELETE FROM nodes WHERE path = ?1", [&relative_from])
+            .map_err(std::io::Error::other)?;
+        place(&tx, &relative_to, id, to)?;
+        tx.commit().map_err(std::io::Error::other)
+    }
+
+    /// Links the file at `from` to `to` as well, replacing any file there
+    pub(crate) fn link(
+        &self,
+        from: &Path,
+        to: &Path,
+        durability: DurabilityOpt,
+    ) -> std::io::Result<()> {
+        let relative_from = self.relative(from)?;
+        let relative_to = self.relative(to)?;
+        let mut conn = self.lock();
+        set_durability(&conn, durability)?;
+        let tx = conn.transaction().map_err(std::io::Error::other)?;
+        let id = require_file(&tx, &relative_from, from)?;
+        // Counted before placing it, in case it replaces another link to the same content
+        tx.execute("UPDATE contents SET links = links + 1 WHERE id = ?1", [id])
+            .map_err(std::io::Error::other)?;
+        place(&tx, &relative_to, id, to)?;
+        tx.commit().map_err(std::io::Error::other)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        // Transactions roll back when they're dropped, the connection is fine even if a thread
+        // panicked while holding it
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `path` as it's stored, relative to the root
+    fn relative(&self, path: &Path) -> std::io::Result<String> {
+        let relative = path.strip_prefix(&self.root).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{path:?} isn't in SQLite storage at {:?}", self.root),
+            )
+        })?;
+        let mut names = Vec::new();
+        for component in relative.components() {
+            let Component::Normal(name) = component else {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{path:?} has an unexpected component {component:?}"),
+                ));
+            };
+            names.push(name.to_str().ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{path:?} isn't utf8, which SQLite storage requires"),
+                )
+            })?);
+        }
+        Ok(names.join("/"))
+    }
+}
+
+fn node(conn: &Connection, relative: &str) -> std::io::Result<Option<Node>> {
+    if relative.is_empty() {
+        return Ok(Some(Node::Dir));
+    }
+    let content = conn
+        .query_row(
+            "SELECT content FROM nodes WHERE path = ?1",
+            [relative],
+            |row| row.get::<_, Option<i64>>(0),
+        )
+        .optional()
+        .map_err(std::io::Error::other)?;
+    Ok(content.map(|content| content.map_or(Node::Dir, Node::File)))
+}
+
+fn require_dir(conn: &Connection, relative: &str, path: &Path) -> std::io::Result<()> {
+    match node(conn, relative)? {
+        Some(Node::Dir) => Ok(()),
+        Some(Node::File(_)) => Err(std::io::Error::new(
+            ErrorKind::NotADirectory,
+            format!("{path:?} is a file"),
+        )),
+        None => Err(not_found(path)),
+    }
+}
+
+fn require_file(conn: &Connection, relative: &str, path: &Path) -> std::io::Result<i64> {
+    match node(conn, relative)? {
+        Some(Node::File(id)) => Ok(id),
+        Some(Node::Dir) => Err(std::io::Error::new(
+            ErrorKind::IsADirectory,
+            format!("{path:?} is a directory"),
+        )),
+        None => Err(not_found(path)),
+    }
+}
+
+/// Puts the content `id` at `relative`, replacing any file there, its directory has to exist
+fn place(conn: &Connection, relative: &str, id: i64, path: &Path) -> std::io::Result<()> {
+    let parent = relative.rsplit_once('/').map_or("", |(parent, _)| parent);
+    require_dir(conn, parent, path.parent().unwrap_or(path))?;
+    match node(conn, relative)? {
+        Some(Node::Dir) => {
+            return Err(std::io::Error::new(
+                ErrorKind::IsADirectory,
+                format!("{path:?} is a directory"),
+            ));
+        }
+        Some(Node::File(replaced)) => unlink(conn, relative, replaced)?,
+        None => {}
+    }
+    conn.execute(
+        "INSERT INTO nodes (path, parent, content) VALUES (?1, ?2, ?3)",
+        params![relative, parent, id],
+    )
+    .map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+/// Removes the file at `relative`, and its content if nothing else links to it
+fn unlink(conn: &Connection, relative: &str, id: i64) -> std::io::Result<()> {
+    conn.execute("DELETE FROM nodes WHERE path = ?1", [relative])
+        .map_err(std::io::Error::other)?;
+    conn.execute("UPDATE contents SET links = links - 1 WHERE id = ?1", [id])
+        .map_err(std::io::Error::other)?;
+    conn.execute("DELETE FROM contents WHERE id = ?1 AND links <= 0", [id])
+        .map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+/// Syncs the following writes as `durability` requires
+fn set_durability(conn: &Connection, durability: DurabilityOpt) -> std::io::Result<()> {
+    let synchronous = match durability {
+        DurabilityOpt::None => "OFF",
+        DurabilityOpt::FlushData => "NORMAL",
+        DurabilityOpt::Full => "FULL",
+    };
+    conn.pragma_update(None, "synchronous", synchronous)
+        .map_err(std::io::Error::other)
+}
+
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(ErrorKind::NotFound, format!("{path:?} doesn't exist"))
+}
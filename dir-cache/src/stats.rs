@@ -0,0 +1,141 @@
+//! Counters of how a [`crate::DirCache`] is used, see [`crate::DirCache::stats`].
+//! With the `metrics` feature they're also emitted through the `metrics` facade, as
+//! `dir_cache_hits_total`, `dir_cache_misses_total`, `dir_cache_inserts_total`,
+//! `dir_cache_evictions_total`, `dir_cache_disk_reads_total`, `dir_cache_disk_writes_total`,
+//! `dir_cache_bytes_read_total` and `dir_cache_bytes_written_total`, along with the gauge
+//! `dir_cache_entries` and the histogram `dir_cache_sync_seconds` recorded on each sync.
+use std::path::Path;
+use std::time::Duration;
+
+/// Counts of lookups, inserts and disk traffic since the [`crate::DirCache`] was opened, or the
+/// stats were last reset with [`crate::DirCache::reset_stats`].
+/// Kept in memory only.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct DirCacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) inserts: u64,
+    pub(crate) evictions: u64,
+    pub(crate) disk_reads: u64,
+    pub(crate) disk_writes: u64,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+}
+
+impl DirCacheStats {
+    /// Lookups of a key that found a valid value, or a cached miss
+    #[inline]
+    #[must_use]
+    pub const fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Lookups of a key that found nothing valid
+    #[inline]
+    #[must_use]
+    pub const fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Values inserted, including those produced on a miss
+    #[inline]
+    #[must_use]
+    pub const fn inserts(&self) -> u64 {
+        self.inserts
+    }
+
+    /// Entries the cache removed itself, because they expired or to stay within its budget
+    #[inline]
+    #[must_use]
+    pub const fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Values read from disk, rather than served from memory
+    #[inline]
+    #[must_use]
+    pub const fn disk_reads(&self) -> u64 {
+        self.disk_reads
+    }
+
+    /// Values written to disk
+    #[inline]
+    #[must_use]
+    pub const fn disk_writes(&self) -> u64 {
+        self.disk_writes
+    }
+
+    /// Bytes of values read from disk, as stored.
+    /// Streamed values are counted in full when they're opened
+    #[inline]
+    #[must_use]
+    pub const fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Bytes of values written to disk, as stored.
+    /// Values hardlinked to an existing file aren't counted
+    #[inline]
+    #[must_use]
+    pub const fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub(crate) fn hit(&mut self, key: &Path) {
+        debug!("dir-cache hit for {key:?}");
+        self.hits += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("dir_cache_hits_total").increment(1);
+    }
+
+    pub(crate) fn miss(&mut self, key: &Path) {
+        debug!("dir-cache miss for {key:?}");
+        self.misses += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("dir_cache_misses_total").increment(1);
+    }
+
+    pub(crate) fn insert(&mut self) {
+        self.inserts += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("dir_cache_inserts_total").increment(1);
+    }
+
+    pub(crate) fn eviction(&mut self) {
+        self.evictions += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!("dir_cache_evictions_total").increment(1);
+    }
+
+    pub(crate) fn disk_read(&mut self, bytes: u64) {
+        self.disk_reads += 1;
+        self.bytes_read += bytes;
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("dir_cache_disk_reads_total").increment(1);
+            metrics::counter!("dir_cache_bytes_read_total").increment(bytes);
+        }
+    }
+
+    pub(crate) fn disk_write(&mut self, bytes: u64) {
+        self.disk_writes += 1;
+        self.bytes_written += bytes;
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("dir_cache_disk_writes_total").increment(1);
+            metrics::counter!("dir_cache_bytes_written_total").increment(bytes);
+        }
+    }
+}
+
+/// Reports a sync that took `took`, leaving the cache with `entries` entries, only emitted
+/// with the `metrics` feature
+pub(crate) fn report_sync(took: Duration, entries: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::histogram!("dir_cache_sync_seconds").record(took);
+        metrics::gauge!("dir_cache_entries").set(u32::try_from(entries).unwrap_or(u32::MAX));
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (took, entries);
+}
@@ -0,0 +1,209 @@
+//! Streaming values to and from disk without holding them in memory
+use crate::disk::{FileReader, FileWriter, Storage};
+use crate::error::{Error, Result};
+use crate::opts::{DirCacheOpts, Encoding, IngestOpt, OversizeOpt};
+use crate::path_util::SafePathJoin;
+use crate::DirCacheInner;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Named like a generation so that one left behind by a crash is cleaned up like any stray generation
+const INCOMING_FILE: &str = "dir-cache-generation-incoming";
+
+/// Streams a new value for a key to disk, see [`crate::DirCache::entry_writer`].
+/// Nothing changes in the cache until [`EntryWriter::finish`] is called, a writer dropped
+/// before then discards what was written.
+/// Writing past [`DirCacheOpts::max_value_size`] discards the value as well, failing the write
+/// or silently swallowing the rest depending on [`OversizeOpt`].
+pub struct EntryWriter<'a> {
+    inner: &'a mut DirCacheInner,
+    key: PathBuf,
+    path: PathBuf,
+    file: Option<BufWriter<FileWriter>>,
+    written: u64,
+    opts: DirCacheOpts,
+}
+
+impl<'a> EntryWriter<'a> {
+    pub(crate) fn new(
+        inner: &'a mut DirCacheInner,
+        key: &Path,
+        opts: DirCacheOpts,
+    ) -> Result<Self> {
+        inner.check_not_reserved(key)?;
+        inner.check_case_collision(key, &opts)?;
+        let dir = inner.base.safe_join(key)?;
+        inner.storage.ensure_dir(&dir)?;
+        let path = dir.safe_join(INCOMING_FILE)?;
+        let file = inner.storage.create_file(&path).map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to create file to write to at {path:?}"),
+                Some(e),
+            )
+        })?;
+        Ok(Self {
+            inner,
+            key: key.to_path_buf(),
+            path,
+            file: Some(BufWriter::new(file)),
+            written: 0,
+            opts,
+        })
+    }
+
+    /// Writes everything written so far as the new current generation of the key,
+    /// aging the previous ones, same as [`crate::DirCache::insert`].
+    /// The value is written straight to disk regardless of [`crate::opts::MemPushOpt`].
+    /// If the value has to be encoded, stored as a delta base, or content-addressed it's read
+    /// into memory, see [`crate::DirCache::insert_file`].
+    /// # Errors
+    /// Various io-errors relating to writing to disk, same as [`crate::DirCache::insert`].
+    /// Fails with [`Error::ValueTooLarge`] if more than [`DirCacheOpts::max_value_size`] was
+    /// written and [`OversizeOpt::Fail`] is used
+    pub fn finish(mut self) -> Result<()> {
+        let Some(file) = self.file.take() else {
+            // Discarded for being too large
+            return match self.opts.oversize_opt {
+                OversizeOpt::Fail => Err(Error::ValueTooLarge(self.oversize_message())),
+                OversizeOpt::Skip => self.inner.remove(&self.key, &self.opts).map(|_| ()),
+            };
+        };
+        let file = file.into_inner().map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to flush written value to {:?}", self.path),
+                Some(e.into_error()),
+            )
+        })?;
+        file.finish(self.opts.durability_opt).map_err(|e| {
+            Error::WriteContent(
+                format!("Failed to sync written value at {:?}", self.path),
+                Some(e),
+            )
+        })?;
+        // Written in the cache's own storage, to be moved into place there
+        let storage = self.inner.storage.clone();
+        let res =
+            self.inner
+                .insert_file_opt(&self.key, &self.path, &storage, IngestOpt::Move, self.opts);
+        // Already moved into place unless inserting failed
+        self.inner.storage.ensure_removed_file(&self.path)?;
+        res
+    }
+
+    fn oversize_message(&self) -> String {
+        format!(
+            "value written for key {:?} exceeds the max of {} bytes",
+            self.key,
+            self.opts.max_value_size.unwrap_or_default()
+        )
+    }
+
+    /// Drops the file written to, and removes it along with any directories left empty
+    fn discard(&mut self) {
+        if self.file.take().is_some() {
+            let _ = self.inner.storage.ensure_removed_file(&self.path);
+            if let Some(dir) = self.path.parent() {
+                let _ = self
+                    .inner
+                    .storage
+                    .remove_empty_ancestors(&self.inner.base, dir);
+            }
+        }
+    }
+}
+
+impl Write for EntryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.written.saturating_add(buf.len() as u64);
+        if self.opts.max_value_size.is_some_and(|max| written > max) {
+            self.discard();
+        }
+        let Some(file) = self.file.as_mut() else {
+            return match self.opts.oversize_opt {
+                OversizeOpt::Fail => Err(std::io::Error::other(self.oversize_message())),
+                OversizeOpt::Skip => Ok(buf.len()),
+            };
+        };
+        let n = file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for EntryWriter<'_> {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+/// Streams the value of a key, see [`crate::DirCache::entry_reader`]
+pub struct EntryReader<'a> {
+    inner: ReaderInner<'a>,
+}
+
+enum ReaderInner<'a> {
+    Memory(&'a [u8]),
+    Decoded(Cursor<Vec<u8>>),
+    File(FileReader),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4::Decoder<FileReader>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::read::Decoder<'static, std::io::BufReader<FileReader>>),
+}
+
+impl<'a> EntryReader<'a> {
+    pub(crate) fn from_memory(content: &'a [u8]) -> Self {
+        Self {
+            inner: ReaderInner::Memory(content),
+        }
+    }
+
+    pub(crate) fn open(storage: &Storage, path: &Path, encoding: Encoding) -> Result<Self> {
+        let mut file = storage.open_file(path).map_err(|e| {
+            Error::ReadContent(format!("Failed to open value to read at {path:?}"), Some(e))
+        })?;
+        let inner =
+            match encoding {
+                Encoding::Plain => ReaderInner::File(file),
+                #[cfg(feature = "lz4")]
+                Encoding::Lz4 { .. } => ReaderInner::Lz4(lz4::Decoder::new(file).map_err(|e| {
+                    Error::DecodingError(format!("Failed to create lz4 decoder: {e}"))
+                })?),
+                #[cfg(feature = "zstd")]
+                Encoding::Zstd { .. } => {
+                    ReaderInner::Zstd(zstd::stream::read::Decoder::new(file).map_err(|e| {
+                        Error::DecodingError(format!("Failed to create zstd decoder: {e}"))
+                    })?)
+                }
+                Encoding::Custom(_) => {
+                    let mut content = Vec::new();
+                    file.read_to_end(&mut content).map_err(|e| {
+                        Error::ReadContent(format!("Failed to read value at {path:?}"), Some(e))
+                    })?;
+                    ReaderInner::Decoded(Cursor::new(encoding.decode(content)?))
+                }
+            };
+        Ok(Self { inner })
+    }
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.inner {
+            ReaderInner::Memory(content) => content.read(buf),
+            ReaderInner::Decoded(content) => content.read(buf),
+            ReaderInner::File(file) => file.read(buf),
+            #[cfg(feature = "lz4")]
+            ReaderInner::Lz4(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            ReaderInner::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
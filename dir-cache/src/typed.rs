@@ -0,0 +1,292 @@
+//! A typed front for a [`DirCache`], storing values serialized with a [`ValueSerializer`]
+use crate::error::{Error, Result};
+use crate::opts::DirCacheOpts;
+use crate::{DirCache, ValueMeta};
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// Turns values of a [`TypedDirCache`] into the bytes stored in the cache and back.
+/// Implement it to store values in a format not covered by [`Format`], such as protobuf.
+pub trait ValueSerializer<V> {
+    /// Identifies the format, it's recorded with each value so that reading a value in
+    /// another format fails with [`Error::FormatMismatch`] rather than producing garbage.
+    /// Should never change for a format.
+    const ID: &'static str;
+
+    /// Serialize `value` to the bytes stored in the cache.
+    /// # Errors
+    /// Should fail with [`Error::SerializeValue`] if `value` can't be serialized
+    fn serialize(&self, value: &V) -> Result<Vec<u8>>;
+
+    /// Deserialize a value from the bytes stored in the cache.
+    /// # Errors
+    /// Should fail with [`Error::DeserializeValue`] if `content` isn't a valid `V`
+    fn deserialize(&self, content: &[u8]) -> Result<V>;
+}
+
+/// A `serde` format, which can serialize any value implementing `serde`'s traits.
+/// Every [`Format`] is a [`ValueSerializer`] for such values.
+#[cfg(feature = "serde")]
+pub trait Format {
+    /// Same as [`ValueSerializer::ID`]
+    const ID: &'static str;
+
+    /// Serialize `value` to the bytes stored in the cache.
+    /// # Errors
+    /// Should fail with [`Error::SerializeValue`] if `value` can't be serialized
+    fn serialize<V: serde::Serialize>(&self, value: &V) -> Result<Vec<u8>>;
+
+    /// Deserialize a value from the bytes stored in the cache.
+    /// # Errors
+    /// Should fail with [`Error::DeserializeValue`] if `content` isn't a valid `V`
+    fn deserialize<V: serde::de::DeserializeOwned>(&self, content: &[u8]) -> Result<V>;
+}
+
+#[cfg(feature = "serde")]
+impl<V, F> ValueSerializer<V> for F
+where
+    V: serde::Serialize + serde::de::DeserializeOwned,
+    F: Format,
+{
+    const ID: &'static str = F::ID;
+
+    #[inline]
+    fn serialize(&self, value: &V) -> Result<Vec<u8>> {
+        Format::serialize(self, value)
+    }
+
+    #[inline]
+    fn deserialize(&self, content: &[u8]) -> Result<V> {
+        Format::deserialize(self, content)
+    }
+}
+
+/// The id of a format as recorded in the manifest
+pub(crate) fn format_id(id: &str) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(id.as_bytes())
+}
+
+/// Stores values as JSON, through `serde_json`
+#[cfg(feature = "json")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Format for Json {
+    const ID: &'static str = "json";
+
+    fn serialize<V: serde::Serialize>(&self, value: &V) -> Result<Vec<u8>> {
+        serde_json::to_vec(value)
+            .map_err(|e| Error::SerializeValue(format!("Failed to write JSON: {e}")))
+    }
+
+    fn deserialize<V: serde::de::DeserializeOwned>(&self, content: &[u8]) -> Result<V> {
+        serde_json::from_slice(content)
+            .map_err(|e| Error::DeserializeValue(format!("Failed to parse JSON: {e}")))
+    }
+}
+
+/// Stores values as bincode, with its standard configuration
+#[cfg(feature = "bincode")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    const ID: &'static str = "bincode";
+
+    fn serialize<V: serde::Serialize>(&self, value: &V) -> Result<Vec<u8>> {
+        bincode::serde::encode_to_vec(value, bincode::config::standard())
+            .map_err(|e| Error::SerializeValue(format!("Failed to write bincode: {e}")))
+    }
+
+    fn deserialize<V: serde::de::DeserializeOwned>(&self, content: &[u8]) -> Result<V> {
+        bincode::serde::decode_from_slice(content, bincode::config::standard())
+            .map(|(value, _read)| value)
+            .map_err(|e| Error::DeserializeValue(format!("Failed to parse bincode: {e}")))
+    }
+}
+
+/// Stores values as `MessagePack`, through `rmp-serde`, with struct fields named so that
+/// fields can be added to stored types
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Format for MsgPack {
+    const ID: &'static str = "msgpack";
+
+    fn serialize<V: serde::Serialize>(&self, value: &V) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value)
+            .map_err(|e| Error::SerializeValue(format!("Failed to write MessagePack: {e}")))
+    }
+
+    fn deserialize<V: serde::de::DeserializeOwned>(&self, content: &[u8]) -> Result<V> {
+        rmp_serde::from_slice(content)
+            .map_err(|e| Error::DeserializeValue(format!("Failed to parse MessagePack: {e}")))
+    }
+}
+
+/// A [`DirCache`] holding values of type `V`, serialized with `S`.
+/// Storage, generations and expiry are all handled by the inner [`DirCache`],
+/// which can still be reached for anything not exposed here.
+/// The format is recorded with each value, reading a value written in another format fails
+/// with [`Error::FormatMismatch`]. Values inserted without a format, through the
+/// [`DirCache`] itself, are assumed to be in the right one.
+/// # Example
+/// ```
+/// use std::convert::Infallible;
+/// use std::path::Path;
+/// use dir_cache::opts::{CacheOpenOptions, DirCacheOpts, DirOpenOpt};
+/// use dir_cache::typed::TypedDirCache;
+/// # #[cfg(feature = "json")]
+/// fn use_cache() {
+///     use dir_cache::typed::Json;
+///     let temp = tempfile::TempDir::with_prefix("dir-cache-typed-doc-test").unwrap();
+///     let dir_cache = DirCacheOpts::default()
+///         .open(temp.path(), CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)).unwrap();
+///     let mut typed = TypedDirCache::<Vec<u32>, _>::new(dir_cache, Json);
+///     let key = Path::new("numbers");
+///     let value = typed.get_or_insert(key, || Ok::<_, Infallible>(vec![1, 2, 3])).unwrap();
+///     assert_eq!(vec![1, 2, 3], value);
+///     assert_eq!(Some(vec![1, 2, 3]), typed.get(key).unwrap());
+/// }
+/// ```
+pub struct TypedDirCache<V, S> {
+    inner: DirCache,
+    serializer: S,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<V, S> TypedDirCache<V, S>
+where
+    S: ValueSerializer<V>,
+{
+    /// Wrap `inner`, storing values in it serialized with `serializer`
+    #[inline]
+    pub fn new(inner: DirCache, serializer: S) -> Self {
+        Self {
+            inner,
+            serializer,
+            _value: PhantomData,
+        }
+    }
+
+    /// Get the wrapped [`DirCache`]
+    #[inline]
+    #[must_use]
+    pub fn inner(&self) -> &DirCache {
+        &self.inner
+    }
+
+    /// Get the wrapped [`DirCache`] mutably, for operations not exposed on the
+    /// [`TypedDirCache`]
+    #[inline]
+    pub fn inner_mut(&mut self) -> &mut DirCache {
+        &mut self.inner
+    }
+
+    /// Unwrap the [`DirCache`]
+    #[inline]
+    #[must_use]
+    pub fn into_inner(self) -> DirCache {
+        self.inner
+    }
+
+    /// Get the value of a key, see [`DirCache::get`].
+    /// # Errors
+    /// Same as [`DirCache::get`], and fails if the stored value can't be deserialized, or was
+    /// written in another format
+    #[inline]
+    pub fn get(&mut self, key: &Path) -> Result<Option<V>> {
+        let opts = self.inner.opts;
+        self.get_opt(key, opts)
+    }
+
+    /// Same as [`TypedDirCache::get`] but with opts other than what the inner [`DirCache`] was
+    /// instantiated with.
+    /// # Errors
+    /// Same as [`TypedDirCache::get`]
+    pub fn get_opt(&mut self, key: &Path, opts: DirCacheOpts) -> Result<Option<V>> {
+        let key = self.inner.inner.stored_key(opts.encode_key(key), &opts)?;
+        let serializer = &self.serializer;
+        self.inner
+            .inner
+            .get_formatted_opt(&key, format_id(S::ID), opts)?
+            .map(|val| serializer.deserialize(&val))
+            .transpose()
+    }
+
+    /// Get the value of a key if it's present, otherwise produce it with `insert_with` and
+    /// insert it, see [`DirCache::get_or_insert`].
+    /// # Errors
+    /// Same as [`DirCache::get_or_insert`], and fails if a value can't be (de)serialized
+    #[inline]
+    pub fn get_or_insert<
+        E: Into<Box<dyn std::error::Error>>,
+        G: FnOnce() -> core::result::Result<V, E>,
+    >(
+        &mut self,
+        key: &Path,
+        insert_with: G,
+    ) -> Result<V> {
+        let opts = self.inner.opts;
+        self.get_or_insert_opt(key, insert_with, opts)
+    }
+
+    /// Same as [`TypedDirCache::get_or_insert`] but with opts other than what the inner
+    /// [`DirCache`] was instantiated with.
+    /// # Errors
+    /// Same as [`TypedDirCache::get_or_insert`]
+    pub fn get_or_insert_opt<
+        E: Into<Box<dyn std::error::Error>>,
+        G: FnOnce() -> core::result::Result<V, E>,
+    >(
+        &mut self,
+        key: &Path,
+        insert_with: G,
+        opts: DirCacheOpts,
+    ) -> Result<V> {
+        if let Some(val) = self.get_opt(key, opts)? {
+            return Ok(val);
+        }
+        let val = insert_with().map_err(|e| Error::InsertWithErr(e.into()))?;
+        self.insert_opt(key, &val, opts)?;
+        Ok(val)
+    }
+
+    /// Serialize `value` and insert it for `key`, see [`DirCache::insert`].
+    /// # Errors
+    /// Same as [`DirCache::insert`], and fails if `value` can't be serialized
+    #[inline]
+    pub fn insert(&mut self, key: &Path, value: &V) -> Result<()> {
+        let opts = self.inner.opts;
+        self.insert_opt(key, value, opts)
+    }
+
+    /// Same as [`TypedDirCache::insert`] but with opts other than what the inner [`DirCache`]
+    /// was instantiated with.
+    /// # Errors
+    /// Same as [`TypedDirCache::insert`]
+    pub fn insert_opt(&mut self, key: &Path, value: &V, opts: DirCacheOpts) -> Result<()> {
+        let key = self.inner.inner.stored_key(opts.encode_key(key), &opts)?;
+        let content = self.serializer.serialize(value)?;
+        let meta = ValueMeta {
+            format: Some(format_id(S::ID)),
+            ..ValueMeta::default()
+        };
+        self.inner
+            .inner
+            .insert_opt(&key, Cow::Owned(content), meta, opts)
+    }
+
+    /// Removes a key, see [`DirCache::remove`].
+    /// # Errors
+    /// Same as [`DirCache::remove`]
+    #[inline]
+    pub fn remove(&mut self, key: &Path) -> Result<bool> {
+        self.inner.remove(key)
+    }
+}
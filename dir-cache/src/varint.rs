@@ -0,0 +1,24 @@
+//! LEB128 style variable length integers, 7 bits per byte with the high bit set on all but the last
+
+pub(crate) fn write(out: &mut Vec<u8>, mut val: u64) {
+    while val >= 0x80 {
+        out.push(val.to_le_bytes()[0] | 0x80);
+        val >>= 7;
+    }
+    out.push(val.to_le_bytes()[0]);
+}
+
+/// Read a varint from the start of `cursor`, advancing it past the varint.
+/// `None` if the varint is truncated or doesn't fit in a `u64`
+pub(crate) fn read(cursor: &mut &[u8]) -> Option<u64> {
+    let mut val = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = cursor.split_first()?;
+        *cursor = rest;
+        val |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(val);
+        }
+    }
+    None
+}
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+
+/// The result of checking the on-disk state of a [`crate::DirCache`] for consistency,
+/// see [`crate::DirCache::verify`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    pub(crate) entries_checked: usize,
+    pub(crate) issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no inconsistencies were found
+    #[inline]
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The number of entries (directories with a manifest) that were checked
+    #[inline]
+    #[must_use]
+    pub const fn entries_checked(&self) -> usize {
+        self.entries_checked
+    }
+
+    /// All inconsistencies found
+    #[inline]
+    #[must_use]
+    pub fn issues(&self) -> &[VerifyIssue] {
+        &self.issues
+    }
+}
+
+/// An inconsistency found in the on-disk state of a [`crate::DirCache`].
+/// Keys are relative to the cache's base directory.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum VerifyIssue {
+    /// The manifest lists a generation which has no file on disk
+    MissingGeneration { key: PathBuf, generation: usize },
+    /// A generation file exists that isn't listed in any manifest
+    UnreferencedGeneration { key: PathBuf, file: PathBuf },
+    /// The manifest couldn't be parsed, its generations weren't checked
+    UnparsableManifest { key: PathBuf, reason: String },
+    /// The content of a generation doesn't match the checksum recorded in the manifest
+    ChecksumMismatch { key: PathBuf, generation: usize },
+    /// The file of a generation isn't the size recorded in the manifest, for example because
+    /// it was truncated
+    SizeMismatch {
+        key: PathBuf,
+        generation: usize,
+        recorded: u64,
+        actual: u64,
+    },
+}
+
+/// An entry that couldn't be read when a [`crate::DirCache`] was opened,
+/// see [`crate::opts::InvalidEntryOpt`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InvalidEntry {
+    pub(crate) key: PathBuf,
+    pub(crate) reason: String,
+}
+
+impl InvalidEntry {
+    /// The key of the entry, relative to the cache's base directory
+    #[inline]
+    #[must_use]
+    pub fn key(&self) -> &Path {
+        &self.key
+    }
+
+    /// Why the entry couldn't be read
+    #[inline]
+    #[must_use]
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+/// The result of re-encoding the generations of a [`crate::DirCache`],
+/// see [`crate::DirCache::migrate_encoding`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MigrationReport {
+    pub(crate) generations_migrated: usize,
+    pub(crate) failures: Vec<MigrationFailure>,
+}
+
+impl MigrationReport {
+    /// Whether every key was migrated
+    #[inline]
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The number of generations that were re-encoded
+    #[inline]
+    #[must_use]
+    pub const fn generations_migrated(&self) -> usize {
+        self.generations_migrated
+    }
+
+    /// Keys that couldn't be fully migrated
+    #[inline]
+    #[must_use]
+    pub fn failures(&self) -> &[MigrationFailure] {
+        &self.failures
+    }
+}
+
+/// A key whose generations couldn't all be re-encoded, generations re-encoded before the failure
+/// are kept, the rest are left as they were.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MigrationFailure {
+    pub(crate) key: PathBuf,
+    pub(crate) reason: String,
+}
+
+impl MigrationFailure {
+    /// The key of the entry, relative to the cache's base directory
+    #[inline]
+    #[must_use]
+    pub fn key(&self) -> &Path {
+        &self.key
+    }
+
+    /// Why the entry couldn't be migrated
+    #[inline]
+    #[must_use]
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
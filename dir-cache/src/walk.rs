@@ -0,0 +1,105 @@
+//! Walking the directory tree of a cache, breadth first
+use crate::disk::{FileMeta, Storage};
+use crate::error::{Error, Result};
+use crate::opts::{CacheOpenOptions, SymlinkOpt};
+use std::collections::{HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct DirWalk<'a> {
+    base: &'a Path,
+    storage: &'a Storage,
+    open_options: &'a CacheOpenOptions,
+    queue: VecDeque<PathBuf>,
+    // Canonical paths of walked directories, to not go in circles when following symlinks
+    visited: HashSet<PathBuf>,
+}
+
+impl<'a> DirWalk<'a> {
+    pub(crate) fn new(
+        base: &'a Path,
+        storage: &'a Storage,
+        open_options: &'a CacheOpenOptions,
+    ) -> Self {
+        Self {
+            base,
+            storage,
+            open_options,
+            queue: VecDeque::from([base.to_path_buf()]),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// The next directory to walk, starting with the base
+    pub(crate) fn next_dir(&mut self) -> Result<Option<PathBuf>> {
+        while let Some(dir) = self.queue.pop_front() {
+            if matches!(self.open_options.symlink_opt, SymlinkOpt::Follow) {
+                let canonical = self.storage.canonicalize(&dir).map_err(|e| {
+                    Error::ReadContent(format!("Failed to canonicalize dir at {dir:?}"), Some(e))
+                })?;
+                if !self.visited.insert(canonical) {
+                    continue;
+                }
+            }
+            return Ok(Some(dir));
+        }
+        Ok(None)
+    }
+
+    /// Calls `on_file` for each file in `dir`, and queues the subdirectories that should be
+    /// walked. Symlinks are handled according to the [`SymlinkOpt`], followed symlinks are
+    /// treated as what they point to.
+    pub(crate) fn read_dir<F: FnMut(&Path, &FileMeta) -> Result<()>>(
+        &mut self,
+        dir: &Path,
+        mut on_file: F,
+    ) -> Result<()> {
+        self.storage
+            .read_all_in_dir(dir, |entry_path, entry_metadata| {
+                let followed;
+                let entry_metadata = if entry_metadata.is_symlink() {
+                    match self.open_options.symlink_opt {
+                        SymlinkOpt::Skip => {
+                            warn!("Skipping symlink at {entry_path:?} in dir-cache");
+                            return Ok(());
+                        }
+                        SymlinkOpt::Refuse => {
+                            return Err(Error::ReadContent(
+                                format!("Found a symlink at {entry_path:?}, refusing to follow it"),
+                                None,
+                            ));
+                        }
+                        SymlinkOpt::Follow => match self.storage.metadata(entry_path) {
+                            Ok(md) => {
+                                followed = md;
+                                &followed
+                            }
+                            // Dangling, nothing to follow
+                            Err(e) if e.kind() == ErrorKind::NotFound => {
+                                warn!("Skipping dangling symlink at {entry_path:?} in dir-cache");
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                return Err(Error::ReadContent(
+                                    format!("Failed to follow symlink at {entry_path:?}"),
+                                    Some(e),
+                                ));
+                            }
+                        },
+                    }
+                } else {
+                    entry_metadata
+                };
+                if entry_metadata.is_dir() {
+                    if self.open_options.traverses(self.base, entry_path) {
+                        self.queue.push_back(entry_path.to_path_buf());
+                    }
+                    Ok(())
+                } else if entry_metadata.is_file() {
+                    on_file(entry_path, entry_metadata)
+                } else {
+                    Ok(())
+                }
+            })
+    }
+}
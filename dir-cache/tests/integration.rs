@@ -1,15 +1,27 @@
+// Joining paths in tests is fine, the disallowed methods guard the library code
+#![allow(clippy::disallowed_methods)]
 use dir_cache::error::Error;
+use dir_cache::inspect;
+use dir_cache::meta::{EntryMeta, Freshness, Label, ValueSource, MAX_LABEL_LEN};
 use dir_cache::opts::{
-    CacheOpenOptions, DirCacheOpts, DirOpenOpt, Encoding, ExpirationOpt, GenerationOpt, MemPullOpt,
-    MemPushOpt, SyncOpt,
+    AccessStatsOpt, CacheOpenOptions, Codec, ConflictPolicy, DirCacheOpts, DirOpenOpt,
+    DiskBudgetOpt, DurabilityOpt, Encoding, EvictReason, EvictionListener, ExpirationOpt,
+    FallbackOpt, GenerationOpt, Hook, Hooks, IngestOpt, InsertMode, Interceptor, InvalidEntryOpt,
+    KeyCaseOpt, KeyCharset, KeyCodecOpt, KeyDiscoveryOpt, KeyPolicy, MemBudgetOpt, MemPullOpt,
+    MemPushOpt, MirrorErrorOpt, MirrorOpt, OversizeOpt, PersistedOptsOpt, RepairOpt, SymlinkOpt,
+    SyncOpt, UnknownEncodingOpt, Weigher,
 };
-use dir_cache::DirCache;
+use dir_cache::stats::DirCacheStats;
+use dir_cache::typed::{TypedDirCache, ValueSerializer};
+use dir_cache::verify::{InvalidEntry, VerifyIssue};
+use dir_cache::{DirCache, Lookup, ValueWithMeta};
 use std::collections::HashSet;
 use std::convert::Infallible;
 use std::io::ErrorKind;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 fn dummy_key() -> &'static Path {
     Path::new("dummykey")
@@ -19,6 +31,61 @@ fn dummy_content() -> &'static [u8] {
     b"Dummy content!"
 }
 
+/// The encoding and flags of each generation in the (binary) manifest of the entry at `path`,
+/// without the checksum, rendered as `{encoding}[:{level or codec id}][,d]`
+fn manifest_generations(path: &Path) -> Vec<String> {
+    fn varint(cursor: &mut &[u8]) -> u64 {
+        let mut val = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = cursor[0];
+            *cursor = &cursor[1..];
+            val |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        val
+    }
+    let content = std::fs::read(path.join("dir-cache-manifest.txt")).unwrap();
+    let mut cursor = content.strip_prefix(b"DCM".as_slice()).unwrap();
+    assert_eq!(3, varint(&mut cursor));
+    let count = varint(&mut cursor);
+    (0..count)
+        .map(|_| {
+            let len = usize::try_from(varint(&mut cursor)).unwrap();
+            let (mut record, rest) = cursor.split_at(len);
+            cursor = rest;
+            // Age secs and nanos
+            varint(&mut record);
+            varint(&mut record);
+            let flags = record[0];
+            let tag = record[1];
+            record = &record[2..];
+            let mut rendered = match tag {
+                0 => "0".to_string(),
+                1 => format!("1:{}", varint(&mut record)),
+                2 => {
+                    let level = varint(&mut record);
+                    let sign = if level & 1 == 0 { "" } else { "-" };
+                    format!("2:{sign}{}", level >> 1)
+                }
+                3 => {
+                    let len = usize::try_from(varint(&mut record)).unwrap();
+                    format!("3:{}", std::str::from_utf8(&record[..len]).unwrap())
+                }
+                tag => panic!("Unknown encoding tag {tag}"),
+            };
+            if flags & 1 != 0 {
+                rendered.push_str(",d");
+            }
+            if flags & 4 != 0 {
+                rendered.push_str(",l");
+            }
+            rendered
+        })
+        .collect()
+}
+
 #[test]
 fn smoke_map_functionality_all_opts() {
     // Make sure all bounded options permutations work as a map, without checking
@@ -127,7 +194,7 @@ fn create_only_if_exists_works_if_exists() {
     let exists = tmp.path();
     DirCacheOpts::default()
         .open(
-            &exists,
+            exists,
             CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, true),
         )
         .unwrap();
@@ -452,6 +519,63 @@ fn write_generational_not_if_in_mem_only() {
     assert_eq!(b"gen0".as_slice(), &content);
 }
 
+#[test]
+fn get_or_insert_writes_once() {
+    let tmp = tempfile::TempDir::with_prefix("get_or_insert_writes_once").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(4).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .with_mem_push_opt(MemPushOpt::RetainAndWrite)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    let my_content = dummy_content();
+    dc.get_or_insert(my_key, || Ok::<_, Infallible>(my_content.to_vec()))
+        .unwrap();
+    // Already written, syncing must not write it again as a new generation
+    dc.sync().unwrap();
+    let path = tmp.path().join(my_key);
+    let files = all_files_in(&path);
+    assert_eq!(2, files.len(), "files: {files:?}");
+    assert!(files.contains(&path.join("dir-cache-generation-0")));
+}
+
+#[test]
+fn reopen_keeps_generation_order() {
+    let tmp = tempfile::TempDir::with_prefix("reopen_keeps_generation_order").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::from_millis(400)),
+    ));
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, b"gen1".to_vec()).unwrap();
+    std::thread::sleep(Duration::from_millis(250));
+    dc.insert(my_key, b"gen0".to_vec()).unwrap();
+    drop(dc);
+    // Only the older generation has expired
+    std::thread::sleep(Duration::from_millis(250));
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
 #[test]
 #[cfg(feature = "lz4")]
 fn write_generational_lz4() {
@@ -460,7 +584,7 @@ fn write_generational_lz4() {
     let mut dc = DirCacheOpts::default()
         .with_generation_opt(GenerationOpt::new(
             NonZeroUsize::new(4).unwrap(),
-            Encoding::Lz4,
+            Encoding::Lz4 { level: 0 },
             ExpirationOpt::NoExpiry,
         ))
         .with_mem_push_opt(MemPushOpt::PassthroughWrite)
@@ -491,6 +615,11 @@ fn write_generational_lz4() {
     assert!(files.remove(&expect_gen1));
     let content = std::fs::read(&expect_gen1).unwrap();
     assert_eq!(encode(b"gen1"), content);
+    assert_eq!(b"gen1".as_slice(), decode(&content));
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        dc.get_generation(my_key, 1).unwrap()
+    );
     let expect_gen2 = path.join("dir-cache-generation-2");
     assert!(files.remove(&expect_gen2));
     let content = std::fs::read(&expect_gen2).unwrap();
@@ -505,6 +634,193 @@ fn write_generational_lz4() {
     assert!(check_path(&tmp.path().join(my_key)).is_none());
 }
 
+#[test]
+#[cfg(feature = "zstd")]
+fn write_generational_zstd_with_level_reopen() {
+    let tmp = tempfile::TempDir::with_prefix("write_generational_zstd_with_level_reopen").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Zstd { level: 19 },
+        ExpirationOpt::NoExpiry,
+    ));
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, b"gen1".to_vec()).unwrap();
+    dc.insert(my_key, b"gen0".to_vec()).unwrap();
+    let path = tmp.path().join(my_key);
+    let content = std::fs::read(path.join("dir-cache-generation-1")).unwrap();
+    assert_eq!(zstd::bulk::compress(b"gen1", 19).unwrap(), content);
+    assert_eq!(
+        b"gen1".as_slice(),
+        zstd::bulk::decompress(&content, 4).unwrap()
+    );
+    assert_eq!(vec!["0", "2:19"], manifest_generations(&path));
+    drop(dc);
+    // The level round-trips through the manifest
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+#[cfg(all(feature = "lz4", feature = "zstd"))]
+fn age_tiered_encodings() {
+    static TIERS: [Encoding; 3] = [
+        Encoding::Plain,
+        Encoding::Lz4 { level: 0 },
+        Encoding::Zstd { level: 19 },
+    ];
+    let tmp = tempfile::TempDir::with_prefix("age_tiered_encodings").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(
+        GenerationOpt::new(
+            NonZeroUsize::new(4).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        )
+        .with_encoding_tiers(&TIERS),
+    );
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    for val in ["gen3", "gen2", "gen1", "gen0"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    let path = tmp.path().join(my_key);
+    assert_eq!(
+        vec!["0", "1:0", "2:19", "2:19"],
+        manifest_generations(&path)
+    );
+    assert_eq!(
+        b"gen0".as_slice(),
+        std::fs::read(path.join("dir-cache-generation-0")).unwrap()
+    );
+    assert_eq!(
+        b"gen1".to_vec(),
+        decode(&std::fs::read(path.join("dir-cache-generation-1")).unwrap())
+    );
+    for (gen, val) in [(2, b"gen2"), (3, b"gen3")] {
+        let content = std::fs::read(path.join(format!("dir-cache-generation-{gen}"))).unwrap();
+        assert_eq!(val.as_slice(), zstd::bulk::decompress(&content, 4).unwrap());
+    }
+    for (gen, val) in ["gen0", "gen1", "gen2", "gen3"].into_iter().enumerate() {
+        assert_eq!(
+            Some(val.as_bytes().to_vec()),
+            dc.get_generation(my_key, gen).unwrap()
+        );
+    }
+    drop(dc);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, true),
+        )
+        .unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn encode_current_generation_lz4() {
+    let tmp = tempfile::TempDir::with_prefix("encode_current_generation_lz4").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_mem_pull_opt(MemPullOpt::DontKeepInMemoryOnRead)
+        .with_generation_opt(
+            GenerationOpt::new(
+                NonZeroUsize::new(2).unwrap(),
+                Encoding::Plain,
+                ExpirationOpt::NoExpiry,
+            )
+            .with_current_gen_encoding(Encoding::Lz4 { level: 4 }),
+        );
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    let path = tmp.path().join(my_key);
+    dc.insert(my_key, b"gen1".to_vec()).unwrap();
+    let on_disk = std::fs::read(path.join("dir-cache-generation-0")).unwrap();
+    assert_eq!(b"gen1".as_slice(), decode(&on_disk));
+    assert_eq!(b"gen1", dc.get(my_key).unwrap().unwrap().as_ref());
+    dc.insert(my_key, b"gen0".to_vec()).unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    // Older generation re-encoded as plain
+    assert_eq!(
+        b"gen1".as_slice(),
+        std::fs::read(path.join("dir-cache-generation-1")).unwrap()
+    );
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        dc.get_generation(my_key, 1).unwrap()
+    );
+    drop(dc);
+    for eager in [true, false] {
+        let mut dc = opts
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, eager),
+            )
+            .unwrap();
+        assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    }
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn skip_encoding_small_values() {
+    let tmp = tempfile::TempDir::with_prefix("skip_encoding_small_values").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(
+        GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Lz4 { level: 0 },
+            ExpirationOpt::NoExpiry,
+        )
+        .with_current_gen_encoding(Encoding::Lz4 { level: 0 })
+        .with_min_compress_size(16),
+    );
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let small = Path::new("small");
+    let large = Path::new("large");
+    let large_content = vec![b'a'; 128];
+    for _ in 0..2 {
+        dc.insert(small, b"tiny".to_vec()).unwrap();
+        dc.insert(large, large_content.clone()).unwrap();
+    }
+    let manifest_encodings = |key: &Path| manifest_generations(&tmp.path().join(key));
+    assert_eq!(vec!["0", "0"], manifest_encodings(small));
+    assert_eq!(vec!["1:0", "1:0"], manifest_encodings(large));
+    assert_eq!(
+        b"tiny".as_slice(),
+        std::fs::read(tmp.path().join(small).join("dir-cache-generation-1")).unwrap()
+    );
+    assert_eq!(
+        large_content,
+        decode(&std::fs::read(tmp.path().join(large).join("dir-cache-generation-1")).unwrap())
+    );
+    assert_eq!(b"tiny", dc.get(small).unwrap().unwrap().as_ref());
+    assert_eq!(&large_content, dc.get(large).unwrap().unwrap().as_ref());
+}
+
 #[test]
 fn tolerates_foreign_files() {
     let tmp = tempfile::TempDir::with_prefix("tolerates_foreign_files").unwrap();
@@ -525,7 +841,7 @@ fn tolerates_foreign_files() {
     assert_eq!(2, files.len());
     std::fs::write(
         tmp.path().join(my_key).join("rogue_user_file"),
-        b"Rogue content!".to_vec(),
+        b"Rogue content!",
     )
     .unwrap();
     let files = all_files_in(&tmp.path().join(my_key));
@@ -581,7 +897,7 @@ fn can_write_and_pick_up_subdirs() {
         dc.get(&my_sub_key).unwrap().unwrap().as_ref()
     );
     // Removing outer first, will leave an empty outer dir
-    assert!(dc.remove(&my_key).unwrap());
+    assert!(dc.remove(my_key).unwrap());
     assert!(dc.get(my_key).unwrap().is_none());
     assert!(all_files_in(&tmp.path().join(my_key)).is_empty());
     assert_dir_at(&tmp.path().join(my_key));
@@ -589,67 +905,4356 @@ fn can_write_and_pick_up_subdirs() {
     assert!(dc.remove(&my_sub_key).unwrap());
     assert!(dc.get(&my_sub_key).unwrap().is_none());
     assert!(check_path(&tmp.path().join(my_sub_key)).is_none());
-    // Remains
-    assert_dir_at(&tmp.path().join(my_key));
+    // The outer dir was only left for the sub key, and is cleaned up with it
+    assert!(check_path(&tmp.path().join(my_key)).is_none());
+    assert_empty_dir_at(tmp.path());
+
+    // Empty directories between the base and a removed key are cleaned up, up to the base
+    let deep_key = Path::new("a/b/c");
+    let sibling_key = Path::new("a/d");
+    dc.insert(deep_key, my_content.to_vec()).unwrap();
+    dc.insert(sibling_key, my_content.to_vec()).unwrap();
+    assert!(dc.remove(deep_key).unwrap());
+    assert!(check_path(&tmp.path().join("a/b")).is_none());
+    assert_dir_at(&tmp.path().join(sibling_key));
+    assert!(dc.remove(sibling_key).unwrap());
+    assert_empty_dir_at(tmp.path());
 }
 
-#[derive(Debug, Eq, PartialEq)]
-enum ExpectedDiskObject {
-    File,
-    Dir,
+#[test]
+fn mem_budget_drops_least_recently_used() {
+    let tmp = tempfile::TempDir::with_prefix("mem_budget_drops_least_recently_used").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::RetainAndWrite)
+        .with_mem_pull_opt(MemPullOpt::KeepInMemoryOnRead)
+        .with_mem_budget_opt(MemBudgetOpt::MaxBytes(8))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    let k3 = Path::new("k3");
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    dc.insert(k2, b"bbbb".to_vec()).unwrap();
+    // Change what's on disk to be able to tell if a value was served from memory
+    std::fs::write(tmp.path().join(k1).join("dir-cache-generation-0"), b"AAAA").unwrap();
+    std::fs::write(tmp.path().join(k2).join("dir-cache-generation-0"), b"BBBB").unwrap();
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"bbbb", dc.get(k2).unwrap().unwrap().as_ref());
+    // Exceeds the budget, k1 is the least recently used
+    dc.insert(k3, b"cccc".to_vec()).unwrap();
+    assert_eq!(b"cccc", dc.get(k3).unwrap().unwrap().as_ref());
+    assert_eq!(b"AAAA", dc.get(k1).unwrap().unwrap().as_ref());
+    // Pulling k1 back into memory pushed out k2
+    assert_eq!(b"BBBB", dc.get(k2).unwrap().unwrap().as_ref());
 }
 
-fn assert_empty_dir_at(path: &Path) {
-    let mut seen = HashSet::new();
-    for e in std::fs::read_dir(path).unwrap() {
-        let entry = e.unwrap();
-        seen.insert(entry.path());
-    }
-    assert!(
-        seen.is_empty(),
-        "Expected an empty dir, found entries: {seen:?}"
-    );
+#[test]
+#[cfg(feature = "moka")]
+fn mem_budget_with_moka() {
+    let tmp = tempfile::TempDir::with_prefix("mem_budget_with_moka").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::RetainAndWrite)
+        .with_mem_pull_opt(MemPullOpt::KeepInMemoryOnRead)
+        .with_mem_budget_opt(MemBudgetOpt::Moka {
+            max_bytes: 6,
+            time_to_idle: Some(Duration::from_millis(50)),
+        })
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let small = Path::new("small");
+    let large = Path::new("large");
+    dc.insert(small, b"aaaa".to_vec()).unwrap();
+    dc.insert(large, b"bbbbbbbb".to_vec()).unwrap();
+    // Change what's on disk to be able to tell if a value was served from memory
+    std::fs::write(
+        tmp.path().join(small).join("dir-cache-generation-0"),
+        b"AAAA",
+    )
+    .unwrap();
+    std::fs::write(
+        tmp.path().join(large).join("dir-cache-generation-0"),
+        b"BBBBBBBB",
+    )
+    .unwrap();
+    assert_eq!(b"aaaa", dc.get(small).unwrap().unwrap().as_ref());
+    // Larger than the whole budget, never kept
+    assert_eq!(b"BBBBBBBB", dc.get(large).unwrap().unwrap().as_ref());
+    std::thread::sleep(Duration::from_millis(100));
+    dc.insert(Path::new("other"), b"c".to_vec()).unwrap();
+    // Idle for longer than allowed
+    assert_eq!(b"AAAA", dc.get(small).unwrap().unwrap().as_ref());
+
+    // Uncommitted values are kept regardless
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::MemoryOnly)
+        .with_mem_budget_opt(MemBudgetOpt::Moka {
+            max_bytes: 1,
+            time_to_idle: None,
+        })
+        .open(
+            &tmp.path().join("uncommitted"),
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    dc.insert(small, b"aaaa".to_vec()).unwrap();
+    dc.insert(large, b"bbbbbbbb".to_vec()).unwrap();
+    assert_eq!(b"aaaa", dc.get(small).unwrap().unwrap().as_ref());
+    assert_eq!(b"bbbbbbbb", dc.get(large).unwrap().unwrap().as_ref());
 }
 
-fn assert_dir_at(path: &Path) {
-    let p = check_path(path).expect("Expected dir, found nothing");
-    assert_eq!(ExpectedDiskObject::Dir, p, "Wanted dir, found file");
+#[test]
+fn mem_budget_keeps_uncommitted() {
+    let tmp = tempfile::TempDir::with_prefix("mem_budget_keeps_uncommitted").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::MemoryOnly)
+        .with_mem_budget_opt(MemBudgetOpt::MaxBytes(1))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    dc.insert(k2, b"bbbb".to_vec()).unwrap();
+    assert_empty_dir_at(tmp.path());
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"bbbb", dc.get(k2).unwrap().unwrap().as_ref());
+    dc.sync().unwrap();
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"bbbb", dc.get(k2).unwrap().unwrap().as_ref());
 }
 
-fn assert_file_at(path: &Path) {
-    let p = check_path(path).expect("Expected file, found nothing");
-    assert_eq!(ExpectedDiskObject::File, p, "Wanted file, found dir");
+#[test]
+fn get_owned_and_get_ref() {
+    let tmp = tempfile::TempDir::with_prefix("get_owned_and_get_ref").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_pull_opt(MemPullOpt::DontKeepInMemoryOnRead)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    let my_content = dummy_content();
+    assert!(dc.get_owned(my_key).unwrap().is_none());
+    assert!(dc.get_ref(my_key).unwrap().is_none());
+    dc.insert(my_key, my_content.to_vec()).unwrap();
+    let owned: Vec<u8> = dc.get_owned(my_key).unwrap().unwrap();
+    assert_eq!(my_content, owned.as_slice());
+    assert_eq!(my_content, dc.get_ref(my_key).unwrap().unwrap());
+    // The borrowed value was retained in memory, even though the opts say not to
+    std::fs::write(
+        tmp.path().join(my_key).join("dir-cache-generation-0"),
+        b"Changed on disk",
+    )
+    .unwrap();
+    assert_eq!(
+        my_content,
+        dc.get_owned(my_key).unwrap().unwrap().as_slice()
+    );
 }
 
-fn check_path(path: &Path) -> Option<ExpectedDiskObject> {
-    match std::fs::metadata(path) {
-        Ok(m) => {
-            if m.is_file() {
-                return Some(ExpectedDiskObject::File);
-            }
-            if m.is_dir() {
-                return Some(ExpectedDiskObject::Dir);
-            }
-            panic!("Unexpected disk object at {m:?}");
-        }
-        Err(e) if e.kind() == ErrorKind::NotFound => None,
-        Err(e) => {
-            panic!("Failed to check path: {e}");
-        }
-    }
+#[test]
+fn retain_only_small_values_on_write() {
+    let tmp = tempfile::TempDir::with_prefix("retain_only_small_values_on_write").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::RetainIfSmallerThan(5))
+        .with_mem_pull_opt(MemPullOpt::DontKeepInMemoryOnRead)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let small = Path::new("small");
+    let large = Path::new("large");
+    dc.insert(small, b"tiny".to_vec()).unwrap();
+    dc.insert(large, b"not so tiny".to_vec()).unwrap();
+    // Both are written to disk
+    assert_file_at(&tmp.path().join(small).join("dir-cache-generation-0"));
+    assert_file_at(&tmp.path().join(large).join("dir-cache-generation-0"));
+    std::fs::write(
+        tmp.path().join(small).join("dir-cache-generation-0"),
+        b"TINY",
+    )
+    .unwrap();
+    std::fs::write(
+        tmp.path().join(large).join("dir-cache-generation-0"),
+        b"NOT SO TINY",
+    )
+    .unwrap();
+    // Only the small one was kept in memory
+    assert_eq!(b"tiny", dc.get(small).unwrap().unwrap().as_ref());
+    assert_eq!(b"NOT SO TINY", dc.get(large).unwrap().unwrap().as_ref());
 }
 
-fn in_all_opts_context<
-    UserFn: FnMut(Box<dyn Fn(&Path) -> DirCache>, DirCacheOpts),
-    UserFilterFn: Fn(&DirCacheOpts, &CacheOpenOptions) -> bool,
->(
-    num_generations: usize,
-    filter: UserFilterFn,
-    mut user_fn: UserFn,
-) {
-    for mem_pull in [
-        MemPullOpt::DontKeepInMemoryOnRead,
-        MemPullOpt::KeepInMemoryOnRead,
+#[test]
+fn keep_only_small_values_on_read() {
+    let tmp = tempfile::TempDir::with_prefix("keep_only_small_values_on_read").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .with_mem_pull_opt(MemPullOpt::KeepIfSmallerThan(5))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let small = Path::new("small");
+    let large = Path::new("large");
+    dc.insert(small, b"tiny".to_vec()).unwrap();
+    dc.insert(large, b"not so tiny".to_vec()).unwrap();
+    assert_eq!(b"tiny", dc.get(small).unwrap().unwrap().as_ref());
+    assert_eq!(b"not so tiny", dc.get(large).unwrap().unwrap().as_ref());
+    std::fs::write(
+        tmp.path().join(small).join("dir-cache-generation-0"),
+        b"TINY",
+    )
+    .unwrap();
+    std::fs::write(
+        tmp.path().join(large).join("dir-cache-generation-0"),
+        b"NOT SO TINY",
+    )
+    .unwrap();
+    assert_eq!(b"tiny", dc.get(small).unwrap().unwrap().as_ref());
+    assert_eq!(b"NOT SO TINY", dc.get(large).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn disk_budget_evicts_least_recently_used() {
+    let tmp = tempfile::TempDir::with_prefix("disk_budget_evicts_least_recently_used").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_disk_budget_opt(DiskBudgetOpt::MaxBytes(10))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    let k3 = Path::new("k3");
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    dc.insert(k2, b"bbbb".to_vec()).unwrap();
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    dc.insert(k3, b"cccc".to_vec()).unwrap();
+    assert!(check_path(&tmp.path().join(k2)).is_none());
+    assert!(dc.get(k2).unwrap().is_none());
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"cccc", dc.get(k3).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn disk_budget_counts_pooled_values_once() {
+    let tmp = tempfile::TempDir::with_prefix("disk_budget_counts_pooled_values_once").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_content_addressed(true)
+        .with_disk_budget_opt(DiskBudgetOpt::MaxBytes(10))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    let k3 = Path::new("k3");
+    dc.insert(k1, b"shared".to_vec()).unwrap();
+    dc.insert(k2, b"shared".to_vec()).unwrap();
+    assert_eq!(b"shared", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"shared", dc.get(k2).unwrap().unwrap().as_ref());
+    // Evicting k1 alone frees nothing, k2 still holds the pooled value
+    dc.insert(k3, b"cccccc".to_vec()).unwrap();
+    assert!(dc.get(k1).unwrap().is_none());
+    assert!(dc.get(k2).unwrap().is_none());
+    assert_eq!(b"cccccc", dc.get(k3).unwrap().unwrap().as_ref());
+    assert_eq!(6, dc.summary().unwrap().disk_bytes());
+}
+
+#[test]
+fn disk_budget_reweighs_pruned_entries() {
+    let tmp = tempfile::TempDir::with_prefix("disk_budget_reweighs_pruned_entries").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .with_disk_budget_opt(DiskBudgetOpt::MaxBytes(10))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    dc.insert(k1, b"AAAA".to_vec()).unwrap();
+    assert_eq!(1, dc.prune_generations(k1, 1).unwrap());
+    // Fits now that k1 only has one generation left
+    dc.insert(k2, b"bbbb".to_vec()).unwrap();
+    assert_eq!(b"AAAA", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"bbbb", dc.get(k2).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn disk_budget_demotes_to_cold_tier() {
+    let tmp = tempfile::TempDir::with_prefix("disk_budget_demotes_to_cold_tier").unwrap();
+    let cold = tmp.path().join("cold");
+    let hot = tmp.path().join("hot");
+    let mut dc = DirCacheOpts::default()
+        .with_disk_budget_opt(DiskBudgetOpt::MaxBytes(10))
+        .open(
+            &hot,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false).with_cold_tier(&cold),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("nested/k2");
+    let k3 = Path::new("k3");
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    dc.insert(k2, b"bbbb".to_vec()).unwrap();
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    dc.insert(k3, b"cccc".to_vec()).unwrap();
+    assert!(check_path(&hot.join(k2)).is_none());
+    assert!(check_path(&cold.join(k2)).is_some());
+    assert!(!dc.keys().unwrap().contains(&k2.to_path_buf()));
+    // Promoted back, demoting the least recently used in its place
+    assert_eq!(b"bbbb", dc.get(k2).unwrap().unwrap().as_ref());
+    assert!(check_path(&cold.join("nested")).is_none());
+    assert!(check_path(&hot.join(k1)).is_none());
+    assert!(check_path(&cold.join(k1)).is_some());
+    assert_eq!(b"aaaa", dc.get(k1).unwrap().unwrap().as_ref());
+    assert_eq!(b"bbbb", dc.get(k2).unwrap().unwrap().as_ref());
+    assert!(check_path(&cold.join(k3)).is_some());
+    // Removing a demoted key removes it from the cold tier
+    assert!(dc.remove(k3).unwrap());
+    assert!(check_path(&cold.join(k3)).is_none());
+    assert!(dc.get(k3).unwrap().is_none());
+}
+
+#[test]
+fn disk_budget_with_custom_weigher() {
+    let tmp = tempfile::TempDir::with_prefix("disk_budget_with_custom_weigher").unwrap();
+    // Weighs each entry as one, captured so that it can't be a plain `fn`
+    let per_entry = 1;
+    let weigher = Weigher::new(move |_key: &Path, _meta: &EntryMeta| per_entry);
+    let opts = DirCacheOpts::default().with_disk_budget_opt(DiskBudgetOpt::MaxWeight(2));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false).with_weigher(weigher);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    let k3 = Path::new("k3");
+    // Large values, doesn't matter since only entries are counted
+    dc.insert(k1, vec![0; 1024]).unwrap();
+    dc.insert(k2, vec![1; 1024]).unwrap();
+    dc.insert(k3, vec![2; 1024]).unwrap();
+    assert!(dc.get(k1).unwrap().is_none());
+    assert!(dc.get(k2).unwrap().is_some());
+    assert!(dc.get(k3).unwrap().is_some());
+    drop(dc);
+    // Evicts on open if over budget
+    let mut dc = opts
+        .with_disk_budget_opt(DiskBudgetOpt::MaxWeight(1))
+        .open(tmp.path(), open_options)
+        .unwrap();
+    let remaining = [k2, k3]
+        .into_iter()
+        .filter(|k| dc.get(k).unwrap().is_some())
+        .count();
+    assert_eq!(1, remaining);
+}
+
+#[test]
+fn eviction_listener_notified_on_expiry_and_eviction() {
+    let evicted: Arc<Mutex<Vec<(PathBuf, EvictReason)>>> = Arc::default();
+    let record = evicted.clone();
+    let tmp = tempfile::TempDir::with_prefix("eviction_listener_notified_on_expiry_and_eviction")
+        .unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        .with_eviction_listener(EvictionListener::new(move |key, reason| {
+            record.lock().unwrap().push((key.to_path_buf(), reason));
+        }));
+    let mut dc = DirCacheOpts::default()
+        .with_disk_budget_opt(DiskBudgetOpt::MaxBytes(4))
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    // Removing manually does not notify
+    assert!(dc.remove(k1).unwrap());
+    assert!(evicted.lock().unwrap().is_empty());
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    dc.insert(k2, b"bbbb".to_vec()).unwrap();
+    assert_eq!(
+        vec![(k1.to_path_buf(), EvictReason::Evicted)],
+        std::mem::take(&mut *evicted.lock().unwrap())
+    );
+    let expire_immediately = dc.opts().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::MIN,
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::ZERO),
+    ));
+    assert!(dc.get_opt(k2, expire_immediately).unwrap().is_none());
+    assert_eq!(
+        vec![(k2.to_path_buf(), EvictReason::Expired)],
+        std::mem::take(&mut *evicted.lock().unwrap())
+    );
+    dc.insert(k1, b"aaaa".to_vec()).unwrap();
+    drop(dc);
+    // Expired on open
+    let _dc = expire_immediately.open(tmp.path(), open_options).unwrap();
+    assert_eq!(
+        vec![(k1.to_path_buf(), EvictReason::Expired)],
+        std::mem::take(&mut *evicted.lock().unwrap())
+    );
+    assert!(check_path(&tmp.path().join(k1)).is_none());
+}
+
+#[derive(Debug)]
+struct XorCodec;
+
+impl Codec for XorCodec {
+    fn id(&self) -> &'static str {
+        "xor"
+    }
+
+    fn encode(&self, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(content.iter().map(|b| b ^ 0xAA).collect())
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(content.iter().map(|b| b ^ 0xAA).collect())
+    }
+}
+
+static XOR_CODEC: XorCodec = XorCodec;
+static CODECS: [&dyn Codec; 1] = [&XOR_CODEC];
+
+#[test]
+fn custom_codec_round_trips_and_requires_registration() {
+    let tmp = tempfile::TempDir::with_prefix("custom_codec_round_trips").unwrap();
+    let generation_opt = GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Custom(&XOR_CODEC),
+        ExpirationOpt::NoExpiry,
+    )
+    .with_current_gen_encoding(Encoding::Custom(&XOR_CODEC));
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(generation_opt)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let key = dummy_key();
+    dc.insert(key, b"first".to_vec()).unwrap();
+    dc.insert(key, dummy_content().to_vec()).unwrap();
+    let on_disk = std::fs::read(tmp.path().join(key).join("dir-cache-generation-0")).unwrap();
+    assert_eq!(XOR_CODEC.encode(dummy_content()).unwrap(), on_disk);
+    assert_eq!(
+        vec!["3:xor", "3:xor"],
+        manifest_generations(&tmp.path().join(key))
+    );
+    drop(dc);
+
+    let mut registered = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::default().with_codecs(&CODECS))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, true),
+        )
+        .unwrap();
+    assert_eq!(
+        dummy_content(),
+        registered.get(key).unwrap().unwrap().as_ref()
+    );
+    drop(registered);
+
+    let unregistered = DirCacheOpts::default().open(
+        tmp.path(),
+        CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, true),
+    );
+    assert!(matches!(unregistered, Err(Error::UnknownCodec(id)) if id == "xor"));
+}
+
+#[test]
+fn migrate_encoding_of_stored_generations() {
+    let tmp = tempfile::TempDir::with_prefix("migrate_encoding_of_stored_generations").unwrap();
+    let generation_opt = GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    );
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(generation_opt)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let migrated = Path::new("migrated");
+    let broken = Path::new("broken");
+    dc.insert(migrated, b"gen1".to_vec()).unwrap();
+    dc.insert(migrated, b"gen0".to_vec()).unwrap();
+    dc.insert(broken, b"gen0".to_vec()).unwrap();
+    std::fs::remove_file(tmp.path().join(broken).join("dir-cache-generation-0")).unwrap();
+
+    let report = dc
+        .migrate_encoding(Encoding::Plain, Encoding::Custom(&XOR_CODEC))
+        .unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(2, report.generations_migrated());
+    assert_eq!(1, report.failures().len());
+    assert_eq!(broken, report.failures()[0].key());
+    assert_eq!(
+        vec!["3:xor", "3:xor"],
+        manifest_generations(&tmp.path().join(migrated))
+    );
+    assert_eq!(
+        XOR_CODEC.encode(b"gen1").unwrap(),
+        std::fs::read(tmp.path().join(migrated).join("dir-cache-generation-1")).unwrap()
+    );
+    // Nothing left to migrate
+    let report = dc
+        .migrate_encoding(Encoding::Plain, Encoding::Custom(&XOR_CODEC))
+        .unwrap();
+    assert_eq!(0, report.generations_migrated());
+    dc.remove(broken).unwrap();
+    drop(dc);
+
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(generation_opt.with_codecs(&CODECS))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"gen0", dc.get(migrated).unwrap().unwrap().as_ref());
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        dc.get_generation(migrated, 1).unwrap()
+    );
+}
+
+#[test]
+#[cfg(feature = "tar")]
+fn export_to_archive() {
+    let tmp = tempfile::TempDir::with_prefix("export_to_archive").unwrap();
+    let cache_dir = tmp.path().join("cache");
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::MemoryOnly)
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            &cache_dir,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    let nested = Path::new("nested/key");
+    dc.insert_opt(
+        dummy_key(),
+        b"gen1".to_vec(),
+        DirCacheOpts::default().with_mem_push_opt(MemPushOpt::PassthroughWrite),
+    )
+    .unwrap();
+    // Only in memory until exported
+    dc.insert(dummy_key(), b"gen0".to_vec()).unwrap();
+    dc.insert(nested, dummy_content().to_vec()).unwrap();
+    let archive = tmp.path().join("cache.tar");
+    dc.export(&archive).unwrap();
+
+    let mut archived = std::collections::HashMap::new();
+    for entry in tar::Archive::new(std::fs::File::open(&archive).unwrap())
+        .entries()
+        .unwrap()
+    {
+        let mut entry = entry.unwrap();
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+        archived.insert(entry.path().unwrap().into_owned(), content);
+    }
+    assert_eq!(5, archived.len(), "archived: {:?}", archived.keys());
+    for (path, content) in [
+        ("dummykey/dir-cache-generation-0", b"gen0".as_slice()),
+        ("dummykey/dir-cache-generation-1", b"gen1".as_slice()),
+        ("nested/key/dir-cache-generation-0", dummy_content()),
+    ] {
+        assert_eq!(content, archived[Path::new(path)].as_slice());
+    }
+    assert!(archived.contains_key(Path::new("dummykey/dir-cache-manifest.txt")));
+    assert!(archived.contains_key(Path::new("nested/key/dir-cache-manifest.txt")));
+}
+
+#[test]
+#[cfg(feature = "tar")]
+fn import_from_archive() {
+    let tmp = tempfile::TempDir::with_prefix("import_from_archive").unwrap();
+    let open = |dir: &str| {
+        DirCacheOpts::default()
+            .open(
+                &tmp.path().join(dir),
+                CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+            )
+            .unwrap()
+    };
+    let replaced = Path::new("replaced");
+    let kept = Path::new("kept");
+    let fresh = Path::new("nested/fresh");
+    let mut target = open("target");
+    target.insert(replaced, b"old".to_vec()).unwrap();
+    let mut source = open("source");
+    source.insert(replaced, b"new".to_vec()).unwrap();
+    source.insert(kept, b"archived".to_vec()).unwrap();
+    source.insert(fresh, b"fresh".to_vec()).unwrap();
+    target.insert(kept, b"existing".to_vec()).unwrap();
+    let archive = tmp.path().join("cache.tar");
+    source.export(&archive).unwrap();
+
+    assert_eq!(
+        2,
+        target.import(&archive, ConflictPolicy::KeepNewer).unwrap()
+    );
+    assert_eq!(b"new", target.get(replaced).unwrap().unwrap().as_ref());
+    assert_eq!(b"existing", target.get(kept).unwrap().unwrap().as_ref());
+    assert_eq!(b"fresh", target.get(fresh).unwrap().unwrap().as_ref());
+    assert_eq!(
+        0,
+        target
+            .import(&archive, ConflictPolicy::KeepExisting)
+            .unwrap()
+    );
+    assert!(matches!(
+        target.import(&archive, ConflictPolicy::Error),
+        Err(Error::KeyCollision(_))
+    ));
+    drop(target);
+
+    let mut target = open("target");
+    assert_eq!(b"new", target.get(replaced).unwrap().unwrap().as_ref());
+    assert_eq!(b"existing", target.get(kept).unwrap().unwrap().as_ref());
+    assert_eq!(b"fresh", target.get(fresh).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn copy_from_another_cache() {
+    let tmp = tempfile::TempDir::with_prefix("copy_from_another_cache").unwrap();
+    let open = |dir: &str, mem_push_opt: MemPushOpt| {
+        DirCacheOpts::default()
+            .with_mem_push_opt(mem_push_opt)
+            .open(
+                &tmp.path().join(dir),
+                CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+            )
+            .unwrap()
+    };
+    let replaced = Path::new("replaced");
+    let kept = Path::new("kept");
+    let fresh = Path::new("nested/fresh");
+    let mut target = open("target", MemPushOpt::PassthroughWrite);
+    target.insert(replaced, b"old".to_vec()).unwrap();
+    let mut worker = open("worker", MemPushOpt::MemoryOnly);
+    worker.insert(replaced, b"new".to_vec()).unwrap();
+    worker.insert(kept, b"worker".to_vec()).unwrap();
+    worker.sync().unwrap();
+    target.insert(kept, b"existing".to_vec()).unwrap();
+    // Only in memory until it's copied
+    worker.insert(fresh, b"fresh".to_vec()).unwrap();
+
+    assert_eq!(
+        2,
+        target
+            .copy_from(&mut worker, ConflictPolicy::KeepNewer)
+            .unwrap()
+    );
+    assert_eq!(b"new", target.get(replaced).unwrap().unwrap().as_ref());
+    assert_eq!(b"existing", target.get(kept).unwrap().unwrap().as_ref());
+    assert_eq!(b"fresh", target.get(fresh).unwrap().unwrap().as_ref());
+    assert_eq!(
+        0,
+        target
+            .copy_from(&mut worker, ConflictPolicy::KeepExisting)
+            .unwrap()
+    );
+    assert!(matches!(
+        target.copy_from(&mut worker, ConflictPolicy::Error),
+        Err(Error::KeyCollision(_))
+    ));
+    let mut same = open("target", MemPushOpt::PassthroughWrite);
+    assert!(matches!(
+        target.copy_from(&mut same, ConflictPolicy::KeepNewer),
+        Err(Error::Open(_))
+    ));
+    drop(same);
+    drop(target);
+
+    let mut target = open("target", MemPushOpt::PassthroughWrite);
+    assert_eq!(b"new", target.get(replaced).unwrap().unwrap().as_ref());
+    assert_eq!(b"existing", target.get(kept).unwrap().unwrap().as_ref());
+    assert_eq!(b"fresh", target.get(fresh).unwrap().unwrap().as_ref());
+    assert!(target.verify().unwrap().is_ok());
+    // The worker is left as it was
+    assert_eq!(b"worker", worker.get(kept).unwrap().unwrap().as_ref());
+    assert_eq!(3, worker.keys().unwrap().len());
+}
+
+#[test]
+#[cfg(all(feature = "dirs", target_os = "linux"))]
+fn open_in_user_cache_dir() {
+    let tmp = tempfile::TempDir::with_prefix("open_in_user_cache_dir").unwrap();
+    // Nothing else reads it
+    std::env::set_var("XDG_CACHE_HOME", tmp.path());
+    let mut dc = DirCache::open_user_cache("my-app").unwrap();
+    dc.insert(dummy_key(), dummy_content().to_vec()).unwrap();
+    assert!(check_path(&tmp.path().join("my-app").join(dummy_key())).is_some());
+    drop(dc);
+    let mut dc = DirCacheOpts::default().open_user_cache("my-app").unwrap();
+    assert_eq!(
+        dummy_content(),
+        dc.get(dummy_key()).unwrap().unwrap().as_ref()
+    );
+    for app_name in ["", "..", "nested/app", "/abs"] {
+        assert!(matches!(
+            DirCache::open_user_cache(app_name),
+            Err(Error::Open(_))
+        ));
+    }
+}
+
+#[test]
+fn ephemeral_removed_on_drop() {
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::MemoryOnly)
+        .with_sync_opt(SyncOpt::SyncOnDrop)
+        .open_ephemeral()
+        .unwrap();
+    let path = dc.path().to_path_buf();
+    assert!(path.starts_with(std::env::temp_dir()));
+    let mut other = DirCacheOpts::default().open_ephemeral().unwrap();
+    assert_ne!(path, other.path());
+    dc.insert(dummy_key(), dummy_content().to_vec()).unwrap();
+    dc.sync().unwrap();
+    assert!(check_path(&path.join(dummy_key())).is_some());
+    assert_eq!(
+        dummy_content(),
+        dc.get(dummy_key()).unwrap().unwrap().as_ref()
+    );
+    assert!(other.get(dummy_key()).unwrap().is_none());
+    drop(dc);
+    assert!(check_path(&path).is_none());
+    let other_path = other.path().to_path_buf();
+    drop(other);
+    assert!(check_path(&other_path).is_none());
+}
+
+#[test]
+fn batch_writes_each_key_once() {
+    let tmp = tempfile::TempDir::with_prefix("batch_writes_each_key_once").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(3).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let twice = Path::new("nested/twice");
+    let removed = Path::new("removed");
+    let staged_then_removed = Path::new("staged_then_removed");
+    dc.insert(twice, b"before".to_vec()).unwrap();
+    dc.insert(removed, b"removed".to_vec()).unwrap();
+    let mut batch = dc.batch();
+    batch
+        .insert(twice, b"first".to_vec())
+        .insert(twice, b"second".to_vec())
+        .remove(removed)
+        .insert(staged_then_removed, b"gone".to_vec())
+        .remove(staged_then_removed);
+    assert_eq!(5, batch.len());
+    // Dropped without being committed
+    drop(batch);
+    assert_eq!(b"before", dc.get(twice).unwrap().unwrap().as_ref());
+
+    let mut batch = dc.batch();
+    batch
+        .insert(twice, b"first".to_vec())
+        .insert(twice, b"second".to_vec())
+        .remove(removed)
+        .insert(staged_then_removed, b"gone".to_vec())
+        .remove(staged_then_removed);
+    batch.commit().unwrap();
+    assert_eq!(b"second", dc.get(twice).unwrap().unwrap().as_ref());
+    // The first staged value never became a generation of its own
+    assert_eq!(
+        Some(b"before".to_vec()),
+        dc.get_generation(twice, 1).unwrap()
+    );
+    assert_eq!(None, dc.get_generation(twice, 2).unwrap());
+    assert!(dc.get(removed).unwrap().is_none());
+    assert!(check_path(&tmp.path().join(staged_then_removed)).is_none());
+    drop(dc);
+
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"second", dc.get(twice).unwrap().unwrap().as_ref());
+    assert_eq!(vec![twice.to_path_buf()], dc.keys().unwrap());
+}
+
+#[test]
+fn extend_and_open_from_iter() {
+    let tmp = tempfile::TempDir::with_prefix("extend_and_open_from_iter").unwrap();
+    let fixtures = || {
+        (0..3).map(|i| {
+            (
+                PathBuf::from(format!("nested/k{i}")),
+                format!("v{i}").into_bytes(),
+            )
+        })
+    };
+    let mut dc = DirCacheOpts::default()
+        .open_from_iter(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            fixtures(),
+        )
+        .unwrap();
+    assert_eq!(3, dc.keys().unwrap().len());
+    dc.extend([(PathBuf::from("k3"), b"v3".to_vec())]);
+    assert_eq!(b"v3", dc.get(Path::new("k3")).unwrap().unwrap().as_ref());
+    assert_eq!(
+        b"v1",
+        dc.get(Path::new("nested/k1")).unwrap().unwrap().as_ref()
+    );
+    // Stops at the first failure
+    let res = dc.try_extend([
+        (PathBuf::from("k4"), b"v4".to_vec()),
+        (PathBuf::from("../outside"), b"nope".to_vec()),
+        (PathBuf::from("k5"), b"v5".to_vec()),
+    ]);
+    assert!(res.is_err());
+    assert!(dc.get(Path::new("k4")).unwrap().is_some());
+    assert!(dc.get(Path::new("k5")).unwrap().is_none());
+}
+
+#[test]
+fn summary_and_debug() {
+    let tmp = tempfile::TempDir::with_prefix("summary_and_debug").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::MemoryOnly)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let empty = dc.summary().unwrap();
+    assert_eq!(0, empty.entries());
+    assert!(empty.oldest_age().is_none());
+    dc.insert(Path::new("k1"), b"one".to_vec()).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    dc.insert(Path::new("k2"), b"two!".to_vec()).unwrap();
+    let summary = dc.summary().unwrap();
+    assert_eq!(2, summary.entries());
+    assert_eq!(2, summary.dirty());
+    assert_eq!(7, summary.in_memory_bytes());
+    assert_eq!(0, summary.disk_bytes());
+    assert!(summary.oldest_age().unwrap() > summary.newest_age().unwrap());
+    dc.sync().unwrap();
+    let summary = dc.summary().unwrap();
+    assert_eq!(0, summary.dirty());
+    assert_eq!(7, summary.disk_bytes());
+    let debug = format!("{dc:?}");
+    assert!(debug.starts_with("DirCache {"), "{debug}");
+    assert!(debug.contains("entries: 2"), "{debug}");
+    assert!(debug.contains("disk_bytes: 7"), "{debug}");
+}
+
+#[test]
+fn drain_takes_every_value() {
+    let tmp = tempfile::TempDir::with_prefix("drain_takes_every_value").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::RetainAndWrite)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("nested/k2");
+    dc.insert(k1, b"one".to_vec()).unwrap();
+    dc.insert(k2, b"two".to_vec()).unwrap();
+    dc.insert_negative(Path::new("negative"), Duration::from_secs(30))
+        .unwrap();
+    let mut drained = dc.drain().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+    drained.sort();
+    assert_eq!(
+        vec![
+            (k1.to_path_buf(), b"one".to_vec()),
+            (k2.to_path_buf(), b"two".to_vec())
+        ],
+        drained
+    );
+    assert!(dc.keys().unwrap().is_empty());
+    assert!(matches!(
+        dc.lookup(Path::new("negative")).unwrap(),
+        Lookup::Missing
+    ));
+    assert_empty_dir_at(tmp.path());
+
+    // Stopping early keeps the rest
+    dc.insert(k1, b"one".to_vec()).unwrap();
+    dc.insert(k2, b"two".to_vec()).unwrap();
+    let (taken, _) = dc.drain().unwrap().next().unwrap().unwrap();
+    assert_eq!(1, dc.keys().unwrap().len());
+    assert!(dc.get(&taken).unwrap().is_none());
+}
+
+#[test]
+fn snapshot_stays_the_same() {
+    let tmp = tempfile::TempDir::with_prefix("snapshot_stays_the_same").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::RetainAndWrite)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("nested/k2");
+    dc.insert(k1, b"one".to_vec()).unwrap();
+    dc.insert(k2, b"two".to_vec()).unwrap();
+    dc.insert_negative(Path::new("negative"), Duration::from_secs(30))
+        .unwrap();
+    let snapshot = dc.snapshot().unwrap();
+    let with_values = dc.snapshot_with_values().unwrap();
+    dc.insert(k1, b"changed".to_vec()).unwrap();
+    dc.remove(k2).unwrap();
+    dc.insert(Path::new("k3"), b"three".to_vec()).unwrap();
+
+    assert_eq!(vec![k1, k2], snapshot.keys().collect::<Vec<_>>());
+    assert!(snapshot.get(k1).unwrap().value().is_none());
+    assert_eq!(
+        3,
+        snapshot.get(k2).unwrap().meta().in_memory_size().unwrap()
+    );
+    assert_eq!(2, with_values.len());
+    assert_eq!(Some(&b"one"[..]), with_values.get(k1).unwrap().value());
+    assert_eq!(Some(&b"two"[..]), with_values.get(k2).unwrap().value());
+    assert_eq!(b"changed", dc.get(k1).unwrap().unwrap().as_ref());
+    assert!(dc.snapshot().unwrap().get(k2).is_none());
+}
+
+#[test]
+fn backup_to_another_directory() {
+    let tmp = tempfile::TempDir::with_prefix("backup_to_another_directory").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_content_addressed(true)
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ));
+    let mut dc = opts
+        .open(
+            &tmp.path().join("cache"),
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    let shared = Path::new("shared");
+    let nested = Path::new("nested/key");
+    dc.insert(shared, b"gen1".to_vec()).unwrap();
+    dc.insert(shared, dummy_content().to_vec()).unwrap();
+    // Only in memory until backed up
+    dc.insert_opt(
+        nested,
+        dummy_content().to_vec(),
+        opts.with_mem_push_opt(MemPushOpt::MemoryOnly),
+    )
+    .unwrap();
+    let occupied = tmp.path().join("occupied");
+    std::fs::create_dir_all(&occupied).unwrap();
+    std::fs::write(occupied.join("file"), b"content").unwrap();
+    assert!(matches!(dc.backup_to(&occupied), Err(Error::Open(_))));
+    assert!(matches!(
+        dc.backup_to(&tmp.path().join("cache").join("inside")),
+        Err(Error::Open(_))
+    ));
+    let backup = tmp.path().join("backup");
+    dc.backup_to(&backup).unwrap();
+    drop(dc);
+
+    let mut restored = opts
+        .open(
+            &backup,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false).with_require_marker(true),
+        )
+        .unwrap();
+    assert!(restored.verify().unwrap().is_ok());
+    assert_eq!(
+        dummy_content(),
+        restored.get(shared).unwrap().unwrap().as_ref()
+    );
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        restored.get_generation(shared, 1).unwrap()
+    );
+    assert_eq!(
+        dummy_content(),
+        restored.get(nested).unwrap().unwrap().as_ref()
+    );
+}
+
+#[test]
+fn mirror_writes_and_restore_from_mirror() {
+    let tmp = tempfile::TempDir::with_prefix("mirror_writes_and_restore_from_mirror").unwrap();
+    let cache_dir = tmp.path().join("cache");
+    let mirror_dir = tmp.path().join("mirror");
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let open_with = |mirror_opt: MirrorOpt| {
+        opts.open(
+            &cache_dir,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false).with_mirror_opt(mirror_opt),
+        )
+        .unwrap()
+    };
+    let open = || open_with(MirrorOpt::new(&mirror_dir));
+    let mut dc = open();
+    let written = Path::new("written");
+    let in_mem = Path::new("nested/in_mem");
+    let removed = Path::new("removed");
+    dc.insert(written, b"gen1".to_vec()).unwrap();
+    dc.insert(written, b"gen0".to_vec()).unwrap();
+    dc.insert(removed, b"removed".to_vec()).unwrap();
+    dc.insert_opt(
+        in_mem,
+        b"in_mem".to_vec(),
+        opts.with_mem_push_opt(MemPushOpt::MemoryOnly),
+    )
+    .unwrap();
+    for (gen, content) in [(0, b"gen0"), (1, b"gen1")] {
+        assert_eq!(
+            content.as_slice(),
+            std::fs::read(
+                mirror_dir
+                    .join(written)
+                    .join(format!("dir-cache-generation-{gen}"))
+            )
+            .unwrap()
+        );
+    }
+    assert!(mirror_dir.join(removed).exists());
+    assert!(!mirror_dir.join(in_mem).exists());
+    dc.sync().unwrap();
+    assert_eq!(
+        b"in_mem".as_slice(),
+        std::fs::read(mirror_dir.join(in_mem).join("dir-cache-generation-0")).unwrap()
+    );
+    dc.remove(removed).unwrap();
+    assert!(!mirror_dir.join(removed).exists());
+    drop(dc);
+
+    // Lost from the cache, but still in the mirror
+    std::fs::remove_dir_all(cache_dir.join(written)).unwrap();
+    let mut dc = open();
+    assert_eq!(b"gen0", dc.get(written).unwrap().unwrap().as_ref());
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        dc.get_generation(written, 1).unwrap()
+    );
+    assert!(cache_dir
+        .join(written)
+        .join("dir-cache-manifest.txt")
+        .exists());
+    assert!(dc.get(removed).unwrap().is_none());
+    drop(dc);
+
+    // A mirror that can't be written to
+    let blocked = tmp.path().join("blocked");
+    std::fs::write(&blocked, b"not a directory").unwrap();
+    let blocked = blocked.join("mirror");
+    let mut dc = open_with(MirrorOpt::new(&blocked));
+    assert!(dc.insert(written, b"fails".to_vec()).is_err());
+    drop(dc);
+    let mut dc = open_with(MirrorOpt::new(&blocked).with_error_opt(MirrorErrorOpt::Ignore));
+    dc.insert(written, b"ignored".to_vec()).unwrap();
+    assert_eq!(b"ignored", dc.get(written).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn overlay_over_lower_layer() {
+    let tmp = tempfile::TempDir::with_prefix("overlay_over_lower_layer").unwrap();
+    let lower_dir = tmp.path().join("lower");
+    let opts = DirCacheOpts::default()
+        .with_content_addressed(true)
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ));
+    let seeded = Path::new("nested/seeded");
+    let shadowed = Path::new("shadowed");
+    let mut lower = opts
+        .open(
+            &lower_dir,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    lower.insert(seeded, b"gen1".to_vec()).unwrap();
+    lower.insert(seeded, dummy_content().to_vec()).unwrap();
+    lower.insert(shadowed, b"lower".to_vec()).unwrap();
+    drop(lower);
+
+    let mut upper = opts
+        .open(
+            &tmp.path().join("upper"),
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false).with_lower_layer(&lower_dir),
+        )
+        .unwrap();
+    assert!(upper.keys().unwrap().is_empty());
+    assert_eq!(
+        dummy_content(),
+        upper.get(seeded).unwrap().unwrap().as_ref()
+    );
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        upper.get_generation(seeded, 1).unwrap()
+    );
+    upper.insert(shadowed, b"upper".to_vec()).unwrap();
+    assert_eq!(b"upper", upper.get(shadowed).unwrap().unwrap().as_ref());
+    assert!(upper.verify().unwrap().is_ok());
+    drop(upper);
+
+    // The lower layer is left as it was
+    let mut lower = opts
+        .open(
+            &lower_dir,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"lower", lower.get(shadowed).unwrap().unwrap().as_ref());
+    assert_eq!(None, lower.get_generation(shadowed, 1).unwrap());
+}
+
+#[test]
+fn read_through_fallback() {
+    let tmp = tempfile::TempDir::with_prefix("read_through_fallback").unwrap();
+    let old_dir = tmp.path().join("old");
+    let mut old = DirCacheOpts::default()
+        .open(
+            &old_dir,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    let warm = Path::new("nested/warm");
+    old.insert(warm, dummy_content().to_vec()).unwrap();
+    old.insert(dummy_key(), b"old".to_vec()).unwrap();
+    drop(old);
+
+    let open_options = CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false);
+    let mut dc = DirCacheOpts::default()
+        .open(
+            &tmp.path().join("read_only"),
+            open_options
+                .clone()
+                .with_fallback_opt(FallbackOpt::new(&old_dir)),
+        )
+        .unwrap();
+    dc.insert(dummy_key(), b"new".to_vec()).unwrap();
+    assert_eq!(b"new", dc.get(dummy_key()).unwrap().unwrap().as_ref());
+    assert_eq!(dummy_content(), dc.get(warm).unwrap().unwrap().as_ref());
+    assert!(dc.get(Path::new("cold")).unwrap().is_none());
+    // Read without being copied
+    assert_eq!(vec![dummy_key().to_path_buf()], dc.keys().unwrap());
+    // Borrowing copies forward
+    assert_eq!(dummy_content(), dc.get_ref(warm).unwrap().unwrap());
+    assert_eq!(2, dc.keys().unwrap().len());
+    drop(dc);
+
+    let mut dc = DirCacheOpts::default()
+        .open(
+            &tmp.path().join("copy_forward"),
+            open_options.with_fallback_opt(FallbackOpt::new(&old_dir).with_copy_forward(true)),
+        )
+        .unwrap();
+    assert_eq!(dummy_content(), dc.get(warm).unwrap().unwrap().as_ref());
+    assert_eq!(vec![warm.to_path_buf()], dc.keys().unwrap());
+    assert!(dc.verify().unwrap().is_ok());
+
+    // The fallback is left as it was
+    let mut old = DirCacheOpts::default()
+        .open(
+            &old_dir,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"old", old.get(dummy_key()).unwrap().unwrap().as_ref());
+    assert_eq!(2, old.keys().unwrap().len());
+}
+
+#[test]
+fn skip_generations_with_unknown_encodings() {
+    let tmp = tempfile::TempDir::with_prefix("skip_generations_with_unknown_encodings").unwrap();
+    let older_xor = GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Custom(&XOR_CODEC),
+        ExpirationOpt::NoExpiry,
+    )
+    .with_current_gen_encoding(Encoding::Plain);
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(older_xor)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let key = dummy_key();
+    for val in ["gen2", "gen1", "gen0"] {
+        dc.insert(key, val.as_bytes().to_vec()).unwrap();
+    }
+    let all_xor = Path::new("all_xor");
+    dc.insert_opt(
+        all_xor,
+        b"xor".to_vec(),
+        DirCacheOpts::default()
+            .with_generation_opt(older_xor.with_current_gen_encoding(Encoding::Custom(&XOR_CODEC))),
+    )
+    .unwrap();
+    drop(dc);
+
+    let unregistered = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let strict = unregistered.open(
+        tmp.path(),
+        CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+            .with_invalid_entry_opt(InvalidEntryOpt::SkipInvalid),
+    );
+    assert_eq!(
+        HashSet::from([all_xor, key]),
+        strict
+            .unwrap()
+            .invalid_entries()
+            .iter()
+            .map(InvalidEntry::key)
+            .collect::<HashSet<_>>()
+    );
+    let mut skipping = unregistered
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+                .with_invalid_entry_opt(InvalidEntryOpt::SkipInvalid)
+                .with_unknown_encoding_opt(UnknownEncodingOpt::SkipGenerations),
+        )
+        .unwrap();
+    // The current generation can't be skipped
+    assert_eq!(1, skipping.invalid_entries().len());
+    assert_eq!(all_xor, skipping.invalid_entries()[0].key());
+    assert_eq!(b"gen0", skipping.get(key).unwrap().unwrap().as_ref());
+    assert_eq!(1, skipping.generations(key).unwrap().count());
+    assert_eq!(None, skipping.get_generation(key, 1).unwrap());
+    drop(skipping);
+
+    // Still on disk for a build that can decode them
+    let mut registered = DirCacheOpts::default()
+        .with_generation_opt(older_xor)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(
+        Some(b"gen2".to_vec()),
+        registered.get_generation(key, 2).unwrap()
+    );
+}
+
+#[test]
+fn compact_migrates_existing_data() {
+    let tmp = tempfile::TempDir::with_prefix("compact_migrates_existing_data").unwrap();
+    let open = |generation_opt: GenerationOpt| {
+        DirCacheOpts::default()
+            .with_generation_opt(generation_opt)
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            )
+            .unwrap()
+    };
+    let mut dc = open(GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let my_key = dummy_key();
+    for val in ["gen2", "gen1", "gen0"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    // Empty directories, as left behind by other tools
+    std::fs::create_dir_all(tmp.path().join("nested").join("key")).unwrap();
+    drop(dc);
+
+    let mut dc = open(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Custom(&XOR_CODEC),
+        ExpirationOpt::NoExpiry,
+    ));
+    dc.compact().unwrap();
+    let path = tmp.path().join(my_key);
+    assert_eq!(vec!["0", "3:xor"], manifest_generations(&path));
+    assert_eq!(
+        XOR_CODEC.encode(b"gen1").unwrap(),
+        std::fs::read(path.join("dir-cache-generation-1")).unwrap()
+    );
+    assert!(!path.join("dir-cache-generation-2").exists());
+    assert!(!tmp.path().join("nested").exists());
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn delta_generations() {
+    let tmp = tempfile::TempDir::with_prefix("delta_generations").unwrap();
+    let open = |delta_generations: bool| {
+        DirCacheOpts::default()
+            .with_generation_opt(
+                GenerationOpt::new(
+                    NonZeroUsize::new(3).unwrap(),
+                    Encoding::Plain,
+                    ExpirationOpt::NoExpiry,
+                )
+                .with_delta_generations(delta_generations),
+            )
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            )
+            .unwrap()
+    };
+    let gen2 = (0..16_384u32)
+        .flat_map(|i| i.wrapping_mul(2_654_435_761).to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut gen1 = gen2.clone();
+    gen1[1_000..1_004].copy_from_slice(b"gen1");
+    let mut gen0 = gen1.clone();
+    gen0.splice(50_000..50_000, b"gen0".iter().copied());
+    let mut dc = open(true);
+    let my_key = dummy_key();
+    for val in [&gen2, &gen1, &gen0] {
+        dc.insert(my_key, val.clone()).unwrap();
+    }
+    let path = tmp.path().join(my_key);
+    let manifest_flags = || manifest_generations(&path);
+    assert_eq!(vec!["0", "0,d", "0,d"], manifest_flags());
+    assert_eq!(
+        gen0,
+        std::fs::read(path.join("dir-cache-generation-0")).unwrap()
+    );
+    for gen in 1..3 {
+        let delta = std::fs::read(path.join(format!("dir-cache-generation-{gen}"))).unwrap();
+        assert!(delta.len() < 1024, "delta was {} bytes", delta.len());
+    }
+    assert_eq!(&gen0, dc.get(my_key).unwrap().unwrap().as_ref());
+    drop(dc);
+
+    // Compacting without delta generations restores the full values
+    let mut dc = open(false);
+    dc.compact().unwrap();
+    assert_eq!(vec!["0", "0", "0"], manifest_flags());
+    for (gen, val) in [(1, &gen1), (2, &gen2)] {
+        assert_eq!(
+            val,
+            &std::fs::read(path.join(format!("dir-cache-generation-{gen}"))).unwrap()
+        );
+    }
+    drop(dc);
+
+    // And compacting with them turns them back into deltas
+    let mut dc = open(true);
+    dc.compact().unwrap();
+    assert_eq!(vec!["0", "0,d", "0,d"], manifest_flags());
+    drop(dc);
+    let mut dc = open(false);
+    dc.compact().unwrap();
+    assert_eq!(
+        gen2,
+        std::fs::read(path.join("dir-cache-generation-2")).unwrap()
+    );
+}
+
+#[test]
+fn verify_on_read_detects_corruption() {
+    let tmp = tempfile::TempDir::with_prefix("verify_on_read_detects_corruption").unwrap();
+    let opts = DirCacheOpts::default().with_verify_on_read(true);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, dummy_content().to_vec()).unwrap();
+    drop(dc);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(dummy_content(), dc.get(my_key).unwrap().unwrap().as_ref());
+    drop(dc);
+    std::fs::write(
+        tmp.path().join(my_key).join("dir-cache-generation-0"),
+        b"Dummy c0ntent!",
+    )
+    .unwrap();
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert!(matches!(dc.get(my_key), Err(Error::ChecksumMismatch(_))));
+    drop(dc);
+    // Eager loading verifies as well
+    assert!(matches!(
+        opts.open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, true),
+        ),
+        Err(Error::ChecksumMismatch(_))
+    ));
+    // Without verification, the corrupted content is handed back
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(b"Dummy c0ntent!", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn verify_reports_inconsistencies() {
+    let tmp = tempfile::TempDir::with_prefix("verify_reports_inconsistencies").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let (missing, stray, corrupt, unparsable) = (
+        Path::new("missing"),
+        Path::new("stray"),
+        Path::new("nested").join("corrupt"),
+        Path::new("unparsable"),
+    );
+    for key in [missing, stray, &corrupt, unparsable] {
+        dc.insert(key, b"gen1".to_vec()).unwrap();
+        dc.insert(key, b"gen0".to_vec()).unwrap();
+    }
+    let report = dc.verify().unwrap();
+    assert!(report.is_ok());
+    assert_eq!(4, report.entries_checked());
+
+    std::fs::remove_file(tmp.path().join(missing).join("dir-cache-generation-1")).unwrap();
+    let stray_file = tmp.path().join(stray).join("dir-cache-generation-2");
+    std::fs::write(&stray_file, b"stray").unwrap();
+    std::fs::write(
+        tmp.path().join(&corrupt).join("dir-cache-generation-0"),
+        b"gen?",
+    )
+    .unwrap();
+    std::fs::write(
+        tmp.path().join(unparsable).join("dir-cache-manifest.txt"),
+        b"garbage",
+    )
+    .unwrap();
+    let report = dc.verify().unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(3, report.entries_checked());
+    let issues = report.issues();
+    assert_eq!(4, issues.len(), "{issues:?}");
+    assert!(issues.contains(&VerifyIssue::MissingGeneration {
+        key: missing.to_path_buf(),
+        generation: 1
+    }));
+    assert!(issues.contains(&VerifyIssue::UnreferencedGeneration {
+        key: stray.to_path_buf(),
+        file: stray_file
+    }));
+    assert!(issues.contains(&VerifyIssue::ChecksumMismatch {
+        key: corrupt,
+        generation: 0
+    }));
+    assert!(issues.iter().any(|issue| matches!(
+        issue,
+        VerifyIssue::UnparsableManifest { key, .. } if key == unparsable
+    )));
+}
+
+#[test]
+fn repair_on_open_after_interrupted_write() {
+    let tmp = tempfile::TempDir::with_prefix("repair_on_open_after_interrupted_write").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(4).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let my_key = dummy_key();
+    for val in ["gen2", "gen1", "gen0"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    drop(dc);
+    let path = tmp.path().join(my_key);
+    let gen_path = |gen: usize| path.join(format!("dir-cache-generation-{gen}"));
+    // The first step of writing a new generation is moving the oldest one up,
+    // pretend the write stopped right after
+    std::fs::rename(gen_path(2), gen_path(3)).unwrap();
+    // Not noticed on open since the sizes are recorded in the manifest, but when it's read
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert!(dc
+        .verify()
+        .unwrap()
+        .issues()
+        .contains(&VerifyIssue::MissingGeneration {
+            key: my_key.to_path_buf(),
+            generation: 2
+        }));
+    assert!(dc.get_generation(my_key, 2).is_err());
+    drop(dc);
+
+    let repair = open_options.with_repair_opt(RepairOpt::Repair);
+    let mut dc = opts.open(tmp.path(), repair.clone()).unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+    assert_eq!(b"gen2".as_slice(), std::fs::read(gen_path(2)).unwrap());
+    assert!(!gen_path(3).exists());
+    drop(dc);
+
+    // Losing the current generation makes the one before it current
+    std::fs::remove_file(gen_path(0)).unwrap();
+    let mut dc = opts.open(tmp.path(), repair).unwrap();
+    assert_eq!(b"gen1", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert_eq!(vec!["0", "0"], manifest_generations(&path));
+    assert_eq!(b"gen2".as_slice(), std::fs::read(gen_path(1)).unwrap());
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn recorded_sizes_detect_truncation() {
+    let tmp = tempfile::TempDir::with_prefix("recorded_sizes_detect_truncation").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let my_key = dummy_key();
+    for val in ["gen2", "gen1", "gen0"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    drop(dc);
+    let path = tmp.path().join(my_key);
+    std::fs::write(path.join("dir-cache-generation-1"), b"ge").unwrap();
+    // Sizes come from the manifest
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert_eq!(12, dc.iter_meta().unwrap().next().unwrap().1.disk_size());
+    assert_eq!(
+        vec![VerifyIssue::SizeMismatch {
+            key: my_key.to_path_buf(),
+            generation: 1,
+            recorded: 4,
+            actual: 2,
+        }],
+        dc.verify().unwrap().issues()
+    );
+    assert!(matches!(
+        dc.get_generation_opt(my_key, 1, opts.with_verify_on_read(true)),
+        Err(Error::ChecksumMismatch(_))
+    ));
+    drop(dc);
+
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            open_options.clone().with_repair_opt(RepairOpt::Repair),
+        )
+        .unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    // The truncated generation is dropped, and the one older than it takes its place
+    assert_eq!(
+        b"gen2".as_slice(),
+        dc.get_generation(my_key, 1).unwrap().unwrap()
+    );
+    assert_eq!(2, dc.generations(my_key).unwrap().count());
+    assert!(dc.verify().unwrap().is_ok());
+
+    // The size of an empty value is recorded as well
+    let empty = Path::new("empty");
+    dc.insert(empty, Vec::new()).unwrap();
+    drop(dc);
+    let generation_opt = opts.generation_opt;
+    let entry = inspect::read_entry(&tmp.path().join(empty), &generation_opt)
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some(0), entry.generations()[0].recorded_size());
+    std::fs::write(tmp.path().join(empty).join("dir-cache-generation-0"), b"x").unwrap();
+    let dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(
+        vec![VerifyIssue::SizeMismatch {
+            key: empty.to_path_buf(),
+            generation: 0,
+            recorded: 0,
+            actual: 1,
+        }],
+        dc.verify().unwrap().issues()
+    );
+}
+
+#[test]
+fn corrupt_manifest_detected_by_checksum() {
+    let tmp = tempfile::TempDir::with_prefix("corrupt_manifest_detected_by_checksum").unwrap();
+    let opts = DirCacheOpts::default();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let (intact, torn) = (Path::new("intact"), Path::new("torn"));
+    dc.insert(intact, dummy_content().to_vec()).unwrap();
+    dc.insert(torn, dummy_content().to_vec()).unwrap();
+    drop(dc);
+    // Manifests from before checksums are still read, and migrated
+    let intact_manifest = tmp.path().join(intact).join("dir-cache-manifest.txt");
+    let content = std::fs::read(&intact_manifest).unwrap();
+    let mut unchecked = content[..content.len() - 8].to_vec();
+    assert_eq!(3, unchecked[3]);
+    unchecked[3] = 2;
+    std::fs::write(&intact_manifest, unchecked).unwrap();
+    // Cut off as if by a write that didn't finish
+    let torn_manifest = tmp.path().join(torn).join("dir-cache-manifest.txt");
+    let content = std::fs::read(&torn_manifest).unwrap();
+    std::fs::write(&torn_manifest, &content[..content.len() - 1]).unwrap();
+    assert!(matches!(
+        opts.open(tmp.path(), open_options.clone()),
+        Err(Error::ChecksumMismatch(_))
+    ));
+    let dc = opts
+        .open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_invalid_entry_opt(InvalidEntryOpt::SkipInvalid),
+        )
+        .unwrap();
+    assert_eq!(torn, dc.invalid_entries()[0].key());
+    drop(dc);
+
+    let mut dc = opts
+        .open(tmp.path(), open_options.with_repair_opt(RepairOpt::Repair))
+        .unwrap();
+    assert!(dc.invalid_entries().is_empty());
+    assert!(dc.get(torn).unwrap().is_none());
+    assert!(!tmp.path().join(torn).exists());
+    assert_eq!(dummy_content(), dc.get(intact).unwrap().unwrap().as_ref());
+    assert_eq!(1, dc.migrate_manifests().unwrap());
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn invalid_entries_on_open() {
+    let tmp = tempfile::TempDir::with_prefix("invalid_entries_on_open").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    let (valid, invalid) = (Path::new("valid"), Path::new("invalid"));
+    dc.insert(valid, dummy_content().to_vec()).unwrap();
+    dc.insert(invalid, dummy_content().to_vec()).unwrap();
+    drop(dc);
+    let manifest = tmp.path().join(invalid).join("dir-cache-manifest.txt");
+    std::fs::write(&manifest, b"garbage").unwrap();
+    assert!(matches!(
+        DirCacheOpts::default().open(tmp.path(), open_options.clone()),
+        Err(Error::ParseMetadata(_))
+    ));
+
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_invalid_entry_opt(InvalidEntryOpt::SkipInvalid),
+        )
+        .unwrap();
+    assert_eq!(1, dc.invalid_entries().len());
+    assert_eq!(invalid, dc.invalid_entries()[0].key());
+    assert!(dc.get(invalid).unwrap().is_none());
+    assert_eq!(dummy_content(), dc.get(valid).unwrap().unwrap().as_ref());
+    drop(dc);
+    assert!(manifest.exists());
+
+    let dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_invalid_entry_opt(InvalidEntryOpt::PurgeInvalid),
+        )
+        .unwrap();
+    assert_eq!(1, dc.invalid_entries().len());
+    drop(dc);
+    assert!(!tmp.path().join(invalid).exists());
+    let dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options)
+        .unwrap();
+    assert!(dc.invalid_entries().is_empty());
+}
+
+#[test]
+fn vacuum_removes_expired_and_orphaned() {
+    let tmp = tempfile::TempDir::with_prefix("vacuum_removes_expired_and_orphaned").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::ExpiresAfter(Duration::from_millis(200)),
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let (expiring, kept) = (Path::new("nested").join("expiring"), Path::new("kept"));
+    dc.insert(&expiring, dummy_content().to_vec()).unwrap();
+    std::thread::sleep(Duration::from_millis(250));
+    dc.insert(kept, dummy_content().to_vec()).unwrap();
+    let stray = tmp.path().join(kept).join("dir-cache-generation-1");
+    std::fs::write(&stray, b"stray").unwrap();
+    let orphan_dir = tmp.path().join("orphan");
+    std::fs::create_dir(&orphan_dir).unwrap();
+    std::fs::write(orphan_dir.join("dir-cache-generation-0"), b"orphan").unwrap();
+
+    dc.vacuum().unwrap();
+    assert!(!tmp.path().join("nested").exists());
+    assert!(!stray.exists());
+    assert!(!orphan_dir.exists());
+    assert!(tmp
+        .path()
+        .join(kept)
+        .join("dir-cache-generation-0")
+        .exists());
+    assert!(dc.verify().unwrap().is_ok());
+    assert!(dc.get(&expiring).unwrap().is_none());
+    assert_eq!(dummy_content(), dc.get(kept).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn writes_replace_files_through_a_temporary_file() {
+    let tmp =
+        tempfile::TempDir::with_prefix("writes_replace_files_through_a_temporary_file").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options)
+        .unwrap();
+    let key = Path::new("k");
+    let dir = tmp.path().join(key);
+    dc.insert(key, b"first".to_vec()).unwrap();
+    let generation = dir.join("dir-cache-generation-0");
+    let outside = tmp.path().join("outside");
+    std::fs::hard_link(&generation, &outside).unwrap();
+    dc.insert(key, b"second".to_vec()).unwrap();
+    // The file is replaced, not written through, and no temporary file is left behind
+    assert_eq!(b"first", std::fs::read(&outside).unwrap().as_slice());
+    assert_eq!(b"second", std::fs::read(&generation).unwrap().as_slice());
+    let mut names = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        vec!["dir-cache-generation-0", "dir-cache-manifest.txt"],
+        names
+    );
+    // One left behind by a crash is removed as an orphan
+    let left_behind = dir.join("dir-cache-generation-0.tmp");
+    std::fs::write(&left_behind, b"partial").unwrap();
+    dc.vacuum().unwrap();
+    assert!(!left_behind.exists());
+    assert_eq!(b"second", dc.get(key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn durable_writes_round_trip() {
+    for durability_opt in [DurabilityOpt::FlushData, DurabilityOpt::Full] {
+        let tmp = tempfile::TempDir::with_prefix("durable_writes_round_trip").unwrap();
+        let opts = DirCacheOpts::default()
+            .with_durability_opt(durability_opt)
+            .with_generation_opt(GenerationOpt::new(
+                NonZeroUsize::new(2).unwrap(),
+                Encoding::Plain,
+                ExpirationOpt::NoExpiry,
+            ));
+        let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+        let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+        let my_key = dummy_key();
+        for val in ["gen2", "gen1", "gen0"] {
+            dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+        }
+        drop(dc);
+        let mut dc = opts.open(tmp.path(), open_options).unwrap();
+        assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+        assert_eq!(
+            b"gen1".as_slice(),
+            std::fs::read(tmp.path().join(my_key).join("dir-cache-generation-1")).unwrap()
+        );
+        assert!(dc.verify().unwrap().is_ok());
+    }
+}
+
+#[test]
+fn journaled_write_recovers_on_open() {
+    let tmp = tempfile::TempDir::with_prefix("journaled_write_recovers_on_open").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_journal(true)
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let my_key = dummy_key();
+    let entry_dir = tmp.path().join(my_key);
+    dc.insert(my_key, b"gen1".to_vec()).unwrap();
+    // Make moving the old generation fail after the journal has been written
+    let blocker = entry_dir.join("dir-cache-generation-1");
+    std::fs::create_dir(&blocker).unwrap();
+    std::fs::write(blocker.join("blocking"), b"").unwrap();
+    assert!(dc.insert(my_key, b"gen0".to_vec()).is_err());
+    drop(dc);
+    assert!(entry_dir.join("dir-cache-journal.bin").exists());
+    std::fs::remove_dir_all(&blocker).unwrap();
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert!(!entry_dir.join("dir-cache-journal.bin").exists());
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert_eq!(
+        b"gen1".as_slice(),
+        std::fs::read(entry_dir.join("dir-cache-generation-1")).unwrap()
+    );
+    assert!(dc.verify().unwrap().is_ok());
+    drop(dc);
+
+    // A journal that was cut short is discarded along with the new content
+    std::fs::write(entry_dir.join("dir-cache-journal.bin"), b"1\n123,0\n").unwrap();
+    std::fs::write(entry_dir.join("dir-cache-generation-pending-0"), b"torn").unwrap();
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert!(!entry_dir.join("dir-cache-journal.bin").exists());
+    assert!(!entry_dir.join("dir-cache-generation-pending-0").exists());
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn reads_and_migrates_text_manifests() {
+    let tmp = tempfile::TempDir::with_prefix("reads_and_migrates_text_manifests").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let my_key = dummy_key();
+    let path = tmp.path().join(my_key);
+    let manifest = path.join("dir-cache-manifest.txt");
+    // Laid out as by a version that wrote text manifests
+    std::fs::create_dir(&path).unwrap();
+    std::fs::write(path.join("dir-cache-generation-0"), b"gen0").unwrap();
+    std::fs::write(path.join("dir-cache-generation-1"), b"gen1").unwrap();
+    std::fs::write(
+        &manifest,
+        b"1\n1700000000000000001,0\n1690000000000000000,0\n",
+    )
+    .unwrap();
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+    assert_eq!(1, dc.migrate_manifests().unwrap());
+    assert!(std::fs::read(&manifest).unwrap().starts_with(b"DCM"));
+    assert_eq!(vec!["0", "0"], manifest_generations(&path));
+    assert_eq!(0, dc.migrate_manifests().unwrap());
+    drop(dc);
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(b"gen0", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+    assert_eq!(0, dc.migrate_manifests().unwrap());
+}
+
+#[test]
+fn open_from_key_index() {
+    let tmp = tempfile::TempDir::with_prefix("open_from_key_index").unwrap();
+    let opts = DirCacheOpts::default();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        .with_key_discovery_opt(KeyDiscoveryOpt::Index);
+    let index = tmp.path().join("dir-cache-index.bin");
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let (a, b) = (Path::new("a"), Path::new("nested/b"));
+    dc.insert(a, b"a".to_vec()).unwrap();
+    dc.insert(b, b"b".to_vec()).unwrap();
+    assert!(!index.exists());
+    dc.sync().unwrap();
+    assert!(index.exists());
+    drop(dc);
+
+    // Entries are only read when accessed, so a broken one doesn't fail open
+    std::fs::write(
+        tmp.path().join(b).join("dir-cache-manifest.txt"),
+        b"garbage",
+    )
+    .unwrap();
+    assert!(opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        )
+        .is_err());
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert_eq!(b"a", dc.get(a).unwrap().unwrap().as_ref());
+    assert!(dc.get(b).is_err());
+    dc.insert(b, b"b".to_vec()).unwrap();
+    assert_eq!(b"b", dc.get(b).unwrap().unwrap().as_ref());
+    // Adding a key invalidates the index until the next sync
+    let c = Path::new("c");
+    dc.insert(c, b"c".to_vec()).unwrap();
+    assert!(!index.exists());
+    drop(dc);
+
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    for (key, val) in [(a, b"a"), (b, b"b"), (c, b"c")] {
+        assert_eq!(val, dc.get(key).unwrap().unwrap().as_ref());
+    }
+    assert!(dc.remove(a).unwrap());
+    dc.sync().unwrap();
+    drop(dc);
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert!(dc.get(a).unwrap().is_none());
+    assert_eq!(b"c", dc.get(c).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn lazy_key_discovery() {
+    let tmp = tempfile::TempDir::with_prefix("lazy_key_discovery").unwrap();
+    let opts = DirCacheOpts::default();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let lazy_options = open_options
+        .clone()
+        .with_key_discovery_opt(KeyDiscoveryOpt::Lazy);
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    let (a, b) = (Path::new("a"), Path::new("nested/b"));
+    dc.insert(a, b"a".to_vec()).unwrap();
+    dc.insert(b, b"b".to_vec()).unwrap();
+    drop(dc);
+    std::fs::write(
+        tmp.path().join(b).join("dir-cache-manifest.txt"),
+        b"garbage",
+    )
+    .unwrap();
+
+    // Nothing is read on open, so the broken entry is only found when accessed
+    let mut dc = opts.open(tmp.path(), lazy_options.clone()).unwrap();
+    assert!(dc.invalid_entries().is_empty());
+    assert_eq!(
+        b"a",
+        dc.get_or_insert(a, || Ok::<_, Infallible>(b"new a".to_vec()))
+            .unwrap()
+            .as_ref()
+    );
+    assert!(dc.get(b).is_err());
+    assert!(dc.get(Path::new("missing")).unwrap().is_none());
+    assert!(dc
+        .get(Path::new("a/dir-cache-generation-0"))
+        .unwrap()
+        .is_none());
+    dc.insert(a, b"a1".to_vec()).unwrap();
+    drop(dc);
+
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            lazy_options.with_invalid_entry_opt(InvalidEntryOpt::SkipInvalid),
+        )
+        .unwrap();
+    assert_eq!(b"a1", dc.get(a).unwrap().unwrap().as_ref());
+    assert!(dc.get(b).unwrap().is_none());
+    assert!(dc.get(b).unwrap().is_none());
+    assert_eq!(1, dc.invalid_entries().len());
+    assert_eq!(b, dc.invalid_entries()[0].key());
+}
+
+#[test]
+fn ignore_patterns_skip_subtrees() {
+    static IGNORE: [&str; 2] = [".git", "node_*"];
+    let tmp = tempfile::TempDir::with_prefix("ignore_patterns_skip_subtrees").unwrap();
+    let opts = DirCacheOpts::default();
+    let open_options =
+        CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false).with_ignore_patterns(&IGNORE);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, dummy_content().to_vec()).unwrap();
+    drop(dc);
+    // Junk from other tools, an empty directory and something that looks like a broken entry
+    let git_refs = tmp.path().join(".git").join("refs");
+    std::fs::create_dir_all(&git_refs).unwrap();
+    let node_modules = tmp.path().join("node_modules").join("dep");
+    std::fs::create_dir_all(&node_modules).unwrap();
+    std::fs::write(node_modules.join("dir-cache-manifest.txt"), b"garbage").unwrap();
+    std::fs::write(node_modules.join("dir-cache-generation-3"), b"junk").unwrap();
+    assert!(opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        )
+        .is_err());
+
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(dummy_content(), dc.get(my_key).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+    dc.vacuum().unwrap();
+    assert!(git_refs.is_dir());
+    assert!(node_modules.join("dir-cache-generation-3").is_file());
+}
+
+#[test]
+fn max_depth_limits_traversal() {
+    let tmp = tempfile::TempDir::with_prefix("max_depth_limits_traversal").unwrap();
+    let opts = DirCacheOpts::default();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let (shallow, deep) = (Path::new("a/b"), Path::new("a/b/c"));
+    dc.insert(shallow, b"shallow".to_vec()).unwrap();
+    dc.insert(deep, b"deep".to_vec()).unwrap();
+    drop(dc);
+    let unrelated = tmp.path().join("x").join("y").join("z");
+    std::fs::create_dir_all(&unrelated).unwrap();
+    std::fs::write(unrelated.join("dir-cache-manifest.txt"), b"garbage").unwrap();
+    assert!(opts.open(tmp.path(), open_options.clone()).is_err());
+
+    let mut dc = opts
+        .open(tmp.path(), open_options.with_max_depth(2))
+        .unwrap();
+    assert_eq!(b"shallow", dc.get(shallow).unwrap().unwrap().as_ref());
+    assert!(dc.get(deep).unwrap().is_none());
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn marker_required_on_open() {
+    let tmp = tempfile::TempDir::with_prefix("marker_required_on_open").unwrap();
+    let opts = DirCacheOpts::default();
+    let require = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false).with_require_marker(true);
+    let created = tmp.path().join("created");
+    let mut dc = opts
+        .open(
+            &created,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    dc.insert(dummy_key(), dummy_content().to_vec()).unwrap();
+    drop(dc);
+    assert!(created.join(".dir-cache").is_file());
+    let mut dc = opts.open(&created, require.clone()).unwrap();
+    assert_eq!(
+        dummy_content(),
+        dc.get(dummy_key()).unwrap().unwrap().as_ref()
+    );
+    drop(dc);
+
+    let not_a_cache = tmp.path().join("not-a-cache");
+    std::fs::create_dir(&not_a_cache).unwrap();
+    std::fs::write(not_a_cache.join("some-file"), b"hi").unwrap();
+    assert!(matches!(
+        opts.open(&not_a_cache, require.clone()),
+        Err(Error::Open(_))
+    ));
+    assert!(matches!(
+        opts.open(
+            &not_a_cache,
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false).with_require_marker(true)
+        ),
+        Err(Error::Open(_))
+    ));
+    assert!(!not_a_cache.join(".dir-cache").exists());
+
+    // An empty directory is fine to take over
+    let empty = tmp.path().join("empty");
+    std::fs::create_dir(&empty).unwrap();
+    opts.open(&empty, require).unwrap();
+    assert!(empty.join(".dir-cache").is_file());
+}
+
+#[test]
+fn persisted_opts_on_open() {
+    let tmp = tempfile::TempDir::with_prefix("persisted_opts_on_open").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let three_gens = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::from_secs(30)),
+    ));
+    let one_gen = DirCacheOpts::default();
+    let dc = three_gens
+        .open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_persisted_opts_opt(PersistedOptsOpt::Strict),
+        )
+        .unwrap();
+    drop(dc);
+    assert!(tmp.path().join("dir-cache-opts.txt").is_file());
+    // The same ones are fine
+    three_gens
+        .open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_persisted_opts_opt(PersistedOptsOpt::Strict),
+        )
+        .unwrap();
+    assert!(matches!(
+        one_gen.open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_persisted_opts_opt(PersistedOptsOpt::Strict)
+        ),
+        Err(Error::OptsConflict(_))
+    ));
+    // Nothing is checked by default
+    one_gen.open(tmp.path(), open_options.clone()).unwrap();
+    let mut dc = one_gen
+        .open(
+            tmp.path(),
+            open_options
+                .clone()
+                .with_persisted_opts_opt(PersistedOptsOpt::Adopt),
+        )
+        .unwrap();
+    assert_eq!(3, dc.opts().generation_opt.max_generations.get());
+    let my_key = dummy_key();
+    for val in ["oldest", "older", "new"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(3, dc.generations(my_key).unwrap().count());
+    drop(dc);
+    // Adopting doesn't change what's recorded
+    assert!(matches!(
+        one_gen.open(
+            tmp.path(),
+            open_options.with_persisted_opts_opt(PersistedOptsOpt::Strict)
+        ),
+        Err(Error::OptsConflict(_))
+    ));
+}
+
+#[test]
+#[cfg(unix)]
+fn symlink_policies() {
+    let tmp = tempfile::TempDir::with_prefix("symlink_policies").unwrap();
+    let base = tmp.path().join("base");
+    let outside = tmp.path().join("outside");
+    std::fs::create_dir(&base).unwrap();
+    std::fs::create_dir(&outside).unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default();
+    let mut dc = opts.open(&base, open_options.clone()).unwrap();
+    dc.insert(Path::new("own"), b"own".to_vec()).unwrap();
+    drop(dc);
+    let mut dc = opts.open(&outside, open_options.clone()).unwrap();
+    dc.insert(Path::new("k"), b"outside".to_vec()).unwrap();
+    drop(dc);
+    std::os::unix::fs::symlink(&outside, base.join("ext")).unwrap();
+    // A cycle back to the base
+    std::os::unix::fs::symlink(&base, outside.join("back")).unwrap();
+    std::os::unix::fs::symlink(base.join("missing"), base.join("dangling")).unwrap();
+    let linked_key = Path::new("ext/k");
+
+    let mut dc = opts.open(&base, open_options.clone()).unwrap();
+    assert_eq!(b"own", dc.get(Path::new("own")).unwrap().unwrap().as_ref());
+    assert!(dc.get(linked_key).unwrap().is_none());
+    assert!(dc.verify().unwrap().is_ok());
+    drop(dc);
+
+    assert!(matches!(
+        opts.open(
+            &base,
+            open_options.clone().with_symlink_opt(SymlinkOpt::Refuse)
+        ),
+        Err(Error::ReadContent(_, None))
+    ));
+
+    let mut dc = opts
+        .open(&base, open_options.with_symlink_opt(SymlinkOpt::Follow))
+        .unwrap();
+    assert_eq!(b"own", dc.get(Path::new("own")).unwrap().unwrap().as_ref());
+    assert_eq!(b"outside", dc.get(linked_key).unwrap().unwrap().as_ref());
+    assert!(dc.get(Path::new("ext/back/own")).unwrap().is_none());
+    let report = dc.verify().unwrap();
+    assert!(report.is_ok());
+    assert_eq!(2, report.entries_checked());
+}
+
+#[test]
+#[cfg(unix)]
+fn link_identical_generations() {
+    use std::os::unix::fs::MetadataExt;
+    let tmp = tempfile::TempDir::with_prefix("link_identical_generations").unwrap();
+    let open = |journal: bool| {
+        DirCacheOpts::default()
+            .with_journal(journal)
+            .with_generation_opt(
+                GenerationOpt::new(
+                    NonZeroUsize::new(3).unwrap(),
+                    Encoding::Plain,
+                    ExpirationOpt::NoExpiry,
+                )
+                .with_link_identical(true),
+            )
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            )
+            .unwrap()
+    };
+    for journal in [false, true] {
+        let my_key = Path::new(if journal { "journaled" } else { "plain" });
+        let path = tmp.path().join(my_key);
+        let inode = |gen: usize| {
+            std::fs::metadata(path.join(format!("dir-cache-generation-{gen}")))
+                .unwrap()
+                .ino()
+        };
+        let mut dc = open(journal);
+        dc.insert(my_key, b"stable".to_vec()).unwrap();
+        dc.insert(my_key, b"stable".to_vec()).unwrap();
+        assert_eq!(vec!["0,l", "0"], manifest_generations(&path));
+        assert_eq!(inode(0), inode(1));
+        dc.insert(my_key, b"stable".to_vec()).unwrap();
+        assert_eq!(vec!["0,l", "0,l", "0"], manifest_generations(&path));
+        assert_eq!(inode(0), inode(2));
+        assert!(dc.verify().unwrap().is_ok());
+        // A new value gets its own file, leaving the older ones intact
+        dc.insert(my_key, b"changed".to_vec()).unwrap();
+        assert_eq!(vec!["0", "0,l", "0"], manifest_generations(&path));
+        assert_ne!(inode(0), inode(1));
+        assert_eq!(inode(1), inode(2));
+        assert_eq!(b"changed", dc.get(my_key).unwrap().unwrap().as_ref());
+        drop(dc);
+        let mut dc = open(journal);
+        assert_eq!(b"changed", dc.get(my_key).unwrap().unwrap().as_ref());
+        for gen in 1..3 {
+            assert_eq!(
+                b"stable",
+                std::fs::read(path.join(format!("dir-cache-generation-{gen}")))
+                    .unwrap()
+                    .as_slice()
+            );
+        }
+        assert!(dc.verify().unwrap().is_ok());
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn content_addressed_values() {
+    use std::os::unix::fs::MetadataExt;
+    let tmp = tempfile::TempDir::with_prefix("content_addressed_values").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_content_addressed(true)
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let blobs = tmp.path().join("dir-cache-blobs");
+    let pooled = || {
+        let mut names = std::fs::read_dir(&blobs)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let inode = |key: &str, gen: usize| {
+        std::fs::metadata(
+            tmp.path()
+                .join(key)
+                .join(format!("dir-cache-generation-{gen}")),
+        )
+        .unwrap()
+        .ino()
+    };
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    dc.insert(Path::new("a"), b"shared".to_vec()).unwrap();
+    dc.insert(Path::new("b/c"), b"shared".to_vec()).unwrap();
+    let pool = pooled();
+    assert_eq!(1, pool.len());
+    let blob_inode = std::fs::metadata(&pool[0]).unwrap().ino();
+    assert_eq!(blob_inode, inode("a", 0));
+    assert_eq!(blob_inode, inode("b/c", 0));
+    // The pool is neither a key nor reachable as one
+    assert!(matches!(
+        dc.insert(Path::new("dir-cache-blobs/a"), b"value".to_vec()),
+        Err(Error::DangerousKey(_))
+    ));
+    assert!(dc.verify().unwrap().is_ok());
+    drop(dc);
+
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(b"shared", dc.get(Path::new("a")).unwrap().unwrap().as_ref());
+    assert_eq!(
+        b"shared",
+        dc.get(Path::new("b/c")).unwrap().unwrap().as_ref()
+    );
+    assert!(dc.get(Path::new("dir-cache-blobs")).unwrap().is_none());
+    // Held by both keys, but only on disk once
+    let meta = dc.entry_meta(Path::new("b/c")).unwrap().unwrap();
+    assert_eq!(0, meta.disk_size());
+    assert_eq!(6, meta.pooled_size());
+    assert_eq!(6, dc.summary().unwrap().disk_bytes());
+    // Still referenced by the older generation of a
+    dc.insert(Path::new("a"), b"other".to_vec()).unwrap();
+    assert_eq!(2, pooled().len());
+    assert_eq!(blob_inode, inode("a", 1));
+    assert!(dc.remove(Path::new("b/c")).unwrap());
+    assert_eq!(2, pooled().len());
+    // Rotated out of a as well
+    dc.insert(Path::new("a"), b"other".to_vec()).unwrap();
+    assert_eq!(1, pooled().len());
+    assert_eq!(inode("a", 0), inode("a", 1));
+    assert_eq!(b"other", dc.get(Path::new("a")).unwrap().unwrap().as_ref());
+    assert!(dc.remove(Path::new("a")).unwrap());
+    assert!(pooled().is_empty());
+
+    // Values nothing references are cleaned up on vacuum
+    let stray = blobs.join("0123456789abcdef0123456789abcdef");
+    std::fs::write(&stray, b"stray").unwrap();
+    dc.vacuum().unwrap();
+    assert!(pooled().is_empty());
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_storage() {
+    use dir_cache::opts::StorageOpt;
+    use std::io::{Read, Write};
+    let tmp = tempfile::TempDir::with_prefix("sqlite_storage").unwrap();
+    let base = tmp.path().join("base");
+    let opts = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .with_content_addressed(true)
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false)
+        .with_require_marker(true)
+        .with_storage_opt(StorageOpt::Sqlite);
+    let nested = Path::new("nested/key");
+    let mut dc = opts.open(&base, open_options.clone()).unwrap();
+    dc.insert(dummy_key(), b"first".to_vec()).unwrap();
+    dc.insert(dummy_key(), dummy_content().to_vec()).unwrap();
+    dc.insert(nested, dummy_content().to_vec()).unwrap();
+    let mut writer = dc.entry_writer(Path::new("streamed")).unwrap();
+    writer.write_all(b"streamed").unwrap();
+    writer.finish().unwrap();
+    let artifact = tmp.path().join("artifact");
+    std::fs::write(&artifact, b"moved").unwrap();
+    dc.insert_file(Path::new("ingested"), &artifact, IngestOpt::Move)
+        .unwrap();
+    assert!(!artifact.exists());
+    assert!(matches!(
+        dc.get_path(dummy_key()),
+        Err(Error::Unsupported(_))
+    ));
+    // Identical values are pooled the same way
+    assert_eq!(32, dc.summary().unwrap().disk_bytes());
+    drop(dc);
+    // Nothing but the database on disk
+    assert_eq!(
+        HashSet::from([base.join("dir-cache.sqlite3")]),
+        all_files_in(&base)
+    );
+
+    let mut dc = opts.open(&base, open_options).unwrap();
+    assert_eq!(4, dc.keys().unwrap().len());
+    assert_eq!(
+        dummy_content(),
+        dc.get(dummy_key()).unwrap().unwrap().as_ref()
+    );
+    assert_eq!(
+        b"first",
+        dc.get_generation(dummy_key(), 1)
+            .unwrap()
+            .unwrap()
+            .as_slice()
+    );
+    let mut streamed = Vec::new();
+    dc.entry_reader(Path::new("streamed"))
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut streamed)
+        .unwrap();
+    assert_eq!(b"streamed", streamed.as_slice());
+    assert_eq!(
+        b"moved",
+        dc.get(Path::new("ingested")).unwrap().unwrap().as_ref()
+    );
+    assert!(dc.verify().unwrap().is_ok());
+    assert!(dc.remove(dummy_key()).unwrap());
+    dc.vacuum().unwrap();
+    assert_eq!(dummy_content(), dc.get(nested).unwrap().unwrap().as_ref());
+    assert!(dc.remove(nested).unwrap());
+    assert_eq!(13, dc.summary().unwrap().disk_bytes());
+    drop(dc);
+
+    // A cache in a directory doesn't see what's in the database
+    let mut dc = opts
+        .open(
+            &base,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert!(dc.keys().unwrap().is_empty());
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_storage_keeps_files_outside_the_cache_on_disk() {
+    use dir_cache::opts::StorageOpt;
+    let tmp =
+        tempfile::TempDir::with_prefix("sqlite_storage_keeps_files_outside_the_cache_on_disk")
+            .unwrap();
+    let base = tmp.path().join("base");
+    let mirror_dir = base.join("mirror");
+    let open_options = CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false)
+        .with_storage_opt(StorageOpt::Sqlite)
+        .with_mirror_opt(MirrorOpt::new(&mirror_dir));
+    let mut dc = DirCacheOpts::default()
+        .open(&base, open_options.clone())
+        .unwrap();
+    // Read from the file system even though it's inside the cache's directory
+    let artifact = base.join("artifact");
+    std::fs::write(&artifact, b"ingested").unwrap();
+    dc.insert_file(dummy_key(), &artifact, IngestOpt::Copy)
+        .unwrap();
+    assert_eq!(b"ingested", dc.get(dummy_key()).unwrap().unwrap().as_ref());
+    // And mirrored onto it
+    assert_eq!(
+        b"ingested".as_slice(),
+        std::fs::read(mirror_dir.join(dummy_key()).join("dir-cache-generation-0"))
+            .unwrap()
+            .as_slice()
+    );
+    let backup = tmp.path().join("backup");
+    dc.backup_to(&backup).unwrap();
+    let mut backed_up = DirCacheOpts::default()
+        .open(
+            &backup,
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(
+        b"ingested",
+        backed_up.get(dummy_key()).unwrap().unwrap().as_ref()
+    );
+    // Another cache opened on the same directory reads the same database
+    let mut other = DirCacheOpts::default().open(&base, open_options).unwrap();
+    assert_eq!(
+        b"ingested",
+        other.get(dummy_key()).unwrap().unwrap().as_ref()
+    );
+}
+
+#[test]
+fn insert_file_ingests_existing_files() {
+    let tmp = tempfile::TempDir::with_prefix("insert_file_ingests_existing_files").unwrap();
+    let base = tmp.path().join("base");
+    let artifacts = tmp.path().join("artifacts");
+    std::fs::create_dir(&artifacts).unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false);
+    let my_key = dummy_key();
+    let artifact = artifacts.join("artifact");
+    let mut dc = opts.open(&base, open_options.clone()).unwrap();
+    std::fs::write(&artifact, b"copied").unwrap();
+    dc.insert_file(my_key, &artifact, IngestOpt::Copy).unwrap();
+    assert!(artifact.exists());
+    std::fs::write(&artifact, b"moved").unwrap();
+    dc.insert_file(my_key, &artifact, IngestOpt::Move).unwrap();
+    assert!(!artifact.exists());
+    assert_eq!(b"moved", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert!(matches!(
+        dc.insert_file(my_key, &artifact, IngestOpt::Copy),
+        Err(Error::ReadContent(_, None))
+    ));
+    // The journal works the same way
+    std::fs::write(&artifact, b"journaled").unwrap();
+    dc.insert_file_opt(my_key, &artifact, IngestOpt::Copy, opts.with_journal(true))
+        .unwrap();
+    assert!(dc.verify().unwrap().is_ok());
+    drop(dc);
+
+    let mut dc = opts
+        .with_verify_on_read(true)
+        .open(&base, open_options)
+        .unwrap();
+    assert_eq!(b"journaled", dc.get(my_key).unwrap().unwrap().as_ref());
+    let path = base.join(my_key);
+    assert_eq!(
+        b"moved",
+        std::fs::read(path.join("dir-cache-generation-1"))
+            .unwrap()
+            .as_slice()
+    );
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn get_path_flushes_in_memory_values() {
+    let tmp = tempfile::TempDir::with_prefix("get_path_flushes_in_memory_values").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::MemoryOnly)
+        .with_sync_opt(SyncOpt::ManualSync);
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let my_key = dummy_key();
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert!(dc.get_path(my_key).unwrap().is_none());
+    dc.insert(my_key, b"in memory".to_vec()).unwrap();
+    let path = dc.get_path(my_key).unwrap().unwrap();
+    assert_eq!(tmp.path().join(my_key).join("dir-cache-generation-0"), path);
+    assert_eq!(b"in memory", std::fs::read(&path).unwrap().as_slice());
+    // Nothing left to sync, the value survives without it
+    drop(dc);
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(b"in memory", dc.get(my_key).unwrap().unwrap().as_ref());
+    assert_eq!(path, dc.get_path(my_key).unwrap().unwrap());
+    assert!(dc.remove(my_key).unwrap());
+    assert!(dc.get_path(my_key).unwrap().is_none());
+}
+
+#[test]
+fn entry_writer_streams_values() {
+    use std::io::Write;
+    let tmp = tempfile::TempDir::with_prefix("entry_writer_streams_values").unwrap();
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let my_key = dummy_key();
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    dc.insert(my_key, b"first".to_vec()).unwrap();
+    let mut writer = dc.entry_writer(my_key).unwrap();
+    for chunk in 0..1_000u32 {
+        writer.write_all(&chunk.to_le_bytes()).unwrap();
+    }
+    writer.finish().unwrap();
+    let expected = (0..1_000u32).flat_map(u32::to_le_bytes).collect::<Vec<_>>();
+    assert_eq!(&expected, dc.get(my_key).unwrap().unwrap().as_ref());
+
+    // Dropped without finishing, nothing changes
+    let other_key = Path::new("other");
+    let mut writer = dc.entry_writer(other_key).unwrap();
+    writer.write_all(b"discarded").unwrap();
+    drop(writer);
+    let mut writer = dc.entry_writer(my_key).unwrap();
+    writer.write_all(b"discarded").unwrap();
+    drop(writer);
+    assert!(dc.get(other_key).unwrap().is_none());
+    assert!(!tmp.path().join(other_key).exists());
+    assert!(dc.verify().unwrap().is_ok());
+    drop(dc);
+
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(&expected, dc.get(my_key).unwrap().unwrap().as_ref());
+    assert_eq!(
+        b"first",
+        std::fs::read(tmp.path().join(my_key).join("dir-cache-generation-1"))
+            .unwrap()
+            .as_slice()
+    );
+}
+
+#[test]
+fn entry_reader_streams_values() {
+    use std::io::Read;
+    let tmp = tempfile::TempDir::with_prefix("entry_reader_streams_values").unwrap();
+    let value = (0..16_384u32)
+        .flat_map(|i| (i % 251).to_le_bytes())
+        .collect::<Vec<_>>();
+    #[allow(unused_mut)]
+    let mut encodings = vec![Encoding::Plain];
+    #[cfg(feature = "lz4")]
+    encodings.push(Encoding::Lz4 { level: 4 });
+    #[cfg(feature = "zstd")]
+    encodings.push(Encoding::Zstd { level: 3 });
+    for encoding in encodings {
+        let opts = DirCacheOpts::default()
+            .with_mem_pull_opt(MemPullOpt::DontKeepInMemoryOnRead)
+            .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+            .with_verify_on_read(true)
+            .with_generation_opt(GenerationOpt::new(
+                NonZeroUsize::new(1).unwrap(),
+                encoding,
+                ExpirationOpt::NoExpiry,
+            ));
+        let mut dc = opts
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            )
+            .unwrap();
+        let my_key = dummy_key();
+        assert!(dc.entry_reader(my_key).unwrap().is_none());
+        dc.insert(my_key, value.clone()).unwrap();
+        let mut read = Vec::new();
+        dc.entry_reader(my_key)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(value, read);
+        // Values in memory are read from there
+        dc.insert_opt(
+            my_key,
+            b"in memory".to_vec(),
+            opts.with_mem_push_opt(MemPushOpt::MemoryOnly),
+        )
+        .unwrap();
+        let mut read = Vec::new();
+        dc.entry_reader(my_key)
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(b"in memory", read.as_slice());
+        assert!(dc.remove(my_key).unwrap());
+    }
+}
+
+#[test]
+#[cfg(feature = "mmap")]
+fn mapped_values_outlive_changes() {
+    let tmp = tempfile::TempDir::with_prefix("mapped_values_outlive_changes").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .with_verify_on_read(true);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    assert!(dc.get_mapped(my_key).unwrap().is_none());
+    dc.insert(my_key, b"first".to_vec()).unwrap();
+    let first = dc.get_mapped(my_key).unwrap().unwrap();
+    assert!(first.is_mapped());
+    assert_eq!(b"first", first.as_ref());
+    dc.insert(my_key, b"second".to_vec()).unwrap();
+    let second = dc.get_mapped(my_key).unwrap().unwrap();
+    #[cfg(unix)]
+    {
+        assert!(dc.remove(my_key).unwrap());
+        assert!(dc.get_mapped(my_key).unwrap().is_none());
+    }
+    assert_eq!(b"first", first.as_ref());
+    assert_eq!(b"second", second.as_ref());
+    drop((first, second));
+    // Values only in memory aren't mapped
+    dc.insert_opt(
+        my_key,
+        b"in memory".to_vec(),
+        opts.with_mem_push_opt(MemPushOpt::MemoryOnly),
+    )
+    .unwrap();
+    let in_memory = dc.get_mapped(my_key).unwrap().unwrap();
+    assert!(!in_memory.is_mapped());
+    assert_eq!(b"in memory", in_memory.as_ref());
+}
+
+#[test]
+fn max_value_size_limits_values() {
+    use std::io::Write;
+    let tmp = tempfile::TempDir::with_prefix("max_value_size_limits_values").unwrap();
+    let opts = DirCacheOpts::default().with_max_value_size(4);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, b"fits".to_vec()).unwrap();
+    assert!(matches!(
+        dc.insert(my_key, b"too large".to_vec()),
+        Err(Error::ValueTooLarge(_))
+    ));
+    let mut writer = dc.entry_writer(my_key).unwrap();
+    assert!(writer.write_all(b"too large").is_err());
+    assert!(matches!(writer.finish(), Err(Error::ValueTooLarge(_))));
+    let file = tmp.path().join("outside");
+    std::fs::write(&file, b"too large").unwrap();
+    assert!(matches!(
+        dc.insert_file(my_key, &file, IngestOpt::Copy),
+        Err(Error::ValueTooLarge(_))
+    ));
+    assert_eq!(b"fits", dc.get(my_key).unwrap().unwrap().as_ref());
+
+    // Skipped values aren't cached, and don't leave the previous one behind
+    let skip = opts.with_oversize_opt(OversizeOpt::Skip);
+    dc.insert_opt(my_key, b"too large".to_vec(), skip).unwrap();
+    assert!(dc.get(my_key).unwrap().is_none());
+    assert!(!tmp.path().join(my_key).exists());
+    let val = dc
+        .get_or_insert_opt(my_key, || Ok::<_, Infallible>(b"too large".to_vec()), skip)
+        .unwrap();
+    assert_eq!(b"too large", val.as_ref());
+    dc.insert(my_key, b"fits".to_vec()).unwrap();
+    let mut writer = dc.entry_writer_opt(my_key, skip).unwrap();
+    writer.write_all(b"too large").unwrap();
+    writer.finish().unwrap();
+    assert!(dc.get(my_key).unwrap().is_none());
+    assert!(!tmp.path().join(my_key).exists());
+}
+
+#[test]
+fn get_shared_hands_out_retained_values() {
+    let tmp = tempfile::TempDir::with_prefix("get_shared_hands_out_retained_values").unwrap();
+    let opts = DirCacheOpts::default()
+        .with_mem_pull_opt(MemPullOpt::KeepInMemoryOnRead)
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    assert!(dc.get_shared(my_key).unwrap().is_none());
+    dc.insert(my_key, b"first".to_vec()).unwrap();
+    let first = dc.get_shared(my_key).unwrap().unwrap();
+    let again = dc.get_shared(my_key).unwrap().unwrap();
+    assert!(Arc::ptr_eq(&first, &again));
+    dc.insert(my_key, b"second".to_vec()).unwrap();
+    let second = dc
+        .get_shared_opt(
+            my_key,
+            opts.with_mem_pull_opt(MemPullOpt::DontKeepInMemoryOnRead),
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(b"first", first.as_ref());
+    assert_eq!(b"second", second.as_ref());
+}
+
+#[test]
+fn insert_accepts_borrowed_values() {
+    let tmp = tempfile::TempDir::with_prefix("insert_accepts_borrowed_values").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, b"static".as_slice()).unwrap();
+    assert_eq!(b"static", dc.get(my_key).unwrap().unwrap().as_ref());
+    let borrowed = String::from("borrowed");
+    dc.insert_opt(
+        my_key,
+        borrowed.as_bytes(),
+        dc.opts().with_mem_push_opt(MemPushOpt::RetainAndWrite),
+    )
+    .unwrap();
+    drop(borrowed);
+    assert_eq!(b"borrowed", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+#[cfg(feature = "toml")]
+fn opts_from_toml_file() {
+    let tmp = tempfile::TempDir::with_prefix("opts_from_toml_file").unwrap();
+    let config = tmp.path().join("config.toml");
+    std::fs::write(
+        &config,
+        r#"
+mem_push_opt = { retain_if_smaller_than = 4096 }
+sync_opt = "sync_on_drop"
+
+[generation_opt]
+max_generations = 3
+expiration = { expires_after = { secs = 3600, nanos = 0 } }
+"#,
+    )
+    .unwrap();
+    let opts = DirCacheOpts::from_toml_file(&config).unwrap();
+    assert!(matches!(
+        opts.mem_push_opt,
+        MemPushOpt::RetainIfSmallerThan(4096)
+    ));
+    assert!(matches!(opts.sync_opt, SyncOpt::SyncOnDrop));
+    assert_eq!(3, opts.generation_opt.max_generations.get());
+    assert!(format!("{:?}", opts.generation_opt).contains("expiration: ExpiresAfter(3600s)"));
+    // Everything else is left at its default
+    assert!(matches!(opts.mem_pull_opt, MemPullOpt::KeepInMemoryOnRead));
+    assert!(!opts.journal);
+    let mut dc = opts
+        .open(
+            &tmp.path().join("cache"),
+            CacheOpenOptions::new(DirOpenOpt::CreateIfMissing, false),
+        )
+        .unwrap();
+    for val in ["oldest", "older", "new"] {
+        dc.insert(dummy_key(), val.as_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(3, dc.generations(dummy_key()).unwrap().count());
+
+    std::fs::write(&config, "sync_opt = \"sometimes\"\n").unwrap();
+    assert!(matches!(
+        DirCacheOpts::from_toml_file(&config),
+        Err(Error::ParseOpts(_))
+    ));
+    assert!(matches!(
+        DirCacheOpts::from_toml_file(&tmp.path().join("missing.toml")),
+        Err(Error::ReadContent(_, _))
+    ));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn json_values_round_trip() {
+    let tmp = tempfile::TempDir::with_prefix("json_values_round_trip").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    assert!(dc.get_json::<Vec<String>>(my_key).unwrap().is_none());
+    let value = vec![("a".to_string(), 1u32), ("b".to_string(), 2)];
+    dc.insert_json(my_key, &value).unwrap();
+    assert_eq!(
+        br#"[["a",1],["b",2]]"#,
+        dc.get(my_key).unwrap().unwrap().as_ref()
+    );
+    assert_eq!(
+        value,
+        dc.get_json::<Vec<(String, u32)>>(my_key).unwrap().unwrap()
+    );
+    assert!(matches!(
+        dc.get_json::<Vec<String>>(my_key),
+        Err(Error::DeserializeValue(_))
+    ));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn typed_cache_delegates_to_inner() {
+    use dir_cache::typed::Json;
+    let tmp = tempfile::TempDir::with_prefix("typed_cache_delegates_to_inner").unwrap();
+    let dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let mut typed = TypedDirCache::<Vec<String>, _>::new(dc, Json);
+    let my_key = dummy_key();
+    assert!(typed.get(my_key).unwrap().is_none());
+    let first = typed
+        .get_or_insert(my_key, || Ok::<_, Infallible>(vec!["first".to_string()]))
+        .unwrap();
+    assert_eq!(vec!["first".to_string()], first);
+    let not_called = typed
+        .get_or_insert(my_key, || Err::<Vec<String>, _>("not called"))
+        .unwrap();
+    assert_eq!(first, not_called);
+    typed.insert(my_key, &vec!["second".to_string()]).unwrap();
+    assert_eq!(
+        vec!["second".to_string()],
+        typed.get(my_key).unwrap().unwrap()
+    );
+    assert!(matches!(
+        typed.get_or_insert(Path::new("other"), || Err::<Vec<String>, _>("failed")),
+        Err(Error::InsertWithErr(_))
+    ));
+    let mut dc = typed.into_inner();
+    assert_eq!(
+        br#"["first"]"#,
+        std::fs::read(tmp.path().join(my_key).join("dir-cache-generation-1"))
+            .unwrap()
+            .as_slice()
+    );
+    dc.insert(my_key, b"not json".as_slice()).unwrap();
+    let mut typed = TypedDirCache::<Vec<String>, _>::new(dc, Json);
+    assert!(matches!(typed.get(my_key), Err(Error::DeserializeValue(_))));
+    assert!(typed.remove(my_key).unwrap());
+}
+
+#[test]
+#[cfg(all(feature = "json", feature = "bincode", feature = "msgpack"))]
+fn typed_formats_are_recorded() {
+    use dir_cache::typed::{Bincode, Json, MsgPack};
+    let tmp = tempfile::TempDir::with_prefix("typed_formats_are_recorded").unwrap();
+    let opts = DirCacheOpts::default().with_mem_push_opt(MemPushOpt::PassthroughWrite);
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let value = vec![(String::from("a"), 1u64), (String::from("b"), u64::MAX)];
+    let bin_key = Path::new("bin");
+    let msgpack_key = Path::new("msgpack");
+    let json_key = Path::new("json");
+    let dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let mut bin = TypedDirCache::<Vec<(String, u64)>, _>::new(dc, Bincode);
+    bin.insert(bin_key, &value).unwrap();
+    let mut msgpack = TypedDirCache::<Vec<(String, u64)>, _>::new(bin.into_inner(), MsgPack);
+    msgpack.insert(msgpack_key, &value).unwrap();
+    let mut dc = msgpack.into_inner();
+    dc.insert_json(json_key, &value).unwrap();
+    drop(dc);
+
+    let dc = opts.open(tmp.path(), open_options).unwrap();
+    let mut bin = TypedDirCache::<Vec<(String, u64)>, _>::new(dc, Bincode);
+    assert_eq!(value, bin.get(bin_key).unwrap().unwrap());
+    assert!(matches!(
+        bin.get(msgpack_key),
+        Err(Error::FormatMismatch(_))
+    ));
+    assert!(matches!(bin.get(json_key), Err(Error::FormatMismatch(_))));
+    let mut msgpack = TypedDirCache::<Vec<(String, u64)>, _>::new(bin.into_inner(), MsgPack);
+    assert_eq!(value, msgpack.get(msgpack_key).unwrap().unwrap());
+    let mut json = TypedDirCache::<Vec<(String, u64)>, _>::new(msgpack.into_inner(), Json);
+    assert_eq!(value, json.get(json_key).unwrap().unwrap());
+    assert!(matches!(json.get(bin_key), Err(Error::FormatMismatch(_))));
+    let mut dc = json.into_inner();
+    assert!(matches!(
+        dc.get_json::<Vec<(String, u64)>>(bin_key),
+        Err(Error::FormatMismatch(_))
+    ));
+    // Untyped values aren't checked
+    dc.insert(json_key, br#"[["c",3]]"#.as_slice()).unwrap();
+    let mut bin = TypedDirCache::<Vec<(String, u64)>, _>::new(dc, Bincode);
+    assert!(matches!(bin.get(json_key), Err(Error::DeserializeValue(_))));
+}
+
+struct Lines;
+
+impl ValueSerializer<Vec<String>> for Lines {
+    const ID: &'static str = "lines";
+
+    fn serialize(&self, value: &Vec<String>) -> dir_cache::error::Result<Vec<u8>> {
+        if value.iter().any(|line| line.contains('\n')) {
+            return Err(Error::SerializeValue("line contains a newline".to_string()));
+        }
+        Ok(value.join("\n").into_bytes())
+    }
+
+    fn deserialize(&self, content: &[u8]) -> dir_cache::error::Result<Vec<String>> {
+        let content = std::str::from_utf8(content)
+            .map_err(|e| Error::DeserializeValue(format!("not utf8: {e}")))?;
+        Ok(content.split('\n').map(str::to_string).collect())
+    }
+}
+
+#[test]
+fn typed_cache_with_custom_serializer() {
+    let tmp = tempfile::TempDir::with_prefix("typed_cache_with_custom_serializer").unwrap();
+    let dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let mut typed = TypedDirCache::new(dc, Lines);
+    let my_key = dummy_key();
+    let value = vec!["first".to_string(), "second".to_string()];
+    typed.insert(my_key, &value).unwrap();
+    assert_eq!(value, typed.get(my_key).unwrap().unwrap());
+    assert!(matches!(
+        typed.insert(my_key, &vec!["first\nsecond".to_string()]),
+        Err(Error::SerializeValue(_))
+    ));
+    let mut dc = typed.into_inner();
+    assert_eq!(b"first\nsecond", dc.get(my_key).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn str_keys_are_escaped() {
+    let tmp = tempfile::TempDir::with_prefix("str_keys_are_escaped").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let url = "https://example.com/a?b=1";
+    assert!(dc.get_str(url).unwrap().is_none());
+    dc.insert_str(url, b"page".as_slice()).unwrap();
+    assert_eq!(b"page", dc.get_str(url).unwrap().unwrap().as_ref());
+    assert!(tmp
+        .path()
+        .join("https%3A%2F%2Fexample.com%2Fa%3Fb%3D1")
+        .join("dir-cache-generation-0")
+        .exists());
+    let val = dc
+        .get_or_insert_str("..", || Ok::<_, Infallible>(b"dots".to_vec()))
+        .unwrap();
+    assert_eq!(b"dots", val.as_ref());
+    assert!(tmp.path().join("%2E%2E").exists());
+    assert!(dc.insert_str("", b"empty".as_slice()).is_err());
+    assert!(dc.remove_str(url).unwrap());
+    assert!(dc.get_str(url).unwrap().is_none());
+}
+
+#[test]
+fn percent_encoded_keys() {
+    static EVICTED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+    fn on_evict(key: &Path, _reason: EvictReason) {
+        EVICTED.lock().unwrap().push(key.to_path_buf());
+    }
+    let tmp = tempfile::TempDir::with_prefix("percent_encoded_keys").unwrap();
+    let opts = DirCacheOpts::default().with_key_codec_opt(KeyCodecOpt::PercentEncode);
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        .with_eviction_listener(EvictionListener::new(on_evict));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let url = Path::new("https://example.com/a?b=1");
+    let dots = Path::new("..");
+    dc.insert(url, b"page".as_slice()).unwrap();
+    dc.insert(dots, b"dots".as_slice()).unwrap();
+    assert_eq!(b"page", dc.get(url).unwrap().unwrap().as_ref());
+    assert!(tmp
+        .path()
+        .join("https%3A%2F%2Fexample.com%2Fa%3Fb%3D1")
+        .join("dir-cache-generation-0")
+        .exists());
+    assert!(tmp.path().join("%2E%2E").exists());
+    // String keys are escaped the same way
+    assert_eq!(
+        b"page",
+        dc.get_str("https://example.com/a?b=1")
+            .unwrap()
+            .unwrap()
+            .as_ref()
+    );
+    drop(dc);
+
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    let mut keys = dc.keys().unwrap();
+    keys.sort();
+    assert_eq!(vec![dots.to_path_buf(), url.to_path_buf()], keys);
+    let expire_all = opts.with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(1).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::ZERO),
+    ));
+    assert!(dc.keys_opt(expire_all).unwrap().is_empty());
+    // The listener gets keys as they were given
+    let mut evicted = EVICTED.lock().unwrap().clone();
+    evicted.sort();
+    assert_eq!(vec![dots.to_path_buf(), url.to_path_buf()], evicted);
+}
+
+#[test]
+fn keys_differing_in_case() {
+    let tmp = tempfile::TempDir::with_prefix("keys_differing_in_case").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let detect = DirCacheOpts::default().with_key_case_opt(KeyCaseOpt::Detect);
+    let mut dc = detect.open(tmp.path(), open_options.clone()).unwrap();
+    dc.insert(Path::new("Foo"), b"upper".as_slice()).unwrap();
+    dc.insert(Path::new("Foo"), b"again".as_slice()).unwrap();
+    assert!(matches!(
+        dc.insert(Path::new("foo"), b"lower".as_slice()),
+        Err(Error::KeyCollision(_))
+    ));
+    assert!(matches!(
+        dc.insert_str("FOO", b"str".as_slice()),
+        Err(Error::KeyCollision(_))
+    ));
+    // Directories above a key count too, until the last key in them is removed
+    dc.insert(&Path::new("Dir").join("a"), b"a".as_slice())
+        .unwrap();
+    dc.insert(&Path::new("Dir").join("b"), b"b".as_slice())
+        .unwrap();
+    assert!(matches!(
+        dc.insert(&Path::new("dir").join("c"), b"c".as_slice()),
+        Err(Error::KeyCollision(_))
+    ));
+    assert!(dc.remove(&Path::new("Dir").join("a")).unwrap());
+    assert!(matches!(
+        dc.insert(Path::new("DIR"), b"c".as_slice()),
+        Err(Error::KeyCollision(_))
+    ));
+    assert!(dc.remove(&Path::new("Dir").join("b")).unwrap());
+    dc.insert(&Path::new("dir").join("c"), b"c".as_slice())
+        .unwrap();
+    drop(dc);
+    // Found on disk without the key being known
+    let lazy = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        .with_key_discovery_opt(KeyDiscoveryOpt::Lazy);
+    let mut dc = detect.open(tmp.path(), lazy).unwrap();
+    assert!(matches!(
+        dc.insert(&Path::new("foo").join("bar"), b"nested".as_slice()),
+        Err(Error::KeyCollision(_))
+    ));
+    assert!(dc.entry_writer(Path::new("fOo")).is_err());
+    dc.insert(Path::new("Foo2"), b"other".as_slice()).unwrap();
+    drop(dc);
+
+    let fold = DirCacheOpts::default().with_key_case_opt(KeyCaseOpt::Fold);
+    let tmp = tempfile::TempDir::with_prefix("keys_differing_in_case_fold").unwrap();
+    let mut dc = fold.open(tmp.path(), open_options).unwrap();
+    dc.insert(Path::new("Foo"), b"upper".as_slice()).unwrap();
+    dc.insert(Path::new("fOO"), b"mixed".as_slice()).unwrap();
+    assert_eq!(
+        b"mixed",
+        dc.get(Path::new("foo")).unwrap().unwrap().as_ref()
+    );
+    dc.insert_str("A/B", b"str".as_slice()).unwrap();
+    assert_eq!(b"str", dc.get_str("a/b").unwrap().unwrap().as_ref());
+    let mut keys = dc.keys().unwrap();
+    keys.sort();
+    assert_eq!(vec![PathBuf::from("a%2Fb"), PathBuf::from("foo")], keys);
+}
+
+#[test]
+fn long_keys_are_hashed() {
+    static EVICTED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+    fn on_evict(key: &Path, _reason: EvictReason) {
+        EVICTED.lock().unwrap().push(key.to_path_buf());
+    }
+    let tmp = tempfile::TempDir::with_prefix("long_keys_are_hashed").unwrap();
+    let opts = DirCacheOpts::default().with_max_path_len(tmp.path().as_os_str().len() + 100);
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        .with_eviction_listener(EvictionListener::new(on_evict));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let short = Path::new("short");
+    let long = Path::new("long").join("a".repeat(80));
+    dc.insert(short, b"short".as_slice()).unwrap();
+    dc.insert(&long, b"long".as_slice()).unwrap();
+    assert!(tmp.path().join(short).exists());
+    assert!(!tmp.path().join("long").exists());
+    let hashed = std::fs::read_dir(tmp.path().join("dir-cache-hashed"))
+        .unwrap()
+        .count();
+    assert_eq!(1, hashed);
+    assert_eq!(b"long", dc.get(&long).unwrap().unwrap().as_ref());
+    // Can't write into the hashed directory directly
+    assert!(matches!(
+        dc.insert(&Path::new("dir-cache-hashed").join("k"), b"v".as_slice()),
+        Err(Error::DangerousKey(_))
+    ));
+    drop(dc);
+
+    // Found both by walking the tree and lazily
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let mut keys = dc.keys().unwrap();
+    keys.sort();
+    assert_eq!(vec![long.clone(), short.to_path_buf()], keys);
+    assert_eq!(b"long", dc.get(&long).unwrap().unwrap().as_ref());
+    drop(dc);
+    let lazy = open_options.with_key_discovery_opt(KeyDiscoveryOpt::Lazy);
+    let mut dc = opts.open(tmp.path(), lazy).unwrap();
+    assert_eq!(b"long", dc.get(&long).unwrap().unwrap().as_ref());
+    let expire_all = opts.with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(1).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::ZERO),
+    ));
+    assert!(dc.get_opt(&long, expire_all).unwrap().is_none());
+    assert_eq!(vec![long], *EVICTED.lock().unwrap());
+}
+
+#[test]
+fn key_policy_limits_keys() {
+    let tmp = tempfile::TempDir::with_prefix("key_policy_limits_keys").unwrap();
+    let policy = KeyPolicy::new()
+        .with_max_components(2)
+        .with_max_len(16)
+        .with_charset(KeyCharset::Portable);
+    let opts = DirCacheOpts::default().with_key_policy(policy);
+    let mut dc = opts
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    dc.insert(&Path::new("a").join("b.txt"), b"ok".as_slice())
+        .unwrap();
+    for refused in [
+        Path::new("a").join("b").join("c"),
+        PathBuf::from("a".repeat(17)),
+        PathBuf::from("with space"),
+        PathBuf::from("å"),
+    ] {
+        assert!(
+            matches!(
+                dc.insert(&refused, b"refused".as_slice()),
+                Err(Error::DangerousKey(_))
+            ),
+            "{refused:?}"
+        );
+        // Refused before looking for it
+        assert!(matches!(dc.get(&refused), Err(Error::DangerousKey(_))));
+    }
+    // Applied to keys as they're stored, `a%20b`
+    assert!(matches!(
+        dc.insert_str("a b", b"refused".as_slice()),
+        Err(Error::DangerousKey(_))
+    ));
+    dc.insert_str("a-b", b"ok".as_slice()).unwrap();
+    let ascii = opts.with_key_policy(KeyPolicy::new().with_charset(KeyCharset::Ascii));
+    dc.insert_opt(Path::new("with space"), b"ok".as_slice(), ascii)
+        .unwrap();
+    assert!(dc
+        .insert_opt(Path::new("a?b"), b"refused".as_slice(), ascii)
+        .is_err());
+}
+
+#[test]
+fn ttl_per_insert() {
+    let tmp = tempfile::TempDir::with_prefix("ttl_per_insert").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    // Everything expires immediately unless inserted with a ttl of its own
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::ZERO),
+    ));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    let k3 = Path::new("k3");
+    dc.insert_with_ttl(k1, b"fresh".as_slice(), Duration::from_hours(1))
+        .unwrap();
+    dc.insert(k2, b"stale".as_slice()).unwrap();
+    dc.insert_with_ttl_opt(
+        k3,
+        b"stale".as_slice(),
+        Duration::ZERO,
+        opts.with_generation_opt(GenerationOpt::default()),
+    )
+    .unwrap();
+    assert_eq!(b"fresh", dc.get(k1).unwrap().unwrap().as_ref());
+    assert!(dc.get(k2).unwrap().is_none());
+    assert!(dc
+        .get_opt(k3, opts.with_generation_opt(GenerationOpt::default()))
+        .unwrap()
+        .is_none());
+    drop(dc);
+
+    // Recorded in the manifest
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert_eq!(b"fresh", dc.get(k1).unwrap().unwrap().as_ref());
+    // Until the key is inserted without one
+    dc.insert(k1, b"stale".as_slice()).unwrap();
+    assert!(dc.get(k1).unwrap().is_none());
+    drop(dc);
+
+    // A generation expiring between others takes the older ones with it on open
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(4).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    dc.insert(k2, b"a".as_slice()).unwrap();
+    dc.insert_with_ttl(k2, b"b".as_slice(), Duration::from_millis(200))
+        .unwrap();
+    dc.insert(k2, b"c".as_slice()).unwrap();
+    dc.sync().unwrap();
+    drop(dc);
+    std::thread::sleep(Duration::from_millis(300));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    assert_eq!(1, dc.generations(k2).unwrap().count());
+    assert_eq!(b"c", dc.get(k2).unwrap().unwrap().as_ref());
+    assert!(dc.get_generation(k2, 1).unwrap().is_none());
+    dc.insert(k2, b"d".as_slice()).unwrap();
+    assert_eq!(2, dc.generations(k2).unwrap().count());
+    assert_eq!(b"c", dc.get_generation(k2, 1).unwrap().unwrap().as_slice());
+    drop(dc);
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(b"d", dc.get(k2).unwrap().unwrap().as_ref());
+    assert_eq!(b"c", dc.get_generation(k2, 1).unwrap().unwrap().as_slice());
+    assert!(dc.verify().unwrap().is_ok());
+}
+
+#[test]
+fn expires_at_a_point_in_time() {
+    let tmp = tempfile::TempDir::with_prefix("expires_at_a_point_in_time").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let future = SystemTime::now() + Duration::from_hours(1);
+    let expiring_at = |at| {
+        DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(1).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::ExpiresAt(at),
+        ))
+    };
+    let mut dc = expiring_at(future)
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    let k1 = Path::new("k1");
+    let k2 = Path::new("k2");
+    dc.insert(k1, b"v1".as_slice()).unwrap();
+    dc.insert_with_expiration(k2, b"v2".as_slice(), ExpirationOpt::ExpiresAt(past))
+        .unwrap();
+    assert_eq!(b"v1", dc.get(k1).unwrap().unwrap().as_ref());
+    assert!(dc.get(k2).unwrap().is_none());
+    dc.insert_with_expiration(k2, b"v2".as_slice(), ExpirationOpt::ExpiresAt(future))
+        .unwrap();
+    drop(dc);
+
+    // The cache's expiration has passed, except for the value with its own
+    let mut dc = expiring_at(past).open(tmp.path(), open_options).unwrap();
+    assert!(dc.get(k1).unwrap().is_none());
+    assert_eq!(b"v2", dc.get(k2).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn expires_after_idle() {
+    let tmp = tempfile::TempDir::with_prefix("expires_after_idle").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(1).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfterIdle(Duration::from_millis(600)),
+    ));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let (read, unread) = (Path::new("read"), Path::new("unread"));
+    dc.insert(read, b"read".as_slice()).unwrap();
+    dc.insert(unread, b"unread".as_slice()).unwrap();
+    std::thread::sleep(Duration::from_millis(350));
+    assert_eq!(b"read", dc.get(read).unwrap().unwrap().as_ref());
+    std::thread::sleep(Duration::from_millis(350));
+    // Written longer ago than the idle time, but read since
+    assert_eq!(b"read", dc.get(read).unwrap().unwrap().as_ref());
+    assert!(dc.get(unread).unwrap().is_none());
+    dc.sync().unwrap();
+    drop(dc);
+
+    // The last read is recorded in the manifest
+    std::thread::sleep(Duration::from_millis(350));
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(b"read", dc.get(read).unwrap().unwrap().as_ref());
+}
+
+#[test]
+fn touch_refreshes_age() {
+    let tmp = tempfile::TempDir::with_prefix("touch_refreshes_age").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(1).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(Duration::from_millis(500)),
+    ));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let (touched, untouched) = (Path::new("touched"), Path::new("untouched"));
+    dc.insert(touched, b"touched".as_slice()).unwrap();
+    dc.insert(untouched, b"untouched".as_slice()).unwrap();
+    assert!(!dc.touch(Path::new("missing")).unwrap());
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(dc.touch(touched).unwrap());
+    drop(dc);
+
+    // Recorded in the manifest
+    std::thread::sleep(Duration::from_millis(300));
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(b"touched", dc.get(touched).unwrap().unwrap().as_ref());
+    assert!(dc.get(untouched).unwrap().is_none());
+    assert!(!dc.touch(untouched).unwrap());
+}
+
+#[test]
+fn remaining_ttl_of_entries() {
+    let tmp = tempfile::TempDir::with_prefix("remaining_ttl_of_entries").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let hour = Duration::from_hours(1);
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(1).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::ExpiresAfter(hour),
+    ));
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    let (k1, k2, k3) = (Path::new("k1"), Path::new("k2"), Path::new("k3"));
+    let before = SystemTime::now();
+    dc.insert(k1, b"v1".as_slice()).unwrap();
+    dc.insert_with_expiration(k2, b"v2".as_slice(), ExpirationOpt::NoExpiry)
+        .unwrap();
+    let after = SystemTime::now();
+    let expires_at = dc.expires_at(k1).unwrap().unwrap();
+    assert!(expires_at >= before + hour && expires_at <= after + hour);
+    let remaining = dc.remaining_ttl(k1).unwrap().unwrap();
+    assert!(remaining <= hour && remaining > Duration::from_mins(59));
+    assert!(dc.expires_at(k2).unwrap().is_none());
+    assert!(dc.remaining_ttl(k3).unwrap().is_none());
+    assert!(dc
+        .remaining_ttl_opt(
+            k1,
+            opts.with_generation_opt(GenerationOpt::new(
+                NonZeroUsize::new(1).unwrap(),
+                Encoding::Plain,
+                ExpirationOpt::ExpiresAfter(Duration::ZERO),
+            ))
+        )
+        .unwrap()
+        .is_none());
+    // Removed as expired
+    assert!(dc.get(k1).unwrap().is_none());
+}
+
+#[test]
+fn last_updated_and_age() {
+    let tmp = tempfile::TempDir::with_prefix("last_updated_and_age").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    let key = Path::new("key");
+    assert!(dc.last_updated(key).unwrap().is_none());
+    assert!(dc.age(key).unwrap().is_none());
+    let before = SystemTime::now();
+    dc.insert(key, b"value".as_slice()).unwrap();
+    let after = SystemTime::now();
+    let last_updated = dc.last_updated(key).unwrap().unwrap();
+    assert!(last_updated >= before && last_updated <= after);
+    drop(dc);
+
+    // Read from the manifest
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options)
+        .unwrap();
+    assert_eq!(Some(last_updated), dc.last_updated(key).unwrap());
+    assert!(dc.age(key).unwrap().unwrap() <= after.elapsed().unwrap() + Duration::from_secs(1));
+}
+
+#[test]
+fn get_or_refresh_serves_stale() {
+    let tmp = tempfile::TempDir::with_prefix("get_or_refresh_serves_stale").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let key = Path::new("key");
+    let hour = Duration::from_hours(1);
+    // Nothing to serve, produced right away
+    let val = dc
+        .get_or_refresh(key, Duration::ZERO, || Ok::<_, Infallible>(b"v1".to_vec()))
+        .unwrap();
+    assert_eq!(b"v1", val.as_ref());
+    // Stale, served while refreshed
+    let val = dc
+        .get_or_refresh(key, Duration::ZERO, || Ok::<_, Infallible>(b"v2".to_vec()))
+        .unwrap();
+    assert_eq!(b"v1", val.as_ref());
+    assert_eq!(b"v2", dc.get(key).unwrap().unwrap().as_ref());
+    // Fresh, not refreshed
+    let val = dc
+        .get_or_refresh(key, hour, || Err::<Vec<u8>, _>("not called"))
+        .unwrap();
+    assert_eq!(b"v2", val.as_ref());
+    // A failed refresh keeps serving the stale value
+    let val = dc
+        .get_or_refresh(key, Duration::ZERO, || Err::<Vec<u8>, _>("origin down"))
+        .unwrap();
+    assert_eq!(b"v2", val.as_ref());
+    assert_eq!(b"v2", dc.get(key).unwrap().unwrap().as_ref());
+    // Unless there's nothing to serve
+    assert!(matches!(
+        dc.get_or_refresh(Path::new("missing"), hour, || Err::<Vec<u8>, _>(
+            "origin down"
+        )),
+        Err(Error::InsertWithErr(_))
+    ));
+}
+
+#[test]
+fn soft_and_hard_ttl() {
+    let tmp = tempfile::TempDir::with_prefix("soft_and_hard_ttl").unwrap();
+    let with_ttls = |soft, hard| {
+        DirCacheOpts::default().with_generation_opt(
+            GenerationOpt::new(
+                NonZeroUsize::new(1).unwrap(),
+                Encoding::Plain,
+                ExpirationOpt::ExpiresAfter(hard),
+            )
+            .with_soft_ttl(soft),
+        )
+    };
+    let hour = Duration::from_hours(1);
+    let mut dc = with_ttls(hour, hour)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let key = Path::new("key");
+    assert!(dc.get_with_freshness(key).unwrap().is_none());
+    dc.insert(key, b"value".as_slice()).unwrap();
+    let (val, freshness) = dc.get_with_freshness(key).unwrap().unwrap();
+    assert_eq!(b"value", val.as_ref());
+    assert_eq!(Freshness::Fresh, freshness);
+    // Past the soft TTL the value is still served
+    let (val, freshness) = dc
+        .get_with_freshness_opt(key, with_ttls(Duration::ZERO, hour))
+        .unwrap()
+        .unwrap();
+    assert_eq!(b"value", val.as_ref());
+    assert_eq!(Freshness::Stale, freshness);
+    // Past the hard TTL it's removed
+    assert!(dc
+        .get_with_freshness_opt(key, with_ttls(Duration::ZERO, Duration::ZERO))
+        .unwrap()
+        .is_none());
+    assert!(dc.get(key).unwrap().is_none());
+}
+
+#[test]
+fn expiry_jitter_spreads_expiration() {
+    let tmp = tempfile::TempDir::with_prefix("expiry_jitter_spreads_expiration").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let hour = Duration::from_hours(1);
+    let opts = DirCacheOpts::default().with_generation_opt(
+        GenerationOpt::new(
+            NonZeroUsize::new(1).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::ExpiresAfter(hour),
+        )
+        .with_expiry_jitter(hour),
+    );
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let keys = (0..10).map(|i| format!("k{i}")).collect::<Vec<_>>();
+    let before = SystemTime::now();
+    for key in &keys {
+        dc.insert(Path::new(key), b"value".as_slice()).unwrap();
+    }
+    let after = SystemTime::now();
+    let mut expires = Vec::new();
+    for key in &keys {
+        let expires_at = dc.expires_at(Path::new(key)).unwrap().unwrap();
+        assert!(expires_at >= before + hour && expires_at < after + hour + hour);
+        expires.push(expires_at);
+    }
+    let mut distinct = expires.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    assert!(distinct.len() > 1);
+    // Fixed points in time aren't jittered
+    let at = SystemTime::now() + hour;
+    dc.insert_with_expiration(
+        Path::new("at"),
+        b"value".as_slice(),
+        ExpirationOpt::ExpiresAt(at),
+    )
+    .unwrap();
+    assert_eq!(Some(at), dc.expires_at(Path::new("at")).unwrap());
+    drop(dc);
+
+    // Stays the same across opens
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    for (key, expires_at) in keys.iter().zip(expires) {
+        assert_eq!(Some(expires_at), dc.expires_at(Path::new(key)).unwrap());
+    }
+}
+
+#[test]
+fn prune_removes_old_entries_and_generations() {
+    let tmp = tempfile::TempDir::with_prefix("prune_removes_old_entries_and_generations").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(2).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let (old, updated, new) = (Path::new("old"), Path::new("updated"), Path::new("new"));
+    dc.insert(old, b"old".as_slice()).unwrap();
+    dc.insert(updated, b"old".as_slice()).unwrap();
+    std::thread::sleep(Duration::from_millis(250));
+    dc.insert(updated, b"new".as_slice()).unwrap();
+    dc.insert(new, b"new".as_slice()).unwrap();
+    assert!(tmp
+        .path()
+        .join(updated)
+        .join("dir-cache-generation-1")
+        .exists());
+
+    assert_eq!(1, dc.prune(Duration::from_millis(200)).unwrap());
+    assert!(!tmp.path().join(old).exists());
+    assert!(!tmp
+        .path()
+        .join(updated)
+        .join("dir-cache-generation-1")
+        .exists());
+    assert!(dc.get(old).unwrap().is_none());
+    assert_eq!(b"new", dc.get(updated).unwrap().unwrap().as_ref());
+    assert_eq!(b"new", dc.get(new).unwrap().unwrap().as_ref());
+    assert!(dc.verify().unwrap().is_ok());
+
+    assert_eq!(2, dc.prune(Duration::ZERO).unwrap());
+    assert!(dc.keys().unwrap().is_empty());
+}
+
+#[test]
+fn negative_entries() {
+    let tmp = tempfile::TempDir::with_prefix("negative_entries").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(2).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let (miss, hit) = (Path::new("miss"), Path::new("hit"));
+    assert!(matches!(dc.lookup(miss).unwrap(), Lookup::Missing));
+    dc.insert(miss, b"value".as_slice()).unwrap();
+    dc.insert_negative(miss, Duration::from_hours(1)).unwrap();
+    dc.insert(hit, b"value".as_slice()).unwrap();
+    assert!(matches!(dc.lookup(miss).unwrap(), Lookup::Negative));
+    assert!(dc.get(miss).unwrap().is_none());
+    assert!(matches!(dc.lookup(hit).unwrap(), Lookup::Value(val) if val.as_ref() == b"value"));
+    assert_eq!(vec![hit.to_path_buf()], dc.keys().unwrap());
+    drop(dc);
+
+    // Recorded in the manifest
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert!(matches!(dc.lookup(miss).unwrap(), Lookup::Negative));
+    // Produced by `get_or_insert` like any key without a value
+    assert_eq!(
+        b"value",
+        dc.get_or_insert(miss, || Ok::<_, Infallible>(b"value".to_vec()))
+            .unwrap()
+            .as_ref()
+    );
+    // Misses expire on their own
+    dc.insert_negative(miss, Duration::ZERO).unwrap();
+    assert!(matches!(dc.lookup(miss).unwrap(), Lookup::Missing));
+}
+
+#[test]
+fn stats_count_usage() {
+    let tmp = tempfile::TempDir::with_prefix("stats_count_usage").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .with_mem_pull_opt(MemPullOpt::KeepInMemoryOnRead)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let (k1, k2) = (Path::new("k1"), Path::new("k2"));
+    dc.insert(k1, b"value".as_slice()).unwrap();
+    // Read from disk, then from memory
+    assert!(dc.get(k1).unwrap().is_some());
+    assert!(dc.get(k1).unwrap().is_some());
+    assert!(dc.get(k2).unwrap().is_none());
+    dc.get_or_insert(k2, || Ok::<_, Infallible>(b"other".to_vec()))
+        .unwrap();
+    let stats = dc.stats();
+    assert_eq!(2, stats.hits());
+    assert_eq!(2, stats.misses());
+    assert_eq!(2, stats.inserts());
+    assert_eq!(0, stats.evictions());
+    assert_eq!(2, stats.disk_writes());
+    assert_eq!(10, stats.bytes_written());
+    // The produced value is read back as stored
+    assert_eq!(2, stats.disk_reads());
+    assert_eq!(10, stats.bytes_read());
+
+    dc.insert_with_ttl(k1, b"value".as_slice(), Duration::ZERO)
+        .unwrap();
+    assert!(dc.get(k1).unwrap().is_none());
+    assert_eq!(1, dc.stats().evictions());
+    dc.reset_stats();
+    assert_eq!(DirCacheStats::default(), dc.stats());
+}
+
+#[test]
+fn entry_access_stats() {
+    let tmp = tempfile::TempDir::with_prefix("entry_access_stats").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default().with_access_stats_opt(AccessStatsOpt::Persisted);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let key = Path::new("key");
+    assert!(dc.entry_meta(key).unwrap().is_none());
+    dc.insert(key, b"value".as_slice()).unwrap();
+    let meta = dc.entry_meta(key).unwrap().unwrap();
+    assert_eq!(0, meta.hits());
+    assert!(meta.last_read().is_none());
+    let before = SystemTime::now();
+    for _ in 0..3 {
+        dc.get(key).unwrap().unwrap();
+    }
+    let meta = dc.entry_meta(key).unwrap().unwrap();
+    assert_eq!(3, meta.hits());
+    let last_read = meta.last_read().unwrap();
+    assert!(last_read >= before);
+    assert_eq!(5, meta.disk_size());
+    dc.sync().unwrap();
+    drop(dc);
+
+    // Persisted in the manifest
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let meta = dc.entry_meta(key).unwrap().unwrap();
+    assert_eq!(3, meta.hits());
+    assert_eq!(Some(last_read), meta.last_read());
+    dc.get(key).unwrap().unwrap();
+    assert_eq!(4, dc.entry_meta(key).unwrap().unwrap().hits());
+    drop(dc);
+
+    // Only counted since opening otherwise
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    assert_eq!(3, dc.entry_meta(key).unwrap().unwrap().hits());
+    dc.get(key).unwrap().unwrap();
+    dc.sync().unwrap();
+    drop(dc);
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options)
+        .unwrap();
+    assert_eq!(0, dc.entry_meta(key).unwrap().unwrap().hits());
+}
+
+#[test]
+fn hooks_fire_on_operations() {
+    static EVENTS: Mutex<Vec<(&str, PathBuf, Option<u64>)>> = Mutex::new(Vec::new());
+    fn record(event: &'static str, key: &Path, meta: Option<EntryMeta>) {
+        EVENTS
+            .lock()
+            .unwrap()
+            .push((event, key.to_path_buf(), meta.map(|meta| meta.disk_size())));
+    }
+    let tmp = tempfile::TempDir::with_prefix("hooks_fire_on_operations").unwrap();
+    let hooks = Hooks::new()
+        .with_on_hit(Hook(&|key, meta| record("hit", key, meta)))
+        .with_on_miss(Hook(&|key, meta| record("miss", key, meta)))
+        .with_on_insert(Hook(&|key, meta| record("insert", key, meta)))
+        .with_on_remove(Hook(&|key, meta| record("remove", key, meta)));
+    let mut dc = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .with_hooks(hooks)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let key = Path::new("key");
+    assert!(dc.get(key).unwrap().is_none());
+    dc.insert(key, b"value".as_slice()).unwrap();
+    dc.get(key).unwrap().unwrap();
+    assert!(dc.remove(key).unwrap());
+    // Nothing to remove
+    assert!(!dc.remove(key).unwrap());
+    assert_eq!(
+        vec![
+            ("miss", key.to_path_buf(), None),
+            ("insert", key.to_path_buf(), Some(5)),
+            ("hit", key.to_path_buf(), Some(5)),
+            ("remove", key.to_path_buf(), Some(5)),
+        ],
+        *EVENTS.lock().unwrap()
+    );
+}
+
+#[test]
+fn interceptors_transform_values() {
+    #[derive(Debug)]
+    struct Envelope;
+    impl Interceptor for Envelope {
+        fn on_write(
+            &self,
+            _key: &Path,
+            content: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            Ok([b"v1:".as_slice(), content].concat())
+        }
+        fn on_read(
+            &self,
+            _key: &Path,
+            content: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            content
+                .strip_prefix(b"v1:")
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| "missing envelope".into())
+        }
+    }
+    #[derive(Debug)]
+    struct Upper;
+    impl Interceptor for Upper {
+        fn on_write(
+            &self,
+            _key: &Path,
+            content: &[u8],
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            Ok(content.to_ascii_uppercase())
+        }
+    }
+    static ENVELOPE: Envelope = Envelope;
+    static UPPER: Upper = Upper;
+    static INTERCEPTORS: [&dyn Interceptor; 2] = [&UPPER, &ENVELOPE];
+    let tmp = tempfile::TempDir::with_prefix("interceptors_transform_values").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default()
+        .with_mem_push_opt(MemPushOpt::PassthroughWrite)
+        .with_interceptors(&INTERCEPTORS);
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let key = Path::new("key");
+    dc.insert(key, b"value".as_slice()).unwrap();
+    assert_eq!(b"VALUE".as_slice(), dc.get(key).unwrap().unwrap().as_ref());
+    // Retained in memory as stored, and read through the interceptors
+    assert_eq!(b"VALUE".as_slice(), dc.get_ref(key).unwrap().unwrap());
+    assert_eq!(b"VALUE".as_slice(), dc.get_ref(key).unwrap().unwrap());
+    // Stored as intercepted
+    assert_eq!(
+        b"v1:VALUE".as_slice(),
+        std::fs::read(dc.get_path(key).unwrap().unwrap())
+            .unwrap()
+            .as_slice()
+    );
+    assert_eq!(
+        b"INSERTED".as_slice(),
+        dc.get_or_insert(Path::new("other"), || Ok::<_, Infallible>(
+            b"inserted".to_vec()
+        ))
+        .unwrap()
+        .as_ref()
+    );
+    drop(dc);
+    // Reading without the envelope in the chain leaves it in place
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    assert_eq!(
+        b"v1:VALUE".as_slice(),
+        dc.get(key).unwrap().unwrap().as_ref()
+    );
+    dc.insert(key, b"bare".as_slice()).unwrap();
+    drop(dc);
+    // And a value without it fails the read
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert!(matches!(dc.get(key), Err(Error::Intercept(_))));
+}
+
+#[test]
+fn get_with_meta_of_entries() {
+    let tmp = tempfile::TempDir::with_prefix("get_with_meta_of_entries").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let key = Path::new("key");
+    assert!(dc.get_with_meta(key).unwrap().is_none());
+    dc.insert(key, b"value".as_slice()).unwrap();
+    let ValueWithMeta {
+        value,
+        meta,
+        source,
+    } = dc.get_with_meta(key).unwrap().unwrap();
+    assert_eq!(b"value".as_slice(), value.as_ref());
+    assert_eq!(ValueSource::Disk, source);
+    assert_eq!(1, meta.hits());
+    assert_eq!(5, meta.disk_size());
+    assert_eq!(1, meta.generations());
+    assert_eq!(Some(Encoding::Plain), meta.encoding());
+    assert!(meta.age() < Duration::from_mins(1));
+    // Kept in memory after the first read
+    let ValueWithMeta {
+        value,
+        meta,
+        source,
+    } = dc.get_with_meta(key).unwrap().unwrap();
+    assert_eq!(b"value".as_slice(), value.as_ref());
+    assert_eq!(ValueSource::Memory, source);
+    assert_eq!(2, meta.hits());
+    assert_eq!(Some(5), meta.in_memory_size());
+    // Not on disk until synced
+    dc.insert_opt(
+        key,
+        b"other".to_vec(),
+        dc.opts().with_mem_push_opt(MemPushOpt::MemoryOnly),
+    )
+    .unwrap();
+    let read = dc.get_with_meta(key).unwrap().unwrap();
+    assert_eq!(ValueSource::Memory, read.source);
+    assert!(read.meta.encoding().is_none());
+}
+
+#[test]
+fn iter_meta_without_reading_values() {
+    let tmp = tempfile::TempDir::with_prefix("iter_meta_without_reading_values").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options.clone())
+        .unwrap();
+    dc.insert(Path::new("a"), b"a".as_slice()).unwrap();
+    dc.insert(Path::new("b/c"), b"bc".as_slice()).unwrap();
+    dc.insert(Path::new("b/c"), b"bcd".as_slice()).unwrap();
+    dc.insert_negative(Path::new("missing"), Duration::from_hours(1))
+        .unwrap();
+    drop(dc);
+    let mut dc = DirCacheOpts::default()
+        .open(tmp.path(), open_options)
+        .unwrap();
+    let mut metas = dc
+        .iter_meta()
+        .unwrap()
+        .map(|(key, meta)| (key, meta.disk_size(), meta.generations()))
+        .collect::<Vec<_>>();
+    metas.sort();
+    assert_eq!(
+        vec![(PathBuf::from("a"), 1, 1), (PathBuf::from("b/c"), 3, 1)],
+        metas
+    );
+    assert_eq!(0, dc.stats().disk_reads());
+}
+
+#[test]
+fn get_older_generations() {
+    let tmp = tempfile::TempDir::with_prefix("get_older_generations").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(
+            GenerationOpt::new(
+                NonZeroUsize::new(3).unwrap(),
+                Encoding::Plain,
+                ExpirationOpt::NoExpiry,
+            )
+            .with_delta_generations(true),
+        )
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let gen2 = (0..4_096u32)
+        .flat_map(|i| i.wrapping_mul(2_654_435_761).to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut gen1 = gen2.clone();
+    gen1[1_000..1_004].copy_from_slice(b"gen1");
+    let mut gen0 = gen1.clone();
+    gen0[2_000..2_004].copy_from_slice(b"gen0");
+    let my_key = dummy_key();
+    assert!(dc.get_generation(my_key, 0).unwrap().is_none());
+    for val in [&gen2, &gen1, &gen0] {
+        dc.insert(my_key, val.clone()).unwrap();
+    }
+    assert_eq!(Some(&gen0), dc.get_generation(my_key, 0).unwrap().as_ref());
+    // Stored as deltas against the newer generations
+    assert_eq!(Some(&gen1), dc.get_generation(my_key, 1).unwrap().as_ref());
+    assert_eq!(Some(&gen2), dc.get_generation(my_key, 2).unwrap().as_ref());
+    assert!(dc.get_generation(my_key, 3).unwrap().is_none());
+    // A value only in memory is ahead of the generations on disk
+    dc.insert_opt(
+        my_key,
+        b"in memory".to_vec(),
+        dc.opts().with_mem_push_opt(MemPushOpt::MemoryOnly),
+    )
+    .unwrap();
+    assert_eq!(
+        Some(b"in memory".to_vec()),
+        dc.get_generation(my_key, 0).unwrap()
+    );
+    assert_eq!(Some(&gen0), dc.get_generation(my_key, 1).unwrap().as_ref());
+}
+
+#[test]
+fn list_generations() {
+    let tmp = tempfile::TempDir::with_prefix("list_generations").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(3).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    assert_eq!(0, dc.generations(my_key).unwrap().count());
+    for val in ["oldest", "older", "new"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    let generations = dc.generations(my_key).unwrap().collect::<Vec<_>>();
+    assert_eq!(
+        vec![(0, 3), (1, 5), (2, 6)],
+        generations
+            .iter()
+            .map(|gen| (gen.index(), gen.size()))
+            .collect::<Vec<_>>()
+    );
+    assert!(generations
+        .windows(2)
+        .all(|pair| pair[0].written() >= pair[1].written()));
+    assert!(generations
+        .iter()
+        .all(|gen| gen.encoding() == Some(Encoding::Plain)));
+    for gen in generations {
+        assert_eq!(
+            gen.size(),
+            dc.get_generation(my_key, gen.index())
+                .unwrap()
+                .unwrap()
+                .len() as u64
+        );
+    }
+}
+
+#[test]
+fn inspect_without_opening() {
+    let tmp = tempfile::TempDir::with_prefix("inspect_without_opening").unwrap();
+    let generation_opt = GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    );
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(generation_opt)
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    for val in ["older", "new"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    let dir = tmp.path().join(my_key);
+    assert!(inspect::read_entry(tmp.path(), &generation_opt)
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        vec![dir.clone()],
+        inspect::entry_dirs(
+            tmp.path(),
+            &CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false)
+        )
+        .unwrap()
+    );
+    let entry = inspect::read_entry(&dir, &generation_opt).unwrap().unwrap();
+    assert!(entry.key().is_none());
+    assert_eq!(
+        vec![(0, Some(3)), (1, Some(5))],
+        entry
+            .generations()
+            .iter()
+            .map(|gen| (gen.index(), gen.size()))
+            .collect::<Vec<_>>()
+    );
+    assert!(entry
+        .generations()
+        .iter()
+        .all(|gen| gen.encoding() == Encoding::Plain
+            && !gen.is_negative()
+            && !gen.expired(generation_opt).unwrap()));
+    assert_eq!(
+        vec![
+            (Some(0), dir.join("dir-cache-generation-0")),
+            (Some(1), dir.join("dir-cache-generation-1"))
+        ],
+        inspect::generation_files(&dir).unwrap()
+    );
+    assert!(entry
+        .generations()
+        .iter()
+        .all(|gen| gen.recorded_size() == gen.size()));
+    // A missing file is reported rather than failing
+    std::fs::remove_file(dir.join("dir-cache-generation-1")).unwrap();
+    let entry = inspect::read_entry(&dir, &generation_opt).unwrap().unwrap();
+    assert_eq!(None, entry.generations()[1].size());
+}
+
+#[test]
+fn prune_generations_of_key() {
+    let tmp = tempfile::TempDir::with_prefix("prune_generations_of_key").unwrap();
+    let mut dc = DirCacheOpts::default()
+        .with_generation_opt(GenerationOpt::new(
+            NonZeroUsize::new(4).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        ))
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    let my_key = dummy_key();
+    assert_eq!(0, dc.prune_generations(my_key, 1).unwrap());
+    for val in ["gen3", "gen2", "gen1", "gen0"] {
+        dc.insert(my_key, val.as_bytes().to_vec()).unwrap();
+    }
+    assert_eq!(2, dc.prune_generations(my_key, 2).unwrap());
+    assert_eq!(2, dc.generations(my_key).unwrap().count());
+    assert_eq!(
+        Some(b"gen1".to_vec()),
+        dc.get_generation(my_key, 1).unwrap()
+    );
+    let path = tmp.path().join(my_key);
+    assert!(path.join("dir-cache-generation-1").exists());
+    assert!(!path.join("dir-cache-generation-2").exists());
+    // Nothing more to prune
+    assert_eq!(0, dc.prune_generations(my_key, 2).unwrap());
+    drop(dc);
+    let mut dc = DirCacheOpts::default()
+        .open(
+            tmp.path(),
+            CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+        )
+        .unwrap();
+    assert_eq!(2, dc.generations(my_key).unwrap().count());
+    assert_eq!(2, dc.prune_generations(my_key, 0).unwrap());
+    assert!(dc.get(my_key).unwrap().is_none());
+    assert!(!path.exists());
+}
+
+#[test]
+fn generation_labels() {
+    let tmp = tempfile::TempDir::with_prefix("generation_labels").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default().with_generation_opt(GenerationOpt::new(
+        NonZeroUsize::new(3).unwrap(),
+        Encoding::Plain,
+        ExpirationOpt::NoExpiry,
+    ));
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let my_key = dummy_key();
+    let labels = |dc: &mut DirCache| {
+        dc.generations(my_key)
+            .unwrap()
+            .map(|gen| gen.label().map(|label| label.to_string()))
+            .collect::<Vec<_>>()
+    };
+    dc.insert_labeled(my_key, b"first".as_slice(), Label::new("etag-1").unwrap())
+        .unwrap();
+    dc.insert(my_key, b"second".as_slice()).unwrap();
+    dc.insert_labeled(my_key, b"third".as_slice(), Label::new("etag-3").unwrap())
+        .unwrap();
+    let expected = vec![Some("etag-3".to_string()), None, Some("etag-1".to_string())];
+    assert_eq!(expected, labels(&mut dc));
+    drop(dc);
+    // Recorded in the manifest
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    assert_eq!(expected, labels(&mut dc));
+    assert_eq!(b"third", dc.get(my_key).unwrap().unwrap().as_ref());
+    let max = "a".repeat(MAX_LABEL_LEN);
+    assert_eq!(max, Label::new(&max).unwrap().as_str());
+    assert!(matches!(
+        Label::new(&format!("{max}a")),
+        Err(Error::InvalidLabel(_))
+    ));
+}
+
+#[test]
+fn insert_overwriting_current_generation() {
+    let tmp = tempfile::TempDir::with_prefix("insert_overwriting_current_generation").unwrap();
+    for journal in [false, true] {
+        let opts = DirCacheOpts::default()
+            .with_journal(journal)
+            .with_generation_opt(
+                GenerationOpt::new(
+                    NonZeroUsize::new(3).unwrap(),
+                    Encoding::Plain,
+                    ExpirationOpt::NoExpiry,
+                )
+                .with_delta_generations(true),
+            );
+        let overwrite = opts.with_insert_mode(InsertMode::Overwrite);
+        let mut dc = opts
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            )
+            .unwrap();
+        let my_key = dummy_key();
+        // Nothing to overwrite
+        dc.insert_opt(my_key, b"first".to_vec(), overwrite).unwrap();
+        assert_eq!(1, dc.generations(my_key).unwrap().count());
+        let gen1 = (0..4_096u32)
+            .flat_map(|i| i.wrapping_mul(2_654_435_761).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut gen0 = gen1.clone();
+        gen0[1_000..1_004].copy_from_slice(b"gen0");
+        dc.insert(my_key, gen1.clone()).unwrap();
+        dc.insert(my_key, gen0.clone()).unwrap();
+        assert_eq!(3, dc.generations(my_key).unwrap().count());
+        let mut fixed = gen0.clone();
+        fixed[2_000..2_005].copy_from_slice(b"fixed");
+        dc.insert_opt(my_key, fixed.clone(), overwrite).unwrap();
+        assert_eq!(3, dc.generations(my_key).unwrap().count());
+        assert_eq!(&fixed, dc.get(my_key).unwrap().unwrap().as_ref());
+        // Was a delta against the overwritten value
+        assert_eq!(Some(&gen1), dc.get_generation(my_key, 1).unwrap().as_ref());
+        assert_eq!(
+            Some(b"first".to_vec()),
+            dc.get_generation(my_key, 2).unwrap()
+        );
+        drop(dc);
+        let mut dc = opts
+            .open(
+                tmp.path(),
+                CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false),
+            )
+            .unwrap();
+        assert_eq!(Some(&gen1), dc.get_generation(my_key, 1).unwrap().as_ref());
+        assert!(dc.verify().unwrap().issues().is_empty());
+        assert!(dc.remove(my_key).unwrap());
+    }
+}
+
+#[test]
+fn skip_unchanged_values() {
+    let tmp = tempfile::TempDir::with_prefix("skip_unchanged_values").unwrap();
+    let open_options = CacheOpenOptions::new(DirOpenOpt::OnlyIfExists, false);
+    let opts = DirCacheOpts::default().with_generation_opt(
+        GenerationOpt::new(
+            NonZeroUsize::new(3).unwrap(),
+            Encoding::Plain,
+            ExpirationOpt::NoExpiry,
+        )
+        .with_skip_unchanged(true),
+    );
+    let mut dc = opts.open(tmp.path(), open_options.clone()).unwrap();
+    let my_key = dummy_key();
+    dc.insert(my_key, b"old".as_slice()).unwrap();
+    dc.insert(my_key, b"value".as_slice()).unwrap();
+    let written = dc.last_updated(my_key).unwrap().unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    dc.insert(my_key, b"value".as_slice()).unwrap();
+    assert_eq!(2, dc.generations(my_key).unwrap().count());
+    assert!(dc.last_updated(my_key).unwrap().unwrap() > written);
+    assert_eq!(Some(b"old".to_vec()), dc.get_generation(my_key, 1).unwrap());
+    // Metadata is still recorded
+    dc.insert_labeled(my_key, b"value".as_slice(), Label::new("etag").unwrap())
+        .unwrap();
+    assert_eq!(2, dc.generations(my_key).unwrap().count());
+    drop(dc);
+    let mut dc = opts.open(tmp.path(), open_options).unwrap();
+    let current = dc.generations(my_key).unwrap().next().unwrap();
+    assert_eq!("etag", current.label().unwrap().as_str());
+    assert!(current.written() > written);
+    // Compared to the value in memory as well
+    dc.get(my_key).unwrap().unwrap();
+    dc.insert(my_key, b"value".as_slice()).unwrap();
+    assert_eq!(2, dc.generations(my_key).unwrap().count());
+    dc.insert(my_key, b"changed".as_slice()).unwrap();
+    assert_eq!(3, dc.generations(my_key).unwrap().count());
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ExpectedDiskObject {
+    File,
+    Dir,
+}
+
+fn assert_empty_dir_at(path: &Path) {
+    let mut seen = HashSet::new();
+    for e in std::fs::read_dir(path).unwrap() {
+        let entry = e.unwrap();
+        seen.insert(entry.path());
+    }
+    assert!(
+        seen.is_empty(),
+        "Expected an empty dir, found entries: {seen:?}"
+    );
+}
+
+fn assert_dir_at(path: &Path) {
+    let p = check_path(path).expect("Expected dir, found nothing");
+    assert_eq!(ExpectedDiskObject::Dir, p, "Wanted dir, found file");
+}
+
+fn assert_file_at(path: &Path) {
+    let p = check_path(path).expect("Expected file, found nothing");
+    assert_eq!(ExpectedDiskObject::File, p, "Wanted file, found dir");
+}
+
+fn check_path(path: &Path) -> Option<ExpectedDiskObject> {
+    match std::fs::metadata(path) {
+        Ok(m) => {
+            if m.is_file() {
+                return Some(ExpectedDiskObject::File);
+            }
+            if m.is_dir() {
+                return Some(ExpectedDiskObject::Dir);
+            }
+            panic!("Unexpected disk object at {m:?}");
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            panic!("Failed to check path: {e}");
+        }
+    }
+}
+
+fn in_all_opts_context<
+    UserFn: FnMut(Box<dyn Fn(&Path) -> DirCache>, DirCacheOpts),
+    UserFilterFn: Fn(&DirCacheOpts, &CacheOpenOptions) -> bool,
+>(
+    num_generations: usize,
+    filter: UserFilterFn,
+    mut user_fn: UserFn,
+) {
+    for mem_pull in [
+        MemPullOpt::DontKeepInMemoryOnRead,
+        MemPullOpt::KeepInMemoryOnRead,
     ] {
         for mem_push in [
             MemPushOpt::MemoryOnly,
@@ -670,7 +5275,7 @@ fn in_all_opts_context<
                                 let cache_open_opts = CacheOpenOptions::new(dir_open, eager);
                                 if filter(&opts, &cache_open_opts) {
                                     let this_fn = Box::new(move |path: &Path| {
-                                        opts.open(path, cache_open_opts).unwrap()
+                                        opts.open(path, cache_open_opts.clone()).unwrap()
                                     });
                                     user_fn(this_fn, opts);
                                 }
@@ -697,8 +5302,17 @@ fn all_files_in(path: &Path) -> HashSet<PathBuf> {
 
 #[cfg(feature = "lz4")]
 fn encode(content: &[u8]) -> Vec<u8> {
+    let mut encoder = lz4::EncoderBuilder::new().build(Vec::new()).unwrap();
+    std::io::Write::write_all(&mut encoder, content).unwrap();
+    let (buf, res) = encoder.finish();
+    res.unwrap();
+    buf
+}
+
+#[cfg(feature = "lz4")]
+fn decode(content: &[u8]) -> Vec<u8> {
+    let mut decoder = lz4::Decoder::new(content).unwrap();
     let mut buf = Vec::new();
-    let mut encoder = lz4::EncoderBuilder::new().build(&mut buf).unwrap();
-    std::io::Write::write(&mut encoder, &content).unwrap();
+    std::io::Read::read_to_end(&mut decoder, &mut buf).unwrap();
     buf
 }